@@ -0,0 +1,355 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use flate2::bufread::MultiGzDecoder;
+use windows::Win32::Storage::ProjectedFileSystem;
+
+use crate::projfs_provider::{compare_file_names, filetime, ProjFSProvider, EnumerationState, ListEnumeration, SeekRead, VirtualizationOptions, VirtualTree, EntryInfo, WindowReader, BlockCache, DecompressingReader};
+use crate::tar_provider::{build_tree, scan, EntryKind, Streamed};
+
+const BLOCK_SIZE: u64 = 64 * 1024;
+const CACHED_BLOCKS: usize = 256;
+/// Crates whose listing is kept, the least recently used one is read again when it's needed
+const OPEN_CRATES: usize = 64;
+
+struct CrateFile {
+    /// `name-version`, what cargo would extract it to
+    name: OsString,
+    path: PathBuf,
+    info: EntryInfo,
+}
+
+/// What's inside a crate, read the first time anything below it is asked for
+struct CrateContents {
+    tree: VirtualTree<Option<u64>>,
+    /// Of the tar inside, which is what offsets point into
+    length: u64,
+}
+
+struct OpenCrate {
+    contents: Arc<CrateContents>,
+    last_used: u64,
+}
+
+/// Keyed by the crate's index
+#[derive(Default)]
+struct OpenCrates {
+    crates: HashMap<usize, OpenCrate>,
+    clock: u64,
+}
+
+enum Found {
+    Root,
+    Crate(usize),
+    Entry(usize, Arc<CrateContents>, usize),
+}
+
+/// Projects the `.crate` files in a cargo registry cache as the source directories cargo would
+/// unpack them to. The listing of crates is read once, the tarballs only when their directory is
+/// looked into, and file data is decompressed as it's read.
+pub struct CargoProvider {
+    cache_directory: PathBuf,
+    // In ProjFS order, there is one cache directory per registry and the first one has a crate wins
+    crates: Vec<CrateFile>,
+    contents: Mutex<OpenCrates>,
+    blocks: Arc<BlockCache>,
+}
+
+/// `$CARGO_HOME/registry/cache`, where cargo keeps the crates it downloaded
+fn default_cache_directory() -> Option<PathBuf> {
+    let cargo_home = match std::env::var_os("CARGO_HOME") {
+        Some(home) => PathBuf::from(home),
+        None => PathBuf::from(std::env::var_os("USERPROFILE").or_else(|| std::env::var_os("HOME"))?).join(".cargo"),
+    };
+    Some(cargo_home.join("registry").join("cache"))
+}
+
+fn read_contents(crate_file: &CrateFile) -> Result<CrateContents, Box<dyn std::error::Error>> {
+    let mut stream = Streamed::new(MultiGzDecoder::new(BufReader::new(File::open(&crate_file.path)?)));
+    let mut entries = scan(&mut stream)?;
+    // Whatever follows the end marker still counts for the length
+    std::io::copy(&mut stream, &mut std::io::sink())?;
+
+    // Everything is in a `name-version` directory, which is the crate's own directory here
+    entries.retain_mut(|entry| {
+        let path = match entry.path.split_once('/') {
+            Some((_, path)) => path.to_string(),
+            None => {
+                return false;
+            }
+        };
+        entry.path = path;
+        if entry.kind == EntryKind::HardLink {
+            entry.link = entry.link.as_ref().and_then(|l| l.split_once('/')).map(|(_, l)| l.to_string());
+        }
+        true
+    });
+    Ok(CrateContents {
+        tree: build_tree(&entries),
+        length: stream.position,
+    })
+}
+
+impl CargoProvider {
+    pub fn new(cache_directory: Option<&Path>) -> Result<CargoProvider, Box<dyn std::error::Error>> {
+        let cache_directory = match cache_directory {
+            Some(d) => d.to_path_buf(),
+            None => default_cache_directory().ok_or("Can't tell where the cargo home is, set CARGO_HOME")?,
+        };
+        Ok(CargoProvider {
+            cache_directory,
+            crates: Vec::new(),
+            contents: Mutex::new(OpenCrates::default()),
+            blocks: Arc::new(BlockCache::new(BLOCK_SIZE, CACHED_BLOCKS)),
+        })
+    }
+
+    fn crate_contents(&self, index: usize) -> Result<Arc<CrateContents>, windows::core::HRESULT> {
+        {
+            let mut open = self.contents.lock().unwrap();
+            open.clock += 1;
+            let clock = open.clock;
+            if let Some(open) = open.crates.get_mut(&index) {
+                open.last_used = clock;
+                return Ok(open.contents.clone());
+            }
+        }
+        let crate_file = &self.crates[index];
+        let contents = Arc::new(read_contents(crate_file).map_err(|e| {
+            println!("Could not read {:?}: {e}", crate_file.path);
+            windows::Win32::Foundation::E_FAIL
+        })?);
+        let mut open = self.contents.lock().unwrap();
+        if open.crates.len() >= OPEN_CRATES && !open.crates.contains_key(&index) {
+            // Few enough that finding the least recently used one by walking is fine
+            if let Some(oldest) = open.crates.iter().min_by_key(|(_, c)| c.last_used).map(|(i, _)| *i) {
+                open.crates.remove(&oldest);
+            }
+        }
+        open.clock += 1;
+        let last_used = open.clock;
+        open.crates.insert(index, OpenCrate {
+            contents: contents.clone(),
+            last_used,
+        });
+        Ok(contents)
+    }
+
+    fn lookup(&self, file_path: &Path) -> Result<Option<Found>, windows::core::HRESULT> {
+        let mut components = file_path.components();
+        let name = match components.next() {
+            Some(Component::Normal(name)) => name,
+            Some(_) => {
+                return Ok(None);
+            }
+            None => {
+                return Ok(Some(Found::Root));
+            }
+        };
+        let index = match self.crates.binary_search_by(|c| compare_file_names(&c.name, name)) {
+            Ok(i) => i,
+            Err(_) => {
+                return Ok(None);
+            }
+        };
+        let rest = components.as_path();
+        if rest.as_os_str().is_empty() {
+            return Ok(Some(Found::Crate(index)));
+        }
+        let contents = self.crate_contents(index)?;
+        Ok(contents.tree.lookup(rest).map(|id| Found::Entry(index, contents.clone(), id)))
+    }
+}
+
+impl ProjFSProvider for CargoProvider {
+    fn init(&mut self, _root: &Path) -> Result<VirtualizationOptions, Box<dyn std::error::Error>> {
+        let registries = fs::read_dir(&self.cache_directory).map_err(|e| format!("{:?}: {e}", self.cache_directory))?;
+        for registry in registries {
+            let registry = registry?.path();
+            if !registry.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(&registry)? {
+                let entry = entry?;
+                let path = entry.path();
+                let name = match path.file_stem() {
+                    Some(name) if path.extension() == Some("crate".as_ref()) => name.to_os_string(),
+                    _ => {
+                        continue;
+                    }
+                };
+                let time = entry.metadata()?.modified().map(filetime::from_system_time).unwrap_or(0);
+                self.crates.push(CrateFile {
+                    name,
+                    path,
+                    info: EntryInfo::directory(time),
+                });
+            }
+        }
+        self.crates.sort_by(|a, b| compare_file_names(&a.name, &b.name));
+        self.crates.dedup_by(|b, a| compare_file_names(&a.name, &b.name) == std::cmp::Ordering::Equal);
+        println!("{} crates in {:?}", self.crates.len(), self.cache_directory);
+        Ok(VirtualizationOptions::default())
+    }
+
+    fn start(&mut self, _context: ProjectedFileSystem::PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn new_enumeration(&self, _id: windows::core::GUID, file_path: &Path) -> Box<dyn EnumerationState> {
+        let mut components = file_path.components();
+        components.next();
+        match self.lookup(file_path) {
+            Ok(Some(Found::Root)) => Box::new(ListEnumeration::new(self.crates.iter().map(|c| (c.name.clone(), c.info.basic_info())).collect())),
+            Ok(Some(Found::Crate(index) | Found::Entry(index, _, _))) => match self.crate_contents(index) {
+                Ok(contents) => contents.tree.enumeration(components.as_path()),
+                Err(_) => Box::new(ListEnumeration::new(Vec::new())),
+            },
+            _ => Box::new(ListEnumeration::new(Vec::new())),
+        }
+    }
+
+    fn get_placeholder_info(&self, file_path: &Path) -> Result<ProjectedFileSystem::PRJ_PLACEHOLDER_INFO, windows::core::HRESULT> {
+        match self.lookup(file_path)? {
+            Some(Found::Root) => Ok(EntryInfo::directory(0).placeholder_info()),
+            Some(Found::Crate(index)) => Ok(self.crates[index].info.placeholder_info()),
+            Some(Found::Entry(_, contents, id)) => Ok(contents.tree.node(id).info.placeholder_info()),
+            None => Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into()),
+        }
+    }
+
+    fn get_file_data(&self, file_path: &Path) -> Result<Box<dyn SeekRead>, windows::core::HRESULT> {
+        let (index, contents, offset, size) = match self.lookup(file_path)? {
+            Some(Found::Entry(index, contents, id)) => match contents.tree.node(id).content {
+                Some(offset) => {
+                    let size = contents.tree.node(id).info.size;
+                    (index, contents, offset, size)
+                }
+                None => {
+                    return Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into());
+                }
+            },
+            _ => {
+                return Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into());
+            }
+        };
+        let path = self.crates[index].path.clone();
+        let open = move || -> std::io::Result<Box<dyn Read + Send>> {
+            Ok(Box::new(MultiGzDecoder::new(BufReader::new(File::open(&path)?))))
+        };
+        let tar = DecompressingReader::new(self.blocks.clone(), index as u64, contents.length, Box::new(open));
+        Ok(Box::new(WindowReader::new(tar, offset, size)))
+    }
+
+    fn query_file_name(&self, file_path: &Path) -> windows::core::HRESULT {
+        match self.lookup(file_path) {
+            Ok(Some(_)) => windows::Win32::Foundation::S_OK,
+            Ok(None) => windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into(),
+            Err(e) => e,
+        }
+    }
+
+    fn notification(&self, _callbackdata: *const ProjectedFileSystem::PRJ_CALLBACK_DATA, _is_directory: bool, _notification: ProjectedFileSystem::PRJ_NOTIFICATION, _destinationfilename: windows::core::PCWSTR, _operationparameters: *mut ProjectedFileSystem::PRJ_NOTIFICATION_PARAMETERS) -> windows::core::HRESULT {
+        windows::Win32::Foundation::S_OK
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::tar_provider::tests::Archive;
+
+    /// A registry cache in a temporary directory, removed again when the test is done
+    struct Registries(PathBuf);
+
+    impl Registries {
+        fn new(name: &str) -> Registries {
+            let path = std::env::temp_dir().join(format!("projfs-cargo-{}-{name}", std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            Registries(path)
+        }
+
+        fn add(&self, registry: &str, file: &str, archive: Archive) {
+            let directory = self.0.join(registry);
+            fs::create_dir_all(&directory).unwrap();
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(&archive.finish()).unwrap();
+            fs::write(directory.join(file), encoder.finish().unwrap()).unwrap();
+        }
+
+        fn provider(&self) -> CargoProvider {
+            let mut provider = CargoProvider::new(Some(&self.0)).unwrap();
+            provider.init(&self.0).unwrap();
+            provider
+        }
+    }
+
+    impl Drop for Registries {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn read(provider: &CargoProvider, path: &str) -> Vec<u8> {
+        let mut data = Vec::new();
+        provider.get_file_data(Path::new(path)).ok().unwrap().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn projects_crates_as_their_source_directories() {
+        let registries = Registries::new("layout");
+        let serde = || Archive::default()
+            .file("serde-1.0.0/Cargo.toml", b"[package]")
+            .file("serde-1.0.0/src/lib.rs", b"pub fn serde() {}")
+            .entry(b'1', "serde-1.0.0/src/copy.rs", "serde-1.0.0/src/lib.rs", b"")
+            .file("stray.txt", b"outside the crate's directory");
+        registries.add("index.crates.io-6f17d22bba15001f", "serde-1.0.0.crate", serde());
+        registries.add("index.crates.io-6f17d22bba15001f", "notes.txt", Archive::default());
+        registries.add("other-registry", "serde-1.0.0.crate", serde());
+        registries.add("other-registry", "log-0.4.0.crate", Archive::default().file("log-0.4.0/src/lib.rs", b"log"));
+        let provider = registries.provider();
+
+        // One directory per crate, whichever registries have it
+        let names: Vec<&OsString> = provider.crates.iter().map(|c| &c.name).collect();
+        assert_eq!(names, ["log-0.4.0", "serde-1.0.0"]);
+        assert_eq!(read(&provider, "serde-1.0.0/src/lib.rs"), b"pub fn serde() {}");
+        assert_eq!(read(&provider, "Serde-1.0.0/src/copy.rs"), b"pub fn serde() {}");
+        assert!(provider.lookup(Path::new("serde-1.0.0/Cargo.toml")).unwrap().is_some());
+        assert!(provider.lookup(Path::new("serde-1.0.0/stray.txt")).unwrap().is_none());
+        assert!(provider.lookup(Path::new("serde-1.0.0/serde-1.0.0")).unwrap().is_none());
+        assert_eq!(read(&provider, "log-0.4.0/src/lib.rs"), b"log");
+        assert!(matches!(provider.lookup(Path::new("log-0.4.0")), Ok(Some(Found::Crate(0)))));
+        assert!(provider.lookup(Path::new("notes")).unwrap().is_none());
+    }
+
+    #[test]
+    fn keeps_the_listings_of_recently_used_crates() {
+        let registries = Registries::new("open");
+        for i in 0..OPEN_CRATES + 1 {
+            registries.add("registry", &format!("crate{i:03}-1.0.0.crate"), Archive::default().file(&format!("crate{i:03}-1.0.0/lib.rs"), b""));
+        }
+        let provider = registries.provider();
+        for i in 0..OPEN_CRATES + 1 {
+            assert!(provider.lookup(Path::new(&format!("crate{i:03}-1.0.0/lib.rs"))).unwrap().is_some());
+            // The first crate stays in use
+            provider.crate_contents(0).ok().unwrap();
+        }
+        let open = provider.contents.lock().unwrap();
+        assert_eq!(open.crates.len(), OPEN_CRATES);
+        assert!(open.crates.contains_key(&0));
+        assert!(!open.crates.contains_key(&1));
+        assert!(open.crates.contains_key(&OPEN_CRATES));
+    }
+}
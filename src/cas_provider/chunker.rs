@@ -0,0 +1,161 @@
+use std::io::Read;
+
+/// Content defined chunking, cut points depend on the bytes around them and not on where they
+/// are, so an insertion only changes the chunks around it. This is FastCDC: a gear rolling hash
+/// with a stricter mask before the average size and a looser one after it, which keeps chunk
+/// sizes close to the average.
+const MIN_SIZE: usize = 16 * 1024;
+const AVERAGE_SIZE: usize = 64 * 1024;
+const MAX_SIZE: usize = 256 * 1024;
+/// Two bits more and two bits less than the 16 the average size takes. The high bits are used
+/// because with a left shifting hash they depend on the most bytes.
+const MASK_SMALL: u64 = !0 << (64 - 18);
+const MASK_LARGE: u64 = !0 << (64 - 14);
+
+/// Random values for every byte, from splitmix64 so they don't have to be spelled out
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x6a09e667f3bcc908;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Length of the chunk at the start of `data`, which has to hold `MAX_SIZE` bytes unless the input ends sooner
+fn cut_point(data: &[u8]) -> usize {
+    if data.len() <= MIN_SIZE {
+        return data.len();
+    }
+    let end = std::cmp::min(data.len(), MAX_SIZE);
+    let normal = std::cmp::min(end, AVERAGE_SIZE);
+    let mut hash = 0u64;
+    for (i, b) in data.iter().enumerate().take(end).skip(MIN_SIZE) {
+        hash = (hash << 1).wrapping_add(GEAR[*b as usize]);
+        let mask = if i < normal { MASK_SMALL } else { MASK_LARGE };
+        if hash & mask == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+pub struct Chunker<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    done: bool,
+}
+
+impl<R: Read> Chunker<R> {
+    pub fn new(reader: R) -> Chunker<R> {
+        Chunker {
+            reader,
+            buffer: Vec::with_capacity(MAX_SIZE),
+            done: false,
+        }
+    }
+
+    pub fn next_chunk(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        while !self.done && self.buffer.len() < MAX_SIZE {
+            let start = self.buffer.len();
+            self.buffer.resize(MAX_SIZE, 0);
+            let read = match self.reader.read(&mut self.buffer[start..]) {
+                Ok(read) => read,
+                Err(e) => {
+                    self.buffer.truncate(start);
+                    if e.kind() == std::io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return Err(e);
+                }
+            };
+            self.buffer.truncate(start + read);
+            if read == 0 {
+                self.done = true;
+            }
+        }
+        if self.buffer.is_empty() {
+            return Ok(None);
+        }
+        let cut = cut_point(&self.buffer);
+        Ok(Some(self.buffer.drain(..cut).collect()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Random bytes that are the same every run
+    fn random(length: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..length)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    /// Hands out at most 1000 bytes a read, chunks mustn't depend on how reads are split up
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let length = std::cmp::min(std::cmp::min(buf.len(), 1000), self.0.len());
+            buf[..length].copy_from_slice(&self.0[..length]);
+            self.0 = &self.0[length..];
+            Ok(length)
+        }
+    }
+
+    fn chunks(reader: impl Read) -> Vec<Vec<u8>> {
+        let mut chunker = Chunker::new(reader);
+        std::iter::from_fn(|| chunker.next_chunk().unwrap()).collect()
+    }
+
+    #[test]
+    fn cuts_the_same_chunks_every_time() {
+        let data = random(4 * 1024 * 1024, 1);
+        let first = chunks(data.as_slice());
+        assert_eq!(first.concat(), data);
+        assert_eq!(chunks(data.as_slice()), first);
+        assert_eq!(chunks(Trickle(&data)), first);
+        assert!(chunks(&[][..]).is_empty());
+    }
+
+    #[test]
+    fn keeps_chunks_within_their_sizes() {
+        let data = random(8 * 1024 * 1024, 2);
+        let cut = chunks(data.as_slice());
+        let (last, all_but_last) = cut.split_last().unwrap();
+        assert!(all_but_last.iter().all(|c| (MIN_SIZE..=MAX_SIZE).contains(&c.len())));
+        assert!(last.len() <= MAX_SIZE);
+        let average = data.len() / cut.len();
+        assert!((AVERAGE_SIZE / 2..AVERAGE_SIZE * 2).contains(&average), "{average}");
+        // Data without any cut point still ends its chunks at the maximum
+        assert!(chunks(vec![0u8; 4 * MAX_SIZE].as_slice()).iter().all(|c| c.len() == MAX_SIZE));
+    }
+
+    #[test]
+    fn insertions_only_change_the_chunks_around_them() {
+        let data = random(4 * 1024 * 1024, 3);
+        let mut changed = data.clone();
+        let inserted = random(100, 4);
+        changed.splice(1024 * 1024..1024 * 1024, inserted);
+        let before = chunks(data.as_slice());
+        let after = chunks(changed.as_slice());
+        let new = after.iter().filter(|c| !before.contains(c)).count();
+        assert!(new <= 2, "{new} of {} chunks changed", after.len());
+    }
+}
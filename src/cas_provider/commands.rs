@@ -0,0 +1,85 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use super::chunker::Chunker;
+use super::manifest::{CasManifest, ChunkRef, FileRecipe};
+use super::store::ChunkStore;
+
+/// Splits every file below `source` into chunks, adds the ones the store doesn't have yet and
+/// writes a manifest of the whole tree. Symlinks and other special files are skipped.
+pub fn ingest(source: &Path, store: &Path, manifest_path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+    let store = ChunkStore::new(store);
+    let mut manifest = CasManifest::default();
+    let (mut total_bytes, mut chunk_count, mut new_chunks, mut new_bytes) = (0u64, 0u64, 0u64, 0u64);
+    let mut pending = vec![(source.to_path_buf(), String::new())];
+    while let Some((directory, relative)) = pending.pop() {
+        let mut entries = fs::read_dir(&directory).map_err(|e| format!("{directory:?}: {e}"))?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        if entries.is_empty() && !relative.is_empty() {
+            manifest.directories.push(relative.clone());
+        }
+        for entry in entries {
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(name) => {
+                    println!("Skipping {name:?} in {directory:?}, manifests only hold Unicode names");
+                    continue;
+                }
+            };
+            let path = if relative.is_empty() { name } else { format!("{relative}/{name}") };
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                pending.push((entry.path(), path));
+                continue;
+            }
+            if !file_type.is_file() {
+                println!("Skipping {:?}, it's not a file or directory", entry.path());
+                continue;
+            }
+
+            let metadata = entry.metadata()?;
+            let mut chunker = Chunker::new(BufReader::new(File::open(entry.path()).map_err(|e| format!("{:?}: {e}", entry.path()))?));
+            let mut chunks = Vec::new();
+            while let Some(chunk) = chunker.next_chunk()? {
+                let (hash, new) = store.put(&chunk)?;
+                if new {
+                    new_chunks += 1;
+                    new_bytes += chunk.len() as u64;
+                }
+                chunks.push(ChunkRef {
+                    hash,
+                    size: chunk.len() as u64,
+                });
+            }
+            let size = chunks.iter().map(|c| c.size).sum();
+            total_bytes += size;
+            chunk_count += chunks.len() as u64;
+            manifest.files.push(FileRecipe {
+                path,
+                size,
+                mtime: metadata.modified().ok().and_then(|m| m.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs() as i64),
+                readonly: metadata.permissions().readonly(),
+                chunks,
+            });
+        }
+    }
+    manifest.save(manifest_path)?;
+    Ok(format!("{} files with {total_bytes} bytes in {chunk_count} chunks, {new_chunks} chunks with {new_bytes} bytes were new",
+        manifest.files.len()))
+}
+
+/// Removes the chunks none of `manifests` refer to, so every manifest still in use has to be given
+pub fn collect_garbage(store: &Path, manifests: &[PathBuf]) -> Result<String, Box<dyn std::error::Error>> {
+    let mut referenced = HashSet::new();
+    // A manifest that can't be read could refer to anything, so that stops everything
+    for path in manifests {
+        for file in CasManifest::load(path)?.files {
+            referenced.extend(file.chunks.into_iter().map(|c| c.hash));
+        }
+    }
+    let (count, bytes) = ChunkStore::new(store).collect_garbage(&referenced)?;
+    Ok(format!("Removed {count} unreferenced chunks with {bytes} bytes"))
+}
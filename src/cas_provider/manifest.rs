@@ -0,0 +1,56 @@
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::store::is_valid_hash;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChunkRef {
+    pub hash: String,
+    pub size: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FileRecipe {
+    /// Relative with '/' separators
+    pub path: String,
+    pub size: u64,
+    /// Unix seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<i64>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub readonly: bool,
+    /// In order, their sizes add up to the size of the file
+    pub chunks: Vec<ChunkRef>,
+}
+
+/// Every file of a snapshot as the chunks it's made of, written by `cas-ingest`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct CasManifest {
+    pub files: Vec<FileRecipe>,
+    /// Directories with nothing in them, the others come with the files
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub directories: Vec<String>,
+}
+
+impl CasManifest {
+    pub fn load(path: &Path) -> Result<CasManifest, Box<dyn std::error::Error>> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{path:?}: {e}"))?;
+        let manifest: CasManifest = serde_json::from_str(&text).map_err(|e| format!("{path:?}: {e}"))?;
+        for file in &manifest.files {
+            if let Some(chunk) = file.chunks.iter().find(|c| !is_valid_hash(&c.hash)) {
+                return Err(format!("{:?} has an invalid chunk hash {:?}", file.path, chunk.hash).into());
+            }
+            if file.chunks.iter().map(|c| c.size).sum::<u64>() != file.size {
+                return Err(format!("The chunks of {:?} don't add up to its size", file.path).into());
+            }
+        }
+        Ok(manifest)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        fs::write(path, serde_json::to_string_pretty(self)?).map_err(|e| format!("{path:?}: {e}"))?;
+        Ok(())
+    }
+}
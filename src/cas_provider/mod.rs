@@ -0,0 +1,8 @@
+mod chunker;
+mod commands;
+mod manifest;
+mod provider;
+mod store;
+
+pub use commands::{collect_garbage, ingest};
+pub use provider::CasProvider;
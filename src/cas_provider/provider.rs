@@ -0,0 +1,184 @@
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Arc;
+
+use sha2::{Digest, Sha256};
+use windows::Win32::Storage::{FileSystem, ProjectedFileSystem};
+
+use crate::projfs_provider::{filetime, ProjFSProvider, EnumerationState, SeekRead, VirtualizationOptions, VirtualTree, EntryInfo, BlockCache};
+use super::manifest::{CasManifest, ChunkRef};
+use super::store::ChunkStore;
+
+const CACHED_CHUNKS: usize = 64;
+
+/// The chunks of a file and where each of them starts in it
+struct Recipe {
+    chunks: Vec<ChunkRef>,
+    offsets: Vec<u64>,
+    size: u64,
+    /// Of the chunk hashes, the same content always gets the same one
+    digest: [u8; 32],
+}
+
+/// Projects a manifest of files made of chunks in a content addressed store. Chunks shared by
+/// files, or by the snapshots the manifests come from, are only stored once.
+pub struct CasProvider {
+    store: Arc<ChunkStore>,
+    tree: Arc<VirtualTree<Option<Arc<Recipe>>>>,
+    cache: Arc<BlockCache>,
+}
+
+/// Reads a file by putting together the chunks the requested range falls into
+struct ChunkReader {
+    store: Arc<ChunkStore>,
+    recipe: Arc<Recipe>,
+    cache: Arc<BlockCache>,
+    position: u64,
+}
+
+impl ChunkReader {
+    fn chunk(&self, index: usize) -> std::io::Result<Arc<[u8]>> {
+        let chunk = &self.recipe.chunks[index];
+        // 128 bits of the hash are plenty to tell chunks apart in the cache
+        let key = (u64::from_str_radix(&chunk.hash[..16], 16).unwrap(), u64::from_str_radix(&chunk.hash[16..32], 16).unwrap());
+        if let Some(data) = self.cache.get(key.0, key.1) {
+            return Ok(data);
+        }
+        let data: Arc<[u8]> = self.store.read(&chunk.hash)?.into();
+        if data.len() as u64 != chunk.size {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Chunk {} has {} bytes, not {}", chunk.hash, data.len(), chunk.size)));
+        }
+        self.cache.insert(key.0, key.1, data.clone());
+        Ok(data)
+    }
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position >= self.recipe.size {
+            return Ok(0);
+        }
+        let index = self.recipe.offsets.partition_point(|o| *o <= self.position) - 1;
+        let chunk = self.chunk(index)?;
+        let within = (self.position - self.recipe.offsets[index]) as usize;
+        let read = std::cmp::min(buf.len(), chunk.len() - within);
+        buf[..read].copy_from_slice(&chunk[within..within + read]);
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for ChunkReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(v) => Some(v),
+            SeekFrom::End(v) => self.recipe.size.checked_add_signed(v),
+            SeekFrom::Current(v) => self.position.checked_add_signed(v),
+        };
+        match new_position {
+            Some(v) => {
+                self.position = v;
+                Ok(self.position)
+            }
+            None => Err(std::io::Error::from(std::io::ErrorKind::InvalidInput)),
+        }
+    }
+}
+
+impl CasProvider {
+    pub fn new(manifest_path: &Path, store: &Path) -> Result<CasProvider, Box<dyn std::error::Error>> {
+        let manifest = CasManifest::load(manifest_path)?;
+        let load_time = filetime::now();
+        let directory_info = EntryInfo::directory(load_time);
+        let mut tree = VirtualTree::new(directory_info, None);
+        for directory in &manifest.directories {
+            if let Err(e) = tree.insert_path(Path::new(directory), directory_info, None, directory_info) {
+                println!("Leaving out {directory:?}: {e}");
+            }
+        }
+        let chunk_count: usize = manifest.files.iter().map(|f| f.chunks.len()).sum();
+        for file in manifest.files.iter() {
+            let mut info = EntryInfo::file(file.size, file.mtime.map(filetime::from_unix_seconds).unwrap_or(load_time));
+            if file.readonly {
+                info.attributes = FileSystem::FILE_ATTRIBUTE_READONLY.0;
+            }
+            let offsets = file.chunks.iter().scan(0, |offset, c| {
+                let start = *offset;
+                *offset += c.size;
+                Some(start)
+            }).collect();
+            let recipe = Recipe {
+                chunks: file.chunks.clone(),
+                offsets,
+                size: file.size,
+                digest: Sha256::digest(file.chunks.iter().map(|c| c.hash.as_str()).collect::<String>()).into(),
+            };
+            if let Err(e) = tree.insert_path(Path::new(&file.path), info, Some(Arc::new(recipe)), directory_info) {
+                println!("Leaving out {:?}: {e}", file.path);
+            }
+        }
+        tree.sort();
+        println!("{} files in {chunk_count} chunks", manifest.files.len());
+
+        Ok(CasProvider {
+            store: Arc::new(ChunkStore::new(store)),
+            tree: Arc::new(tree),
+            // Chunks vary in size, only decompressing readers go by the block size
+            cache: Arc::new(BlockCache::new(0, CACHED_CHUNKS)),
+        })
+    }
+}
+
+impl ProjFSProvider for CasProvider {
+    fn init(&mut self, _root: &Path) -> Result<VirtualizationOptions, Box<dyn std::error::Error>> {
+        Ok(VirtualizationOptions::default())
+    }
+
+    fn start(&mut self, _context: ProjectedFileSystem::PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn new_enumeration(&self, _id: windows::core::GUID, file_path: &Path) -> Box<dyn EnumerationState> {
+        self.tree.enumeration(file_path)
+    }
+
+    fn get_placeholder_info(&self, file_path: &Path) -> Result<ProjectedFileSystem::PRJ_PLACEHOLDER_INFO, windows::core::HRESULT> {
+        match self.tree.lookup(file_path).map(|id| self.tree.node(id)) {
+            Some(node) => {
+                let mut placeholder = node.info.placeholder_info();
+                if let Some(recipe) = &node.content {
+                    placeholder.VersionInfo.ContentID[..32].copy_from_slice(&recipe.digest);
+                }
+                Ok(placeholder)
+            }
+            None => Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into()),
+        }
+    }
+
+    fn get_file_data(&self, file_path: &Path) -> Result<Box<dyn SeekRead>, windows::core::HRESULT> {
+        match self.tree.lookup(file_path).map(|id| &self.tree.node(id).content) {
+            Some(Some(recipe)) => Ok(Box::new(ChunkReader {
+                store: self.store.clone(),
+                recipe: recipe.clone(),
+                cache: self.cache.clone(),
+                position: 0,
+            })),
+            _ => Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into()),
+        }
+    }
+
+    fn query_file_name(&self, file_path: &Path) -> windows::core::HRESULT {
+        match self.tree.lookup(file_path) {
+            Some(_) => windows::Win32::Foundation::S_OK,
+            None => windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into(),
+        }
+    }
+
+    fn notification(&self, _callbackdata: *const ProjectedFileSystem::PRJ_CALLBACK_DATA, _is_directory: bool, _notification: ProjectedFileSystem::PRJ_NOTIFICATION, _destinationfilename: windows::core::PCWSTR, _operationparameters: *mut ProjectedFileSystem::PRJ_NOTIFICATION_PARAMETERS) -> windows::core::HRESULT {
+        windows::Win32::Foundation::S_OK
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use sha2::{Digest, Sha256};
+#[cfg(windows)]
+use windows::Win32::Storage::FileSystem;
+
+/// Chunks written or stored again this recently are kept by the garbage collector even if
+/// nothing refers to them, an ingest that is still running hasn't written its manifest yet
+const GARBAGE_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+pub fn hash(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Hashes end up in paths, so only what `hash` produces is accepted
+pub fn is_valid_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Directory of chunks named by the SHA-256 of their content, spread over subdirectories by
+/// the first two hex digits so none of them gets huge
+pub struct ChunkStore {
+    root: PathBuf,
+}
+
+impl ChunkStore {
+    pub fn new(root: &Path) -> ChunkStore {
+        ChunkStore {
+            root: root.to_path_buf(),
+        }
+    }
+
+    fn path(&self, hash: &str) -> PathBuf {
+        self.root.join(&hash[..2]).join(hash)
+    }
+
+    /// Opens a chunk to change its times, which works on read-only chunks too
+    fn open_for_times(&self, hash: &str) -> std::io::Result<fs::File> {
+        let mut options = fs::File::options();
+        options.read(true);
+        // Setting times takes the right to write attributes, not the data
+        #[cfg(windows)]
+        std::os::windows::fs::OpenOptionsExt::access_mode(&mut options, FileSystem::FILE_READ_ATTRIBUTES.0 | FileSystem::FILE_WRITE_ATTRIBUTES.0);
+        options.open(self.path(hash))
+    }
+
+    /// Stores a chunk unless it's there already, returns its hash and whether it was new. Chunks
+    /// that are there get a new modification time, so a garbage collection running alongside
+    /// treats them like new ones.
+    pub fn put(&self, data: &[u8]) -> std::io::Result<(String, bool)> {
+        let hash = hash(data);
+        let path = self.path(&hash);
+        match self.open_for_times(&hash) {
+            Ok(existing) => {
+                existing.set_modified(SystemTime::now())?;
+                return Ok((hash, false));
+            }
+            // Collected since, or never stored
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e);
+            }
+        }
+        fs::create_dir_all(path.parent().unwrap())?;
+        // Readers must never see half a chunk
+        let temporary = path.with_extension("partial");
+        fs::write(&temporary, data)?;
+        fs::rename(&temporary, &path)?;
+        Ok((hash, true))
+    }
+
+    /// Reads a chunk and checks it still has the content its name says
+    pub fn read(&self, hash: &str) -> std::io::Result<Vec<u8>> {
+        let data = fs::read(self.path(hash))?;
+        if self::hash(&data) != hash {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Chunk {hash} is corrupt")));
+        }
+        Ok(data)
+    }
+
+    /// Removes every chunk not in `referenced`, returns how many and how many bytes
+    pub fn collect_garbage(&self, referenced: &HashSet<String>) -> std::io::Result<(u64, u64)> {
+        let (mut count, mut bytes) = (0, 0);
+        let now = SystemTime::now();
+        for directory in fs::read_dir(&self.root)? {
+            let directory = directory?;
+            if !directory.file_type()?.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(directory.path())? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let metadata = entry.metadata()?;
+                let recent = metadata.modified().ok().and_then(|m| now.duration_since(m).ok()).map(|age| age < GARBAGE_GRACE_PERIOD).unwrap_or(true);
+                // Leftovers of interrupted writes go too, once they're old enough
+                if referenced.contains(&name) || recent {
+                    continue;
+                }
+                fs::remove_file(entry.path())?;
+                count += 1;
+                bytes += metadata.len();
+            }
+        }
+        Ok((count, bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A store in a temporary directory that is removed again when the test is done
+    struct Store(ChunkStore);
+
+    impl Store {
+        fn new(name: &str) -> Store {
+            let root = std::env::temp_dir().join(format!("projfs-cas-{}-{name}", std::process::id()));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(&root).unwrap();
+            Store(ChunkStore::new(&root))
+        }
+
+        fn age(&self, hash: &str) {
+            let old = SystemTime::now() - GARBAGE_GRACE_PERIOD * 2;
+            self.0.open_for_times(hash).unwrap().set_modified(old).unwrap();
+        }
+    }
+
+    impl Drop for Store {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0.root);
+        }
+    }
+
+    #[test]
+    fn stores_chunks_by_their_hash() {
+        let store = Store::new("put");
+        let (hash, new) = store.0.put(b"chunk").unwrap();
+        assert!(new && is_valid_hash(&hash));
+        assert_eq!(store.0.put(b"chunk").unwrap(), (hash.clone(), false));
+        assert_eq!(store.0.read(&hash).unwrap(), b"chunk");
+        fs::write(store.0.path(&hash), b"changed").unwrap();
+        assert_eq!(store.0.read(&hash).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn collects_old_unreferenced_chunks() {
+        let store = Store::new("gc");
+        let (referenced, _) = store.0.put(b"referenced").unwrap();
+        let (old, _) = store.0.put(b"old").unwrap();
+        let (recent, _) = store.0.put(b"recent").unwrap();
+        let (reused, _) = store.0.put(b"reused").unwrap();
+        let (read_only, _) = store.0.put(b"read only").unwrap();
+        for hash in [&referenced, &old, &reused, &read_only] {
+            store.age(hash);
+        }
+        let mut permissions = fs::metadata(store.0.path(&read_only)).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(store.0.path(&read_only), permissions).unwrap();
+        // Stored again by an ingest that hasn't written its manifest yet
+        assert!(!store.0.put(b"reused").unwrap().1);
+        assert!(!store.0.put(b"read only").unwrap().1);
+        assert_eq!(store.0.collect_garbage(&HashSet::from([referenced.clone()])).unwrap(), (1, 3));
+        assert!(store.0.read(&old).is_err());
+        for hash in [&referenced, &recent, &reused, &read_only] {
+            assert!(store.0.read(hash).is_ok());
+        }
+    }
+}
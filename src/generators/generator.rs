@@ -0,0 +1,196 @@
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Synthetic content where every byte is a function of its offset, so the expected content
+/// of any range can be recomputed to check what was hydrated
+#[derive(Clone, Debug, PartialEq)]
+pub enum Generator {
+    /// 0x00 bytes
+    Zeros,
+    Fill(u8),
+    /// Repeats the bytes from the start of the file
+    Pattern(Arc<[u8]>),
+    /// Pseudo-random bytes from a seed, each 8-byte block only depends on the seed and its index
+    Random(u64),
+    /// Each 8-byte block holds its own index as a little endian u64
+    Counter,
+}
+
+// splitmix64, good enough to look random and cheap to compute for any block
+fn mix(seed: u64, block: u64) -> u64 {
+    let mut z = seed.wrapping_add(block.wrapping_add(1).wrapping_mul(0x9e3779b97f4a7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+impl Generator {
+    fn block(&self, block: u64) -> [u8; 8] {
+        match self {
+            Generator::Random(seed) => mix(*seed, block).to_le_bytes(),
+            _ => block.to_le_bytes(),
+        }
+    }
+
+    pub fn byte_at(&self, offset: u64) -> u8 {
+        match self {
+            Generator::Zeros => 0,
+            Generator::Fill(b) => *b,
+            Generator::Pattern(p) => p[(offset % p.len() as u64) as usize],
+            Generator::Random(_) | Generator::Counter => self.block(offset / 8)[(offset % 8) as usize],
+        }
+    }
+
+    /// Fills `buf` with the content starting at `offset`
+    pub fn fill(&self, offset: u64, buf: &mut [u8]) {
+        match self {
+            Generator::Zeros => buf.fill(0),
+            Generator::Fill(b) => buf.fill(*b),
+            Generator::Pattern(p) => {
+                let mut start = (offset % p.len() as u64) as usize;
+                for chunk in buf.chunks_mut(p.len()) {
+                    // Each chunk wraps around the end of the pattern at most once
+                    let first = std::cmp::min(chunk.len(), p.len() - start);
+                    chunk[..first].copy_from_slice(&p[start..start + first]);
+                    let rest = chunk.len() - first;
+                    chunk[first..].copy_from_slice(&p[..rest]);
+                    start = rest;
+                }
+            }
+            Generator::Random(_) | Generator::Counter => {
+                let mut position = offset;
+                let mut written = 0;
+                while written < buf.len() {
+                    let block = self.block(position / 8);
+                    let in_block = (position % 8) as usize;
+                    let count = std::cmp::min(8 - in_block, buf.len() - written);
+                    buf[written..written + count].copy_from_slice(&block[in_block..in_block + count]);
+                    written += count;
+                    position += count as u64;
+                }
+            }
+        }
+    }
+}
+
+/// Parses `zeros`, `fill:<byte>`, `pattern:<text>`, `random:<seed>` or `counter`.
+/// Bytes and seeds can be decimal or 0x prefixed hex.
+impl FromStr for Generator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        fn number(v: &str) -> Result<u64, String> {
+            let parsed = match v.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16),
+                None => v.parse(),
+            };
+            parsed.map_err(|e| format!("Invalid number {v:?}: {e}"))
+        }
+
+        let (kind, argument) = match s.split_once(':') {
+            Some((k, a)) => (k, Some(a)),
+            None => (s, None),
+        };
+        match (kind, argument) {
+            ("zeros", None) => Ok(Generator::Zeros),
+            ("counter", None) => Ok(Generator::Counter),
+            ("fill", Some(b)) => {
+                let b = number(b)?;
+                u8::try_from(b).map(Generator::Fill).map_err(|_| format!("Fill byte {b} doesn't fit in a byte"))
+            }
+            ("pattern", Some(p)) if !p.is_empty() => Ok(Generator::Pattern(p.as_bytes().into())),
+            ("random", Some(seed)) => Ok(Generator::Random(number(seed)?)),
+            _ => Err(format!("Unknown generator {s:?}, expected zeros, fill:<byte>, pattern:<text>, random:<seed> or counter")),
+        }
+    }
+}
+
+/// Reads everything from `reader` and returns the offset of the first byte that doesn't
+/// match what the generator produces, if any
+pub fn verify(generator: &Generator, reader: &mut dyn std::io::Read) -> std::io::Result<Option<u64>> {
+    let mut actual = vec![0u8; 1024*1024];
+    let mut expected = vec![0u8; 1024*1024];
+    let mut offset = 0u64;
+    loop {
+        let read = reader.read(&mut actual)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        generator.fill(offset, &mut expected[..read]);
+        if let Some(i) = actual[..read].iter().zip(&expected[..read]).position(|(a, e)| a != e) {
+            return Ok(Some(offset + i as u64));
+        }
+        offset += read as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generators() -> Vec<Generator> {
+        vec![
+            Generator::Zeros,
+            Generator::Fill(0x30),
+            Generator::Pattern(b"abc".as_slice().into()),
+            Generator::Random(42),
+            Generator::Counter,
+        ]
+    }
+
+    #[test]
+    fn fill_matches_byte_at() {
+        for generator in generators() {
+            for offset in [0, 1, 7, 8, 13, (1 << 32) - 5] {
+                let mut buf = vec![0u8; 29];
+                generator.fill(offset, &mut buf);
+                for (i, b) in buf.iter().enumerate() {
+                    assert_eq!(*b, generator.byte_at(offset + i as u64), "{generator:?} at {offset}+{i}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn produces_the_documented_bytes() {
+        assert_eq!(Generator::Fill(7).byte_at(123), 7);
+        let pattern = Generator::Pattern(b"abc".as_slice().into());
+        assert_eq!((0..7).map(|o| pattern.byte_at(o)).collect::<Vec<_>>(), b"abcabca");
+        assert_eq!(Generator::Counter.byte_at(8 * 258), 2);
+        assert_eq!(Generator::Counter.byte_at(8 * 258 + 1), 1);
+    }
+
+    #[test]
+    fn random_depends_on_the_seed_only() {
+        let mut a = [0u8; 64];
+        let mut b = [0u8; 64];
+        Generator::Random(1).fill(1000, &mut a);
+        Generator::Random(1).fill(1000, &mut b);
+        assert_eq!(a, b);
+        Generator::Random(2).fill(1000, &mut b);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn parses_generators() {
+        assert_eq!("zeros".parse(), Ok(Generator::Zeros));
+        assert_eq!("fill:0x30".parse(), Ok(Generator::Fill(0x30)));
+        assert_eq!("fill:48".parse(), Ok(Generator::Fill(48)));
+        assert_eq!("pattern:ab".parse(), Ok(Generator::Pattern(b"ab".as_slice().into())));
+        assert_eq!("random:0x10".parse(), Ok(Generator::Random(16)));
+        assert_eq!("counter".parse(), Ok(Generator::Counter));
+        for invalid in ["fill:256", "fill", "pattern:", "random:x", "zeros:1", "ones"] {
+            assert!(invalid.parse::<Generator>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn verify_finds_the_first_difference() {
+        let generator = Generator::Counter;
+        let mut data = vec![0u8; 3 * 1024 * 1024];
+        generator.fill(0, &mut data);
+        assert_eq!(verify(&generator, &mut data.as_slice()).unwrap(), None);
+        data[2 * 1024 * 1024 + 5] ^= 1;
+        assert_eq!(verify(&generator, &mut data.as_slice()).unwrap(), Some(2 * 1024 * 1024 + 5));
+    }
+}
@@ -0,0 +1,5 @@
+mod generator;
+mod reader;
+
+pub use generator::{Generator, verify};
+pub use reader::GeneratorReader;
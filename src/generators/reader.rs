@@ -0,0 +1,97 @@
+use super::generator::Generator;
+
+/// Seekable reader over `length` bytes of generated content
+pub struct GeneratorReader {
+    generator: Generator,
+    length: u64,
+    current: u64,
+}
+
+impl GeneratorReader {
+    pub fn new(generator: Generator, length: u64) -> GeneratorReader {
+        GeneratorReader {
+            generator,
+            length,
+            current: 0,
+        }
+    }
+}
+
+impl std::io::Read for GeneratorReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining_bytes = self.length.saturating_sub(self.current);
+        if remaining_bytes == 0 {
+            return Ok(0);
+        }
+        let read_len = std::cmp::min(buf.len() as u64, remaining_bytes) as usize;
+        self.generator.fill(self.current, &mut buf[..read_len]);
+        self.current += read_len as u64;
+
+        Ok(read_len)
+    }
+}
+
+impl std::io::Seek for GeneratorReader {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        let new_position = match pos {
+            std::io::SeekFrom::Start(v) => Some(v),
+            std::io::SeekFrom::End(v) => self.length.checked_add_signed(v),
+            std::io::SeekFrom::Current(v) => self.current.checked_add_signed(v),
+        };
+        match new_position {
+            Some(v) => {
+                self.current = v;
+                Ok(self.current)
+            }
+            None => Err(std::io::Error::from(std::io::ErrorKind::InvalidInput)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Seek, SeekFrom};
+
+    use super::*;
+    use crate::projfs_provider::FILE_TRANSFER_CHUNK_SIZE;
+
+    fn read_at(reader: &mut GeneratorReader, offset: u64, length: usize) -> Vec<u8> {
+        reader.seek(SeekFrom::Start(offset)).unwrap();
+        let mut data = vec![0u8; length];
+        let read = reader.read(&mut data).unwrap();
+        data.truncate(read);
+        data
+    }
+
+    #[test]
+    fn stops_at_the_length() {
+        let mut reader = GeneratorReader::new(Generator::Counter, 10);
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, [0, 0, 0, 0, 0, 0, 0, 0, 1, 0]);
+        assert!(read_at(&mut reader, 20, 4).is_empty());
+    }
+
+    #[test]
+    fn reads_across_the_chunk_size() {
+        let generator = Generator::Random(7);
+        let mut reader = GeneratorReader::new(generator.clone(), FILE_TRANSFER_CHUNK_SIZE + 1);
+        let data = read_at(&mut reader, FILE_TRANSFER_CHUNK_SIZE - 3, 16);
+        assert_eq!(data.len(), 4);
+        for (i, b) in data.iter().enumerate() {
+            assert_eq!(*b, generator.byte_at(FILE_TRANSFER_CHUNK_SIZE - 3 + i as u64));
+        }
+    }
+
+    #[test]
+    fn reads_past_4gib() {
+        let length = (5u64 << 30) + 3;
+        let mut reader = GeneratorReader::new(Generator::Counter, length);
+        // Block 2^29 is the first one whose index doesn't fit in 32 bits once multiplied by 8
+        let data = read_at(&mut reader, 1 << 32, 8);
+        assert_eq!(u64::from_le_bytes(data.try_into().unwrap()), 1 << 29);
+        assert_eq!(read_at(&mut reader, length - 2, 8).len(), 2);
+        assert_eq!(reader.seek(SeekFrom::End(-1)).unwrap(), length - 1);
+        assert!(reader.seek(SeekFrom::Current(-(length as i64))).is_err());
+    }
+}
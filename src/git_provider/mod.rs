@@ -0,0 +1,5 @@
+mod object_store;
+mod provider;
+mod repository;
+
+pub use provider::GitProvider;
@@ -0,0 +1,705 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+pub type ObjectId = [u8; 20];
+
+const PACK_INDEX_MAGIC: &[u8] = b"\xfftOc";
+const OBJ_OFS_DELTA: u8 = 6;
+const OBJ_REF_DELTA: u8 = 7;
+/// Deltas of deltas only go so deep, anything beyond this is a broken pack
+const MAX_DELTA_DEPTH: usize = 10_000;
+/// Sizes come from the objects themselves, buffers beyond this grow as the data arrives
+const MAX_PREALLOCATED: u64 = 16 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ObjectKind {
+    Commit,
+    Tree,
+    Blob,
+    Tag,
+}
+
+impl ObjectKind {
+    fn from_code(code: u8) -> Option<ObjectKind> {
+        match code {
+            1 => Some(ObjectKind::Commit),
+            2 => Some(ObjectKind::Tree),
+            3 => Some(ObjectKind::Blob),
+            4 => Some(ObjectKind::Tag),
+            _ => None,
+        }
+    }
+
+    fn from_name(name: &[u8]) -> Option<ObjectKind> {
+        match name {
+            b"commit" => Some(ObjectKind::Commit),
+            b"tree" => Some(ObjectKind::Tree),
+            b"blob" => Some(ObjectKind::Blob),
+            b"tag" => Some(ObjectKind::Tag),
+            _ => None,
+        }
+    }
+}
+
+pub fn to_hex(id: &ObjectId) -> String {
+    id.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<ObjectId> {
+    if hex.len() != 40 || !hex.is_ascii() {
+        return None;
+    }
+    let mut id = [0u8; 20];
+    for (i, b) in id.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(id)
+}
+
+fn invalid(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+fn read_byte(reader: &mut dyn Read) -> std::io::Result<u8> {
+    let mut b = [0u8; 1];
+    reader.read_exact(&mut b)?;
+    Ok(b[0])
+}
+
+/// Little endian base-128 as used for sizes in deltas
+fn read_varint(reader: &mut dyn Read) -> std::io::Result<u64> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let b = read_byte(reader)?;
+        value |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+        if shift > 63 {
+            return Err(invalid("Size too large".into()));
+        }
+    }
+}
+
+/// Type and inflated size in front of every object of a pack
+fn read_pack_header(reader: &mut dyn Read) -> std::io::Result<(u8, u64)> {
+    let b = read_byte(reader)?;
+    let code = (b >> 4) & 0x7;
+    let mut size = (b & 0x0f) as u64;
+    let mut shift = 4;
+    let mut more = b & 0x80 != 0;
+    while more {
+        // 4 bits and then 7 per byte, more than 9 bytes don't fit the size
+        if shift > 4 + 7 * 7 {
+            return Err(invalid("Pack object header too long".into()));
+        }
+        let b = read_byte(reader)?;
+        size |= ((b & 0x7f) as u64) << shift;
+        shift += 7;
+        more = b & 0x80 != 0;
+    }
+    Ok((code, size))
+}
+
+/// Distance back to the base of an offset delta, big endian with an offset added per byte
+fn read_base_distance(reader: &mut dyn Read) -> std::io::Result<u64> {
+    let mut b = read_byte(reader)?;
+    let mut distance = (b & 0x7f) as u64;
+    while b & 0x80 != 0 {
+        b = read_byte(reader)?;
+        distance = ((distance + 1) << 7) | (b & 0x7f) as u64;
+    }
+    Ok(distance)
+}
+
+fn capacity(size: u64) -> usize {
+    size.min(MAX_PREALLOCATED) as usize
+}
+
+fn inflate(reader: &mut dyn BufRead, size: u64) -> std::io::Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(capacity(size));
+    flate2::bufread::ZlibDecoder::new(reader).take(size).read_to_end(&mut data)?;
+    if data.len() as u64 != size {
+        return Err(invalid(format!("Object is {} bytes instead of {size}", data.len())));
+    }
+    Ok(data)
+}
+
+fn apply_delta(base: &[u8], delta: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut reader = delta;
+    let base_size = read_varint(&mut reader)?;
+    let target_size = read_varint(&mut reader)?;
+    if base_size != base.len() as u64 {
+        return Err(invalid("Delta doesn't fit its base".into()));
+    }
+    let mut target = Vec::with_capacity(capacity(target_size));
+    while !reader.is_empty() {
+        let instruction = read_byte(&mut reader)?;
+        if instruction & 0x80 != 0 {
+            // Copy from the base, the low bits say which offset and size bytes follow
+            let mut offset = 0usize;
+            let mut size = 0usize;
+            for i in 0..4 {
+                if instruction & (1 << i) != 0 {
+                    offset |= (read_byte(&mut reader)? as usize) << (i * 8);
+                }
+            }
+            for i in 0..3 {
+                if instruction & (0x10 << i) != 0 {
+                    size |= (read_byte(&mut reader)? as usize) << (i * 8);
+                }
+            }
+            if size == 0 {
+                size = 0x10000;
+            }
+            let copied = base.get(offset..offset + size).ok_or_else(|| invalid("Delta copies past its base".into()))?;
+            target.extend_from_slice(copied);
+        } else if instruction != 0 {
+            let size = instruction as usize;
+            let inserted = reader.get(..size).ok_or_else(|| invalid("Truncated delta".into()))?;
+            target.extend_from_slice(inserted);
+            reader = &reader[size..];
+        } else {
+            return Err(invalid("Invalid delta instruction".into()));
+        }
+    }
+    if target.len() as u64 != target_size {
+        return Err(invalid("Delta produced the wrong size".into()));
+    }
+    Ok(target)
+}
+
+/// Version 2 pack index, kept in memory
+struct Pack {
+    path: PathBuf,
+    fanout: Vec<u32>,
+    ids: Vec<u8>,
+    offsets: Vec<u8>,
+    large_offsets: Vec<u8>,
+}
+
+impl Pack {
+    fn open(index_path: &Path) -> Result<Pack, Box<dyn std::error::Error>> {
+        let index = std::fs::read(index_path)?;
+        if index.len() < 8 + 256 * 4 || &index[..4] != PACK_INDEX_MAGIC || index[4..8] != [0, 0, 0, 2] {
+            return Err(format!("{index_path:?} is not a version 2 pack index").into());
+        }
+        let fanout: Vec<u32> = index[8..8 + 256 * 4].chunks(4).map(|c| u32::from_be_bytes(c.try_into().unwrap())).collect();
+        // Counts of ids up to each first byte, so the last one is the number of objects
+        if fanout.windows(2).any(|w| w[0] > w[1]) {
+            return Err(format!("{index_path:?} has a broken fanout table").into());
+        }
+        let count = fanout[255] as usize;
+        let ids_start = 8 + 256 * 4;
+        let offsets_start = ids_start + count * 20 + count * 4;
+        let large_start = offsets_start + count * 4;
+        // The index ends with the checksums of the pack and of itself
+        if index.len() < large_start + 40 {
+            return Err(format!("{index_path:?} is truncated").into());
+        }
+        Ok(Pack {
+            path: index_path.with_extension("pack"),
+            fanout,
+            ids: index[ids_start..ids_start + count * 20].to_vec(),
+            offsets: index[offsets_start..large_start].to_vec(),
+            large_offsets: index[large_start..index.len() - 40].to_vec(),
+        })
+    }
+
+    fn find(&self, id: &ObjectId) -> Option<u64> {
+        let first = id[0] as usize;
+        let start = if first == 0 { 0 } else { self.fanout[first - 1] as usize };
+        let end = self.fanout[first] as usize;
+        let (mut low, mut high) = (start, end);
+        let i = loop {
+            if low >= high {
+                return None;
+            }
+            let middle = (low + high) / 2;
+            match self.ids[middle * 20..middle * 20 + 20].cmp(&id[..]) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => break middle,
+            }
+        };
+        let offset = u32::from_be_bytes(self.offsets[i * 4..i * 4 + 4].try_into().unwrap());
+        if offset & 0x8000_0000 == 0 {
+            return Some(offset as u64);
+        }
+        let large = (offset & 0x7fff_ffff) as usize;
+        self.large_offsets.get(large * 8..large * 8 + 8).map(|b| u64::from_be_bytes(b.try_into().unwrap()))
+    }
+}
+
+enum DeltaBase {
+    Offset(u64),
+    Id(ObjectId),
+}
+
+/// What the header in front of an object of a pack says it is
+enum PackedObject {
+    Whole(ObjectKind),
+    Delta(DeltaBase),
+}
+
+/// Pack files opened so far, by their position in the store
+struct OpenPacks(Vec<Option<BufReader<File>>>);
+
+impl OpenPacks {
+    fn get(&mut self, pack: &Pack, index: usize) -> std::io::Result<&mut BufReader<File>> {
+        if self.0[index].is_none() {
+            self.0[index] = Some(BufReader::new(File::open(&pack.path)?));
+        }
+        Ok(self.0[index].as_mut().unwrap())
+    }
+}
+
+/// Loose objects and packs of a repository, read without running git
+pub struct ObjectStore {
+    objects_dir: PathBuf,
+    packs: Vec<Pack>,
+}
+
+impl ObjectStore {
+    pub fn open(objects_dir: &Path) -> Result<ObjectStore, Box<dyn std::error::Error>> {
+        let mut packs = Vec::new();
+        if let Ok(entries) = std::fs::read_dir(objects_dir.join("pack")) {
+            for entry in entries {
+                let path = entry?.path();
+                if path.extension().map(|e| e == "idx").unwrap_or(false) {
+                    packs.push(Pack::open(&path)?);
+                }
+            }
+        }
+        Ok(ObjectStore {
+            objects_dir: objects_dir.to_path_buf(),
+            packs,
+        })
+    }
+
+    fn loose_path(&self, id: &ObjectId) -> PathBuf {
+        let hex = to_hex(id);
+        self.objects_dir.join(&hex[..2]).join(&hex[2..])
+    }
+
+    fn find_packed(&self, id: &ObjectId) -> Option<(usize, u64)> {
+        self.packs.iter().enumerate().find_map(|(i, p)| p.find(id).map(|o| (i, o)))
+    }
+
+    fn not_found(id: &ObjectId) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::NotFound, format!("Object {} not found", to_hex(id)))
+    }
+
+    /// Loose objects start with "<kind> <size>\0"
+    fn read_loose_header(reader: &mut dyn Read) -> std::io::Result<(ObjectKind, u64)> {
+        let mut header = Vec::new();
+        loop {
+            match read_byte(reader)? {
+                0 => break,
+                b => header.push(b),
+            }
+            if header.len() > 32 {
+                return Err(invalid("Invalid loose object header".into()));
+            }
+        }
+        let (kind, size) = header.split_at(header.iter().position(|b| *b == b' ').ok_or_else(|| invalid("Invalid loose object header".into()))?);
+        let kind = ObjectKind::from_name(kind).ok_or_else(|| invalid("Unknown loose object kind".into()))?;
+        let size = std::str::from_utf8(&size[1..]).ok().and_then(|s| s.parse().ok()).ok_or_else(|| invalid("Invalid loose object size".into()))?;
+        Ok((kind, size))
+    }
+
+    pub fn read(&self, id: &ObjectId) -> std::io::Result<(ObjectKind, Vec<u8>)> {
+        match self.find_packed(id) {
+            Some((pack, offset)) => self.read_packed(pack, offset),
+            None => self.read_loose(id),
+        }
+    }
+
+    fn read_loose(&self, id: &ObjectId) -> std::io::Result<(ObjectKind, Vec<u8>)> {
+        let file = match File::open(self.loose_path(id)) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(Self::not_found(id));
+            }
+            Err(e) => {
+                return Err(e);
+            }
+        };
+        let mut decoder = flate2::read::ZlibDecoder::new(BufReader::new(file));
+        let (kind, size) = Self::read_loose_header(&mut decoder)?;
+        let mut data = Vec::with_capacity(capacity(size));
+        decoder.take(size).read_to_end(&mut data)?;
+        if data.len() as u64 != size {
+            return Err(invalid(format!("Object {} is truncated", to_hex(id))));
+        }
+        Ok((kind, data))
+    }
+
+    /// Kind and size of objects without reading all of them, opening every pack only once
+    pub fn headers(&self, ids: &[ObjectId]) -> std::io::Result<Vec<(ObjectKind, u64)>> {
+        let mut files = OpenPacks(self.packs.iter().map(|_| None).collect());
+        ids.iter().map(|id| self.header(&mut files, id)).collect()
+    }
+
+    fn header(&self, files: &mut OpenPacks, id: &ObjectId) -> std::io::Result<(ObjectKind, u64)> {
+        match self.find_packed(id) {
+            Some((pack, offset)) => self.packed_header(files, pack, offset),
+            None => self.loose_header(id),
+        }
+    }
+
+    fn loose_header(&self, id: &ObjectId) -> std::io::Result<(ObjectKind, u64)> {
+        let file = File::open(self.loose_path(id)).map_err(|_| Self::not_found(id))?;
+        Self::read_loose_header(&mut flate2::read::ZlibDecoder::new(BufReader::new(file)))
+    }
+
+    /// Reads up to the data of the object at `offset`, which is `size` bytes inflated
+    fn read_packed_header(file: &mut BufReader<File>, offset: u64) -> std::io::Result<(PackedObject, u64)> {
+        file.seek(SeekFrom::Start(offset))?;
+        let (code, size) = read_pack_header(file)?;
+        let object = match code {
+            OBJ_OFS_DELTA => PackedObject::Delta(DeltaBase::Offset(offset.checked_sub(read_base_distance(file)?).ok_or_else(|| invalid("Delta base before the pack".into()))?)),
+            OBJ_REF_DELTA => {
+                let mut base = [0u8; 20];
+                file.read_exact(&mut base)?;
+                PackedObject::Delta(DeltaBase::Id(base))
+            }
+            _ => PackedObject::Whole(ObjectKind::from_code(code).ok_or_else(|| invalid(format!("Unknown pack object type {code}")))?),
+        };
+        Ok((object, size))
+    }
+
+    fn packed_header(&self, files: &mut OpenPacks, pack: usize, offset: u64) -> std::io::Result<(ObjectKind, u64)> {
+        // The size of a delta is its own, the object's size is in front of the instructions.
+        // The kind is the one of the base at the end of the chain. Chains are followed in a
+        // loop, ref deltas that form a cycle run into the depth limit instead of the stack's.
+        let mut object_size = None;
+        let (mut pack, mut offset) = (pack, offset);
+        for _ in 0..=MAX_DELTA_DEPTH {
+            let file = files.get(&self.packs[pack], pack)?;
+            let base = match Self::read_packed_header(file, offset)? {
+                (PackedObject::Delta(base), _) => base,
+                (PackedObject::Whole(kind), size) => {
+                    return Ok((kind, object_size.unwrap_or(size)));
+                }
+            };
+            if object_size.is_none() {
+                let mut delta = flate2::bufread::ZlibDecoder::new(&mut *file);
+                read_varint(&mut delta)?;
+                object_size = Some(read_varint(&mut delta)?);
+            }
+            match base {
+                DeltaBase::Offset(base) => offset = base,
+                DeltaBase::Id(id) => match self.find_packed(&id) {
+                    Some(next) => (pack, offset) = next,
+                    None => {
+                        return Ok((self.loose_header(&id)?.0, object_size.unwrap()));
+                    }
+                },
+            }
+        }
+        Err(invalid("Delta chain too long".into()))
+    }
+
+    fn read_packed(&self, pack: usize, offset: u64) -> std::io::Result<(ObjectKind, Vec<u8>)> {
+        let mut files = OpenPacks(self.packs.iter().map(|_| None).collect());
+        let mut deltas = Vec::new();
+        let (mut pack, mut offset) = (pack, offset);
+        let (kind, mut data) = loop {
+            if deltas.len() > MAX_DELTA_DEPTH {
+                return Err(invalid("Delta chain too long".into()));
+            }
+            let file = files.get(&self.packs[pack], pack)?;
+            let base = match Self::read_packed_header(file, offset)? {
+                (PackedObject::Delta(base), size) => {
+                    deltas.push(inflate(file, size)?);
+                    base
+                }
+                (PackedObject::Whole(kind), size) => break (kind, inflate(file, size)?),
+            };
+            // The base of a ref delta can be anywhere, even in another pack
+            match base {
+                DeltaBase::Offset(base) => offset = base,
+                DeltaBase::Id(id) => match self.find_packed(&id) {
+                    Some(next) => (pack, offset) = next,
+                    None => break self.read_loose(&id)?,
+                },
+            }
+        };
+        for delta in deltas.iter().rev() {
+            data = apply_delta(&data, delta)?;
+        }
+        Ok((kind, data))
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use sha2::Digest;
+
+    use super::*;
+
+    fn zlib(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn varint(mut value: usize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        loop {
+            let b = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                bytes.push(b);
+                return bytes;
+            }
+            bytes.push(b | 0x80);
+        }
+    }
+
+    /// A delta that copies `copy` from the base and appends `insert`
+    pub fn delta(base: &[u8], copy: std::ops::Range<usize>, insert: &[u8]) -> Vec<u8> {
+        let mut delta = varint(base.len());
+        delta.extend(varint(copy.len() + insert.len()));
+        let offset = copy.start.to_le_bytes();
+        let size = copy.len().to_le_bytes();
+        delta.push(0x80 | 0xf | 0x70);
+        delta.extend_from_slice(&offset[..4]);
+        delta.extend_from_slice(&size[..3]);
+        if !insert.is_empty() {
+            delta.push(insert.len() as u8);
+            delta.extend_from_slice(insert);
+        }
+        delta
+    }
+
+    pub enum Packed {
+        Whole(ObjectKind, Vec<u8>),
+        /// Delta against an earlier object of the same pack, by its position
+        OfsDelta(usize, Vec<u8>),
+        RefDelta(ObjectId, Vec<u8>),
+    }
+
+    /// A repository in a temporary directory, removed again when the test is done. Object ids
+    /// are made up from the content, nothing checks them.
+    pub struct Fixture(pub PathBuf);
+
+    impl Fixture {
+        pub fn new(name: &str) -> Fixture {
+            let path = std::env::temp_dir().join(format!("projfs-git-{}-{name}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(path.join(".git").join("objects").join("pack")).unwrap();
+            std::fs::write(path.join(".git").join("HEAD"), "ref: refs/heads/main\n").unwrap();
+            Fixture(path)
+        }
+
+        pub fn git_dir(&self) -> PathBuf {
+            self.0.join(".git")
+        }
+
+        pub fn objects(&self) -> PathBuf {
+            self.git_dir().join("objects")
+        }
+
+        pub fn id(seed: &[u8]) -> ObjectId {
+            sha2::Sha256::digest(seed)[..20].try_into().unwrap()
+        }
+
+        pub fn loose(&self, kind: &str, data: &[u8]) -> ObjectId {
+            let mut object = format!("{kind} {}\0", data.len()).into_bytes();
+            object.extend_from_slice(data);
+            let id = Self::id(&object);
+            let hex = to_hex(&id);
+            std::fs::create_dir_all(self.objects().join(&hex[..2])).unwrap();
+            std::fs::write(self.objects().join(&hex[..2]).join(&hex[2..]), zlib(&object)).unwrap();
+            id
+        }
+
+        /// Writes a pack and its index, `large_offsets` puts every offset into the 64-bit table
+        pub fn pack(&self, name: &str, objects: &[(ObjectId, Packed)], large_offsets: bool) {
+            let mut pack = b"PACK\0\0\0\x02".to_vec();
+            pack.extend_from_slice(&(objects.len() as u32).to_be_bytes());
+            let mut offsets = Vec::new();
+            for (_, object) in objects {
+                let offset = pack.len();
+                offsets.push(offset);
+                let (code, data, prefix) = match object {
+                    Packed::Whole(kind, data) => {
+                        let code = match kind {
+                            ObjectKind::Commit => 1,
+                            ObjectKind::Tree => 2,
+                            ObjectKind::Blob => 3,
+                            ObjectKind::Tag => 4,
+                        };
+                        (code, data, Vec::new())
+                    }
+                    Packed::OfsDelta(base, data) => {
+                        let mut distance = offset - offsets[*base];
+                        let mut encoded = vec![(distance & 0x7f) as u8];
+                        distance >>= 7;
+                        while distance > 0 {
+                            distance -= 1;
+                            encoded.push(0x80 | (distance & 0x7f) as u8);
+                            distance >>= 7;
+                        }
+                        encoded.reverse();
+                        (OBJ_OFS_DELTA, data, encoded)
+                    }
+                    Packed::RefDelta(base, data) => (OBJ_REF_DELTA, data, base.to_vec()),
+                };
+                let mut size = data.len() >> 4;
+                let mut header = vec![code << 4 | (data.len() & 0xf) as u8];
+                while size > 0 {
+                    *header.last_mut().unwrap() |= 0x80;
+                    header.push((size & 0x7f) as u8);
+                    size >>= 7;
+                }
+                pack.extend(header);
+                pack.extend(prefix);
+                pack.extend(zlib(data));
+            }
+            pack.extend_from_slice(&[0; 20]);
+
+            let mut sorted: Vec<(ObjectId, usize)> = objects.iter().zip(offsets).map(|((id, _), o)| (*id, o)).collect();
+            sorted.sort();
+            let mut index = PACK_INDEX_MAGIC.to_vec();
+            index.extend_from_slice(&[0, 0, 0, 2]);
+            for first in 0..=255u8 {
+                index.extend_from_slice(&(sorted.iter().filter(|(id, _)| id[0] <= first).count() as u32).to_be_bytes());
+            }
+            sorted.iter().for_each(|(id, _)| index.extend_from_slice(id));
+            sorted.iter().for_each(|_| index.extend_from_slice(&[0; 4]));
+            for (i, (_, offset)) in sorted.iter().enumerate() {
+                let entry = if large_offsets { 0x8000_0000 | i as u32 } else { *offset as u32 };
+                index.extend_from_slice(&entry.to_be_bytes());
+            }
+            if large_offsets {
+                sorted.iter().for_each(|(_, offset)| index.extend_from_slice(&(*offset as u64).to_be_bytes()));
+            }
+            index.extend_from_slice(&[0; 40]);
+
+            let pack_dir = self.objects().join("pack");
+            std::fs::write(pack_dir.join(format!("{name}.pack")), pack).unwrap();
+            std::fs::write(pack_dir.join(format!("{name}.idx")), index).unwrap();
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn applies_deltas() {
+        let base = b"0123456789";
+        assert_eq!(apply_delta(base, &delta(base, 2..5, b"abc")).unwrap(), b"234abc");
+        assert_eq!(apply_delta(base, &delta(base, 0..10, b"")).unwrap(), base);
+        assert!(apply_delta(b"012345678", &delta(base, 2..5, b"")).is_err());
+        assert!(apply_delta(base, &delta(base, 8..12, b"")).is_err());
+        // A zero instruction is reserved
+        let mut reserved = delta(base, 0..1, b"");
+        reserved.push(0);
+        assert!(apply_delta(base, &reserved).is_err());
+    }
+
+    #[test]
+    fn reads_loose_objects() {
+        let fixture = Fixture::new("loose");
+        let id = fixture.loose("blob", b"loose data");
+        let store = ObjectStore::open(&fixture.objects()).unwrap();
+        assert_eq!(store.read(&id).unwrap(), (ObjectKind::Blob, b"loose data".to_vec()));
+        assert_eq!(store.headers(&[id]).unwrap(), [(ObjectKind::Blob, 10)]);
+        let missing = Fixture::id(b"missing");
+        assert_eq!(store.read(&missing).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+        assert!(store.headers(&[id, missing]).is_err());
+    }
+
+    #[test]
+    fn reads_packed_objects_through_delta_chains() {
+        let fixture = Fixture::new("packed");
+        let base = b"The quick brown fox jumps over the lazy dog".repeat(20);
+        let first = delta(&base, 4..19, b" leaps");
+        let first_target = apply_delta(&base, &first).unwrap();
+        let second = delta(&first_target, 0..9, b"!");
+        let loose_base = fixture.loose("blob", b"loose base");
+        let (whole, ofs, ofs_ofs, ref_packed, ref_loose) = (Fixture::id(b"1"), Fixture::id(b"2"), Fixture::id(b"3"), Fixture::id(b"4"), Fixture::id(b"5"));
+        fixture.pack("pack-test", &[
+            (whole, Packed::Whole(ObjectKind::Blob, base.clone())),
+            (ofs, Packed::OfsDelta(0, first)),
+            (ofs_ofs, Packed::OfsDelta(1, second)),
+            (ref_packed, Packed::RefDelta(whole, delta(&base, 0..3, b""))),
+            (ref_loose, Packed::RefDelta(loose_base, delta(b"loose base", 6..10, b"d"))),
+        ], false);
+        let store = ObjectStore::open(&fixture.objects()).unwrap();
+        let expected: [(ObjectId, &[u8]); 5] = [
+            (whole, &base),
+            (ofs, b"quick brown fox leaps"),
+            (ofs_ofs, b"quick bro!"),
+            (ref_packed, b"The"),
+            (ref_loose, b"based"),
+        ];
+        for (id, data) in expected {
+            assert_eq!(store.read(&id).unwrap(), (ObjectKind::Blob, data.to_vec()));
+        }
+        let ids: Vec<ObjectId> = expected.iter().map(|(id, _)| *id).collect();
+        let sizes: Vec<(ObjectKind, u64)> = expected.iter().map(|(_, d)| (ObjectKind::Blob, d.len() as u64)).collect();
+        assert_eq!(store.headers(&ids).unwrap(), sizes);
+    }
+
+    #[test]
+    fn reads_large_pack_offsets() {
+        let fixture = Fixture::new("large-offsets");
+        let (tree, blob) = (Fixture::id(b"tree"), Fixture::id(b"blob"));
+        fixture.pack("pack-large", &[(tree, Packed::Whole(ObjectKind::Tree, Vec::new())), (blob, Packed::Whole(ObjectKind::Blob, b"far".to_vec()))], true);
+        let store = ObjectStore::open(&fixture.objects()).unwrap();
+        assert_eq!(store.read(&blob).unwrap(), (ObjectKind::Blob, b"far".to_vec()));
+        assert_eq!(store.headers(&[tree]).unwrap(), [(ObjectKind::Tree, 0)]);
+    }
+
+    #[test]
+    fn rejects_corrupt_headers_and_sizes() {
+        let mut long = &[0xffu8; 12][..];
+        assert!(read_pack_header(&mut long).is_err());
+        assert_eq!(long.len(), 3);
+        let mut longest = &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f][..];
+        assert_eq!(read_pack_header(&mut longest).unwrap(), (7, (1 << 60) - 1));
+        // Sizes far beyond the data fail on the data instead of allocating them up front
+        assert!(inflate(&mut &zlib(b"small")[..], u64::MAX).is_err());
+        let mut huge = varint(3);
+        huge.extend(varint(usize::MAX));
+        assert!(apply_delta(b"abc", &huge).is_err());
+    }
+
+    #[test]
+    fn stops_at_ref_delta_cycles() {
+        let fixture = Fixture::new("cycle");
+        let (first, second) = (Fixture::id(b"first"), Fixture::id(b"second"));
+        fixture.pack("pack-cycle", &[
+            (first, Packed::RefDelta(second, delta(b"abc", 0..3, b""))),
+            (second, Packed::RefDelta(first, delta(b"abc", 0..3, b""))),
+        ], false);
+        let store = ObjectStore::open(&fixture.objects()).unwrap();
+        assert_eq!(store.read(&first).unwrap_err().to_string(), "Delta chain too long");
+        assert_eq!(store.headers(&[second]).unwrap_err().to_string(), "Delta chain too long");
+    }
+
+    #[test]
+    fn rejects_broken_fanout_tables() {
+        let fixture = Fixture::new("fanout");
+        fixture.pack("pack-fanout", &[(Fixture::id(b"blob"), Packed::Whole(ObjectKind::Blob, b"data".to_vec()))], false);
+        let index = fixture.objects().join("pack").join("pack-fanout.idx");
+        let mut data = std::fs::read(&index).unwrap();
+        data[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+        std::fs::write(&index, data).unwrap();
+        let error = ObjectStore::open(&fixture.objects()).err().unwrap().to_string();
+        assert!(error.contains("broken fanout table"), "{error}");
+    }
+}
@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::{Component, Path};
+use std::sync::{Arc, Mutex, RwLock};
+
+use windows::Win32::Storage::{FileSystem, ProjectedFileSystem};
+
+use crate::projfs_provider::{compare_file_names, filetime, ProjFSProvider, EnumerationState, ListEnumeration, SeekRead, VirtualizationOptions, EntryInfo};
+use super::object_store::{to_hex, ObjectId};
+use super::repository::{Repository, MODE_DIRECTORY, MODE_GITLINK, MODE_SYMLINK};
+
+/// Decoded blobs kept for the next callbacks, ProjFS asks for large files in several
+const CACHED_BLOB_BYTES: usize = 64 * 1024 * 1024;
+
+#[derive(Clone)]
+struct GitEntry {
+    name: OsString,
+    mode: u32,
+    id: ObjectId,
+    info: EntryInfo,
+}
+
+/// Projects the tree of a commit read-only, straight from the object store. Trees are read
+/// when they are first needed and kept, blobs are decoded once and kept while they were used
+/// recently. Windows has nothing like the executable bit, executables are plain files. Symlinks
+/// become files holding their target like git does without symlink support, marked as system files.
+pub struct GitProvider {
+    repository: Repository,
+    root: GitEntry,
+    // Entries of the trees read so far, in ProjFS order
+    trees: RwLock<HashMap<ObjectId, Arc<Vec<GitEntry>>>>,
+    blobs: Mutex<CachedBlobs>,
+}
+
+struct CachedBlob {
+    data: Arc<[u8]>,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct CachedBlobs {
+    blobs: HashMap<ObjectId, CachedBlob>,
+    size: usize,
+    clock: u64,
+}
+
+impl CachedBlobs {
+    fn get(&mut self, id: &ObjectId) -> Option<Arc<[u8]>> {
+        self.clock += 1;
+        let clock = self.clock;
+        self.blobs.get_mut(id).map(|b| {
+            b.last_used = clock;
+            b.data.clone()
+        })
+    }
+
+    /// Drops the least recently used blobs until the new one fits, one that is larger than
+    /// all of the cache is kept until the next one comes
+    fn insert(&mut self, id: ObjectId, data: Arc<[u8]>) {
+        while self.size + data.len() > CACHED_BLOB_BYTES {
+            match self.blobs.iter().min_by_key(|(_, b)| b.last_used).map(|(k, _)| *k) {
+                Some(oldest) => {
+                    self.size -= self.blobs.remove(&oldest).unwrap().data.len();
+                }
+                None => break,
+            }
+        }
+        self.clock += 1;
+        self.size += data.len();
+        let last_used = self.clock;
+        if let Some(replaced) = self.blobs.insert(id, CachedBlob { data, last_used }) {
+            self.size -= replaced.data.len();
+        }
+    }
+}
+
+impl GitProvider {
+    pub fn new(repository_path: &Path, revision: &str) -> Result<GitProvider, Box<dyn std::error::Error>> {
+        let repository = Repository::open(repository_path)?;
+        let commit = repository.resolve(revision)?;
+        let (tree, time) = repository.commit_tree(&commit)?;
+        println!("Projecting tree {} of {revision:?}", to_hex(&tree));
+        let time = time.map(filetime::from_unix_seconds).unwrap_or_else(filetime::now);
+        Ok(GitProvider {
+            repository,
+            root: GitEntry {
+                name: OsString::new(),
+                mode: MODE_DIRECTORY,
+                id: tree,
+                info: EntryInfo::directory(time),
+            },
+            trees: RwLock::new(HashMap::new()),
+            blobs: Mutex::new(CachedBlobs::default()),
+        })
+    }
+
+    fn tree(&self, directory: &GitEntry) -> std::io::Result<Arc<Vec<GitEntry>>> {
+        // Submodules are other repositories, they show up empty
+        if directory.mode == MODE_GITLINK {
+            return Ok(Arc::new(Vec::new()));
+        }
+        if let Some(entries) = self.trees.read().unwrap().get(&directory.id) {
+            return Ok(entries.clone());
+        }
+
+        let time = self.root.info.last_write_time;
+        let tree = self.repository.read_tree(&directory.id)?;
+        let files: Vec<ObjectId> = tree.iter().filter(|e| !matches!(e.mode, MODE_DIRECTORY | MODE_GITLINK)).map(|e| e.id).collect();
+        let mut sizes = self.repository.objects.headers(&files)?.into_iter().map(|(_, size)| size);
+        let mut entries = Vec::new();
+        for entry in tree {
+            let info = match entry.mode {
+                MODE_DIRECTORY | MODE_GITLINK => EntryInfo::directory(time),
+                // Executables (and the group writable files of old repositories) are plain files,
+                // Windows goes by the extension to decide what runs
+                mode => {
+                    let mut info = EntryInfo::file(sizes.next().unwrap(), time);
+                    info.attributes = FileSystem::FILE_ATTRIBUTE_READONLY.0;
+                    if mode == MODE_SYMLINK {
+                        info.attributes |= FileSystem::FILE_ATTRIBUTE_SYSTEM.0;
+                    }
+                    info
+                }
+            };
+            entries.push(GitEntry {
+                name: entry.name.into(),
+                mode: entry.mode,
+                id: entry.id,
+                info,
+            });
+        }
+        // Git is case sensitive, of names that only differ in case the first one in git order wins
+        entries.sort_by(|a, b| compare_file_names(&a.name, &b.name));
+        entries.dedup_by(|b, a| {
+            let duplicate = compare_file_names(&a.name, &b.name) == std::cmp::Ordering::Equal;
+            if duplicate {
+                println!("Leaving out {:?}, it only differs in case from {:?}", b.name, a.name);
+            }
+            duplicate
+        });
+
+        let entries = Arc::new(entries);
+        self.trees.write().unwrap().insert(directory.id, entries.clone());
+        Ok(entries)
+    }
+
+    fn lookup(&self, file_path: &Path) -> Result<Option<GitEntry>, windows::core::HRESULT> {
+        let mut current = self.root.clone();
+        for component in file_path.components() {
+            let name = match component {
+                Component::Normal(n) => n,
+                _ => {
+                    return Ok(None);
+                }
+            };
+            if !current.info.is_directory {
+                return Ok(None);
+            }
+            let entries = self.tree(&current).map_err(|e| {
+                println!("Could not read the tree of {file_path:?}: {e}");
+                windows::Win32::Foundation::E_FAIL
+            })?;
+            current = match entries.binary_search_by(|e| compare_file_names(&e.name, name)) {
+                Ok(i) => entries[i].clone(),
+                Err(_) => {
+                    return Ok(None);
+                }
+            };
+        }
+        Ok(Some(current))
+    }
+}
+
+impl ProjFSProvider for GitProvider {
+    fn init(&mut self, _root: &Path) -> Result<VirtualizationOptions, Box<dyn std::error::Error>> {
+        Ok(VirtualizationOptions::default())
+    }
+
+    fn start(&mut self, _context: ProjectedFileSystem::PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn new_enumeration(&self, _id: windows::core::GUID, file_path: &Path) -> Box<dyn EnumerationState> {
+        let entries = match self.lookup(file_path) {
+            Ok(Some(directory)) if directory.info.is_directory => match self.tree(&directory) {
+                Ok(entries) => entries.iter().map(|e| (e.name.clone(), e.info.basic_info())).collect(),
+                Err(e) => {
+                    println!("Could not read the tree of {file_path:?}: {e}");
+                    Vec::new()
+                }
+            },
+            _ => Vec::new(),
+        };
+        Box::new(ListEnumeration::new(entries))
+    }
+
+    fn get_placeholder_info(&self, file_path: &Path) -> Result<ProjectedFileSystem::PRJ_PLACEHOLDER_INFO, windows::core::HRESULT> {
+        match self.lookup(file_path)? {
+            Some(entry) => {
+                let mut placeholder = entry.info.placeholder_info();
+                if !entry.info.is_directory {
+                    placeholder.VersionInfo.ContentID[..20].copy_from_slice(&entry.id);
+                }
+                Ok(placeholder)
+            }
+            None => Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into()),
+        }
+    }
+
+    fn get_file_data(&self, file_path: &Path) -> Result<Box<dyn SeekRead>, windows::core::HRESULT> {
+        let entry = match self.lookup(file_path)? {
+            Some(e) if !e.info.is_directory => e,
+            _ => {
+                return Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into());
+            }
+        };
+        if let Some(data) = self.blobs.lock().unwrap().get(&entry.id) {
+            return Ok(Box::new(std::io::Cursor::new(data)));
+        }
+        match self.repository.objects.read(&entry.id) {
+            Ok((_, data)) => {
+                let data: Arc<[u8]> = data.into();
+                self.blobs.lock().unwrap().insert(entry.id, data.clone());
+                Ok(Box::new(std::io::Cursor::new(data)))
+            }
+            Err(e) => {
+                println!("Could not read {} for {file_path:?}: {e}", to_hex(&entry.id));
+                Err(windows::Win32::Foundation::E_FAIL)
+            }
+        }
+    }
+
+    fn query_file_name(&self, file_path: &Path) -> windows::core::HRESULT {
+        match self.lookup(file_path) {
+            Ok(Some(_)) => windows::Win32::Foundation::S_OK,
+            Ok(None) => windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into(),
+            Err(e) => e,
+        }
+    }
+
+    fn notification(&self, _callbackdata: *const ProjectedFileSystem::PRJ_CALLBACK_DATA, _is_directory: bool, _notification: ProjectedFileSystem::PRJ_NOTIFICATION, _destinationfilename: windows::core::PCWSTR, _operationparameters: *mut ProjectedFileSystem::PRJ_NOTIFICATION_PARAMETERS) -> windows::core::HRESULT {
+        windows::Win32::Foundation::S_OK
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+    use super::super::object_store::tests::Fixture;
+    use super::super::repository::tests::{commit, tree};
+
+    fn attributes(provider: &GitProvider, path: &str) -> (i64, u32) {
+        let info = provider.get_placeholder_info(Path::new(path)).unwrap().FileBasicInfo;
+        (info.FileSize, info.FileAttributes)
+    }
+
+    fn data(provider: &GitProvider, path: &str) -> Vec<u8> {
+        let mut data = Vec::new();
+        provider.get_file_data(Path::new(path)).ok().unwrap().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn projects_a_commit() {
+        let fixture = Fixture::new("provider");
+        let (text, script) = (fixture.loose("blob", b"text"), fixture.loose("blob", b"#!/bin/sh\n"));
+        let link = fixture.loose("blob", b"text.txt");
+        let subtree = fixture.loose("tree", &tree(&[("100644", "inner.txt", text)]));
+        let root = fixture.loose("tree", &tree(&[
+            ("100644", "README", text),
+            ("40000", "dir", subtree),
+            ("100644", "readme", script),
+            ("100755", "run.sh", script),
+            ("160000", "submodule", Fixture::id(b"elsewhere")),
+            ("120000", "link", link),
+        ]));
+        commit(&fixture, &root, 1_650_000_000);
+        let provider = GitProvider::new(&fixture.0, "main").unwrap();
+
+        let read_only = FileSystem::FILE_ATTRIBUTE_READONLY.0;
+        assert_eq!(attributes(&provider, "run.sh"), (10, read_only));
+        assert_eq!(attributes(&provider, "dir/inner.txt"), (4, read_only));
+        assert_eq!(attributes(&provider, "link"), (8, read_only | FileSystem::FILE_ATTRIBUTE_SYSTEM.0));
+        // The first of the names that only differ in case
+        assert_eq!(data(&provider, "readme"), b"text");
+        assert_eq!(data(&provider, "run.sh"), b"#!/bin/sh\n");
+        assert_eq!(provider.query_file_name(Path::new("submodule")), windows::Win32::Foundation::S_OK);
+        assert_eq!(provider.query_file_name(Path::new("submodule/anything")), windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into());
+        assert_eq!(provider.query_file_name(Path::new("run.sh/below")), windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into());
+        assert_eq!(provider.root.info.last_write_time, filetime::from_unix_seconds(1_650_000_000));
+    }
+
+    #[test]
+    fn reads_blobs_once() {
+        let fixture = Fixture::new("blob-cache");
+        let blob = fixture.loose("blob", b"decoded once");
+        let root = fixture.loose("tree", &tree(&[("100644", "file", blob)]));
+        commit(&fixture, &root, 0);
+        let provider = GitProvider::new(&fixture.0, "HEAD").unwrap();
+        assert_eq!(data(&provider, "file"), b"decoded once");
+        let hex = to_hex(&blob);
+        std::fs::remove_file(fixture.objects().join(&hex[..2]).join(&hex[2..])).unwrap();
+        assert_eq!(data(&provider, "file"), b"decoded once");
+    }
+
+    #[test]
+    fn drops_the_least_recently_used_blobs() {
+        let mut cache = CachedBlobs::default();
+        let third: Arc<[u8]> = vec![0u8; CACHED_BLOB_BYTES / 3].into();
+        let (a, b, c, d) = (Fixture::id(b"a"), Fixture::id(b"b"), Fixture::id(b"c"), Fixture::id(b"d"));
+        cache.insert(a, third.clone());
+        cache.insert(b, third.clone());
+        cache.insert(c, third.clone());
+        cache.get(&a);
+        cache.insert(d, third.clone());
+        assert!(cache.get(&b).is_none());
+        assert!(cache.get(&a).is_some() && cache.get(&c).is_some() && cache.get(&d).is_some());
+
+        let large: Arc<[u8]> = vec![0u8; CACHED_BLOB_BYTES + 1].into();
+        cache.insert(a, large);
+        assert_eq!(cache.blobs.len(), 1);
+        assert_eq!(cache.size, CACHED_BLOB_BYTES + 1);
+        cache.insert(b, third);
+        assert!(cache.get(&a).is_none());
+        assert_eq!(cache.size, CACHED_BLOB_BYTES / 3);
+    }
+}
@@ -0,0 +1,248 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::object_store::{from_hex, to_hex, ObjectId, ObjectKind, ObjectStore};
+
+/// Symbolic refs and tags can point at each other, but never this deep in a sane repository
+const MAX_INDIRECTIONS: usize = 16;
+
+pub const MODE_DIRECTORY: u32 = 0o040000;
+pub const MODE_SYMLINK: u32 = 0o120000;
+/// Submodules, the id is a commit of another repository
+pub const MODE_GITLINK: u32 = 0o160000;
+
+pub struct TreeEntry {
+    pub name: String,
+    pub mode: u32,
+    pub id: ObjectId,
+}
+
+pub struct Repository {
+    git_dir: PathBuf,
+    // Where worktrees share refs and objects with the main repository
+    common_dir: PathBuf,
+    pub objects: ObjectStore,
+}
+
+fn header_id(text: &str, name: &str) -> Result<ObjectId, String> {
+    text.lines()
+        .take_while(|l| !l.is_empty())
+        .find_map(|l| l.strip_prefix(name).and_then(|v| v.strip_prefix(' ')))
+        .and_then(from_hex)
+        .ok_or_else(|| format!("Object has no {name} header"))
+}
+
+impl Repository {
+    /// A working tree with a `.git` directory or file, or a bare repository
+    pub fn open(path: &Path) -> Result<Repository, Box<dyn std::error::Error>> {
+        let dot_git = path.join(".git");
+        let git_dir = if dot_git.is_dir() {
+            dot_git
+        } else if dot_git.is_file() {
+            let text = fs::read_to_string(&dot_git)?;
+            let target = text.trim().strip_prefix("gitdir:").ok_or_else(|| format!("{dot_git:?} doesn't point at a git directory"))?;
+            path.join(target.trim())
+        } else if path.join("objects").is_dir() && path.join("HEAD").is_file() {
+            path.to_path_buf()
+        } else {
+            return Err(format!("{path:?} is not a git repository").into());
+        };
+        let common_dir = match fs::read_to_string(git_dir.join("commondir")) {
+            Ok(text) => git_dir.join(text.trim()),
+            Err(_) => git_dir.clone(),
+        };
+        let objects = ObjectStore::open(&common_dir.join("objects"))?;
+        Ok(Repository {
+            git_dir,
+            common_dir,
+            objects,
+        })
+    }
+
+    fn packed_ref(&self, name: &str) -> Option<ObjectId> {
+        let text = fs::read_to_string(self.common_dir.join("packed-refs")).ok()?;
+        text.lines()
+            .filter(|l| !l.starts_with('#') && !l.starts_with('^'))
+            .find_map(|l| match l.split_once(' ') {
+                Some((id, n)) if n == name => from_hex(id),
+                _ => None,
+            })
+    }
+
+    fn read_ref(&self, name: &str) -> Option<ObjectId> {
+        let mut name = name.to_string();
+        for _ in 0..MAX_INDIRECTIONS {
+            let loose = [&self.git_dir, &self.common_dir].iter().find_map(|d| fs::read_to_string(d.join(&name)).ok());
+            let value = match loose {
+                Some(v) => v.trim().to_string(),
+                None => {
+                    return self.packed_ref(&name);
+                }
+            };
+            match value.strip_prefix("ref:") {
+                Some(target) => name = target.trim().to_string(),
+                None => {
+                    return from_hex(&value);
+                }
+            }
+        }
+        None
+    }
+
+    /// A full object id, or a ref looked up the way `git rev-parse` does
+    pub fn resolve(&self, revision: &str) -> Result<ObjectId, String> {
+        if let Some(id) = from_hex(revision) {
+            return Ok(id);
+        }
+        let candidates = [
+            revision.to_string(),
+            format!("refs/{revision}"),
+            format!("refs/tags/{revision}"),
+            format!("refs/heads/{revision}"),
+            format!("refs/remotes/{revision}"),
+            format!("refs/remotes/{revision}/HEAD"),
+        ];
+        candidates.iter().find_map(|c| self.read_ref(c)).ok_or_else(|| format!("Unknown revision {revision:?}"))
+    }
+
+    /// Root tree of a commit, going through tags, and the Unix time it was committed at if there is a commit
+    pub fn commit_tree(&self, id: &ObjectId) -> Result<(ObjectId, Option<i64>), Box<dyn std::error::Error>> {
+        let mut id = *id;
+        for _ in 0..MAX_INDIRECTIONS {
+            let (kind, data) = self.objects.read(&id)?;
+            let text = String::from_utf8_lossy(&data);
+            match kind {
+                ObjectKind::Tag => {
+                    id = header_id(&text, "object")?;
+                }
+                ObjectKind::Commit => {
+                    let time = text.lines()
+                        .take_while(|l| !l.is_empty())
+                        .find_map(|l| l.strip_prefix("committer "))
+                        .and_then(|c| c.rsplit(' ').nth(1))
+                        .and_then(|t| t.parse().ok());
+                    return Ok((header_id(&text, "tree")?, time));
+                }
+                ObjectKind::Tree => {
+                    return Ok((id, None));
+                }
+                ObjectKind::Blob => {
+                    return Err(format!("{} is a blob, not a commit", to_hex(&id)).into());
+                }
+            }
+        }
+        Err(format!("Too many tags in front of {}", to_hex(&id)).into())
+    }
+
+    /// Entries are "<octal mode> <name>\0<20 byte id>"
+    pub fn read_tree(&self, id: &ObjectId) -> std::io::Result<Vec<TreeEntry>> {
+        let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Invalid tree {}", to_hex(id)));
+        let (kind, data) = self.objects.read(id)?;
+        if kind != ObjectKind::Tree {
+            return Err(invalid());
+        }
+        let mut entries = Vec::new();
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            let space = rest.iter().position(|b| *b == b' ').ok_or_else(invalid)?;
+            let nul = rest.iter().position(|b| *b == 0).ok_or_else(invalid)?;
+            if nul < space || rest.len() < nul + 21 {
+                return Err(invalid());
+            }
+            let mode = std::str::from_utf8(&rest[..space]).ok().and_then(|m| u32::from_str_radix(m, 8).ok()).ok_or_else(invalid)?;
+            entries.push(TreeEntry {
+                name: String::from_utf8_lossy(&rest[space + 1..nul]).into_owned(),
+                mode,
+                id: rest[nul + 1..nul + 21].try_into().unwrap(),
+            });
+            rest = &rest[nul + 21..];
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use super::super::object_store::tests::Fixture;
+
+    /// Tree object data, in the order given
+    pub fn tree(entries: &[(&str, &str, ObjectId)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for (mode, name, id) in entries {
+            data.extend_from_slice(format!("{mode} {name}\0").as_bytes());
+            data.extend_from_slice(id);
+        }
+        data
+    }
+
+    /// A commit of the tree at `time`, checked out on main
+    pub fn commit(fixture: &Fixture, tree: &ObjectId, time: i64) -> ObjectId {
+        let text = format!("tree {}\nauthor A <a@example.com> {time} +0200\ncommitter C <c@example.com> {time} +0200\n\nMessage\n", to_hex(tree));
+        let commit = fixture.loose("commit", text.as_bytes());
+        fs::create_dir_all(fixture.git_dir().join("refs").join("heads")).unwrap();
+        fs::write(fixture.git_dir().join("refs").join("heads").join("main"), format!("{}\n", to_hex(&commit))).unwrap();
+        commit
+    }
+
+    #[test]
+    fn reads_trees() {
+        let fixture = Fixture::new("tree");
+        let (blob, subtree) = (Fixture::id(b"blob"), Fixture::id(b"tree"));
+        let id = fixture.loose("tree", &tree(&[("100644", "file.txt", blob), ("40000", "dir", subtree), ("100755", "run.sh", blob)]));
+        let truncated = fixture.loose("tree", &tree(&[("100644", "file.txt", blob)])[..20]);
+        let repository = Repository::open(&fixture.0).unwrap();
+        let entries: Vec<_> = repository.read_tree(&id).unwrap().into_iter().map(|e| (e.name, e.mode, e.id)).collect();
+        assert_eq!(entries, [
+            ("file.txt".to_string(), 0o100644, blob),
+            ("dir".to_string(), MODE_DIRECTORY, subtree),
+            ("run.sh".to_string(), 0o100755, blob),
+        ]);
+        assert!(repository.read_tree(&truncated).is_err());
+        let blob = fixture.loose("blob", b"not a tree");
+        assert!(repository.read_tree(&blob).is_err());
+    }
+
+    #[test]
+    fn resolves_revisions() {
+        let fixture = Fixture::new("refs");
+        let tree = fixture.loose("tree", b"");
+        let commit = commit(&fixture, &tree, 1_650_000_000);
+        let tag = fixture.loose("tag", format!("object {}\ntype commit\ntag v1\n\nRelease\n", to_hex(&commit)).as_bytes());
+        fs::write(fixture.git_dir().join("packed-refs"), format!("# pack-refs with: peeled\n{} refs/tags/v1\n^{}\n", to_hex(&tag), to_hex(&commit))).unwrap();
+        let repository = Repository::open(&fixture.0).unwrap();
+        for revision in ["HEAD", "main", "refs/heads/main", "heads/main"] {
+            assert_eq!(repository.resolve(revision), Ok(commit), "{revision}");
+        }
+        assert_eq!(repository.resolve("v1"), Ok(tag));
+        assert_eq!(repository.resolve(&to_hex(&tree)), Ok(tree));
+        assert!(repository.resolve("missing").is_err());
+
+        assert_eq!(repository.commit_tree(&tag).unwrap(), (tree, Some(1_650_000_000)));
+        assert_eq!(repository.commit_tree(&tree).unwrap(), (tree, None));
+        let blob = fixture.loose("blob", b"");
+        assert!(repository.commit_tree(&blob).is_err());
+    }
+
+    #[test]
+    fn opens_worktrees_and_bare_repositories() {
+        let fixture = Fixture::new("worktree");
+        let tree = fixture.loose("tree", b"");
+        let commit = commit(&fixture, &tree, 0);
+        // A worktree with its own HEAD, sharing refs and objects with the main repository
+        let worktree_dir = fixture.git_dir().join("worktrees").join("other");
+        fs::create_dir_all(&worktree_dir).unwrap();
+        fs::write(worktree_dir.join("commondir"), "../..\n").unwrap();
+        fs::write(worktree_dir.join("HEAD"), format!("{}\n", to_hex(&commit))).unwrap();
+        let checkout = fixture.0.join("checkout");
+        fs::create_dir_all(&checkout).unwrap();
+        fs::write(checkout.join(".git"), format!("gitdir: {}\n", worktree_dir.display())).unwrap();
+        let worktree = Repository::open(&checkout).unwrap();
+        assert_eq!(worktree.resolve("HEAD"), Ok(commit));
+        assert_eq!(worktree.resolve("main"), Ok(commit));
+
+        let bare = Repository::open(&fixture.git_dir()).unwrap();
+        assert_eq!(bare.commit_tree(&commit).unwrap(), (tree, Some(0)));
+        assert!(Repository::open(&checkout.join("nothing")).is_err());
+    }
+}
@@ -0,0 +1,566 @@
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Arc;
+
+use windows::Win32::Storage::FileSystem;
+
+use crate::projfs_provider::{filetime, EntryInfo, VirtualTree};
+
+const SECTOR_SIZE: u64 = 2048;
+/// The first 16 sectors are left to the system, like boot code
+const FIRST_DESCRIPTOR_SECTOR: u64 = 16;
+const MAX_DESCRIPTORS: u64 = 64;
+const STANDARD_IDENTIFIER: &[u8] = b"CD001";
+
+const PRIMARY_DESCRIPTOR: u8 = 1;
+const SUPPLEMENTARY_DESCRIPTOR: u8 = 2;
+const TERMINATOR_DESCRIPTOR: u8 = 255;
+/// Escape sequences of the UCS-2 levels that make a supplementary descriptor Joliet
+const JOLIET_ESCAPES: [&[u8]; 3] = [b"%/@", b"%/C", b"%/E"];
+
+const FLAG_HIDDEN: u8 = 0x01;
+const FLAG_DIRECTORY: u8 = 0x02;
+/// The file goes on in the next record with the same name
+const FLAG_MULTI_EXTENT: u8 = 0x80;
+
+/// Rock Ridge keeps chasing continuation areas, but not forever
+const MAX_CONTINUATIONS: usize = 16;
+
+/// Where a piece of a file is, in bytes from the start of the image
+#[derive(Clone, Copy, Debug)]
+pub struct Extent {
+    pub start: u64,
+    pub length: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub enum IsoContent {
+    /// Directories
+    #[default]
+    None,
+    /// Mostly one, files over 4GiB take more
+    Extents(Arc<[Extent]>),
+    /// Where a Rock Ridge symlink leads, from the root. Only until the whole tree is read.
+    Link(String),
+}
+
+/// Which names the tree gets, Rock Ridge keeps everything and Joliet at least keeps case and length
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Naming {
+    Primary,
+    Joliet,
+    /// System use entries start `skip` bytes into the system use area
+    RockRidge { skip: usize },
+}
+
+struct Record {
+    extent: u32,
+    size: u32,
+    flags: u8,
+    time: i64,
+    identifier: Vec<u8>,
+    system_use: Vec<u8>,
+}
+
+/// What the Rock Ridge entries of a record have to say
+#[derive(Default)]
+struct RockRidge {
+    name: Option<String>,
+    mode: Option<u32>,
+    link: Option<String>,
+    modified: Option<i64>,
+    /// The directory was moved out of the way to keep the primary tree shallow, it lives here
+    child: Option<u32>,
+    /// This is where a moved directory ended up, it shows up at its real place
+    relocated: bool,
+}
+
+fn u16_at(b: &[u8], o: usize) -> u16 {
+    u16::from_le_bytes([b[o], b[o + 1]])
+}
+
+fn u32_at(b: &[u8], o: usize) -> u32 {
+    u32::from_le_bytes(b[o..o + 4].try_into().unwrap())
+}
+
+fn read_at(file: &mut File, offset: u64, size: usize) -> std::io::Result<Vec<u8>> {
+    let mut data = vec![0u8; size];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// Unix seconds of the 7 byte date of directory records, its last byte is the offset from UTC in 15 minute steps
+fn record_time(b: &[u8]) -> i64 {
+    let local = filetime::unix_seconds_from_civil(b[0] as i64 + 1900, b[1] as i64, b[2] as i64, b[3] as i64, b[4] as i64, b[5] as i64);
+    local - (b[6] as i8) as i64 * 15 * 60
+}
+
+/// Unix seconds of the 17 byte date of volume descriptors, digits with the same offset at the end
+fn long_time(b: &[u8]) -> Option<i64> {
+    let text = std::str::from_utf8(&b[..14]).ok()?;
+    let field = |range: std::ops::Range<usize>| text.get(range).and_then(|t| t.parse::<i64>().ok());
+    let local = filetime::unix_seconds_from_civil(field(0..4)?, field(4..6)?, field(6..8)?, field(8..10)?, field(10..12)?, field(12..14)?);
+    Some(local - (b[16] as i8) as i64 * 15 * 60)
+}
+
+fn parse_record(b: &[u8]) -> Option<Record> {
+    let length = *b.first()? as usize;
+    let identifier_length = *b.get(32)? as usize;
+    if length < 34 || b.len() < length || 33 + identifier_length > length {
+        return None;
+    }
+    // A padding byte keeps the system use area at an even offset
+    let system_use_start = 33 + identifier_length + (1 - identifier_length % 2);
+    Some(Record {
+        extent: u32_at(b, 2),
+        size: u32_at(b, 10),
+        time: record_time(&b[18..25]),
+        flags: b[25],
+        identifier: b[33..33 + identifier_length].to_vec(),
+        system_use: b.get(system_use_start..length).unwrap_or_default().to_vec(),
+    })
+}
+
+/// Primary names are upper case 8.3 with a version, `NAME.TXT;1`, and a dot even without extension
+fn primary_name(identifier: &[u8]) -> String {
+    let name = String::from_utf8_lossy(identifier);
+    let name = name.split(';').next().unwrap_or_default();
+    name.strip_suffix('.').unwrap_or(name).to_string()
+}
+
+/// Joliet names are UCS-2 big endian, with a version too
+fn joliet_name(identifier: &[u8]) -> String {
+    let units: Vec<u16> = identifier.chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect();
+    let name = String::from_utf16_lossy(&units);
+    match name.rsplit_once(';') {
+        Some((name, version)) if version.chars().all(|c| c.is_ascii_digit()) => name.to_string(),
+        _ => name,
+    }
+}
+
+/// Follows the symlink components of an SL entry, which can go on in the next one
+fn parse_symlink(data: &[u8], link: &mut String, continuing: &mut bool) {
+    let mut components = data;
+    while components.len() >= 2 {
+        let (flags, length) = (components[0], components[1] as usize);
+        let text = components.get(2..2 + length).unwrap_or_default();
+        components = components.get(2 + length..).unwrap_or_default();
+        let part = if flags & 0x02 != 0 {
+            ".".into()
+        } else if flags & 0x04 != 0 {
+            "..".into()
+        } else {
+            String::from_utf8_lossy(text)
+        };
+        if flags & 0x08 != 0 {
+            // The root makes the link absolute
+            link.clear();
+            link.push('/');
+        } else {
+            if !*continuing && !link.is_empty() && !link.ends_with('/') {
+                link.push('/');
+            }
+            link.push_str(&part);
+        }
+        *continuing = flags & 0x01 != 0;
+    }
+}
+
+/// Reads a volume and builds the tree from whichever naming it has that keeps the most
+pub struct Image {
+    file: File,
+    /// Nothing the records say is read past it
+    length: u64,
+    block_size: u64,
+    root: Record,
+    naming: Naming,
+    pub modified: Option<i64>,
+}
+
+impl Image {
+    pub fn open(path: &Path) -> Result<Image, Box<dyn std::error::Error>> {
+        let mut file = File::open(path).map_err(|e| format!("{path:?}: {e}"))?;
+        let length = file.metadata()?.len();
+        let mut primary = None;
+        let mut joliet = None;
+        for sector in FIRST_DESCRIPTOR_SECTOR..FIRST_DESCRIPTOR_SECTOR + MAX_DESCRIPTORS {
+            let descriptor = read_at(&mut file, sector * SECTOR_SIZE, SECTOR_SIZE as usize)?;
+            if &descriptor[1..6] != STANDARD_IDENTIFIER {
+                break;
+            }
+            match descriptor[0] {
+                PRIMARY_DESCRIPTOR if primary.is_none() => primary = Some(descriptor),
+                SUPPLEMENTARY_DESCRIPTOR if joliet.is_none() && JOLIET_ESCAPES.iter().any(|e| descriptor[88..120].starts_with(e)) => {
+                    joliet = Some(descriptor);
+                }
+                TERMINATOR_DESCRIPTOR => break,
+                _ => {}
+            }
+        }
+        let primary = primary.ok_or_else(|| format!("{path:?} is not an ISO 9660 image"))?;
+        let block_size = u16_at(&primary, 128) as u64;
+        if block_size == 0 {
+            return Err("Invalid logical block size".into());
+        }
+        let modified = long_time(&primary[830..847]);
+        let primary_root = parse_record(&primary[156..190]).ok_or("Invalid root directory record")?;
+
+        let mut image = Image {
+            file,
+            length,
+            block_size,
+            root: primary_root,
+            naming: Naming::Primary,
+            modified,
+        };
+        // The SP entry at the start of the root's first record says system use entries are there
+        let dot = image.read_directory(image.root.extent, image.root.size)?.into_iter().next();
+        let skip = dot
+            .filter(|d| d.system_use.starts_with(b"SP") && d.system_use.get(4..6) == Some(&[0xbe, 0xef][..]))
+            .and_then(|d| d.system_use.get(6).copied());
+        if let Some(skip) = skip {
+            image.naming = Naming::RockRidge { skip: skip as usize };
+        } else if let Some(joliet) = joliet {
+            image.root = parse_record(&joliet[156..190]).ok_or("Invalid Joliet root directory record")?;
+            image.naming = Naming::Joliet;
+        }
+        println!("Using {:?} names", image.naming);
+        Ok(image)
+    }
+
+    /// Reads what a record points to, which has to be inside the image
+    fn read_at(&mut self, offset: u64, size: u64) -> std::io::Result<Vec<u8>> {
+        if offset.saturating_add(size) > self.length {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{size} bytes at {offset} are past the end of the image")));
+        }
+        read_at(&mut self.file, offset, size as usize)
+    }
+
+    /// Records of a directory in order, `.` and `..` included. Records don't cross sectors and
+    /// the rest of a sector is zeros once they don't fit.
+    fn read_directory(&mut self, extent: u32, size: u32) -> std::io::Result<Vec<Record>> {
+        let data = self.read_at(extent as u64 * self.block_size, size as u64)?;
+        let mut records = Vec::new();
+        for sector in data.chunks(SECTOR_SIZE as usize) {
+            let mut position = 0;
+            while position < sector.len() && sector[position] != 0 {
+                match parse_record(&sector[position..]) {
+                    Some(record) => records.push(record),
+                    None => break,
+                }
+                position += sector[position] as usize;
+            }
+        }
+        Ok(records)
+    }
+
+    fn rock_ridge(&mut self, record: &Record, skip: usize) -> std::io::Result<RockRidge> {
+        let mut result = RockRidge::default();
+        let mut area = record.system_use.get(skip..).unwrap_or_default().to_vec();
+        let mut continuations = 0;
+        let mut name_continues = false;
+        let mut link_continues = false;
+        loop {
+            let mut continuation = None;
+            let mut entries = &area[..];
+            while entries.len() >= 4 {
+                let length = entries[2] as usize;
+                if length < 4 || length > entries.len() {
+                    break;
+                }
+                let (entry, rest) = entries.split_at(length);
+                entries = rest;
+                match &entry[..2] {
+                    b"NM" if entry.len() > 4 && entry[4] & 0x06 == 0 => {
+                        let part = String::from_utf8_lossy(&entry[5..]);
+                        match &mut result.name {
+                            Some(name) if name_continues => name.push_str(&part),
+                            name => *name = Some(part.into_owned()),
+                        }
+                        name_continues = entry[4] & 0x01 != 0;
+                    }
+                    b"PX" if entry.len() >= 8 => result.mode = Some(u32_at(entry, 4)),
+                    b"SL" if entry.len() > 4 => {
+                        parse_symlink(&entry[5..], result.link.get_or_insert_with(String::new), &mut link_continues);
+                    }
+                    b"TF" if entry.len() > 4 => {
+                        let flags = entry[4];
+                        let size = if flags & 0x80 != 0 { 17 } else { 7 };
+                        // Creation comes first if it's there, then modification
+                        let at = 5 + if flags & 0x01 != 0 { size } else { 0 };
+                        if flags & 0x02 != 0 {
+                            if let Some(time) = entry.get(at..at + size) {
+                                result.modified = if size == 17 { long_time(time) } else { Some(record_time(time)) };
+                            }
+                        }
+                    }
+                    b"CL" if entry.len() >= 8 => result.child = Some(u32_at(entry, 4)),
+                    b"RE" => result.relocated = true,
+                    b"CE" if entry.len() >= 28 => {
+                        continuation = Some((u32_at(entry, 4) as u64 * self.block_size + u32_at(entry, 12) as u64, u32_at(entry, 20) as u64));
+                    }
+                    b"ST" => break,
+                    _ => {}
+                }
+            }
+            match continuation {
+                Some((offset, length)) if continuations < MAX_CONTINUATIONS => {
+                    area = self.read_at(offset, length)?;
+                    continuations += 1;
+                }
+                _ => break,
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn build_tree(&mut self) -> Result<VirtualTree<IsoContent>, Box<dyn std::error::Error>> {
+        let root_time = filetime::from_unix_seconds(self.modified.unwrap_or(self.root.time));
+        let mut tree = VirtualTree::new(EntryInfo::directory(root_time), IsoContent::None);
+        let mut pending = vec![(VirtualTree::<IsoContent>::ROOT, String::new(), self.root.extent, self.root.size)];
+        // Broken images can point back up, every directory only gets read once
+        let mut visited = HashSet::new();
+        while let Some((parent, parent_path, extent, size)) = pending.pop() {
+            if !visited.insert(extent) {
+                continue;
+            }
+            let records = self.read_directory(extent, size)?;
+            let mut extents = Vec::new();
+            // The first two are `.` and `..`
+            for record in records.into_iter().skip(2) {
+                let (mut name, mut time) = match self.naming {
+                    Naming::Joliet => (joliet_name(&record.identifier), record.time),
+                    _ => (primary_name(&record.identifier), record.time),
+                };
+                let mut directory = record.flags & FLAG_DIRECTORY != 0;
+                let (mut extent, mut size) = (record.extent, record.size);
+                let mut mode = None;
+                let mut link = None;
+                if let Naming::RockRidge { skip } = self.naming {
+                    let rock_ridge = self.rock_ridge(&record, skip)?;
+                    if rock_ridge.relocated {
+                        continue;
+                    }
+                    if let Some(child) = rock_ridge.child {
+                        // The moved directory's own `.` record knows how big it is
+                        match self.read_directory(child, SECTOR_SIZE as u32)?.into_iter().next() {
+                            Some(dot) => {
+                                (extent, size, directory) = (dot.extent, dot.size, true);
+                            }
+                            None => {
+                                continue;
+                            }
+                        }
+                    }
+                    name = rock_ridge.name.unwrap_or(name);
+                    time = rock_ridge.modified.unwrap_or(time);
+                    mode = rock_ridge.mode;
+                    link = rock_ridge.link;
+                }
+                if name.is_empty() || name == "." || name == ".." {
+                    continue;
+                }
+                let time = filetime::from_unix_seconds(time);
+                let path = format!("{parent_path}/{name}");
+
+                if directory {
+                    let mut info = EntryInfo::directory(time);
+                    if record.flags & FLAG_HIDDEN != 0 {
+                        info.attributes = FileSystem::FILE_ATTRIBUTE_HIDDEN.0;
+                    }
+                    let id = tree.insert(parent, OsStr::new(&name), info, IsoContent::None);
+                    pending.push((id, path, extent, size));
+                    continue;
+                }
+                extents.push(Extent {
+                    start: extent as u64 * self.block_size,
+                    length: size as u64,
+                });
+                if record.flags & FLAG_MULTI_EXTENT != 0 {
+                    continue;
+                }
+
+                let extents: Arc<[Extent]> = std::mem::take(&mut extents).into();
+                let (info, content) = match link {
+                    Some(target) => {
+                        let target = if target.starts_with('/') {
+                            target
+                        } else {
+                            format!("{parent_path}/{target}")
+                        };
+                        (EntryInfo::file(0, time), IsoContent::Link(target))
+                    }
+                    None => {
+                        let mut info = EntryInfo::file(extents.iter().map(|e| e.length).sum(), time);
+                        // Without a mode there's only the fact that it's on read only media
+                        info.attributes = match mode {
+                            Some(mode) if mode & 0o222 != 0 => 0,
+                            _ => FileSystem::FILE_ATTRIBUTE_READONLY.0,
+                        };
+                        if record.flags & FLAG_HIDDEN != 0 {
+                            info.attributes |= FileSystem::FILE_ATTRIBUTE_HIDDEN.0;
+                        }
+                        (info, IsoContent::Extents(extents))
+                    }
+                };
+                tree.insert(parent, OsStr::new(&name), info, content);
+            }
+        }
+
+        tree.sort();
+        let left_out = tree.resolve_links(|c| match c {
+            IsoContent::Link(target) => Some(target),
+            _ => None,
+        });
+        if left_out > 0 {
+            println!("Left out {left_out} links to directories or to nothing");
+        }
+        Ok(tree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOT: u32 = 20;
+    const JOLIET_ROOT: u32 = 21;
+
+    /// A directory record, the system use area goes after the identifier
+    fn record(extent: u32, size: u32, flags: u8, identifier: &[u8], system_use: &[u8]) -> Vec<u8> {
+        let padding = 1 - identifier.len() % 2;
+        let mut record = vec![0u8; 33];
+        record[0] = (33 + identifier.len() + padding + system_use.len()) as u8;
+        record[2..6].copy_from_slice(&extent.to_le_bytes());
+        record[10..14].copy_from_slice(&size.to_le_bytes());
+        record[18..25].copy_from_slice(&[122, 1, 1, 0, 0, 0, 0]);
+        record[25] = flags;
+        record[32] = identifier.len() as u8;
+        record.extend_from_slice(identifier);
+        record.resize(record.len() + padding, 0);
+        record.extend_from_slice(system_use);
+        record
+    }
+
+    /// A directory sector, `.` with `dot_system_use` and `..` first
+    fn directory(extent: u32, dot_system_use: &[u8], records: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = record(extent, SECTOR_SIZE as u32, FLAG_DIRECTORY, &[0], dot_system_use);
+        data.extend(record(extent, SECTOR_SIZE as u32, FLAG_DIRECTORY, &[1], &[]));
+        for r in records {
+            data.extend_from_slice(r);
+        }
+        data
+    }
+
+    fn ucs2(name: &str) -> Vec<u8> {
+        name.encode_utf16().flat_map(|u| u.to_be_bytes()).collect()
+    }
+
+    /// Rock Ridge entries of one kind
+    fn susp(kind: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut entry = kind.to_vec();
+        entry.extend_from_slice(&[(4 + body.len()) as u8, 1]);
+        entry.extend_from_slice(body);
+        entry
+    }
+
+    /// An image file with a primary volume, a Joliet one if there's a root for it, and the given
+    /// sectors. It's removed again when the test is done.
+    struct Iso(std::path::PathBuf);
+
+    impl Iso {
+        fn new(name: &str, root_size: u32, joliet: bool, sectors: &[(u32, Vec<u8>)]) -> Iso {
+            let mut data = vec![0u8; 32 * SECTOR_SIZE as usize];
+            let mut descriptor = |sector: u64, kind: u8, root: u32| {
+                let d = &mut data[(sector * SECTOR_SIZE) as usize..][..SECTOR_SIZE as usize];
+                d[0] = kind;
+                d[1..6].copy_from_slice(STANDARD_IDENTIFIER);
+                d[128..130].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+                d[156..190].copy_from_slice(&record(root, root_size, FLAG_DIRECTORY, &[0], &[]));
+                d[830..847].copy_from_slice(b"2022010100000000\0");
+            };
+            descriptor(FIRST_DESCRIPTOR_SECTOR, PRIMARY_DESCRIPTOR, ROOT);
+            let mut next = FIRST_DESCRIPTOR_SECTOR + 1;
+            if joliet {
+                descriptor(next, SUPPLEMENTARY_DESCRIPTOR, JOLIET_ROOT);
+                data[(next * SECTOR_SIZE) as usize + 88..][..3].copy_from_slice(b"%/E");
+                next += 1;
+            }
+            data[(next * SECTOR_SIZE) as usize] = TERMINATOR_DESCRIPTOR;
+            data[(next * SECTOR_SIZE) as usize + 1..][..5].copy_from_slice(STANDARD_IDENTIFIER);
+            for (sector, content) in sectors {
+                data[*sector as usize * SECTOR_SIZE as usize..][..content.len()].copy_from_slice(content);
+            }
+            let path = std::env::temp_dir().join(format!("projfs-iso-{}-{name}.iso", std::process::id()));
+            std::fs::write(&path, data).unwrap();
+            Iso(path)
+        }
+
+        fn tree(&self) -> VirtualTree<IsoContent> {
+            Image::open(&self.0).unwrap().build_tree().unwrap()
+        }
+    }
+
+    impl Drop for Iso {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn names(tree: &VirtualTree<IsoContent>) -> Vec<String> {
+        tree.children(VirtualTree::<IsoContent>::ROOT).map(|c| tree.node(c).name.to_string_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn prefers_rock_ridge_then_joliet_names() {
+        let sp = susp(b"SP", &[0xbe, 0xef, 0]);
+        let mut nm = vec![0];
+        nm.extend_from_slice(b"ReadMe.txt");
+        let primary = [record(25, 5, 0, b"README.TXT;1", &susp(b"NM", &nm)), record(25, 5, 0, b"NOEXT.;1", &[])];
+        let joliet = [record(25, 5, 0, &ucs2("Joliet name.txt;1"), &[])];
+
+        let rock_ridge = Iso::new("rock-ridge", SECTOR_SIZE as u32, true, &[(ROOT, directory(ROOT, &sp, &primary)), (JOLIET_ROOT, directory(JOLIET_ROOT, &[], &joliet))]);
+        assert_eq!(names(&rock_ridge.tree()), ["NOEXT", "ReadMe.txt"]);
+
+        let joliet = Iso::new("joliet", SECTOR_SIZE as u32, true, &[(ROOT, directory(ROOT, &[], &primary)), (JOLIET_ROOT, directory(JOLIET_ROOT, &[], &joliet))]);
+        assert_eq!(names(&joliet.tree()), ["Joliet name.txt"]);
+
+        let primary = Iso::new("primary", SECTOR_SIZE as u32, false, &[(ROOT, directory(ROOT, &[], &primary))]);
+        assert_eq!(names(&primary.tree()), ["NOEXT", "README.TXT"]);
+    }
+
+    #[test]
+    fn joins_the_extents_of_a_file() {
+        let records = [
+            record(25, SECTOR_SIZE as u32, FLAG_MULTI_EXTENT, b"BIG.BIN;1", &[]),
+            record(27, 100, 0, b"BIG.BIN;1", &[]),
+            record(26, 3, 0, b"SMALL.BIN;1", &[]),
+        ];
+        let iso = Iso::new("multi-extent", SECTOR_SIZE as u32, false, &[(ROOT, directory(ROOT, &[], &records))]);
+        let tree = iso.tree();
+        let extents = |path: &str| {
+            let node = tree.node(tree.lookup(Path::new(path)).unwrap());
+            let extents = match &node.content {
+                IsoContent::Extents(extents) => extents.iter().map(|e| (e.start, e.length)).collect(),
+                _ => Vec::new(),
+            };
+            (node.info.size, extents)
+        };
+        assert_eq!(extents("BIG.BIN"), (2148, vec![(25 * SECTOR_SIZE, SECTOR_SIZE), (27 * SECTOR_SIZE, 100)]));
+        assert_eq!(extents("SMALL.BIN"), (3, vec![(26 * SECTOR_SIZE, 3)]));
+    }
+
+    #[test]
+    fn rejects_records_past_the_image() {
+        let too_big = Iso::new("too-big", u32::MAX, false, &[(ROOT, directory(ROOT, &[], &[]))]);
+        let error = Image::open(&too_big.0).err().unwrap().to_string();
+        assert!(error.contains("past the end"), "{error}");
+        // An SP entry cut off before it says where the entries start
+        let short_sp = Iso::new("short-sp", SECTOR_SIZE as u32, false, &[(ROOT, directory(ROOT, &[b'S', b'P', 6, 1, 0xbe, 0xef], &[record(25, 1, 0, b"A.TXT;1", &[])]))]);
+        assert_eq!(names(&short_sp.tree()), ["A.TXT"]);
+    }
+}
@@ -0,0 +1,4 @@
+mod image;
+mod provider;
+
+pub use provider::IsoProvider;
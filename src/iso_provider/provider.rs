@@ -0,0 +1,131 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use windows::Win32::Storage::ProjectedFileSystem;
+
+use crate::projfs_provider::{ProjFSProvider, EnumerationState, SeekRead, VirtualizationOptions, VirtualTree, WindowReader};
+use super::image::{Extent, Image, IsoContent};
+
+/// Projects the contents of an ISO 9660 image, file data is read straight out of its extents
+pub struct IsoProvider {
+    image: PathBuf,
+    tree: Arc<VirtualTree<IsoContent>>,
+}
+
+/// Files that take more than one extent, read one after the other
+struct ExtentReader {
+    file: File,
+    extents: Arc<[Extent]>,
+    size: u64,
+    position: u64,
+}
+
+impl Read for ExtentReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut start = 0;
+        for extent in self.extents.iter() {
+            if self.position < start + extent.length {
+                let within = self.position - start;
+                let read_len = std::cmp::min(buf.len() as u64, extent.length - within) as usize;
+                self.file.seek(SeekFrom::Start(extent.start + within))?;
+                let read = self.file.read(&mut buf[..read_len])?;
+                self.position += read as u64;
+                return Ok(read);
+            }
+            start += extent.length;
+        }
+        Ok(0)
+    }
+}
+
+impl Seek for ExtentReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(v) => Some(v),
+            SeekFrom::End(v) => self.size.checked_add_signed(v),
+            SeekFrom::Current(v) => self.position.checked_add_signed(v),
+        };
+        match new_position {
+            Some(v) => {
+                self.position = v;
+                Ok(self.position)
+            }
+            None => Err(std::io::Error::from(std::io::ErrorKind::InvalidInput)),
+        }
+    }
+}
+
+impl IsoProvider {
+    pub fn new(image: &Path) -> IsoProvider {
+        IsoProvider {
+            image: image.to_path_buf(),
+            tree: Default::default(),
+        }
+    }
+}
+
+impl ProjFSProvider for IsoProvider {
+    fn init(&mut self, _root: &Path) -> Result<VirtualizationOptions, Box<dyn std::error::Error>> {
+        self.tree = Arc::new(Image::open(&self.image)?.build_tree()?);
+        Ok(VirtualizationOptions::default())
+    }
+
+    fn start(&mut self, _context: ProjectedFileSystem::PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn new_enumeration(&self, _id: windows::core::GUID, file_path: &Path) -> Box<dyn EnumerationState> {
+        self.tree.enumeration(file_path)
+    }
+
+    fn get_placeholder_info(&self, file_path: &Path) -> Result<ProjectedFileSystem::PRJ_PLACEHOLDER_INFO, windows::core::HRESULT> {
+        match self.tree.lookup(file_path) {
+            Some(id) => Ok(self.tree.node(id).info.placeholder_info()),
+            None => Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into()),
+        }
+    }
+
+    fn get_file_data(&self, file_path: &Path) -> Result<Box<dyn SeekRead>, windows::core::HRESULT> {
+        let (extents, size) = match self.tree.lookup(file_path).map(|id| self.tree.node(id)) {
+            Some(node) => match &node.content {
+                IsoContent::Extents(extents) => (extents.clone(), node.info.size),
+                _ => {
+                    return Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into());
+                }
+            },
+            None => {
+                return Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into());
+            }
+        };
+        match File::open(&self.image) {
+            Ok(file) if extents.len() == 1 => Ok(Box::new(WindowReader::new(file, extents[0].start, size))),
+            Ok(file) => Ok(Box::new(ExtentReader {
+                file,
+                extents,
+                size,
+                position: 0,
+            })),
+            Err(e) => {
+                println!("Could not open {:?} for {file_path:?}: {e}", self.image);
+                Err(windows::Win32::Foundation::E_FAIL)
+            }
+        }
+    }
+
+    fn query_file_name(&self, file_path: &Path) -> windows::core::HRESULT {
+        match self.tree.lookup(file_path) {
+            Some(_) => windows::Win32::Foundation::S_OK,
+            None => windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into(),
+        }
+    }
+
+    fn notification(&self, _callbackdata: *const ProjectedFileSystem::PRJ_CALLBACK_DATA, _is_directory: bool, _notification: ProjectedFileSystem::PRJ_NOTIFICATION, _destinationfilename: windows::core::PCWSTR, _operationparameters: *mut ProjectedFileSystem::PRJ_NOTIFICATION_PARAMETERS) -> windows::core::HRESULT {
+        windows::Win32::Foundation::S_OK
+    }
+}
@@ -6,8 +6,21 @@ use clap::Parser;
 #[macro_use]
 extern crate lazy_static;
 
+mod cargo_provider;
+mod cas_provider;
+mod generators;
+mod git_provider;
+mod iso_provider;
+mod manifest_provider;
+mod mirror_provider;
+mod oci_provider;
 mod projfs_provider;
+mod sqlite_data_provider;
+mod sqlite_provider;
+mod squashfs_provider;
+mod tar_provider;
 mod zeros_provider;
+mod zip_provider;
 
 
 #[derive(Parser, Debug)]
@@ -20,6 +33,187 @@ struct Args {
     /// Number of times to greet
     #[clap(short, long, default_value_t = 1)]
     count: u8,
+
+    /// Access rule as <allow|deny>:<operations>:<process>, e.g. deny:hydrate,enumerate:SearchProtocolHost.exe. First match wins.
+    #[clap(long = "access")]
+    access_rules: Vec<projfs_provider::AccessRule>,
+
+    /// Hide a subtree from matching processes as <process>:<path>, e.g. MSBuild.exe:docs
+    #[clap(long = "hide")]
+    visibility_rules: Vec<projfs_provider::VisibilityRule>,
+
+    /// Write a JSON Lines audit log of hydrations, placeholders and notifications
+    #[clap(long, parse(from_os_str))]
+    audit_log: Option<PathBuf>,
+
+    /// Learn which files are read together and hydrate the likely next ones in the background
+    #[clap(long)]
+    prefetch: bool,
+
+    /// Where the learned prefetch model is loaded from and saved to, implies --prefetch
+    #[clap(long, parse(from_os_str))]
+    prefetch_model: Option<PathBuf>,
+
+    /// Cache placeholder info of the provider for this many seconds
+    #[clap(long)]
+    cache_ttl: Option<u64>,
+
+    /// Cache paths the provider doesn't know for this many seconds
+    #[clap(long, default_value_t = 5)]
+    negative_cache_ttl: u64,
+
+    /// Also fill the placeholder cache from directory enumerations
+    #[clap(long)]
+    cache_from_enumeration: bool,
+
+    /// Add a hidden .projfs directory with live stats, open enumerations, configuration, recent errors and the audit tail
+    #[clap(long)]
+    control: bool,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+/// What gets projected, zeros if nothing is given
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Small fixed tree of generated files
+    Zeros {
+        /// zeros, fill:<byte>, pattern:<text>, random:<seed> or counter
+        #[clap(long, default_value = "fill:0x30")]
+        content: generators::Generator,
+    },
+    /// Namespace described by a JSON or TOML manifest
+    Manifest {
+        #[clap(parse(from_os_str))]
+        manifest: PathBuf,
+    },
+    /// Existing local directory
+    Mirror {
+        #[clap(parse(from_os_str))]
+        source: PathBuf,
+        /// Keep the projection up to date with changes to the source
+        #[clap(long)]
+        watch: bool,
+    },
+    /// Tree of a commit in a local git repository
+    Git {
+        #[clap(parse(from_os_str))]
+        repository: PathBuf,
+        /// Commit id, branch, tag or other ref
+        #[clap(default_value = "HEAD")]
+        revision: String,
+    },
+    /// Merged file system of an image in an OCI image layout directory
+    Oci {
+        #[clap(parse(from_os_str))]
+        layout: PathBuf,
+        /// Image to pick by its ref name, the first one otherwise
+        #[clap(long)]
+        reference: Option<String>,
+        /// Image to pick from multi-platform images as os/architecture[/variant]
+        #[clap(long)]
+        platform: Option<String>,
+    },
+    /// Crates in the cargo registry cache as the source directories cargo would unpack
+    Cargo {
+        /// Registry cache directory, `$CARGO_HOME/registry/cache` by default
+        #[clap(parse(from_os_str))]
+        cache: Option<PathBuf>,
+    },
+    /// Files of a manifest written by cas-ingest, put together from the chunks in a store
+    Cas {
+        #[clap(parse(from_os_str))]
+        manifest: PathBuf,
+        #[clap(long, parse(from_os_str))]
+        store: PathBuf,
+    },
+    /// Split the files of a directory into chunks, add them to a store and write their manifest
+    CasIngest {
+        #[clap(parse(from_os_str))]
+        source: PathBuf,
+        #[clap(long, parse(from_os_str))]
+        store: PathBuf,
+        #[clap(long, parse(from_os_str))]
+        manifest: PathBuf,
+    },
+    /// Remove the chunks of a store that none of the given manifests use
+    CasGc {
+        #[clap(long, parse(from_os_str))]
+        store: PathBuf,
+        /// Every manifest that is still in use
+        #[clap(required = true, parse(from_os_str))]
+        manifests: Vec<PathBuf>,
+    },
+    /// Contents of an ISO 9660 image, with Joliet or Rock Ridge names when it has them
+    Iso {
+        #[clap(parse(from_os_str))]
+        image: PathBuf,
+    },
+    /// Files and directories stored in the entries and content tables of a SQLite database
+    Sqlite {
+        #[clap(parse(from_os_str))]
+        database: PathBuf,
+    },
+    /// Tables of a SQLite database and saved queries on it as CSV and JSON Lines files
+    SqliteData {
+        #[clap(parse(from_os_str))]
+        database: PathBuf,
+        /// JSON or TOML file with the queries to project under queries/
+        #[clap(long, parse(from_os_str))]
+        queries: Option<PathBuf>,
+    },
+    /// Contents of a squashfs image
+    Squashfs {
+        #[clap(parse(from_os_str))]
+        image: PathBuf,
+    },
+    /// Contents of a tar archive
+    Tar {
+        #[clap(parse(from_os_str))]
+        archive: PathBuf,
+    },
+    /// Contents of a zip archive
+    Zip {
+        #[clap(parse(from_os_str))]
+        archive: PathBuf,
+    },
+    /// Check a hydrated file against the generator it was projected with, without projecting anything
+    Verify {
+        #[clap(parse(from_os_str))]
+        file: PathBuf,
+        #[clap(long)]
+        content: generators::Generator,
+    },
+}
+
+fn create_provider(command: Command) -> Result<Box<dyn projfs_provider::ProjFSProvider>, Box<dyn std::error::Error>> {
+    Ok(match command {
+        Command::Zeros { content } => Box::new(zeros_provider::ZerosProvider::new(content)),
+        Command::Manifest { manifest } => Box::new(manifest_provider::ManifestProvider::new(&manifest)?),
+        Command::Mirror { source, watch } => Box::new(mirror_provider::MirrorProvider::new(&source, watch)?),
+        Command::Git { repository, revision } => Box::new(git_provider::GitProvider::new(&repository, &revision)?),
+        Command::Cargo { cache } => Box::new(cargo_provider::CargoProvider::new(cache.as_deref())?),
+        Command::Cas { manifest, store } => Box::new(cas_provider::CasProvider::new(&manifest, &store)?),
+        Command::Iso { image } => Box::new(iso_provider::IsoProvider::new(&image)),
+        Command::Oci { layout, reference, platform } => Box::new(oci_provider::OciProvider::new(&layout, reference.as_deref(), platform.as_deref())?),
+        Command::Sqlite { database } => Box::new(sqlite_provider::SqliteProvider::new(&database)?),
+        Command::SqliteData { database, queries } => Box::new(sqlite_data_provider::SqliteDataProvider::new(&database, queries.as_deref())?),
+        Command::Squashfs { image } => Box::new(squashfs_provider::SquashfsProvider::new(&image)?),
+        Command::Tar { archive } => Box::new(tar_provider::TarProvider::new(&archive)),
+        Command::Zip { archive } => Box::new(zip_provider::ZipProvider::new(&archive)),
+        Command::Verify { .. } | Command::CasIngest { .. } | Command::CasGc { .. } => {
+            return Err("This command doesn't project anything".into());
+        }
+    })
+}
+
+fn verify(file: &std::path::Path, content: &generators::Generator) -> Result<(), Box<dyn std::error::Error>> {
+    let mut f = std::fs::File::open(file)?;
+    match generators::verify(content, &mut f)? {
+        Some(offset) => Err(format!("{file:?} differs at offset {offset}, expected {:#04x}", content.byte_at(offset)).into()),
+        None => Ok(()),
+    }
 }
 
 fn wait_for_shutdown() {
@@ -37,10 +231,56 @@ fn wait_for_shutdown() {
 fn main() {
     let args = Args::parse();
 
+    // Commands that only work on files and are done once they return
+    let offline = match &args.command {
+        Some(Command::Verify { file, content }) => Some(verify(file, content).map(|_| format!("{file:?} matches"))),
+        Some(Command::CasIngest { source, store, manifest }) => Some(cas_provider::ingest(source, store, manifest)),
+        Some(Command::CasGc { store, manifests }) => Some(cas_provider::collect_garbage(store, manifests)),
+        _ => None,
+    };
+    if let Some(result) = offline {
+        match result {
+            Ok(message) => println!("{message}"),
+            Err(e) => {
+                println!("{e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     println!("Hello {:?}!", &args.projection);
 
-    let mut runner = projfs_provider::ProjFSRunner::new();
-    runner.start(&args.projection, Box::new(zeros_provider::ZerosProvider::new())).unwrap();
+    let mut options = projfs_provider::RunnerOptions::default();
+    options.access_control.rules = args.access_rules;
+    options.namespace_filter.rules = args.visibility_rules;
+    options.audit = args.audit_log.as_deref().map(projfs_provider::AuditOptions::new);
+    if let Some(ttl) = args.cache_ttl {
+        options.cache = Some(projfs_provider::CacheOptions {
+            ttl: std::time::Duration::from_secs(ttl),
+            negative_ttl: std::time::Duration::from_secs(args.negative_cache_ttl),
+            populate_from_enumeration: args.cache_from_enumeration,
+            ..Default::default()
+        });
+    }
+    options.control = args.control;
+    if args.prefetch || args.prefetch_model.is_some() {
+        options.prefetch = Some(projfs_provider::PrefetchOptions {
+            model: args.prefetch_model,
+            ..Default::default()
+        });
+    }
+
+    let provider = match create_provider(args.command.unwrap_or(Command::Zeros { content: generators::Generator::Fill(b'0') })) {
+        Ok(p) => p,
+        Err(e) => {
+            println!("Could not create the provider: {e}");
+            std::process::exit(1);
+        }
+    };
+
+    let mut runner = projfs_provider::ProjFSRunner::new(options);
+    runner.start(&args.projection, provider).unwrap();
 
     wait_for_shutdown();
 
@@ -0,0 +1,260 @@
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How long the output of a run serves callbacks. The callbacks of a hydration come one
+/// after another, a hydration that was cancelled before the end runs the command again.
+const KEEP_OUTPUT: Duration = Duration::from_secs(5);
+
+fn default_timeout() -> u64 {
+    30
+}
+
+fn default_max_output() -> u64 {
+    16 * 1024 * 1024
+}
+
+/// A program whose standard output is the content of a file. Scripts need their
+/// interpreter as the program, e.g. `{"program": "powershell", "args": ["-File", "info.ps1"]}`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CommandSpec {
+    pub program: PathBuf,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Working directory, the provider's by default
+    pub directory: Option<PathBuf>,
+    /// Seconds the command gets before it's killed
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// Bytes of output at most, more fails the run
+    #[serde(default = "default_max_output")]
+    pub max_output: u64,
+}
+
+impl CommandSpec {
+    fn describe(&self) -> String {
+        std::iter::once(self.program.to_string_lossy().into_owned())
+            .chain(self.args.iter().cloned())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Runs the command and collects what it writes to stdout. It fails if it
+    /// doesn't exit successfully within the timeout or writes too much.
+    pub fn run(&self) -> std::io::Result<Vec<u8>> {
+        let mut command = Command::new(&self.program);
+        command.args(&self.args).stdin(Stdio::null()).stdout(Stdio::piped());
+        if let Some(directory) = &self.directory {
+            command.current_dir(directory);
+        }
+        let mut child = command.spawn().map_err(|e| std::io::Error::new(e.kind(), format!("{}: {e}", self.describe())))?;
+        let stdout = child.stdout.take().unwrap();
+        // One byte over the limit is enough to know it's over
+        let limit = self.max_output;
+        let mut reader = Some(std::thread::spawn(move || {
+            let mut output = Vec::new();
+            stdout.take(limit + 1).read_to_end(&mut output).map(|_| output)
+        }));
+
+        let deadline = Instant::now() + Duration::from_secs(self.timeout);
+        let mut output = None;
+        let status = loop {
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, format!("{} took longer than {}s", self.describe(), self.timeout)));
+            }
+            if matches!(&reader, Some(r) if r.is_finished()) {
+                let read = reader.take().unwrap().join().expect("Reading the output panicked")?;
+                if read.len() as u64 > limit {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{} wrote more than {limit} bytes", self.describe())));
+                }
+                output = Some(read);
+            }
+            // Done once it exited and stdout is closed, whatever it left running may hold on to it
+            if let (Some(_), Some(status)) = (&output, child.try_wait()?) {
+                break status;
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        };
+        if !status.success() {
+            return Err(std::io::Error::other(format!("{} failed with {status}", self.describe())));
+        }
+        Ok(output.unwrap_or_default())
+    }
+}
+
+/// Output of a later run cut or padded with spaces to the size the placeholder was given
+pub fn fit_to_size(mut output: Vec<u8>, size: u64) -> Vec<u8> {
+    output.resize(size as usize, b' ');
+    output
+}
+
+/// A command behind a file. A hydration can take several callbacks, they all read the
+/// output of the same run, which is kept until one of them has read it to the end or
+/// for `KEEP_OUTPUT` since the last callback.
+#[derive(Debug)]
+pub struct CommandFile {
+    spec: CommandSpec,
+    output: Mutex<Option<KeptOutput>>,
+    keep: Duration,
+}
+
+#[derive(Debug)]
+struct KeptOutput {
+    data: Arc<[u8]>,
+    used: Instant,
+}
+
+impl CommandFile {
+    pub fn new(spec: CommandSpec) -> CommandFile {
+        CommandFile {
+            spec,
+            output: Mutex::new(None),
+            keep: KEEP_OUTPUT,
+        }
+    }
+
+    /// Reads the kept output, or runs the command to get one fitted to `size`, which is
+    /// no more than `max_output`. Callbacks arriving during the run wait for it rather
+    /// than starting their own.
+    pub fn reader(self: &Arc<Self>, size: u64) -> std::io::Result<CommandOutput> {
+        let size = size.min(self.spec.max_output);
+        let mut output = self.output.lock().unwrap();
+        let now = Instant::now();
+        let data = match &mut *output {
+            Some(kept) if now.duration_since(kept.used) < self.keep && kept.data.len() as u64 == size => {
+                kept.used = now;
+                kept.data.clone()
+            }
+            _ => {
+                let data: Arc<[u8]> = fit_to_size(self.spec.run()?, size).into();
+                *output = Some(KeptOutput {
+                    data: data.clone(),
+                    used: Instant::now(),
+                });
+                data
+            }
+        };
+        Ok(CommandOutput {
+            data: Cursor::new(data),
+            file: self.clone(),
+        })
+    }
+}
+
+/// Reads the kept output of a `CommandFile`, the one that reaches the end lets it go
+pub struct CommandOutput {
+    data: Cursor<Arc<[u8]>>,
+    file: Arc<CommandFile>,
+}
+
+impl Read for CommandOutput {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.data.read(buf)
+    }
+}
+
+impl Seek for CommandOutput {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.data.seek(pos)
+    }
+}
+
+impl Drop for CommandOutput {
+    fn drop(&mut self) {
+        if self.data.position() < self.data.get_ref().len() as u64 {
+            return;
+        }
+        let mut output = self.file.output.lock().unwrap();
+        // Unless it's from a later run already
+        if output.as_ref().map(|o| Arc::ptr_eq(&o.data, self.data.get_ref())).unwrap_or(false) {
+            *output = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A command that counts its runs in `counter` and prints how many there were
+    fn counting(counter: &std::path::Path) -> CommandSpec {
+        let counter = counter.to_string_lossy();
+        let (program, args) = if cfg!(windows) {
+            ("cmd", vec!["/C".to_string(), format!("echo x>>\"{counter}\"& find /C \"x\" < \"{counter}\"")])
+        } else {
+            ("sh", vec!["-c".to_string(), format!("echo x >> '{counter}'; wc -l < '{counter}'")])
+        };
+        CommandSpec {
+            program: program.into(),
+            args,
+            directory: None,
+            timeout: default_timeout(),
+            max_output: default_max_output(),
+        }
+    }
+
+    fn read(mut reader: CommandOutput, length: usize) -> String {
+        let mut data = vec![0; length];
+        reader.read_exact(&mut data).unwrap();
+        String::from_utf8(data).unwrap().trim().to_string()
+    }
+
+    #[test]
+    fn fits_output_to_the_size() {
+        assert_eq!(fit_to_size(b"output".to_vec(), 3), b"out");
+        assert_eq!(fit_to_size(b"out".to_vec(), 5), b"out  ");
+    }
+
+    #[test]
+    fn runs_once_per_hydration() {
+        let counter = std::env::temp_dir().join(format!("projfs-command-{}-runs", std::process::id()));
+        let _ = std::fs::remove_file(&counter);
+        let file = Arc::new(CommandFile::new(counting(&counter)));
+        // The first callback stops halfway, the second one goes on with the same output
+        assert_eq!(read(file.reader(8).unwrap(), 4), "1");
+        let mut rest = file.reader(8).unwrap();
+        rest.seek(SeekFrom::Start(4)).unwrap();
+        assert_eq!(read(rest, 4), "");
+        // Hydrating it again runs it again
+        assert_eq!(read(file.reader(8).unwrap(), 8), "2");
+        let _ = std::fs::remove_file(&counter);
+    }
+
+    #[test]
+    fn runs_again_after_a_cancelled_hydration() {
+        let counter = std::env::temp_dir().join(format!("projfs-command-{}-cancelled", std::process::id()));
+        let _ = std::fs::remove_file(&counter);
+        let file = Arc::new(CommandFile {
+            keep: Duration::from_millis(100),
+            ..CommandFile::new(counting(&counter))
+        });
+        assert_eq!(read(file.reader(8).unwrap(), 4), "1");
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(read(file.reader(8).unwrap(), 4), "2");
+        let _ = std::fs::remove_file(&counter);
+    }
+
+    #[test]
+    fn fits_no_more_than_the_output_limit() {
+        let counter = std::env::temp_dir().join(format!("projfs-command-{}-limit", std::process::id()));
+        let _ = std::fs::remove_file(&counter);
+        let file = Arc::new(CommandFile::new(CommandSpec {
+            max_output: 16,
+            ..counting(&counter)
+        }));
+        let mut data = Vec::new();
+        file.reader(u64::MAX).unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data.len(), 16);
+        let _ = std::fs::remove_file(&counter);
+    }
+}
@@ -0,0 +1,342 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use serde::Deserialize;
+use windows::Win32::Storage::FileSystem;
+
+use crate::generators::Generator;
+use crate::projfs_provider::{filetime, EntryInfo, VirtualTree};
+use super::command::{CommandFile, CommandSpec};
+use super::remote::RemoteFile;
+use super::template::Template;
+
+/// On-disk description of the namespace, as JSON or TOML depending on the extension:
+///
+/// ```json
+/// {"entries": [
+///     {"path": "docs", "directory": true},
+///     {"path": "docs/readme.txt", "modified": 1650000000, "attributes": ["read_only"], "content": {"inline": "Hello"}},
+///     {"path": "docs/big.bin", "content": {"path": "D:\\data\\big.bin"}},
+///     {"path": "docs/filler", "size": 1048576, "content": {"generator": "random:42"}},
+///     {"path": "docs/remote.iso", "content": {"url": "https://example.com/remote.iso"}},
+///     {"path": "docs/log.txt", "size": 4096, "content": {"command": {"program": "git", "args": ["log", "-20"]}}},
+///     {"path": "team", "directory": true, "variables": {"owner": "Build team"}},
+///     {"path": "team/about.txt", "content": {"template": "{{owner}} on {{machine}} since {{mount_time}}"}}
+/// ]}
+/// ```
+///
+/// Times are Unix seconds and default to when the manifest was loaded. Parent directories
+/// that aren't listed are created with default metadata. URLs are asked for their size and
+/// ETag on load, a declared size is only used if the server doesn't send one.
+///
+/// Commands run again every time their file is hydrated, once for all the reads of that
+/// hydration. Without a size they're run once on load to find one, output that doesn't match
+/// the size is cut or padded with spaces.
+/// Templates see the variables of their own entry and of the directories above it, the
+/// nearest one wins, see `Template` for what else they can use.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    #[serde(default, alias = "entry")]
+    pub entries: Vec<ManifestEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestEntry {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub directory: bool,
+    pub size: Option<u64>,
+    pub created: Option<i64>,
+    pub accessed: Option<i64>,
+    pub modified: Option<i64>,
+    #[serde(default)]
+    pub attributes: Vec<Attribute>,
+    pub content: Option<ContentSource>,
+    #[serde(default)]
+    pub variables: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Attribute {
+    ReadOnly,
+    Hidden,
+    System,
+    Archive,
+}
+
+impl Attribute {
+    fn flag(self) -> u32 {
+        match self {
+            Attribute::ReadOnly => FileSystem::FILE_ATTRIBUTE_READONLY.0,
+            Attribute::Hidden => FileSystem::FILE_ATTRIBUTE_HIDDEN.0,
+            Attribute::System => FileSystem::FILE_ATTRIBUTE_SYSTEM.0,
+            Attribute::Archive => FileSystem::FILE_ATTRIBUTE_ARCHIVE.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContentSource {
+    Inline(String),
+    Path(PathBuf),
+    /// Synthetic content, needs an explicit size. See `Generator` for the syntax.
+    Generator(String),
+    /// Fetched with range requests as it's read
+    Url(String),
+    /// Standard output of running a program
+    Command(CommandSpec),
+    /// Rendered when it's first needed
+    Template(String),
+    TemplateFile(PathBuf),
+}
+
+/// What the tree keeps to produce the content of an entry
+#[derive(Clone, Debug, Default)]
+pub enum Content {
+    /// Directories that are only implied by the paths below them
+    #[default]
+    None,
+    Directory,
+    Inline(std::sync::Arc<[u8]>),
+    Path(PathBuf),
+    Generated(Generator),
+    Url(std::sync::Arc<RemoteFile>),
+    Command(std::sync::Arc<CommandFile>),
+    Template(std::sync::Arc<Template>),
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> Result<Manifest, Box<dyn std::error::Error>> {
+        let text = fs::read_to_string(path)?;
+        let is_toml = path.extension().map(|e| e.eq_ignore_ascii_case("toml")).unwrap_or(false);
+        let manifest = if is_toml {
+            toml::from_str(&text)?
+        } else {
+            serde_json::from_str(&text)?
+        };
+        Ok(manifest)
+    }
+
+    /// Validates the entries and builds the namespace from them, `agent` is for asking about URLs
+    pub fn build_tree(&self, agent: &ureq::Agent) -> Result<VirtualTree<Content>, Box<dyn std::error::Error>> {
+        let load_time = filetime::now();
+        let directory_info = EntryInfo::directory(load_time);
+        let mut tree = VirtualTree::new(directory_info, Content::None);
+        let variables: HashMap<&Path, &BTreeMap<String, String>> = self.entries
+            .iter()
+            .filter(|e| !e.variables.is_empty())
+            .map(|e| (e.path.as_path(), &e.variables))
+            .collect();
+        for (index, entry) in self.entries.iter().enumerate() {
+            let (info, content) = entry.resolve(load_time, agent, index as u64, &variables)?;
+            if let Some(existing) = tree.lookup(&entry.path) {
+                // Directories implicitly created by earlier entries may still be described
+                if !(entry.directory && matches!(tree.node(existing).content, Content::None)) {
+                    return Err(format!("{:?} is listed twice", entry.path).into());
+                }
+            }
+            tree.insert_path(&entry.path, info, content, directory_info)?;
+        }
+        tree.sort();
+        Ok(tree)
+    }
+}
+
+impl ManifestEntry {
+    /// Variables of this entry and the directories it's in, nearer ones override the others
+    fn scope(&self, variables: &HashMap<&Path, &BTreeMap<String, String>>) -> BTreeMap<String, String> {
+        let mut scope = BTreeMap::new();
+        for ancestor in self.path.ancestors().collect::<Vec<_>>().into_iter().rev() {
+            if let Some(v) = variables.get(ancestor) {
+                scope.extend(v.iter().map(|(name, value)| (name.clone(), value.clone())));
+            }
+        }
+        scope
+    }
+
+    /// The size is only known once it's rendered, the provider fills it in
+    fn template(&self, text: &str, variables: &HashMap<&Path, &BTreeMap<String, String>>) -> Result<Content, Box<dyn std::error::Error>> {
+        if self.size.is_some() {
+            return Err(format!("Template {:?} can't have a size, it's as long as it renders", self.path).into());
+        }
+        Ok(Content::Template(std::sync::Arc::new(Template::parse(text, &self.path, &self.scope(variables))?)))
+    }
+
+    /// `index` is unique to the entry, remote files use it to tell their cached blocks apart
+    fn resolve(&self, load_time: i64, agent: &ureq::Agent, index: u64, variables: &HashMap<&Path, &BTreeMap<String, String>>) -> Result<(EntryInfo, Content), Box<dyn std::error::Error>> {
+        let path = &self.path;
+        if path.as_os_str().is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(format!("{path:?} must be a relative path without '.' or '..'").into());
+        }
+
+        let (size, content) = if self.directory {
+            if self.content.is_some() || self.size.is_some() {
+                return Err(format!("Directory {path:?} can't have a size or content").into());
+            }
+            (0, Content::Directory)
+        } else {
+            match &self.content {
+                None => (0, Content::Inline(std::sync::Arc::from(&[][..]))),
+                Some(ContentSource::Inline(text)) => (text.len() as u64, Content::Inline(text.as_bytes().into())),
+                Some(ContentSource::Path(source)) => {
+                    let metadata = fs::metadata(source).map_err(|e| format!("Content of {path:?} at {source:?}: {e}"))?;
+                    if !metadata.is_file() {
+                        return Err(format!("Content of {path:?} at {source:?} is not a file").into());
+                    }
+                    (metadata.len(), Content::Path(source.clone()))
+                }
+                Some(ContentSource::Generator(generator)) => {
+                    let size = match self.size {
+                        Some(s) => s,
+                        None => {
+                            return Err(format!("Generated file {path:?} needs a size").into());
+                        }
+                    };
+                    let generator: Generator = generator.parse().map_err(|e| format!("{path:?}: {e}"))?;
+                    (size, Content::Generated(generator))
+                }
+                Some(ContentSource::Url(url)) => {
+                    let remote = RemoteFile::probe(agent, url, self.size, index).map_err(|e| format!("Content of {path:?}: {e}"))?;
+                    (remote.size, Content::Url(std::sync::Arc::new(remote)))
+                }
+                Some(ContentSource::Command(command)) => {
+                    let size = match self.size {
+                        Some(s) if s > command.max_output => {
+                            return Err(format!("{path:?} declares {s} bytes but its command may only write {}", command.max_output).into());
+                        }
+                        Some(s) => s,
+                        None => command.run().map_err(|e| format!("Sizing {path:?}: {e}"))?.len() as u64,
+                    };
+                    (size, Content::Command(std::sync::Arc::new(CommandFile::new(command.clone()))))
+                }
+                Some(ContentSource::Template(text)) => (0, self.template(text, variables)?),
+                Some(ContentSource::TemplateFile(file)) => {
+                    let text = fs::read_to_string(file).map_err(|e| format!("Template of {path:?} at {file:?}: {e}"))?;
+                    (0, self.template(&text, variables)?)
+                }
+            }
+        };
+        if let Some(declared) = self.size {
+            if declared != size {
+                return Err(format!("{path:?} declares {declared} bytes but its content has {size}").into());
+            }
+        }
+
+        let time = |t: Option<i64>| t.map(filetime::from_unix_seconds).unwrap_or(load_time);
+        let modified = time(self.modified);
+        let info = EntryInfo {
+            is_directory: self.directory,
+            size,
+            creation_time: time(self.created),
+            last_access_time: time(self.accessed),
+            last_write_time: modified,
+            change_time: modified,
+            attributes: self.attributes.iter().fold(0, |a, f| a | f.flag()),
+        };
+        Ok((info, content))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::remote;
+
+    /// Loads `text` as a manifest file of the given extension and builds its tree
+    fn build(name: &str, extension: &str, text: &str) -> Result<VirtualTree<Content>, String> {
+        let path = std::env::temp_dir().join(format!("projfs-manifest-{}-{name}.{extension}", std::process::id()));
+        fs::write(&path, text).unwrap();
+        let tree = Manifest::load(&path).and_then(|m| m.build_tree(&remote::agent())).map_err(|e| e.to_string());
+        let _ = fs::remove_file(&path);
+        tree
+    }
+
+    /// The same entries as JSON and as TOML
+    fn both(name: &str, entries: &[(&str, &str)]) -> [Result<VirtualTree<Content>, String>; 2] {
+        let json = entries.iter().map(|(json, _)| *json).collect::<Vec<_>>().join(",\n");
+        let toml = entries.iter().map(|(_, toml)| format!("[[entry]]\n{toml}\n")).collect::<String>();
+        [build(name, "json", &format!("{{\"entries\": [{json}]}}")), build(name, "toml", &toml)]
+    }
+
+    #[test]
+    fn builds_the_same_tree_from_json_and_toml() {
+        for tree in both("valid", &[
+            (r#"{"path": "docs/readme.txt", "attributes": ["read_only"], "content": {"inline": "Hello"}}"#, "path = \"docs/readme.txt\"\nattributes = [\"read_only\"]\ncontent = { inline = \"Hello\" }"),
+            (r#"{"path": "docs", "directory": true, "modified": 1650000000}"#, "path = \"docs\"\ndirectory = true\nmodified = 1650000000"),
+            (r#"{"path": "filler", "size": 4, "content": {"generator": "zeros"}}"#, "path = \"filler\"\nsize = 4\ncontent = { generator = \"zeros\" }"),
+        ]) {
+            let tree = tree.unwrap();
+            let readme = tree.node(tree.lookup(Path::new("Docs/README.txt")).unwrap());
+            assert_eq!(readme.info.size, 5);
+            assert_eq!(readme.info.attributes, FileSystem::FILE_ATTRIBUTE_READONLY.0);
+            // Describing a directory after something below it created it is fine
+            let docs = tree.node(tree.lookup(Path::new("docs")).unwrap());
+            assert!(matches!(docs.content, Content::Directory));
+            assert_eq!(docs.info.last_write_time, filetime::from_unix_seconds(1_650_000_000));
+            assert_eq!(tree.node(tree.lookup(Path::new("filler")).unwrap()).info.size, 4);
+        }
+    }
+
+    #[test]
+    fn rejects_paths_listed_twice() {
+        for error in both("twice", &[
+            (r#"{"path": "docs/a.txt"}"#, "path = \"docs/a.txt\""),
+            (r#"{"path": "DOCS/A.txt"}"#, "path = \"DOCS/A.txt\""),
+        ]) {
+            let error = error.err().unwrap();
+            assert!(error.contains("is listed twice"), "{error}");
+        }
+        for error in both("directory-twice", &[
+            (r#"{"path": "docs", "directory": true}"#, "path = \"docs\"\ndirectory = true"),
+            (r#"{"path": "docs", "directory": true}"#, "path = \"docs\"\ndirectory = true"),
+        ]) {
+            let error = error.err().unwrap();
+            assert!(error.contains("is listed twice"), "{error}");
+        }
+    }
+
+    #[test]
+    fn rejects_entries_below_files() {
+        for error in both("below-file", &[
+            (r#"{"path": "docs", "content": {"inline": "not a directory"}}"#, "path = \"docs\"\ncontent = { inline = \"not a directory\" }"),
+            (r#"{"path": "docs/a.txt"}"#, "path = \"docs/a.txt\""),
+        ]) {
+            let error = error.err().unwrap();
+            assert!(error.contains("is below a file"), "{error}");
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_content_kinds_and_fields() {
+        for error in both("unknown-kind", &[(r#"{"path": "a.txt", "content": {"script": "echo"}}"#, "path = \"a.txt\"\ncontent = { script = \"echo\" }")]) {
+            let error = error.err().unwrap();
+            assert!(error.contains("unknown variant `script`"), "{error}");
+        }
+        for error in both("unknown-field", &[(r#"{"path": "a.txt", "owner": "me"}"#, "path = \"a.txt\"\nowner = \"me\"")]) {
+            let error = error.err().unwrap();
+            assert!(error.contains("unknown field `owner`"), "{error}");
+        }
+    }
+
+    #[test]
+    fn rejects_contents_that_contradict_the_entry() {
+        let cases = [
+            ((r#"{"path": "docs", "directory": true, "content": {"inline": ""}}"#, "path = \"docs\"\ndirectory = true\ncontent = { inline = \"\" }"), "can't have a size or content"),
+            ((r#"{"path": "a.txt", "size": 3, "content": {"inline": "Hello"}}"#, "path = \"a.txt\"\nsize = 3\ncontent = { inline = \"Hello\" }"), "declares 3 bytes but its content has 5"),
+            ((r#"{"path": "filler", "content": {"generator": "zeros"}}"#, "path = \"filler\"\ncontent = { generator = \"zeros\" }"), "needs a size"),
+            ((r#"{"path": "../a.txt"}"#, "path = \"../a.txt\""), "must be a relative path"),
+            ((r#"{"path": "log", "size": 100, "content": {"command": {"program": "git", "max_output": 10}}}"#, "path = \"log\"\nsize = 100\ncontent = { command = { program = \"git\", max_output = 10 } }"), "may only write 10"),
+        ];
+        for (i, (entry, expected)) in cases.into_iter().enumerate() {
+            for error in both(&format!("contradicting-{i}"), &[entry]) {
+                let error = error.err().unwrap();
+                assert!(error.contains(expected), "{error}");
+            }
+        }
+    }
+}
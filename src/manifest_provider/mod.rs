@@ -0,0 +1,7 @@
+mod command;
+mod manifest;
+mod provider;
+mod remote;
+mod template;
+
+pub use provider::ManifestProvider;
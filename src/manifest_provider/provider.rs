@@ -0,0 +1,122 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use windows::Win32::Storage::ProjectedFileSystem;
+
+use crate::generators::GeneratorReader;
+use crate::projfs_provider::{BlockCache, ProjFSProvider, EnumerationState, SeekRead, VirtualizationOptions, VirtualTree, EntryInfo};
+use super::manifest::{Content, Manifest};
+use super::remote::{self, RemoteReader};
+use super::template::TemplateContext;
+
+/// Blocks of remote files kept around, 64 MiB
+const CACHED_BLOCKS: usize = 64;
+
+/// Serves a namespace described by a manifest file, see `Manifest` for the format
+pub struct ManifestProvider {
+    tree: Arc<VirtualTree<Content>>,
+    agent: ureq::Agent,
+    cache: Arc<BlockCache>,
+    templates: TemplateContext,
+}
+
+impl ManifestProvider {
+    pub fn new(manifest_path: &Path) -> Result<ManifestProvider, Box<dyn std::error::Error>> {
+        let manifest = Manifest::load(manifest_path)?;
+        let agent = remote::agent();
+        Ok(ManifestProvider {
+            tree: Arc::new(manifest.build_tree(&agent)?),
+            agent,
+            cache: Arc::new(BlockCache::new(remote::BLOCK_SIZE, CACHED_BLOCKS)),
+            templates: TemplateContext::now(),
+        })
+    }
+
+    /// Templates are as large as their rendering
+    fn info(&self, content: &Content, info: EntryInfo) -> EntryInfo {
+        match content {
+            Content::Template(template) => EntryInfo {
+                size: template.rendered(&self.templates).len() as u64,
+                ..info
+            },
+            _ => info,
+        }
+    }
+}
+
+impl ProjFSProvider for ManifestProvider {
+    fn init(&mut self, _root: &Path) -> Result<VirtualizationOptions, Box<dyn std::error::Error>> {
+        Ok(VirtualizationOptions::default())
+    }
+
+    fn start(&mut self, _context: ProjectedFileSystem::PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT) -> Result<(), Box<dyn std::error::Error>> {
+        self.templates = TemplateContext::now();
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn new_enumeration(&self, _id: windows::core::GUID, file_path: &Path) -> Box<dyn EnumerationState> {
+        self.tree.enumeration_with(file_path, |node| self.info(&node.content, node.info))
+    }
+
+    fn get_placeholder_info(&self, file_path: &Path) -> Result<ProjectedFileSystem::PRJ_PLACEHOLDER_INFO, windows::core::HRESULT> {
+        let node = match self.tree.lookup(file_path) {
+            Some(id) => self.tree.node(id),
+            None => {
+                return Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into());
+            }
+        };
+        let mut placeholder = self.info(&node.content, node.info).placeholder_info();
+        if let Some(content_id) = match &node.content {
+            Content::Url(remote) => remote.content_id(),
+            _ => None,
+        } {
+            placeholder.VersionInfo.ContentID[..content_id.len()].copy_from_slice(&content_id);
+        }
+        Ok(placeholder)
+    }
+
+    fn get_file_data(&self, file_path: &Path) -> Result<Box<dyn SeekRead>, windows::core::HRESULT> {
+        let node = match self.tree.lookup(file_path) {
+            Some(id) => self.tree.node(id),
+            None => {
+                return Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into());
+            }
+        };
+        match &node.content {
+            Content::Inline(data) => Ok(Box::new(std::io::Cursor::new(data.clone()))),
+            Content::Path(source) => match std::fs::File::open(source) {
+                Ok(f) => Ok(Box::new(f)),
+                Err(e) => {
+                    println!("Could not open {source:?} for {file_path:?}: {e}");
+                    Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into())
+                }
+            },
+            Content::Generated(generator) => Ok(Box::new(GeneratorReader::new(generator.clone(), node.info.size))),
+            Content::Url(remote) => Ok(Box::new(RemoteReader::new(self.agent.clone(), remote.clone(), self.cache.clone()))),
+            Content::Template(template) => Ok(Box::new(std::io::Cursor::new(template.rendered(&self.templates)))),
+            Content::Command(command) => match command.reader(node.info.size) {
+                Ok(output) => Ok(Box::new(output)),
+                Err(e) => {
+                    println!("Could not produce {file_path:?}: {e}");
+                    Err(windows::Win32::Foundation::E_FAIL)
+                }
+            },
+            Content::None | Content::Directory => Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into()),
+        }
+    }
+
+    fn query_file_name(&self, file_path: &Path) -> windows::core::HRESULT {
+        match self.tree.lookup(file_path) {
+            Some(_) => windows::Win32::Foundation::S_OK,
+            None => windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into(),
+        }
+    }
+
+    fn notification(&self, _callbackdata: *const ProjectedFileSystem::PRJ_CALLBACK_DATA, _is_directory: bool, _notification: ProjectedFileSystem::PRJ_NOTIFICATION, _destinationfilename: windows::core::PCWSTR, _operationparameters: *mut ProjectedFileSystem::PRJ_NOTIFICATION_PARAMETERS) -> windows::core::HRESULT {
+        windows::Win32::Foundation::S_OK
+    }
+}
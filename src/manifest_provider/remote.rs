@@ -0,0 +1,345 @@
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::Arc;
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+
+use crate::projfs_provider::BlockCache;
+
+/// Unit of the range requests and of the cache
+pub const BLOCK_SIZE: u64 = 1024 * 1024;
+
+/// Blocks fetched by a single request at most, well below what the cache holds
+const MAX_REQUEST_BLOCKS: u64 = 16;
+
+const RETRIES: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// A file behind an HTTP URL as it was when the manifest was loaded
+#[derive(Debug)]
+pub struct RemoteFile {
+    pub url: String,
+    pub size: u64,
+    pub etag: Option<String>,
+    /// Tells the file's blocks apart in the cache
+    pub stream: u64,
+}
+
+pub fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout_connect(Duration::from_secs(10))
+        .timeout_read(Duration::from_secs(30))
+        .build()
+}
+
+/// Whether trying again could help: the connection broke, the server is overloaded or struggling
+fn is_transient(e: &ureq::Error) -> bool {
+    match e {
+        ureq::Error::Status(status, _) => *status == 429 || *status >= 500,
+        ureq::Error::Transport(_) => true,
+    }
+}
+
+fn with_retries<T>(mut attempt: impl FnMut() -> Result<T, Box<ureq::Error>>) -> Result<T, Box<ureq::Error>> {
+    let mut retries = 0;
+    loop {
+        match attempt() {
+            Err(e) if retries < RETRIES && is_transient(&e) => {
+                println!("Retrying {e}");
+                std::thread::sleep(RETRY_DELAY * 2u32.pow(retries));
+                retries += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+impl RemoteFile {
+    /// Asks the server for the size and ETag, `declared_size` is for servers that don't say
+    pub fn probe(agent: &ureq::Agent, url: &str, declared_size: Option<u64>, stream: u64) -> Result<RemoteFile, Box<dyn std::error::Error>> {
+        let response = with_retries(|| agent.head(url).call().map_err(Box::new)).map_err(|e| e.to_string())?;
+        let size = match (response.header("Content-Length").and_then(|l| l.parse().ok()), declared_size) {
+            (Some(size), _) | (None, Some(size)) => size,
+            (None, None) => return Err(format!("{url} doesn't tell its size, the entry needs one").into()),
+        };
+        if !matches!(response.header("Accept-Ranges"), Some(r) if r.eq_ignore_ascii_case("bytes")) {
+            println!("{url} may not support range requests, reads will fetch it from the start");
+        }
+        Ok(RemoteFile {
+            url: url.to_string(),
+            size,
+            // Weak ETags don't promise the same bytes
+            etag: response.header("ETag").filter(|e| !e.starts_with("W/")).map(String::from),
+            stream,
+        })
+    }
+
+    /// Changes with the ETag, so PrjUpdateFileIfNeeded can tell a placeholder is out of date
+    pub fn content_id(&self) -> Option<[u8; 32]> {
+        self.etag.as_ref().map(|etag| Sha256::digest(etag.as_bytes()).into())
+    }
+
+    /// Bytes `start..end` of the file. Servers that ignore the range send the whole file,
+    /// then everything in front of `start` is skipped.
+    fn fetch(&self, agent: &ureq::Agent, start: u64, end: u64) -> std::io::Result<Vec<u8>> {
+        let response = with_retries(|| {
+            let response = agent.get(&self.url).set("Range", &format!("bytes={start}-{}", end - 1)).call().map_err(Box::new)?;
+            if response.status() != 206 && response.status() != 200 {
+                return Err(Box::new(ureq::Error::Status(response.status(), response)));
+            }
+            Ok(response)
+        })
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+        if self.etag.is_some() && response.header("ETag") != self.etag.as_deref() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{} changed since the manifest was loaded", self.url),
+            ));
+        }
+        let skip = if response.status() == 200 { start } else { 0 };
+        let mut reader = response.into_reader();
+        std::io::copy(&mut (&mut reader).take(skip), &mut std::io::sink())?;
+        let mut data = Vec::with_capacity((end - start) as usize);
+        reader.take(end - start).read_to_end(&mut data)?;
+        if data.len() as u64 != end - start {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("{} ended at {} bytes", self.url, start + data.len() as u64),
+            ));
+        }
+        Ok(data)
+    }
+}
+
+/// Reads a remote file through the cache. A read of blocks that aren't cached yet fetches
+/// all of them with one range request, up to `MAX_REQUEST_BLOCKS`.
+pub struct RemoteReader {
+    agent: ureq::Agent,
+    file: Arc<RemoteFile>,
+    cache: Arc<BlockCache>,
+    position: u64,
+}
+
+impl RemoteReader {
+    pub fn new(agent: ureq::Agent, file: Arc<RemoteFile>, cache: Arc<BlockCache>) -> RemoteReader {
+        RemoteReader {
+            agent,
+            file,
+            cache,
+            position: 0,
+        }
+    }
+
+    fn block(&self, index: u64, wanted: u64) -> std::io::Result<Arc<[u8]>> {
+        if let Some(data) = self.cache.get(self.file.stream, index) {
+            return Ok(data);
+        }
+        let blocks = self.file.size.div_ceil(BLOCK_SIZE);
+        let mut last = std::cmp::min(index + wanted, std::cmp::min(blocks, index + MAX_REQUEST_BLOCKS));
+        // Blocks behind this one that are already cached don't need to be fetched again
+        if let Some(cached) = (index + 1..last).find(|b| self.cache.get(self.file.stream, *b).is_some()) {
+            last = cached;
+        }
+        let start = index * BLOCK_SIZE;
+        let data = self.file.fetch(&self.agent, start, std::cmp::min(last * BLOCK_SIZE, self.file.size))?;
+        let mut first = None;
+        for (i, chunk) in data.chunks(BLOCK_SIZE as usize).enumerate() {
+            let chunk: Arc<[u8]> = chunk.into();
+            self.cache.insert(self.file.stream, index + i as u64, chunk.clone());
+            first.get_or_insert(chunk);
+        }
+        first.ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+    }
+}
+
+impl Read for RemoteReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position >= self.file.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let index = self.position / BLOCK_SIZE;
+        let within = (self.position % BLOCK_SIZE) as usize;
+        let wanted = (within as u64 + buf.len() as u64).div_ceil(BLOCK_SIZE);
+        let block = self.block(index, wanted)?;
+        let read = std::cmp::min(buf.len(), block.len().saturating_sub(within));
+        buf[..read].copy_from_slice(&block[within..within + read]);
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for RemoteReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(v) => Some(v),
+            SeekFrom::End(v) => self.file.size.checked_add_signed(v),
+            SeekFrom::Current(v) => self.position.checked_add_signed(v),
+        };
+        match new_position {
+            Some(v) => {
+                self.position = v;
+                Ok(self.position)
+            }
+            None => Err(std::io::Error::from(std::io::ErrorKind::InvalidInput)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// How the test server behaves, it answers one request per connection
+    #[derive(Default)]
+    struct Behavior {
+        etag: Mutex<Option<String>>,
+        ignores_ranges: bool,
+        /// Answered with 503 before anything else
+        failures: AtomicUsize,
+    }
+
+    struct Server {
+        url: String,
+        /// Method and range of every request
+        requests: Arc<Mutex<Vec<String>>>,
+        behavior: Arc<Behavior>,
+    }
+
+    fn serve(data: Vec<u8>, behavior: Behavior) -> Server {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/file.bin", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let behavior = Arc::new(behavior);
+        let (log, state) = (requests.clone(), behavior.clone());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut lines = BufReader::new(stream.try_clone().unwrap()).lines().map(|l| l.unwrap());
+                let method = lines.next().unwrap().split(' ').next().unwrap().to_string();
+                let range = lines.by_ref().take_while(|l| !l.is_empty()).find_map(|l| l.strip_prefix("Range: bytes=").map(String::from));
+                log.lock().unwrap().push(format!("{method} {}", range.clone().unwrap_or_default()));
+                if state.failures.load(Ordering::SeqCst) > 0 {
+                    state.failures.fetch_sub(1, Ordering::SeqCst);
+                    let _ = stream.write_all(b"HTTP/1.1 503 Busy\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+                    continue;
+                }
+                let range = match range {
+                    Some(r) if method == "GET" && !state.ignores_ranges => {
+                        let (start, end) = r.split_once('-').unwrap();
+                        let (start, end): (usize, usize) = (start.parse().unwrap(), end.parse().unwrap());
+                        Some(start..std::cmp::min(end + 1, data.len()))
+                    }
+                    _ => None,
+                };
+                let (status, body) = match &range {
+                    Some(r) => ("206 Partial Content", &data[r.clone()]),
+                    None => ("200 OK", &data[..]),
+                };
+                let mut head = format!("HTTP/1.1 {status}\r\nContent-Length: {}\r\nAccept-Ranges: bytes\r\nConnection: close\r\n", body.len());
+                if let Some(etag) = state.etag.lock().unwrap().as_ref() {
+                    head.push_str(&format!("ETag: {etag}\r\n"));
+                }
+                head.push_str("\r\n");
+                let _ = stream.write_all(head.as_bytes());
+                if method == "GET" {
+                    let _ = stream.write_all(body);
+                }
+            }
+        });
+        Server {
+            url,
+            requests,
+            behavior,
+        }
+    }
+
+    fn data() -> Vec<u8> {
+        (0..BLOCK_SIZE * 5 / 2).map(|i| (i % 241) as u8).collect()
+    }
+
+    fn reader(server: &Server, cache: &Arc<BlockCache>) -> RemoteReader {
+        let agent = agent();
+        let file = RemoteFile::probe(&agent, &server.url, None, 3).unwrap();
+        RemoteReader::new(agent, Arc::new(file), cache.clone())
+    }
+
+    fn read_at(reader: &mut RemoteReader, offset: u64, length: usize) -> std::io::Result<Vec<u8>> {
+        let mut buf = vec![0u8; length];
+        reader.seek(SeekFrom::Start(offset))?;
+        reader.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    #[test]
+    fn probes_size_and_etag() {
+        let server = serve(data(), Behavior {
+            etag: Mutex::new(Some("\"v1\"".to_string())),
+            ..Default::default()
+        });
+        let file = RemoteFile::probe(&agent(), &server.url, Some(1), 0).unwrap();
+        assert_eq!(file.size, BLOCK_SIZE * 5 / 2);
+        assert_eq!(file.etag.as_deref(), Some("\"v1\""));
+        assert!(file.content_id().is_some());
+        *server.behavior.etag.lock().unwrap() = Some("W/\"weak\"".to_string());
+        assert_eq!(RemoteFile::probe(&agent(), &server.url, None, 0).unwrap().etag, None);
+    }
+
+    #[test]
+    fn reads_blocks_with_range_requests() {
+        let (data, server) = (data(), serve(data(), Behavior::default()));
+        let cache = Arc::new(BlockCache::new(BLOCK_SIZE, 16));
+        let mut reader = reader(&server, &cache);
+        let middle = BLOCK_SIZE as usize * 3 / 2;
+        assert_eq!(read_at(&mut reader, middle as u64, 100).unwrap(), data[middle..][..100]);
+        // Everything in one go, but the block that's cached isn't asked for again
+        let mut all = Vec::new();
+        reader.seek(SeekFrom::Start(0)).unwrap();
+        reader.read_to_end(&mut all).unwrap();
+        assert!(all == data);
+        assert_eq!(*server.requests.lock().unwrap(), [
+            "HEAD ".to_string(),
+            format!("GET {}-{}", BLOCK_SIZE, BLOCK_SIZE * 2 - 1),
+            format!("GET 0-{}", BLOCK_SIZE - 1),
+            format!("GET {}-{}", BLOCK_SIZE * 2, data.len() - 1),
+        ]);
+    }
+
+    #[test]
+    fn skips_to_the_range_when_the_server_sends_everything() {
+        let (data, server) = (data(), serve(data(), Behavior {
+            ignores_ranges: true,
+            ..Default::default()
+        }));
+        let mut reader = reader(&server, &Arc::new(BlockCache::new(BLOCK_SIZE, 16)));
+        let end = data.len() - 10;
+        assert_eq!(read_at(&mut reader, end as u64, 10).unwrap(), data[end..]);
+    }
+
+    #[test]
+    fn retries_transient_failures() {
+        let (data, server) = (data(), serve(data(), Behavior::default()));
+        let mut reader = reader(&server, &Arc::new(BlockCache::new(BLOCK_SIZE, 16)));
+        server.behavior.failures.store(2, Ordering::SeqCst);
+        assert_eq!(read_at(&mut reader, 0, 10).unwrap(), data[..10]);
+        assert_eq!(server.requests.lock().unwrap().len(), 4);
+        server.behavior.failures.store(RETRIES as usize + 1, Ordering::SeqCst);
+        assert!(read_at(&mut reader, BLOCK_SIZE, 10).is_err());
+    }
+
+    #[test]
+    fn notices_changed_files() {
+        let server = serve(data(), Behavior {
+            etag: Mutex::new(Some("\"v1\"".to_string())),
+            ..Default::default()
+        });
+        let mut reader = reader(&server, &Arc::new(BlockCache::new(BLOCK_SIZE, 16)));
+        assert!(read_at(&mut reader, 0, 10).is_ok());
+        *server.behavior.etag.lock().unwrap() = Some("\"v2\"".to_string());
+        assert_eq!(read_at(&mut reader, BLOCK_SIZE * 2, 10).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+}
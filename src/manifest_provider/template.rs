@@ -0,0 +1,166 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::projfs_provider::filetime;
+
+/// What templates can refer to besides their variables, fixed once the projection is running
+pub struct TemplateContext {
+    pub machine: String,
+    /// Unix seconds
+    pub mount_time: i64,
+}
+
+impl TemplateContext {
+    pub fn now() -> TemplateContext {
+        let machine = std::env::var("COMPUTERNAME")
+            .or_else(|_| std::env::var("HOSTNAME"))
+            .unwrap_or_default();
+        let mount_time = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0);
+        TemplateContext {
+            machine,
+            mount_time,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Part {
+    Text(String),
+    Machine,
+    MountTime,
+    Environment(String),
+}
+
+/// Text with `{{name}}` placeholders. Names are `machine`, `mount_time`, `path`, `name`,
+/// `env.<VARIABLE>` and the variables given to the file and the directories it's in.
+/// The rendering is kept, so the file's size and data always agree.
+#[derive(Debug)]
+pub struct Template {
+    parts: Vec<Part>,
+    rendered: Mutex<Option<Arc<[u8]>>>,
+}
+
+impl Template {
+    /// `path` is where the file is in the projection, `variables` the ones in its scope
+    pub fn parse(text: &str, path: &Path, variables: &BTreeMap<String, String>) -> Result<Template, String> {
+        let mut parts = Vec::new();
+        let mut rest = text;
+        while let Some(start) = rest.find("{{") {
+            parts.push(Part::Text(rest[..start].to_string()));
+            let end = match rest[start..].find("}}") {
+                Some(end) => start + end,
+                None => return Err(format!("Unclosed {{{{ in the template of {path:?}")),
+            };
+            let name = rest[start + 2..end].trim();
+            parts.push(match name {
+                "machine" => Part::Machine,
+                "mount_time" => Part::MountTime,
+                "path" => Part::Text(path.to_string_lossy().into_owned()),
+                "name" => Part::Text(path.file_name().unwrap_or_default().to_string_lossy().into_owned()),
+                _ => match (name.strip_prefix("env."), variables.get(name)) {
+                    (Some(variable), _) => Part::Environment(variable.to_string()),
+                    (None, Some(value)) => Part::Text(value.clone()),
+                    (None, None) => return Err(format!("The template of {path:?} uses {name:?}, which isn't defined")),
+                },
+            });
+            rest = &rest[end + 2..];
+        }
+        parts.push(Part::Text(rest.to_string()));
+        Ok(Template {
+            parts,
+            rendered: Mutex::new(None),
+        })
+    }
+
+    pub fn rendered(&self, context: &TemplateContext) -> Arc<[u8]> {
+        let mut rendered = self.rendered.lock().unwrap();
+        rendered
+            .get_or_insert_with(|| {
+                let (year, month, day, hour, minute, second) = filetime::civil_from_unix_seconds(context.mount_time);
+                let text: String = self.parts.iter().map(|part| match part {
+                    Part::Text(text) => text.clone(),
+                    Part::Machine => context.machine.clone(),
+                    Part::MountTime => format!("{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}Z"),
+                    // Unset variables render as nothing
+                    Part::Environment(variable) => std::env::var(variable).unwrap_or_default(),
+                }).collect();
+                text.into_bytes().into()
+            })
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::manifest::{Content, Manifest};
+    use super::super::remote;
+
+    fn context(machine: &str, mount_time: i64) -> TemplateContext {
+        TemplateContext {
+            machine: machine.to_string(),
+            mount_time,
+        }
+    }
+
+    fn render(template: &Template, context: &TemplateContext) -> String {
+        String::from_utf8(template.rendered(context).to_vec()).unwrap()
+    }
+
+    #[test]
+    fn rejects_unclosed_and_undefined_placeholders() {
+        let parse = |text| Template::parse(text, Path::new("about.txt"), &BTreeMap::new()).unwrap_err();
+        assert!(parse("Hello {{machine").contains("Unclosed"), "{}", parse("Hello {{machine"));
+        assert!(parse("Hello {{ owner }}").contains("\"owner\", which isn't defined"));
+        assert!(parse("{{}}").contains("\"\", which isn't defined"));
+    }
+
+    #[test]
+    fn fills_in_placeholders() {
+        let variables = BTreeMap::from([("owner".to_string(), "Build team".to_string())]);
+        let template = Template::parse("{{ owner }} on {{machine}} since {{mount_time}} in {{path}} as {{name}}", Path::new("team/about.txt"), &variables).unwrap();
+        assert_eq!(render(&template, &context("build01", 1_650_000_000)), "Build team on build01 since 2022-04-15T05:20:00Z in team/about.txt as about.txt");
+    }
+
+    #[test]
+    fn nearest_variables_win() {
+        let manifest: Manifest = serde_json::from_str(r#"{"entries": [
+            {"path": "team", "directory": true, "variables": {"owner": "Team", "site": "Berlin"}},
+            {"path": "team/tools", "directory": true, "variables": {"owner": "Tools"}},
+            {"path": "team/tools/about.txt", "variables": {"site": "Remote"}, "content": {"template": "{{owner}} {{site}}"}},
+            {"path": "team/about.txt", "content": {"template": "{{owner}} {{site}}"}},
+            {"path": "other/about.txt", "content": {"template": "{{owner}}"}}
+        ]}"#).unwrap();
+        let error = manifest.build_tree(&remote::agent()).err().unwrap().to_string();
+        assert!(error.contains("\"owner\", which isn't defined"), "{error}");
+
+        let manifest = Manifest {
+            entries: manifest.entries.into_iter().filter(|e| !e.path.starts_with("other")).collect(),
+        };
+        let tree = manifest.build_tree(&remote::agent()).unwrap();
+        let rendered = |path| match &tree.node(tree.lookup(Path::new(path)).unwrap()).content {
+            Content::Template(template) => render(template, &context("", 0)),
+            _ => panic!("{path} isn't a template"),
+        };
+        assert_eq!(rendered("team/tools/about.txt"), "Tools Remote");
+        assert_eq!(rendered("team/about.txt"), "Team Berlin");
+    }
+
+    #[test]
+    fn renders_the_same_every_time() {
+        let variable = format!("PROJFS_TEMPLATE_TEST_{}", std::process::id());
+        std::env::set_var(&variable, "first");
+        let template = Template::parse(&format!("{{{{env.{variable}}}}} on {{{{machine}}}}"), Path::new("about.txt"), &BTreeMap::new()).unwrap();
+        // The placeholder gets the size of the first rendering, hydrating it later reads the same
+        let size = template.rendered(&context("build01", 0)).len();
+        std::env::set_var(&variable, "a longer value");
+        let data = template.rendered(&context("another machine", 0));
+        assert_eq!(data.len(), size);
+        assert_eq!(&data[..], b"first on build01");
+        std::env::remove_var(&variable);
+    }
+}
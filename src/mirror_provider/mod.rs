@@ -0,0 +1,4 @@
+mod provider;
+mod watcher;
+
+pub use provider::MirrorProvider;
@@ -0,0 +1,241 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use windows::Win32::Storage::{FileSystem, ProjectedFileSystem};
+
+use crate::projfs_provider::{filetime, ProjFSProvider, EnumerationState, ListEnumeration, SeekRead, VirtualizationOptions, EntryInfo};
+use super::watcher::SourceWatcher;
+
+/// Attributes that make sense to project, everything else describes the source file on disk
+const PROJECTED_ATTRIBUTES: u32 = FileSystem::FILE_ATTRIBUTE_READONLY.0
+    | FileSystem::FILE_ATTRIBUTE_HIDDEN.0
+    | FileSystem::FILE_ATTRIBUTE_SYSTEM.0
+    | FileSystem::FILE_ATTRIBUTE_ARCHIVE.0;
+
+/// Projects an existing directory tree. Nothing is read ahead, every callback goes to the source.
+/// With `watch` changes to the source also reach files that are already on disk in the projection.
+pub struct MirrorProvider {
+    source: PathBuf,
+    watch: bool,
+    watcher: Option<SourceWatcher>,
+}
+
+impl MirrorProvider {
+    pub fn new(source: &Path, watch: bool) -> Result<MirrorProvider, Box<dyn std::error::Error>> {
+        if !fs::metadata(source)?.is_dir() {
+            return Err(format!("{source:?} is not a directory").into());
+        }
+        Ok(MirrorProvider {
+            source: source.to_path_buf(),
+            watch,
+            watcher: None,
+        })
+    }
+
+    fn source_path(&self, file_path: &Path) -> Option<PathBuf> {
+        // ProjFS only hands out relative paths, but don't let anything escape the source
+        if file_path.components().all(|c| matches!(c, Component::Normal(_))) {
+            Some(self.source.join(file_path))
+        } else {
+            None
+        }
+    }
+}
+
+fn entry_info(metadata: &fs::Metadata) -> EntryInfo {
+    let time = |t: std::io::Result<std::time::SystemTime>| t.map(filetime::from_system_time).unwrap_or(0);
+    let modified = time(metadata.modified());
+    #[cfg(windows)]
+    let attributes = std::os::windows::fs::MetadataExt::file_attributes(metadata);
+    // Elsewhere read-only is the only attribute there is
+    #[cfg(not(windows))]
+    let attributes = if metadata.permissions().readonly() {
+        FileSystem::FILE_ATTRIBUTE_READONLY.0
+    } else {
+        0
+    };
+    EntryInfo {
+        is_directory: metadata.is_dir(),
+        size: if metadata.is_dir() { 0 } else { metadata.len() },
+        creation_time: time(metadata.created()),
+        last_access_time: time(metadata.accessed()),
+        last_write_time: modified,
+        change_time: modified,
+        attributes: attributes & PROJECTED_ATTRIBUTES,
+    }
+}
+
+/// Placeholder info with a content ID made from the size and last write time, so
+/// PrjUpdateFileIfNeeded can tell whether a placeholder is out of date
+pub fn placeholder_info(metadata: &fs::Metadata) -> ProjectedFileSystem::PRJ_PLACEHOLDER_INFO {
+    let info = entry_info(metadata);
+    let mut placeholder = info.placeholder_info();
+    if !info.is_directory {
+        let content_id = &mut placeholder.VersionInfo.ContentID;
+        content_id[..8].copy_from_slice(&info.size.to_le_bytes());
+        content_id[8..16].copy_from_slice(&info.last_write_time.to_le_bytes());
+    }
+    placeholder
+}
+
+fn io_error_result(e: &std::io::Error) -> windows::core::HRESULT {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into(),
+        std::io::ErrorKind::PermissionDenied => windows::Win32::Foundation::ERROR_ACCESS_DENIED.into(),
+        _ => windows::Win32::Foundation::E_FAIL,
+    }
+}
+
+impl ProjFSProvider for MirrorProvider {
+    fn init(&mut self, _root: &Path) -> Result<VirtualizationOptions, Box<dyn std::error::Error>> {
+        Ok(VirtualizationOptions::default())
+    }
+
+    fn start(&mut self, context: ProjectedFileSystem::PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT) -> Result<(), Box<dyn std::error::Error>> {
+        if self.watch {
+            self.watcher = Some(SourceWatcher::start(&self.source, context)?);
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        match self.watcher.take() {
+            Some(watcher) => watcher.stop(),
+            None => Ok(()),
+        }
+    }
+
+    fn new_enumeration(&self, _id: windows::core::GUID, file_path: &Path) -> Box<dyn EnumerationState> {
+        let mut entries = Vec::new();
+        let read_dir = match self.source_path(file_path).map(fs::read_dir) {
+            Some(Ok(r)) => Some(r),
+            Some(Err(e)) => {
+                println!("Could not list {file_path:?}: {e}");
+                None
+            }
+            None => None,
+        };
+        for entry in read_dir.into_iter().flatten() {
+            // Entries that vanish or can't be read while listing are left out
+            let (name, metadata) = match entry.and_then(|e| Ok((e.file_name(), e.metadata()?))) {
+                Ok(v) => v,
+                Err(e) => {
+                    println!("Skipping an entry of {file_path:?}: {e}");
+                    continue;
+                }
+            };
+            entries.push((name, entry_info(&metadata).basic_info()));
+        }
+        Box::new(ListEnumeration::sorted(entries))
+    }
+
+    fn get_placeholder_info(&self, file_path: &Path) -> Result<ProjectedFileSystem::PRJ_PLACEHOLDER_INFO, windows::core::HRESULT> {
+        let source = self.source_path(file_path).ok_or(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND)?;
+        match fs::metadata(source) {
+            Ok(m) => Ok(placeholder_info(&m)),
+            Err(e) => Err(io_error_result(&e)),
+        }
+    }
+
+    fn get_file_data(&self, file_path: &Path) -> Result<Box<dyn SeekRead>, windows::core::HRESULT> {
+        let source = self.source_path(file_path).ok_or(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND)?;
+        match fs::File::open(&source) {
+            Ok(f) => Ok(Box::new(f)),
+            Err(e) => {
+                println!("Could not open {source:?}: {e}");
+                Err(io_error_result(&e))
+            }
+        }
+    }
+
+    fn query_file_name(&self, file_path: &Path) -> windows::core::HRESULT {
+        match self.source_path(file_path).map(fs::symlink_metadata) {
+            Some(Ok(_)) => windows::Win32::Foundation::S_OK,
+            _ => windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into(),
+        }
+    }
+
+    fn notification(&self, _callbackdata: *const ProjectedFileSystem::PRJ_CALLBACK_DATA, _is_directory: bool, _notification: ProjectedFileSystem::PRJ_NOTIFICATION, _destinationfilename: windows::core::PCWSTR, _operationparameters: *mut ProjectedFileSystem::PRJ_NOTIFICATION_PARAMETERS) -> windows::core::HRESULT {
+        windows::Win32::Foundation::S_OK
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    /// A source directory that is removed again when the test is done
+    struct Source(PathBuf);
+
+    impl Source {
+        fn new(name: &str) -> Source {
+            let path = std::env::temp_dir().join(format!("projfs-mirror-{}-{name}", std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(path.join("dir")).unwrap();
+            fs::write(path.join("dir").join("file.txt"), b"mirrored").unwrap();
+            Source(path)
+        }
+    }
+
+    impl Drop for Source {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn not_found() -> windows::core::HRESULT {
+        windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into()
+    }
+
+    #[test]
+    fn projects_metadata() {
+        let source = Source::new("metadata");
+        let provider = MirrorProvider::new(&source.0, false).unwrap();
+        let file = provider.get_placeholder_info(Path::new("dir/file.txt")).unwrap();
+        assert_eq!(file.FileBasicInfo.FileSize, 8);
+        assert_eq!(file.FileBasicInfo.IsDirectory, windows::Win32::Foundation::BOOLEAN(0));
+        assert_eq!(&file.VersionInfo.ContentID[..8], &8u64.to_le_bytes());
+        let directory = provider.get_placeholder_info(Path::new("dir")).unwrap();
+        assert_eq!(directory.FileBasicInfo.IsDirectory, windows::Win32::Foundation::BOOLEAN(1));
+        assert_eq!(provider.get_placeholder_info(Path::new("dir/missing")).err(), Some(not_found()));
+    }
+
+    #[test]
+    fn changes_the_content_id_with_the_file() {
+        let source = Source::new("content-id");
+        let provider = MirrorProvider::new(&source.0, false).unwrap();
+        let before = provider.get_placeholder_info(Path::new("dir/file.txt")).unwrap();
+        fs::write(source.0.join("dir").join("file.txt"), b"mirrored again").unwrap();
+        let after = provider.get_placeholder_info(Path::new("dir/file.txt")).unwrap();
+        assert_ne!(before.VersionInfo.ContentID, after.VersionInfo.ContentID);
+    }
+
+    #[test]
+    fn streams_file_data() {
+        let source = Source::new("data");
+        let provider = MirrorProvider::new(&source.0, false).unwrap();
+        let mut data = String::new();
+        provider.get_file_data(Path::new("dir/file.txt")).ok().unwrap().read_to_string(&mut data).unwrap();
+        assert_eq!(data, "mirrored");
+        assert_eq!(provider.query_file_name(Path::new("dir/file.txt")), windows::Win32::Foundation::S_OK);
+        assert_eq!(provider.query_file_name(Path::new("nothing")), not_found());
+    }
+
+    #[test]
+    fn stays_inside_the_source() {
+        let source = Source::new("escape");
+        let provider = MirrorProvider::new(&source.0.join("dir"), false).unwrap();
+        assert!(provider.get_file_data(Path::new("../dir/file.txt")).is_err());
+        assert_eq!(provider.get_placeholder_info(Path::new("..")).err(), Some(not_found()));
+        assert_eq!(provider.query_file_name(Path::new("/etc")), not_found());
+    }
+
+    #[test]
+    fn needs_a_directory() {
+        let source = Source::new("not-a-directory");
+        assert!(MirrorProvider::new(&source.0.join("dir").join("file.txt"), false).is_err());
+        assert!(MirrorProvider::new(&source.0.join("missing"), false).is_err());
+    }
+}
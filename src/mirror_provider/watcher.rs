@@ -0,0 +1,217 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use notify::Watcher;
+use windows::Win32::Storage::ProjectedFileSystem;
+
+use crate::projfs_provider;
+use super::provider::placeholder_info;
+
+/// How long the source has to be quiet before changes are applied
+const DEBOUNCE: Duration = Duration::from_millis(200);
+/// Changes are applied at least this often, even if the source never goes quiet
+const MAX_DELAY: Duration = Duration::from_secs(2);
+
+/// Watches the source and brings the projection up to date with it. New entries need nothing
+/// but dropping cached not-found results, changed files get their placeholders updated through
+/// the content ID and deleted ones are removed.
+pub struct SourceWatcher {
+    // Dropping it closes the event channel, which ends the thread applying the changes
+    watcher: notify::RecommendedWatcher,
+    stopping: Arc<AtomicBool>,
+    thread: std::thread::JoinHandle<()>,
+}
+
+impl SourceWatcher {
+    pub fn start(source: &Path, context: ProjectedFileSystem::PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT) -> Result<SourceWatcher, Box<dyn std::error::Error>> {
+        let (tx, rx) = channel();
+        let mut watcher = notify::recommended_watcher(tx)?;
+        watcher.watch(source, notify::RecursiveMode::Recursive)?;
+        let source = source.to_path_buf();
+        let stopping = Arc::new(AtomicBool::new(false));
+        let thread_stopping = stopping.clone();
+        let thread = std::thread::spawn(move || debounce(&source, rx, |changed| apply(&source, context, changed, &thread_stopping)));
+        Ok(SourceWatcher {
+            watcher,
+            stopping,
+            thread,
+        })
+    }
+
+    /// Waits for changes being applied, anything not applied yet is dropped. Applying
+    /// changes needs the runner's state, so this can't be called while it's locked.
+    pub fn stop(self) -> Result<(), Box<dyn std::error::Error>> {
+        self.stopping.store(true, Ordering::Relaxed);
+        drop(self.watcher);
+        if self.thread.join().is_err() {
+            return Err("Applying source changes panicked".into());
+        }
+        Ok(())
+    }
+}
+
+/// Collects the paths of events below `source` until it's quiet and hands them to `apply`,
+/// an empty path stands for everything
+fn debounce(source: &Path, rx: Receiver<notify::Result<notify::Event>>, mut apply: impl FnMut(HashSet<PathBuf>)) {
+    let mut changed = HashSet::new();
+    let mut first_change = Instant::now();
+    loop {
+        let event = if changed.is_empty() {
+            rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        } else if first_change.elapsed() >= MAX_DELAY {
+            Err(RecvTimeoutError::Timeout)
+        } else {
+            rx.recv_timeout(std::cmp::min(DEBOUNCE, MAX_DELAY.saturating_sub(first_change.elapsed())))
+        };
+        match event {
+            Ok(Ok(event)) => {
+                if matches!(event.kind, notify::EventKind::Access(_)) {
+                    continue;
+                }
+                if changed.is_empty() {
+                    first_change = Instant::now();
+                }
+                if event.need_rescan() {
+                    // Events were lost, all that can be done is to forget what is cached
+                    println!("Missed changes in {source:?}, already projected files may be stale");
+                    changed.insert(PathBuf::new());
+                }
+                changed.extend(event.paths.iter().filter_map(|p| p.strip_prefix(source).ok()).map(Path::to_path_buf));
+            }
+            Ok(Err(e)) => {
+                println!("Watching {source:?} failed: {e}");
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                apply(std::mem::take(&mut changed));
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                return;
+            }
+        }
+    }
+}
+
+fn apply(source: &Path, context: ProjectedFileSystem::PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT, changed: HashSet<PathBuf>, stopping: &AtomicBool) {
+    let mut changed: Vec<PathBuf> = changed.into_iter().collect();
+    // Children first, so deleted directories are already empty when their turn comes
+    changed.sort_by_key(|p| std::cmp::Reverse(p.components().count()));
+    // Local modifications win over changes at the source
+    let update_flags = ProjectedFileSystem::PRJ_UPDATE_ALLOW_DIRTY_METADATA;
+    for path in &changed {
+        // The context goes away once the runner is done stopping
+        if stopping.load(Ordering::Relaxed) {
+            return;
+        }
+        projfs_provider::invalidate_cache(context, path);
+        if path.as_os_str().is_empty() {
+            continue;
+        }
+        let result = match std::fs::metadata(source.join(path)) {
+            // Directories only change by what they contain, which enumeration picks up
+            Ok(m) if m.is_dir() => Ok(ProjectedFileSystem::PRJ_UPDATE_FAILURE_CAUSE_NONE),
+            Ok(m) => unsafe {
+                let info = placeholder_info(&m);
+                ProjectedFileSystem::PrjUpdateFileIfNeeded(context, path.as_os_str(), &info, std::mem::size_of_val(&info) as u32, update_flags)
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => unsafe {
+                ProjectedFileSystem::PrjDeleteFile(context, path.as_os_str(), update_flags)
+            },
+            Err(e) => {
+                println!("Could not check {path:?} in {source:?}: {e}");
+                continue;
+            }
+        };
+        match result {
+            Ok(_) => {}
+            // Nothing of it is on disk in the projection yet
+            Err(e) if e.code() == windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into()
+                || e.code() == windows::Win32::Foundation::ERROR_PATH_NOT_FOUND.into() => {}
+            Err(e) => {
+                println!("Could not bring {path:?} up to date: {e:?}");
+            }
+        }
+    }
+    if stopping.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Err(e) = unsafe { ProjectedFileSystem::PrjClearNegativePathCache(context) } {
+        println!("PrjClearNegativePathCache failed: {e:?}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::Sender;
+
+    use notify::event::{AccessKind, CreateKind, Flag, ModifyKind};
+    use notify::{Event, EventKind};
+
+    use super::*;
+
+    /// Runs `debounce` on a thread, what it applies comes out of the returned channel
+    fn start() -> (Sender<notify::Result<Event>>, Receiver<HashSet<PathBuf>>) {
+        let (events, rx) = channel();
+        let (applied, batches) = channel();
+        std::thread::spawn(move || debounce(Path::new("/source"), rx, |changed| applied.send(changed).unwrap()));
+        (events, batches)
+    }
+
+    fn event(kind: EventKind, paths: &[&str]) -> notify::Result<Event> {
+        Ok(paths.iter().fold(Event::new(kind), |e, p| e.add_path(PathBuf::from(p))))
+    }
+
+    fn modified(path: &str) -> notify::Result<Event> {
+        event(EventKind::Modify(ModifyKind::Any), &[path])
+    }
+
+    fn paths(paths: &[&str]) -> HashSet<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn applies_changes_once_the_source_is_quiet() {
+        let (events, batches) = start();
+        events.send(modified("/source/a.txt")).unwrap();
+        events.send(event(EventKind::Access(AccessKind::Any), &["/source/read.txt"])).unwrap();
+        events.send(event(EventKind::Create(CreateKind::Any), &["/source/dir/b.txt", "/elsewhere/c.txt"])).unwrap();
+        events.send(modified("/source/a.txt")).unwrap();
+        let started = Instant::now();
+        assert_eq!(batches.recv().unwrap(), paths(&["a.txt", "dir/b.txt"]));
+        assert!(started.elapsed() >= DEBOUNCE - Duration::from_millis(20));
+        assert!(batches.recv_timeout(DEBOUNCE * 2).is_err());
+        // Closing the channel ends it
+        drop(events);
+        assert_eq!(batches.recv(), Err(std::sync::mpsc::RecvError));
+    }
+
+    #[test]
+    fn applies_changes_at_least_every_max_delay() {
+        let (events, batches) = start();
+        let started = Instant::now();
+        while started.elapsed() < MAX_DELAY + DEBOUNCE * 2 {
+            events.send(modified("/source/busy.log")).unwrap();
+            if let Ok(batch) = batches.recv_timeout(DEBOUNCE / 4) {
+                assert_eq!(batch, paths(&["busy.log"]));
+                let elapsed = started.elapsed();
+                assert!(elapsed >= MAX_DELAY && elapsed < MAX_DELAY + DEBOUNCE, "{elapsed:?}");
+                return;
+            }
+        }
+        panic!("Nothing was applied while the source kept changing");
+    }
+
+    #[test]
+    fn debounces_rescans_like_other_changes() {
+        let (events, batches) = start();
+        // Long enough that applying right away would be due if the rescan didn't start the wait
+        std::thread::sleep(MAX_DELAY + DEBOUNCE);
+        events.send(Ok(Event::new(EventKind::Other).set_flag(Flag::Rescan))).unwrap();
+        std::thread::sleep(DEBOUNCE / 4);
+        events.send(modified("/source/a.txt")).unwrap();
+        assert_eq!(batches.recv().unwrap(), paths(&["", "a.txt"]));
+    }
+}
@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+const INDEX_MEDIA_TYPES: [&str; 2] = [
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+];
+const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+/// Image indexes can point at image indexes
+const MAX_INDEX_DEPTH: usize = 8;
+
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    #[serde(default)]
+    media_type: String,
+    digest: String,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+    platform: Option<Platform>,
+}
+
+#[derive(Clone, Deserialize)]
+struct Platform {
+    os: String,
+    architecture: String,
+    variant: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Index {
+    manifests: Vec<Descriptor>,
+}
+
+#[derive(Deserialize)]
+struct Manifest {
+    layers: Vec<Descriptor>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+}
+
+pub struct Layer {
+    pub path: PathBuf,
+    pub compression: Compression,
+}
+
+/// Image layout directory as written by `skopeo copy ... oci:<dir>` and friends
+pub struct ImageLayout {
+    root: PathBuf,
+}
+
+impl Platform {
+    /// `os/architecture[/variant]`, leaving out the variant matches any
+    fn matches(&self, filter: &str) -> bool {
+        let mut parts = filter.split('/');
+        parts.next() == Some(self.os.as_str())
+            && parts.next() == Some(self.architecture.as_str())
+            && parts.next().map(|v| Some(v) == self.variant.as_deref()).unwrap_or(true)
+    }
+}
+
+impl ImageLayout {
+    pub fn open(root: &Path) -> Result<ImageLayout, Box<dyn std::error::Error>> {
+        if !root.join("oci-layout").is_file() {
+            return Err(format!("{root:?} is not an OCI image layout").into());
+        }
+        Ok(ImageLayout {
+            root: root.to_path_buf(),
+        })
+    }
+
+    fn blob_path(&self, digest: &str) -> Result<PathBuf, String> {
+        match digest.split_once(':') {
+            // Digests end up in a path, so they can't be allowed to contain anything but names
+            Some((algorithm, hex)) if !algorithm.is_empty() && !hex.is_empty()
+                && algorithm.chars().chain(hex.chars()).all(|c| c.is_ascii_alphanumeric()) => {
+                Ok(self.root.join("blobs").join(algorithm).join(hex))
+            }
+            _ => Err(format!("Invalid digest {digest:?}")),
+        }
+    }
+
+    fn read_json<T: DeserializeOwned>(&self, path: &Path) -> Result<T, Box<dyn std::error::Error>> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{path:?}: {e}"))?;
+        Ok(serde_json::from_str(&text).map_err(|e| format!("{path:?}: {e}"))?)
+    }
+
+    /// Layers of an image, bottom first. `reference` picks the image by its ref name annotation
+    /// and `platform` picks from multi-platform images, otherwise the first one wins.
+    pub fn layers(&self, reference: Option<&str>, platform: Option<&str>) -> Result<Vec<Layer>, Box<dyn std::error::Error>> {
+        let index: Index = self.read_json(&self.root.join("index.json"))?;
+        let mut candidates = index.manifests;
+        if let Some(reference) = reference {
+            candidates.retain(|d| d.annotations.get(REF_NAME_ANNOTATION).map(String::as_str) == Some(reference));
+            if candidates.is_empty() {
+                return Err(format!("No image is called {reference:?}").into());
+            }
+        }
+
+        for _ in 0..MAX_INDEX_DEPTH {
+            let descriptor = candidates.iter()
+                .find(|d| match (platform, &d.platform) {
+                    (Some(filter), Some(p)) => p.matches(filter),
+                    _ => true,
+                })
+                .ok_or_else(|| format!("No image for platform {platform:?}"))?
+                .clone();
+            let path = self.blob_path(&descriptor.digest)?;
+            if INDEX_MEDIA_TYPES.contains(&descriptor.media_type.as_str()) {
+                candidates = self.read_json::<Index>(&path)?.manifests;
+                continue;
+            }
+
+            let manifest: Manifest = self.read_json(&path)?;
+            return manifest.layers.iter().map(|l| {
+                let compression = if l.media_type.ends_with("+gzip") || l.media_type.ends_with(".tar.gzip") {
+                    Compression::Gzip
+                } else if l.media_type.ends_with(".tar") || l.media_type.ends_with(".tar.v1") {
+                    Compression::None
+                } else {
+                    return Err(format!("Layers of type {:?} aren't supported", l.media_type).into());
+                };
+                Ok(Layer {
+                    path: self.blob_path(&l.digest)?,
+                    compression,
+                })
+            }).collect();
+        }
+        Err("Image indexes nest too deep".into())
+    }
+}
@@ -0,0 +1,4 @@
+mod layout;
+mod provider;
+
+pub use provider::OciProvider;
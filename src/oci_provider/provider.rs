@@ -0,0 +1,308 @@
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+use std::sync::Arc;
+
+use windows::Win32::Storage::{FileSystem, ProjectedFileSystem};
+
+use crate::projfs_provider::{filetime, ProjFSProvider, EnumerationState, SeekRead, VirtualizationOptions, VirtualTree, EntryInfo, WindowReader, BlockCache, DecompressingReader};
+use crate::tar_provider::{normalize, scan, EntryKind, Streamed, TarEntry};
+use super::layout::{Compression, ImageLayout, Layer};
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+const BLOCK_SIZE: u64 = 64 * 1024;
+const CACHED_BLOCKS: usize = 1024;
+
+#[derive(Clone, Debug, Default)]
+enum OciContent {
+    /// Directories
+    #[default]
+    None,
+    Data {
+        layer: usize,
+        offset: u64,
+    },
+    /// Where a symlink leads, from the root. Only until all layers are merged.
+    Link(String),
+}
+
+struct LayerData {
+    layer: Layer,
+    /// Of the tar inside, which is what offsets point into
+    size: u64,
+}
+
+/// Projects the merged file system of an OCI image. Layers are applied in order, whiteouts
+/// included, and file data is read out of the layer blobs, decompressing gzip layers as needed.
+pub struct OciProvider {
+    layers: Vec<LayerData>,
+    tree: Arc<VirtualTree<OciContent>>,
+    cache: Arc<BlockCache>,
+}
+
+fn split_parent(path: &str) -> (&str, &str) {
+    path.rsplit_once('/').unwrap_or(("", path))
+}
+
+fn apply_layer(tree: &mut VirtualTree<OciContent>, layer: usize, entries: &[TarEntry], directory_info: EntryInfo) {
+    // Whiteouts only hide what the layers below have, so they go first
+    for entry in entries {
+        let (parent, name) = split_parent(&entry.path);
+        let parent = match tree.lookup(Path::new(parent)) {
+            Some(id) => id,
+            None => {
+                continue;
+            }
+        };
+        if name == OPAQUE_WHITEOUT {
+            tree.clear_children(parent);
+        } else if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+            tree.remove(parent, OsStr::new(hidden));
+        }
+    }
+
+    for entry in entries {
+        let (parent, name) = split_parent(&entry.path);
+        if name.starts_with(WHITEOUT_PREFIX) {
+            continue;
+        }
+        let time = filetime::from_unix_seconds(entry.mtime);
+        let (info, content) = match entry.kind {
+            EntryKind::Directory => (EntryInfo::directory(time), OciContent::None),
+            EntryKind::File => {
+                let mut info = EntryInfo::file(entry.size, time);
+                if entry.mode & 0o222 == 0 {
+                    info.attributes = FileSystem::FILE_ATTRIBUTE_READONLY.0;
+                }
+                (info, OciContent::Data { layer, offset: entry.offset })
+            }
+            // Hard links share the data of an earlier entry, even if a later layer removes that one
+            EntryKind::HardLink => {
+                // Relative to the root of the layer, written like entry paths
+                let link = entry.link.as_deref().unwrap_or_default();
+                match normalize(link).and_then(|l| tree.lookup(Path::new(&l))).map(|id| tree.node(id)) {
+                    Some(target) if matches!(target.content, OciContent::Data { .. }) => (target.info, target.content.clone()),
+                    _ => {
+                        println!("Leaving out {:?} of layer {layer}, it links to {link:?} which isn't a file", entry.path);
+                        continue;
+                    }
+                }
+            }
+            EntryKind::Symlink => {
+                let link = entry.link.as_deref().unwrap_or_default();
+                let target = if link.starts_with('/') {
+                    link.to_string()
+                } else {
+                    format!("/{parent}/{link}")
+                };
+                (EntryInfo::file(0, time), OciContent::Link(target))
+            }
+        };
+        // Directories only update the metadata of a directory below, anything else replaces what was there
+        if let Some(parent_id) = tree.lookup(Path::new(parent)) {
+            match tree.child(parent_id, OsStr::new(name)) {
+                Some(existing) if !(info.is_directory && tree.node(existing).info.is_directory) => {
+                    tree.remove(parent_id, OsStr::new(name));
+                }
+                _ => {}
+            }
+        }
+        if let Err(e) = tree.insert_path(Path::new(&entry.path), info, content, directory_info) {
+            println!("Leaving out {:?} of layer {layer}: {e}", entry.path);
+        }
+    }
+}
+
+fn link_target(content: &OciContent) -> Option<&str> {
+    match content {
+        OciContent::Link(target) => Some(target),
+        _ => None,
+    }
+}
+
+impl OciProvider {
+    pub fn new(layout_path: &Path, reference: Option<&str>, platform: Option<&str>) -> Result<OciProvider, Box<dyn std::error::Error>> {
+        let layout = ImageLayout::open(layout_path)?;
+        let directory_info = EntryInfo::directory(filetime::now());
+        let mut tree = VirtualTree::new(directory_info, OciContent::None);
+        let mut layers = Vec::new();
+        for (i, layer) in layout.layers(reference, platform)?.into_iter().enumerate() {
+            let file = BufReader::new(File::open(&layer.path).map_err(|e| format!("{:?}: {e}", layer.path))?);
+            let (entries, size) = match layer.compression {
+                Compression::None => {
+                    let size = file.get_ref().metadata()?.len();
+                    (scan(&mut { file })?, size)
+                }
+                Compression::Gzip => {
+                    let mut stream = Streamed::new(flate2::bufread::MultiGzDecoder::new(file));
+                    let entries = scan(&mut stream)?;
+                    // Whatever follows the end marker still counts for the size
+                    std::io::copy(&mut stream, &mut std::io::sink())?;
+                    (entries, stream.position)
+                }
+            };
+            println!("Layer {i} has {} entries", entries.len());
+            apply_layer(&mut tree, i, &entries, directory_info);
+            layers.push(LayerData {
+                layer,
+                size,
+            });
+        }
+        tree.sort();
+        let left_out = tree.resolve_links(link_target);
+        if left_out > 0 {
+            println!("Left out {left_out} links to directories or to nothing");
+        }
+
+        Ok(OciProvider {
+            layers,
+            tree: Arc::new(tree),
+            cache: Arc::new(BlockCache::new(BLOCK_SIZE, CACHED_BLOCKS)),
+        })
+    }
+}
+
+impl ProjFSProvider for OciProvider {
+    fn init(&mut self, _root: &Path) -> Result<VirtualizationOptions, Box<dyn std::error::Error>> {
+        Ok(VirtualizationOptions::default())
+    }
+
+    fn start(&mut self, _context: ProjectedFileSystem::PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn new_enumeration(&self, _id: windows::core::GUID, file_path: &Path) -> Box<dyn EnumerationState> {
+        self.tree.enumeration(file_path)
+    }
+
+    fn get_placeholder_info(&self, file_path: &Path) -> Result<ProjectedFileSystem::PRJ_PLACEHOLDER_INFO, windows::core::HRESULT> {
+        match self.tree.lookup(file_path) {
+            Some(id) => Ok(self.tree.node(id).info.placeholder_info()),
+            None => Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into()),
+        }
+    }
+
+    fn get_file_data(&self, file_path: &Path) -> Result<Box<dyn SeekRead>, windows::core::HRESULT> {
+        let (layer, offset, size) = match self.tree.lookup(file_path).map(|id| self.tree.node(id)) {
+            Some(node) => match node.content {
+                OciContent::Data { layer, offset } => (layer, offset, node.info.size),
+                _ => {
+                    return Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into());
+                }
+            },
+            None => {
+                return Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into());
+            }
+        };
+        let data = &self.layers[layer];
+        let path = data.layer.path.clone();
+        match data.layer.compression {
+            Compression::None => match File::open(&path) {
+                Ok(f) => Ok(Box::new(WindowReader::new(f, offset, size))),
+                Err(e) => {
+                    println!("Could not open {path:?} for {file_path:?}: {e}");
+                    Err(windows::Win32::Foundation::E_FAIL)
+                }
+            },
+            Compression::Gzip => {
+                let open = move || -> std::io::Result<Box<dyn Read + Send>> {
+                    Ok(Box::new(flate2::bufread::MultiGzDecoder::new(BufReader::new(File::open(&path)?))))
+                };
+                let layer_reader = DecompressingReader::new(self.cache.clone(), layer as u64, data.size, Box::new(open));
+                Ok(Box::new(WindowReader::new(layer_reader, offset, size)))
+            }
+        }
+    }
+
+    fn query_file_name(&self, file_path: &Path) -> windows::core::HRESULT {
+        match self.tree.lookup(file_path) {
+            Some(_) => windows::Win32::Foundation::S_OK,
+            None => windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into(),
+        }
+    }
+
+    fn notification(&self, _callbackdata: *const ProjectedFileSystem::PRJ_CALLBACK_DATA, _is_directory: bool, _notification: ProjectedFileSystem::PRJ_NOTIFICATION, _destinationfilename: windows::core::PCWSTR, _operationparameters: *mut ProjectedFileSystem::PRJ_NOTIFICATION_PARAMETERS) -> windows::core::HRESULT {
+        windows::Win32::Foundation::S_OK
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tar_provider::tests::Archive;
+
+    /// The tree of the layers applied in order, what `OciProvider::new` does without the blobs
+    fn merge(layers: Vec<Archive>) -> VirtualTree<OciContent> {
+        let directory_info = EntryInfo::directory(0);
+        let mut tree = VirtualTree::new(directory_info, OciContent::None);
+        for (i, layer) in layers.into_iter().enumerate() {
+            apply_layer(&mut tree, i, &layer.scan().unwrap(), directory_info);
+        }
+        tree.sort();
+        tree
+    }
+
+    fn layer_of(tree: &VirtualTree<OciContent>, path: &str) -> Option<usize> {
+        match tree.node(tree.lookup(Path::new(path))?).content {
+            OciContent::Data { layer, .. } => Some(layer),
+            _ => None,
+        }
+    }
+
+    fn names(tree: &VirtualTree<OciContent>, path: &str) -> Vec<String> {
+        let id = tree.lookup(Path::new(path)).unwrap();
+        tree.children(id).map(|c| tree.node(c).name.to_string_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn later_layers_replace_earlier_entries() {
+        let tree = merge(vec![
+            Archive::default().file("etc/config", b"old").file("etc/keep", b"").file("var", b"file"),
+            Archive::default().file("etc/config", b"newer").entry(b'5', "var/", "", b"").file("var/log", b""),
+        ]);
+        assert_eq!(layer_of(&tree, "etc/config"), Some(1));
+        assert_eq!(tree.node(tree.lookup(Path::new("etc/config")).unwrap()).info.size, 5);
+        assert_eq!(layer_of(&tree, "etc/keep"), Some(0));
+        // A directory in place of a file
+        assert!(tree.node(tree.lookup(Path::new("var")).unwrap()).info.is_directory);
+        assert_eq!(layer_of(&tree, "var/log"), Some(1));
+    }
+
+    #[test]
+    fn whiteouts_hide_what_the_layers_below_have() {
+        let tree = merge(vec![
+            Archive::default().file("a/gone", b"").file("a/stays", b"").file("b/old", b"").file("b/older", b""),
+            Archive::default()
+                .file("a/.wh.gone", b"")
+                .file("b/.wh..wh..opq", b"")
+                .file("b/new", b"")
+                .file("c/.wh.nothing", b""),
+        ]);
+        assert_eq!(names(&tree, "a"), ["stays"]);
+        // Opaque directories keep what their own layer puts in them
+        assert_eq!(names(&tree, "b"), ["new"]);
+        assert_eq!(tree.lookup(Path::new("c/.wh.nothing")), None);
+    }
+
+    #[test]
+    fn hard_links_share_the_data_of_their_target() {
+        let tree = merge(vec![
+            Archive::default()
+                .file("usr/bin/x", b"binary")
+                .entry(b'1', "usr/bin/y", "./usr/bin/x", b"")
+                .entry(b'1', "usr/bin/z", "/usr/bin/x", b"")
+                .entry(b'1', "usr/bin/dangling", "usr/bin/missing", b""),
+        ]);
+        let content = |path: &str| tree.lookup(Path::new(path)).map(|id| format!("{:?}", tree.node(id).content));
+        assert!(content("usr/bin/x").is_some());
+        assert_eq!(content("usr/bin/y"), content("usr/bin/x"));
+        assert_eq!(content("usr/bin/z"), content("usr/bin/x"));
+        assert_eq!(content("usr/bin/dangling"), None);
+    }
+}
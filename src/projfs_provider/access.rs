@@ -0,0 +1,201 @@
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use windows::Win32::Storage::ProjectedFileSystem;
+use widestring::WideCStr;
+
+/// The process that caused ProjFS to invoke a callback
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TriggeringProcess {
+    pub id: u32,
+    pub image_file_name: PathBuf,
+}
+
+impl TriggeringProcess {
+    pub fn from_callback_data(callbackdata: *const ProjectedFileSystem::PRJ_CALLBACK_DATA) -> TriggeringProcess {
+        unsafe {
+            let image = (*callbackdata).TriggeringProcessImageFileName;
+            TriggeringProcess {
+                id: (*callbackdata).TriggeringProcessId,
+                // The image name is not always available, e.g. for requests coming from the system process
+                image_file_name: if image.is_null() {
+                    PathBuf::new()
+                } else {
+                    WideCStr::from_ptr_str(image.0).to_os_string().into()
+                },
+            }
+        }
+    }
+}
+
+/// The callbacks the runner can refuse on behalf of the provider
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operation {
+    Enumerate,
+    Placeholder,
+    Hydrate,
+}
+
+impl FromStr for Operation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "enumerate" => Ok(Operation::Enumerate),
+            "placeholder" => Ok(Operation::Placeholder),
+            "hydrate" => Ok(Operation::Hydrate),
+            _ => Err(format!("Unknown operation {s:?}")),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProcessMatch {
+    Any,
+    Id(u32),
+    /// Wildcard pattern matched against the image file name, or the whole image path if the pattern contains a '\'
+    Image(OsString),
+}
+
+impl ProcessMatch {
+    pub fn matches(&self, process: &TriggeringProcess) -> bool {
+        match self {
+            ProcessMatch::Any => true,
+            ProcessMatch::Id(id) => *id == process.id,
+            ProcessMatch::Image(pattern) => {
+                let name = if pattern.to_string_lossy().contains('\\') {
+                    process.image_file_name.as_os_str()
+                } else {
+                    match process.image_file_name.file_name() {
+                        Some(n) => n,
+                        None => {
+                            return false;
+                        }
+                    }
+                };
+                unsafe {
+                    ProjectedFileSystem::PrjFileNameMatch(name, pattern.as_os_str()) != windows::Win32::Foundation::BOOLEAN(0)
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for ProcessMatch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "*" {
+            Ok(ProcessMatch::Any)
+        } else if let Some(id) = s.strip_prefix("pid=") {
+            id.parse().map(ProcessMatch::Id).map_err(|e| format!("Invalid process id {id:?}: {e}"))
+        } else if s.is_empty() {
+            Err("Empty process pattern".to_string())
+        } else {
+            Ok(ProcessMatch::Image(s.into()))
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Allow,
+    Deny,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccessRule {
+    pub access: Access,
+    pub operations: Vec<Operation>,
+    pub process: ProcessMatch,
+}
+
+/// Parses `<allow|deny>:<operations>:<process>` where operations is a comma separated list or `*`,
+/// e.g. `deny:hydrate,enumerate:SearchProtocolHost.exe` or `allow:*:pid=1234`
+impl FromStr for AccessRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, ':');
+        let access = match parts.next() {
+            Some("allow") => Access::Allow,
+            Some("deny") => Access::Deny,
+            _ => {
+                return Err(format!("Access rule {s:?} must start with allow or deny"));
+            }
+        };
+        let operations = match parts.next() {
+            Some("*") => vec![Operation::Enumerate, Operation::Placeholder, Operation::Hydrate],
+            Some(ops) => ops.split(',').map(Operation::from_str).collect::<Result<Vec<_>, _>>()?,
+            None => {
+                return Err(format!("Access rule {s:?} is missing the operations"));
+            }
+        };
+        let process = match parts.next() {
+            Some(p) => p.parse()?,
+            None => {
+                return Err(format!("Access rule {s:?} is missing the process"));
+            }
+        };
+        Ok(AccessRule {
+            access,
+            operations,
+            process,
+        })
+    }
+}
+
+/// Ordered list of rules, the first rule matching both the operation and the process decides. Anything unmatched is allowed.
+#[derive(Clone, Debug, Default)]
+pub struct AccessControl {
+    pub rules: Vec<AccessRule>,
+}
+
+impl AccessControl {
+    pub fn is_allowed(&self, process: &TriggeringProcess, operation: Operation) -> bool {
+        for rule in &self.rules {
+            if rule.operations.contains(&operation) && rule.process.matches(process) {
+                return rule.access == Access::Allow;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_operations_and_process() {
+        let rule: AccessRule = "deny:hydrate,enumerate:SearchProtocolHost.exe".parse().unwrap();
+        assert_eq!(rule.access, Access::Deny);
+        assert_eq!(rule.operations, vec![Operation::Hydrate, Operation::Enumerate]);
+        assert_eq!(rule.process, ProcessMatch::Image("SearchProtocolHost.exe".into()));
+    }
+
+    #[test]
+    fn parses_wildcards_and_ids() {
+        let rule: AccessRule = "allow:*:pid=1234".parse().unwrap();
+        assert_eq!(rule.access, Access::Allow);
+        assert_eq!(rule.operations, vec![Operation::Enumerate, Operation::Placeholder, Operation::Hydrate]);
+        assert_eq!(rule.process, ProcessMatch::Id(1234));
+        assert_eq!("allow:hydrate:*".parse::<AccessRule>().unwrap().process, ProcessMatch::Any);
+    }
+
+    #[test]
+    fn keeps_colons_in_image_paths() {
+        let rule: AccessRule = "deny:hydrate:C:\\Tools\\indexer.exe".parse().unwrap();
+        assert_eq!(rule.process, ProcessMatch::Image("C:\\Tools\\indexer.exe".into()));
+    }
+
+    #[test]
+    fn rejects_malformed_rules() {
+        assert!("block:*:*".parse::<AccessRule>().is_err());
+        assert!("deny:read:*".parse::<AccessRule>().is_err());
+        assert!("deny:hydrate".parse::<AccessRule>().is_err());
+        assert!("deny:hydrate:".parse::<AccessRule>().is_err());
+        assert!("deny:hydrate:pid=abc".parse::<AccessRule>().is_err());
+    }
+}
@@ -0,0 +1,229 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::Serialize;
+use windows::Win32::Storage::ProjectedFileSystem;
+
+use super::access::TriggeringProcess;
+
+#[derive(Clone, Debug)]
+pub struct AuditOptions {
+    pub path: PathBuf,
+    /// The log is rotated to `<path>.1`, `<path>.2`, ... once it grows past this
+    pub max_file_size: u64,
+    /// Number of rotated files kept next to the live one
+    pub max_files: usize,
+}
+
+impl AuditOptions {
+    pub fn new(path: &Path) -> AuditOptions {
+        AuditOptions {
+            path: path.to_path_buf(),
+            max_file_size: 10*1024*1024,
+            max_files: 5,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEvent {
+    Hydrate,
+    Placeholder,
+    Notification,
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    timestamp_ms: u64,
+    event: AuditEvent,
+    path: &'a Path,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    notification: Option<&'a str>,
+    bytes: u64,
+    process_id: u32,
+    process_image: &'a Path,
+    result: i32,
+}
+
+struct AuditFile {
+    file: fs::File,
+    size: u64,
+}
+
+/// JSON Lines record of what was projected, for whom and how it went
+pub struct AuditLog {
+    options: AuditOptions,
+    file: Mutex<AuditFile>,
+}
+
+impl AuditLog {
+    pub fn open(options: AuditOptions) -> std::io::Result<AuditLog> {
+        let file = fs::OpenOptions::new().create(true).append(true).open(&options.path)?;
+        let size = file.metadata()?.len();
+        Ok(AuditLog {
+            options,
+            file: Mutex::new(AuditFile {
+                file,
+                size,
+            }),
+        })
+    }
+
+    pub fn record(&self, event: AuditEvent, file_path: &Path, notification: Option<ProjectedFileSystem::PRJ_NOTIFICATION>, bytes: u64, process: &TriggeringProcess, result: windows::core::HRESULT) {
+        let timestamp_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        let record = AuditRecord {
+            timestamp_ms,
+            event,
+            path: file_path,
+            notification: notification.map(notification_name),
+            bytes,
+            process_id: process.id,
+            process_image: &process.image_file_name,
+            result: result.0,
+        };
+        let mut line = match serde_json::to_vec(&record) {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Could not serialize audit record for {file_path:?}: {e}");
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        let mut audit_file = self.file.lock().unwrap();
+        if audit_file.size > 0 && audit_file.size + line.len() as u64 > self.options.max_file_size {
+            if let Err(e) = self.rotate(&mut audit_file) {
+                println!("Could not rotate audit log {:?}: {e}", self.options.path);
+            }
+        }
+        match audit_file.file.write_all(&line) {
+            Ok(()) => {
+                audit_file.size += line.len() as u64;
+            }
+            Err(e) => {
+                println!("Could not write audit log {:?}: {e}", self.options.path);
+            }
+        }
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut name = self.options.path.as_os_str().to_os_string();
+        name.push(format!(".{index}"));
+        name.into()
+    }
+
+    fn rotate(&self, audit_file: &mut AuditFile) -> std::io::Result<()> {
+        audit_file.file.flush()?;
+        if self.options.max_files == 0 {
+            audit_file.file = fs::File::create(&self.options.path)?;
+            audit_file.size = 0;
+            return Ok(());
+        }
+        // Shift <path>.N-1 -> <path>.N, dropping the oldest
+        for index in (1..self.options.max_files).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                fs::rename(&from, self.rotated_path(index + 1))?;
+            }
+        }
+        fs::rename(&self.options.path, self.rotated_path(1))?;
+        audit_file.file = fs::OpenOptions::new().create(true).append(true).open(&self.options.path)?;
+        audit_file.size = 0;
+        Ok(())
+    }
+}
+
+pub fn notification_name(notification: ProjectedFileSystem::PRJ_NOTIFICATION) -> &'static str {
+    match notification {
+        ProjectedFileSystem::PRJ_NOTIFICATION_FILE_OPENED => "file_opened",
+        ProjectedFileSystem::PRJ_NOTIFICATION_NEW_FILE_CREATED => "new_file_created",
+        ProjectedFileSystem::PRJ_NOTIFICATION_FILE_OVERWRITTEN => "file_overwritten",
+        ProjectedFileSystem::PRJ_NOTIFICATION_PRE_DELETE => "pre_delete",
+        ProjectedFileSystem::PRJ_NOTIFICATION_PRE_RENAME => "pre_rename",
+        ProjectedFileSystem::PRJ_NOTIFICATION_PRE_SET_HARDLINK => "pre_set_hardlink",
+        ProjectedFileSystem::PRJ_NOTIFICATION_FILE_RENAMED => "file_renamed",
+        ProjectedFileSystem::PRJ_NOTIFICATION_HARDLINK_CREATED => "hardlink_created",
+        ProjectedFileSystem::PRJ_NOTIFICATION_FILE_HANDLE_CLOSED_NO_MODIFICATION => "file_handle_closed_no_modification",
+        ProjectedFileSystem::PRJ_NOTIFICATION_FILE_HANDLE_CLOSED_FILE_MODIFIED => "file_handle_closed_file_modified",
+        ProjectedFileSystem::PRJ_NOTIFICATION_FILE_HANDLE_CLOSED_FILE_DELETED => "file_handle_closed_file_deleted",
+        ProjectedFileSystem::PRJ_NOTIFICATION_FILE_PRE_CONVERT_TO_FULL => "file_pre_convert_to_full",
+        _ => "unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory for the log and its rotated files, removed again when the test is done
+    struct LogDirectory(PathBuf);
+
+    impl Drop for LogDirectory {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn lines(path: &Path) -> Vec<serde_json::Value> {
+        fs::read_to_string(path).unwrap().lines().map(|l| serde_json::from_str(l).unwrap()).collect()
+    }
+
+    fn log_path(options: &AuditOptions, index: usize) -> PathBuf {
+        let mut name = options.path.clone().into_os_string();
+        name.push(format!(".{index}"));
+        name.into()
+    }
+
+    #[test]
+    fn rotates_past_the_size_and_keeps_the_newest_files() {
+        let directory = LogDirectory(std::env::temp_dir().join(format!("projfs-audit-{}", std::process::id())));
+        let _ = fs::remove_dir_all(&directory.0);
+        fs::create_dir_all(&directory.0).unwrap();
+        let options = AuditOptions {
+            max_file_size: 1000,
+            max_files: 2,
+            ..AuditOptions::new(&directory.0.join("audit.jsonl"))
+        };
+        let log = AuditLog::open(options.clone()).unwrap();
+        let process = TriggeringProcess {
+            id: 42,
+            image_file_name: PathBuf::from("C:/Windows/notepad.exe"),
+        };
+        for i in 0..40u64 {
+            let notification = (i % 2 == 1).then_some(ProjectedFileSystem::PRJ_NOTIFICATION_FILE_OPENED);
+            let event = if notification.is_some() { AuditEvent::Notification } else { AuditEvent::Hydrate };
+            log.record(event, Path::new(&format!("dir/file{i:02}.txt")), notification, i, &process, windows::Win32::Foundation::S_OK);
+        }
+        drop(log);
+
+        let files = [options.path.clone(), log_path(&options, 1), log_path(&options, 2)];
+        assert!(!log_path(&options, 3).exists());
+        let mut records = Vec::new();
+        for file in files.iter().rev() {
+            assert!(fs::metadata(file).unwrap().len() <= options.max_file_size);
+            records.extend(lines(file));
+        }
+        // Oldest first across the files, with the ones rotated out of the last file gone
+        let bytes: Vec<u64> = records.iter().map(|r| r["bytes"].as_u64().unwrap()).collect();
+        assert_eq!(*bytes.last().unwrap(), 39);
+        assert!(bytes.windows(2).all(|w| w[1] == w[0] + 1));
+        assert!(bytes[0] > 0);
+
+        let hydrate = records.iter().find(|r| r["event"] == "hydrate").unwrap().as_object().unwrap();
+        let mut keys: Vec<&str> = hydrate.keys().map(|k| k.as_str()).collect();
+        keys.sort();
+        assert_eq!(keys, ["bytes", "event", "path", "process_id", "process_image", "result", "timestamp_ms"]);
+        let notification = records.iter().find(|r| r["event"] == "notification").unwrap();
+        assert_eq!(notification["notification"], "file_opened");
+        assert_eq!(notification["process_id"], 42);
+        assert_eq!(notification["process_image"], "C:/Windows/notepad.exe");
+        assert_eq!(notification["result"], 0);
+        assert!(notification["path"].as_str().unwrap().starts_with("dir/file"));
+    }
+}
@@ -1,4 +1,5 @@
 
+use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
 
 use windows::Win32::Storage::ProjectedFileSystem;
@@ -12,10 +13,44 @@ pub enum MatchType {
     Wildcards(std::ffi::OsString),
 }
 
+/// Orders names the way ProjFS expects enumerations to be sorted
+pub fn compare_file_names(a: &OsStr, b: &OsStr) -> std::cmp::Ordering {
+    unsafe {
+        ProjectedFileSystem::PrjFileNameCompare(a, b).cmp(&0)
+    }
+}
+
+/// Wraps the ProjFS directory entry buffer so the runner can see and filter what providers enumerate
+pub struct DirEntryBuffer<'a> {
+    handle: ProjectedFileSystem::PRJ_DIR_ENTRY_BUFFER_HANDLE,
+    filter: &'a dyn Fn(&OsStr, &ProjectedFileSystem::PRJ_FILE_BASIC_INFO) -> bool,
+}
+
+impl<'a> DirEntryBuffer<'a> {
+    pub fn new(handle: ProjectedFileSystem::PRJ_DIR_ENTRY_BUFFER_HANDLE, filter: &'a dyn Fn(&OsStr, &ProjectedFileSystem::PRJ_FILE_BASIC_INFO) -> bool) -> DirEntryBuffer<'a> {
+        DirEntryBuffer {
+            handle,
+            filter,
+        }
+    }
+
+    /// Adds an entry to the buffer. Entries hidden by the runner are silently dropped.
+    /// An error means the buffer is full and the entry has to be sent again on the next call.
+    pub fn add(&mut self, name: &OsStr, file_info: &ProjectedFileSystem::PRJ_FILE_BASIC_INFO) -> windows::core::Result<()> {
+        if !(self.filter)(name, file_info) {
+            return Ok(());
+        }
+        unsafe {
+            // If symlinks are needed, use PrjFillDirEntryBuffer2
+            ProjectedFileSystem::PrjFillDirEntryBuffer(name, file_info, self.handle)
+        }
+    }
+}
+
 pub trait EnumerationState: Send + Sync {
     fn get_search(&self) -> Option<&MatchType>;
     fn set_search(&mut self, search: MatchType);
-    fn enumerate(&mut self, callbackdata: *const ProjectedFileSystem::PRJ_CALLBACK_DATA, direntrybufferhandle: ProjectedFileSystem::PRJ_DIR_ENTRY_BUFFER_HANDLE) -> windows::core::HRESULT;
+    fn enumerate(&mut self, callbackdata: *const ProjectedFileSystem::PRJ_CALLBACK_DATA, buffer: &mut DirEntryBuffer) -> windows::core::HRESULT;
     fn end(&mut self);
 }
 
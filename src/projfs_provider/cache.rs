@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use windows::Win32::Storage::ProjectedFileSystem;
+
+#[derive(Clone, Debug)]
+pub struct CacheOptions {
+    pub ttl: Duration,
+    pub negative_ttl: Duration,
+    /// Fill the cache from enumerated entries. Enumeration only carries the basic info, so
+    /// leave this off for providers that put content IDs or other VersionInfo into placeholders.
+    pub populate_from_enumeration: bool,
+    /// Expired entries are dropped once the cache grows past this, and everything if that is not enough
+    pub max_entries: usize,
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        CacheOptions {
+            ttl: Duration::from_secs(60),
+            negative_ttl: Duration::from_secs(5),
+            populate_from_enumeration: false,
+            max_entries: 100_000,
+        }
+    }
+}
+
+struct CacheEntry {
+    // None for paths the provider said don't exist
+    info: Option<ProjectedFileSystem::PRJ_PLACEHOLDER_INFO>,
+    expires: Instant,
+}
+
+/// Placeholder info and not-found results of the provider, keyed case insensitively like ProjFS paths
+pub struct PlaceholderCache {
+    options: CacheOptions,
+    entries: RwLock<HashMap<String, CacheEntry>>,
+}
+
+fn cache_key(file_path: &Path) -> String {
+    file_path.to_string_lossy().to_uppercase()
+}
+
+impl PlaceholderCache {
+    pub fn new(options: CacheOptions) -> PlaceholderCache {
+        PlaceholderCache {
+            options,
+            entries: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn populate_from_enumeration(&self) -> bool {
+        self.options.populate_from_enumeration
+    }
+
+    /// `Some(None)` means the path is known not to exist
+    pub fn get(&self, file_path: &Path) -> Option<Option<ProjectedFileSystem::PRJ_PLACEHOLDER_INFO>> {
+        let entries = self.entries.read().unwrap();
+        match entries.get(&cache_key(file_path)) {
+            Some(e) if e.expires > Instant::now() => Some(e.info),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&self, file_path: &Path, info: Option<ProjectedFileSystem::PRJ_PLACEHOLDER_INFO>) {
+        let ttl = if info.is_some() {
+            self.options.ttl
+        } else {
+            self.options.negative_ttl
+        };
+        let now = Instant::now();
+        let mut entries = self.entries.write().unwrap();
+        if entries.len() >= self.options.max_entries {
+            entries.retain(|_, e| e.expires > now);
+            if entries.len() >= self.options.max_entries {
+                entries.clear();
+            }
+        }
+        entries.insert(cache_key(file_path), CacheEntry {
+            info,
+            expires: now + ttl,
+        });
+    }
+
+    /// Drops the path and everything below it
+    pub fn invalidate(&self, file_path: &Path) {
+        let key = cache_key(file_path);
+        if key.is_empty() {
+            self.entries.write().unwrap().clear();
+            return;
+        }
+        let mut prefix = key.clone();
+        prefix.push('\\');
+        let mut entries = self.entries.write().unwrap();
+        entries.retain(|k, _| *k != key && !k.starts_with(&prefix));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::virtual_tree::EntryInfo;
+
+    fn cache(ttl: Duration, max_entries: usize) -> PlaceholderCache {
+        PlaceholderCache::new(CacheOptions {
+            ttl,
+            negative_ttl: ttl,
+            max_entries,
+            ..Default::default()
+        })
+    }
+
+    fn size(info: Option<Option<ProjectedFileSystem::PRJ_PLACEHOLDER_INFO>>) -> Option<Option<i64>> {
+        info.map(|i| i.map(|p| p.FileBasicInfo.FileSize))
+    }
+
+    #[test]
+    fn remembers_info_and_missing_paths_case_insensitively() {
+        let cache = cache(Duration::from_secs(60), 10);
+        cache.insert(Path::new("Dir\\File.txt"), Some(EntryInfo::file(5, 0).placeholder_info()));
+        cache.insert(Path::new("missing"), None);
+        assert_eq!(size(cache.get(Path::new("dir\\file.TXT"))), Some(Some(5)));
+        assert_eq!(size(cache.get(Path::new("MISSING"))), Some(None));
+        assert_eq!(size(cache.get(Path::new("other"))), None);
+    }
+
+    #[test]
+    fn expires_entries() {
+        let cache = cache(Duration::ZERO, 10);
+        cache.insert(Path::new("a"), None);
+        assert_eq!(size(cache.get(Path::new("a"))), None);
+    }
+
+    #[test]
+    fn invalidates_a_path_and_what_is_below_it() {
+        let cache = cache(Duration::from_secs(60), 10);
+        for path in ["dir", "dir\\a", "dir\\sub\\b", "directory", "other"] {
+            cache.insert(Path::new(path), None);
+        }
+        cache.invalidate(Path::new("DIR"));
+        for path in ["dir", "dir\\a", "dir\\sub\\b"] {
+            assert_eq!(size(cache.get(Path::new(path))), None, "{path}");
+        }
+        assert_eq!(size(cache.get(Path::new("directory"))), Some(None));
+        cache.invalidate(Path::new(""));
+        assert_eq!(size(cache.get(Path::new("other"))), None);
+    }
+
+    #[test]
+    fn stays_within_max_entries() {
+        let cache = cache(Duration::from_secs(60), 3);
+        for i in 0..10 {
+            cache.insert(Path::new(&i.to_string()), None);
+        }
+        assert!(cache.entries.read().unwrap().len() <= 3);
+        assert_eq!(size(cache.get(Path::new("9"))), Some(None));
+    }
+}
@@ -0,0 +1,345 @@
+use std::cmp::Ordering as NameOrdering;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::fmt::Write;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use windows::Win32::Storage::{FileSystem, ProjectedFileSystem};
+
+use super::audit::AuditEvent;
+use super::base::{compare_file_names, EnumerationState, SeekRead};
+use super::filetime;
+use super::list_enumeration::ListEnumeration;
+use super::virtual_tree::EntryInfo;
+
+pub const CONTROL_DIRECTORY: &str = ".projfs";
+
+const RECENT_ERRORS: usize = 100;
+const AUDIT_TAIL_LINES: usize = 100;
+/// Only the end of the audit log is read to find its last lines
+const AUDIT_TAIL_BYTES: u64 = 256 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum ControlFile {
+    Stats,
+    Enumerations,
+    Config,
+    Errors,
+    Audit,
+}
+
+impl ControlFile {
+    const ALL: [ControlFile; 5] = [ControlFile::Stats, ControlFile::Enumerations, ControlFile::Config, ControlFile::Errors, ControlFile::Audit];
+
+    fn name(self) -> &'static str {
+        match self {
+            ControlFile::Stats => "stats.txt",
+            ControlFile::Enumerations => "enumerations.txt",
+            ControlFile::Config => "config.txt",
+            ControlFile::Errors => "errors.txt",
+            ControlFile::Audit => "audit.jsonl",
+        }
+    }
+}
+
+struct RecentError {
+    time: SystemTime,
+    event: AuditEvent,
+    path: PathBuf,
+    result: windows::core::HRESULT,
+}
+
+#[derive(Default)]
+struct Counters {
+    enumerations: AtomicU64,
+    placeholders: AtomicU64,
+    hydrations: AtomicU64,
+    bytes_hydrated: AtomicU64,
+    notifications: AtomicU64,
+    not_found: AtomicU64,
+    denied: AtomicU64,
+    failed: AtomicU64,
+}
+
+/// The hidden `.projfs` directory the runner answers for itself. It isn't listed in the
+/// root, but can be opened by name. Its files are written out when they're read and
+/// dropped from disk again once closed, so the next read shows the current state.
+pub struct ControlDirectory {
+    started: Instant,
+    start_time: i64,
+    config: String,
+    audit_path: Option<PathBuf>,
+    counters: Counters,
+    open_enumerations: Mutex<HashMap<windows::core::GUID, (PathBuf, Instant)>>,
+    errors: Mutex<VecDeque<RecentError>>,
+    // What the placeholder of each file was sized by, so hydration writes the same bytes
+    renderings: Mutex<HashMap<ControlFile, Arc<[u8]>>>,
+}
+
+fn timestamp(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+    let (year, month, day, hour, minute, second) = filetime::civil_from_unix_seconds(seconds);
+    format!("{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}Z")
+}
+
+/// Last lines of a file, reading no more than its end
+fn tail(path: &Path, lines: usize) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let length = file.metadata()?.len();
+    let start = length.saturating_sub(AUDIT_TAIL_BYTES);
+    file.seek(SeekFrom::Start(start))?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    let text = String::from_utf8_lossy(&data);
+    let mut all: Vec<&str> = text.lines().collect();
+    // Starting in the middle of the file most likely means starting in the middle of a line
+    if start > 0 && !all.is_empty() {
+        all.remove(0);
+    }
+    let kept = &all[all.len().saturating_sub(lines)..];
+    Ok(kept.iter().map(|l| format!("{l}\n")).collect())
+}
+
+impl ControlDirectory {
+    /// `config` is shown as is in config.txt
+    pub fn new(config: String, audit_path: Option<PathBuf>) -> ControlDirectory {
+        ControlDirectory {
+            started: Instant::now(),
+            start_time: filetime::now(),
+            config,
+            audit_path,
+            counters: Counters::default(),
+            open_enumerations: Mutex::new(HashMap::new()),
+            errors: Mutex::new(VecDeque::new()),
+            renderings: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `file_path` is the control directory or something in it
+    pub fn contains(&self, file_path: &Path) -> bool {
+        match file_path.components().next() {
+            Some(Component::Normal(name)) => compare_file_names(name, OsStr::new(CONTROL_DIRECTORY)) == NameOrdering::Equal,
+            _ => false,
+        }
+    }
+
+    fn files(&self) -> impl Iterator<Item = ControlFile> + '_ {
+        ControlFile::ALL.into_iter().filter(|f| *f != ControlFile::Audit || self.audit_path.is_some())
+    }
+
+    /// None for the directory itself, Err for paths that aren't there
+    fn resolve(&self, file_path: &Path) -> Result<Option<ControlFile>, windows::core::HRESULT> {
+        let mut components = file_path.components().skip(1);
+        match (components.next(), components.next()) {
+            (None, _) => Ok(None),
+            (Some(Component::Normal(name)), None) => self
+                .files()
+                .find(|f| compare_file_names(OsStr::new(f.name()), name) == NameOrdering::Equal)
+                .map(Some)
+                .ok_or_else(|| windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into()),
+            _ => Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into()),
+        }
+    }
+
+    pub fn enumeration_started(&self, id: windows::core::GUID, file_path: &Path) {
+        self.counters.enumerations.fetch_add(1, Ordering::Relaxed);
+        self.open_enumerations.lock().unwrap().insert(id, (file_path.to_path_buf(), Instant::now()));
+    }
+
+    pub fn enumeration_ended(&self, id: windows::core::GUID) {
+        self.open_enumerations.lock().unwrap().remove(&id);
+    }
+
+    /// Counts what the runner audits, whether or not there is an audit log
+    pub fn record(&self, event: AuditEvent, file_path: &Path, bytes: u64, result: windows::core::HRESULT) {
+        let counter = match event {
+            AuditEvent::Placeholder => &self.counters.placeholders,
+            AuditEvent::Hydrate => &self.counters.hydrations,
+            AuditEvent::Notification => &self.counters.notifications,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.counters.bytes_hydrated.fetch_add(bytes, Ordering::Relaxed);
+        if result.is_ok() {
+            return;
+        }
+        // Not found is how every probe for a name that isn't there ends, it's not worth listing
+        if result == windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into() {
+            self.counters.not_found.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        if result == windows::Win32::Foundation::ERROR_ACCESS_DENIED.into() {
+            self.counters.denied.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.counters.failed.fetch_add(1, Ordering::Relaxed);
+        }
+        let mut errors = self.errors.lock().unwrap();
+        if errors.len() >= RECENT_ERRORS {
+            errors.pop_front();
+        }
+        errors.push_back(RecentError {
+            time: SystemTime::now(),
+            event,
+            path: file_path.to_path_buf(),
+            result,
+        });
+    }
+
+    fn render(&self, file: ControlFile) -> String {
+        let mut text = String::new();
+        match file {
+            ControlFile::Stats => {
+                let counter = |c: &AtomicU64| c.load(Ordering::Relaxed);
+                let c = &self.counters;
+                let _ = writeln!(text, "uptime_seconds: {}", self.started.elapsed().as_secs());
+                let _ = writeln!(text, "enumerations: {}", counter(&c.enumerations));
+                let _ = writeln!(text, "open_enumerations: {}", self.open_enumerations.lock().unwrap().len());
+                let _ = writeln!(text, "placeholders: {}", counter(&c.placeholders));
+                let _ = writeln!(text, "hydrations: {}", counter(&c.hydrations));
+                let _ = writeln!(text, "bytes_hydrated: {}", counter(&c.bytes_hydrated));
+                let _ = writeln!(text, "notifications: {}", counter(&c.notifications));
+                let _ = writeln!(text, "not_found: {}", counter(&c.not_found));
+                let _ = writeln!(text, "denied: {}", counter(&c.denied));
+                let _ = writeln!(text, "failed: {}", counter(&c.failed));
+            }
+            ControlFile::Enumerations => {
+                let open = self.open_enumerations.lock().unwrap();
+                let mut open: Vec<_> = open.iter().collect();
+                open.sort_by_key(|(_, (_, started))| *started);
+                for (id, (path, started)) in open {
+                    let _ = writeln!(text, "{id:?} {path:?} {}ms", started.elapsed().as_millis());
+                }
+            }
+            ControlFile::Config => text = self.config.clone(),
+            ControlFile::Errors => {
+                for error in self.errors.lock().unwrap().iter() {
+                    let _ = writeln!(text, "{} {:?} {:?} {:#010x}", timestamp(error.time), error.event, error.path, error.result.0);
+                }
+            }
+            ControlFile::Audit => {
+                if let Some(path) = &self.audit_path {
+                    text = match tail(path, AUDIT_TAIL_LINES) {
+                        Ok(t) => t,
+                        Err(e) => format!("Could not read {path:?}: {e}\n"),
+                    };
+                }
+            }
+        }
+        text
+    }
+
+    /// Renders the file anew and keeps it for hydrating, only placeholders may replace what a
+    /// hydration is going to write
+    fn rendered(&self, file: ControlFile) -> Arc<[u8]> {
+        let data: Arc<[u8]> = self.render(file).into_bytes().into();
+        self.renderings.lock().unwrap().insert(file, data.clone());
+        data
+    }
+
+    /// Size of the kept rendering, or of a new one that isn't kept
+    fn listed_size(&self, file: ControlFile) -> u64 {
+        match self.renderings.lock().unwrap().get(&file) {
+            Some(kept) => kept.len() as u64,
+            None => self.render(file).len() as u64,
+        }
+    }
+
+    fn directory_info(&self) -> EntryInfo {
+        let mut info = EntryInfo::directory(self.start_time);
+        info.attributes = FileSystem::FILE_ATTRIBUTE_HIDDEN.0;
+        info
+    }
+
+    pub fn enumeration(&self, file_path: &Path) -> Box<dyn EnumerationState> {
+        let entries = match self.resolve(file_path) {
+            Ok(None) => {
+                let now = filetime::now();
+                self.files()
+                    .map(|f| (OsString::from(f.name()), EntryInfo::file(self.listed_size(f), now).basic_info()))
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+        Box::new(ListEnumeration::sorted(entries))
+    }
+
+    pub fn placeholder_info(&self, file_path: &Path) -> Result<ProjectedFileSystem::PRJ_PLACEHOLDER_INFO, windows::core::HRESULT> {
+        Ok(match self.resolve(file_path)? {
+            None => self.directory_info().placeholder_info(),
+            Some(file) => EntryInfo::file(self.rendered(file).len() as u64, filetime::now()).placeholder_info(),
+        })
+    }
+
+    pub fn file_data(&self, file_path: &Path) -> Result<Box<dyn SeekRead>, windows::core::HRESULT> {
+        let file = match self.resolve(file_path)? {
+            Some(file) => file,
+            None => {
+                return Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into());
+            }
+        };
+        let kept = self.renderings.lock().unwrap().get(&file).cloned();
+        let data = kept.unwrap_or_else(|| self.rendered(file));
+        Ok(Box::new(std::io::Cursor::new(data)))
+    }
+
+    pub fn query_file_name(&self, file_path: &Path) -> windows::core::HRESULT {
+        match self.resolve(file_path) {
+            Ok(_) => windows::Win32::Foundation::S_OK,
+            Err(e) => e,
+        }
+    }
+
+    /// Closed files are deleted from disk, so the next read asks for a fresh placeholder
+    pub fn notification(&self, callbackdata: *const ProjectedFileSystem::PRJ_CALLBACK_DATA, is_directory: bool, notification: ProjectedFileSystem::PRJ_NOTIFICATION) -> windows::core::HRESULT {
+        if notification == ProjectedFileSystem::PRJ_NOTIFICATION_FILE_HANDLE_CLOSED_NO_MODIFICATION && !is_directory {
+            let update_flags = ProjectedFileSystem::PRJ_UPDATE_ALLOW_DIRTY_DATA | ProjectedFileSystem::PRJ_UPDATE_ALLOW_DIRTY_METADATA;
+            // From within the notification callback, as ZerosProvider drops its closed files
+            unsafe {
+                if let Err(e) = ProjectedFileSystem::PrjDeleteFile((*callbackdata).NamespaceVirtualizationContext, (*callbackdata).FilePathName, update_flags) {
+                    println!("Could not drop a control file: {e:?}");
+                }
+            }
+        }
+        windows::Win32::Foundation::S_OK
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_control_paths() {
+        let control = ControlDirectory::new(String::new(), None);
+        assert!(control.contains(Path::new(".PROJFS/stats.txt")));
+        assert!(!control.contains(Path::new("data/.projfs")));
+        assert_eq!(control.resolve(Path::new(".projfs")), Ok(None));
+        assert_eq!(control.resolve(Path::new(".projfs/Stats.TXT")), Ok(Some(ControlFile::Stats)));
+        let not_found = Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into());
+        assert_eq!(control.resolve(Path::new(".projfs/other.txt")), not_found);
+        assert_eq!(control.resolve(Path::new(".projfs/stats.txt/more")), not_found);
+        // The audit tail is only there with an audit log
+        assert_eq!(control.resolve(Path::new(".projfs/audit.jsonl")), not_found);
+        let audited = ControlDirectory::new(String::new(), Some(PathBuf::from("audit.jsonl")));
+        assert_eq!(audited.resolve(Path::new(".projfs/audit.jsonl")), Ok(Some(ControlFile::Audit)));
+    }
+
+    #[test]
+    fn tails_the_end_of_a_file() {
+        let path = std::env::temp_dir().join(format!("projfs-control-{}-tail", std::process::id()));
+        std::fs::write(&path, "one\ntwo\nthree\n").unwrap();
+        assert_eq!(tail(&path, 2).unwrap(), "two\nthree\n");
+        assert_eq!(tail(&path, 10).unwrap(), "one\ntwo\nthree\n");
+        // Only the end is read, the line it starts in the middle of is left out
+        let line = "x".repeat(99);
+        let lines = (AUDIT_TAIL_BYTES / 100 + 10) as usize;
+        std::fs::write(&path, format!("{line}\n").repeat(lines)).unwrap();
+        let tailed = tail(&path, usize::MAX).unwrap();
+        assert!(tailed.lines().all(|l| l == line));
+        assert_eq!(tailed.lines().count(), (AUDIT_TAIL_BYTES / 100) as usize);
+        let _ = std::fs::remove_file(&path);
+    }
+}
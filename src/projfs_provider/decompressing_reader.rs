@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::{Arc, Mutex};
+
+/// Decoders kept between readers, one per stream at most
+const MAX_PARKED_DECODERS: usize = 16;
+
+/// Decompressed blocks shared by the readers of a provider, so reading at an offset that was
+/// read before doesn't mean decompressing everything in front of it again. Readers that are done
+/// leave their decoder here, the next reader of the stream goes on from where it stopped.
+pub struct BlockCache {
+    block_size: u64,
+    capacity: usize,
+    blocks: Mutex<CachedBlocks>,
+    decoders: Mutex<HashMap<u64, ParkedDecoder>>,
+}
+
+struct ParkedDecoder {
+    decoder: Box<dyn Read + Send>,
+    position: u64,
+    parked: std::time::Instant,
+}
+
+struct CachedBlock {
+    data: Arc<[u8]>,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct CachedBlocks {
+    // Keyed by stream and block index
+    blocks: HashMap<(u64, u64), CachedBlock>,
+    clock: u64,
+}
+
+impl BlockCache {
+    /// Keeps up to `capacity` blocks of `block_size` bytes
+    pub fn new(block_size: u64, capacity: usize) -> BlockCache {
+        BlockCache {
+            block_size,
+            capacity,
+            blocks: Mutex::new(CachedBlocks::default()),
+            decoders: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// For formats that compress blocks on their own, where there's no stream to decode
+    pub fn get(&self, stream: u64, block: u64) -> Option<Arc<[u8]>> {
+        let mut cached = self.blocks.lock().unwrap();
+        cached.clock += 1;
+        let clock = cached.clock;
+        cached.blocks.get_mut(&(stream, block)).map(|b| {
+            b.last_used = clock;
+            b.data.clone()
+        })
+    }
+
+    pub fn insert(&self, stream: u64, block: u64, data: Arc<[u8]>) {
+        let mut cached = self.blocks.lock().unwrap();
+        if cached.blocks.len() >= self.capacity {
+            // Small enough that finding the least recently used one by walking is fine
+            if let Some(oldest) = cached.blocks.iter().min_by_key(|(_, b)| b.last_used).map(|(k, _)| *k) {
+                cached.blocks.remove(&oldest);
+            }
+        }
+        cached.clock += 1;
+        let clock = cached.clock;
+        cached.blocks.insert((stream, block), CachedBlock {
+            data,
+            last_used: clock,
+        });
+    }
+
+    /// The decoder a reader left for the stream, if it isn't past `before`
+    fn take_decoder(&self, stream: u64, before: u64) -> Option<(Box<dyn Read + Send>, u64)> {
+        let mut decoders = self.decoders.lock().unwrap();
+        match decoders.get(&stream) {
+            Some(parked) if parked.position <= before => decoders.remove(&stream).map(|p| (p.decoder, p.position)),
+            _ => None,
+        }
+    }
+
+    fn park_decoder(&self, stream: u64, decoder: Box<dyn Read + Send>, position: u64) {
+        let mut decoders = self.decoders.lock().unwrap();
+        decoders.insert(stream, ParkedDecoder {
+            decoder,
+            position,
+            parked: std::time::Instant::now(),
+        });
+        if decoders.len() > MAX_PARKED_DECODERS {
+            if let Some(oldest) = decoders.iter().min_by_key(|(_, d)| d.parked).map(|(k, _)| *k) {
+                decoders.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Opens the compressed stream again from its start
+pub type OpenStream = Box<dyn Fn() -> std::io::Result<Box<dyn Read + Send>>>;
+
+/// Seekable view of a stream that can only be decompressed front to back. Blocks that aren't in the
+/// cache are decompressed by going forward, or by starting over if they are behind the decoder.
+/// `stream` tells the streams in the cache apart and has to be unique for the cache.
+pub struct DecompressingReader {
+    cache: Arc<BlockCache>,
+    stream: u64,
+    length: u64,
+    open: OpenStream,
+    // The decoder and the offset it is at, always the start of a block
+    decoder: Option<(Box<dyn Read + Send>, u64)>,
+    current: u64,
+}
+
+impl DecompressingReader {
+    pub fn new(cache: Arc<BlockCache>, stream: u64, length: u64, open: OpenStream) -> DecompressingReader {
+        DecompressingReader {
+            cache,
+            stream,
+            length,
+            open,
+            decoder: None,
+            current: 0,
+        }
+    }
+
+    fn block(&mut self, block: u64) -> std::io::Result<Arc<[u8]>> {
+        if let Some(data) = self.cache.get(self.stream, block) {
+            return Ok(data);
+        }
+        let block_start = block * self.cache.block_size;
+        let resumed = match self.decoder.take() {
+            Some((d, p)) if p <= block_start => Some((d, p)),
+            _ => self.cache.take_decoder(self.stream, block_start),
+        };
+        let (mut decoder, mut position) = match resumed {
+            Some(v) => v,
+            None => ((self.open)()?, 0),
+        };
+        loop {
+            let size = std::cmp::min(self.cache.block_size, self.length - position) as usize;
+            let mut data = vec![0u8; size];
+            decoder.read_exact(&mut data)?;
+            let data: Arc<[u8]> = data.into();
+            self.cache.insert(self.stream, position / self.cache.block_size, data.clone());
+            position += size as u64;
+            if position > block_start {
+                self.decoder = Some((decoder, position));
+                return Ok(data);
+            }
+        }
+    }
+}
+
+impl Read for DecompressingReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.current >= self.length || buf.is_empty() {
+            return Ok(0);
+        }
+        let data = self.block(self.current / self.cache.block_size)?;
+        let in_block = (self.current % self.cache.block_size) as usize;
+        let read_len = std::cmp::min(buf.len(), data.len() - in_block);
+        buf[..read_len].copy_from_slice(&data[in_block..in_block + read_len]);
+        self.current += read_len as u64;
+
+        Ok(read_len)
+    }
+}
+
+impl Drop for DecompressingReader {
+    fn drop(&mut self) {
+        if let Some((decoder, position)) = self.decoder.take() {
+            self.cache.park_decoder(self.stream, decoder, position);
+        }
+    }
+}
+
+impl Seek for DecompressingReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(v) => Some(v),
+            SeekFrom::End(v) => self.length.checked_add_signed(v),
+            SeekFrom::Current(v) => self.current.checked_add_signed(v),
+        };
+        match new_position {
+            Some(v) => {
+                self.current = v;
+                Ok(self.current)
+            }
+            None => Err(std::io::Error::from(std::io::ErrorKind::InvalidInput)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    /// Plain data standing in for a compressed stream, counting how often it's opened
+    fn reader(cache: &Arc<BlockCache>, data: &Arc<Vec<u8>>, opened: &Arc<AtomicUsize>) -> DecompressingReader {
+        let length = data.len() as u64;
+        let (data, opened) = (data.clone(), opened.clone());
+        let open = move || -> std::io::Result<Box<dyn Read + Send>> {
+            opened.fetch_add(1, Ordering::SeqCst);
+            Ok(Box::new(std::io::Cursor::new(data.to_vec())))
+        };
+        DecompressingReader::new(cache.clone(), 7, length, Box::new(open))
+    }
+
+    fn read_at(reader: &mut DecompressingReader, offset: u64, length: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; length];
+        reader.seek(SeekFrom::Start(offset)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    fn data() -> Arc<Vec<u8>> {
+        Arc::new((0..10 * 1024).map(|i| (i % 251) as u8).collect())
+    }
+
+    #[test]
+    fn reads_across_blocks() {
+        let (data, opened) = (data(), Arc::new(AtomicUsize::new(0)));
+        let cache = Arc::new(BlockCache::new(1024, 4));
+        let mut reader = reader(&cache, &data, &opened);
+        assert_eq!(read_at(&mut reader, 1000, 3000), data[1000..4000]);
+        assert_eq!(read_at(&mut reader, 9000, 1240), data[9000..]);
+        // The first blocks fell out of the cache, going back starts over
+        assert_eq!(read_at(&mut reader, 0, 10), data[..10]);
+        assert_eq!(opened.load(Ordering::SeqCst), 2);
+        let mut rest = Vec::new();
+        reader.seek(SeekFrom::End(-5)).unwrap();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, data[data.len() - 5..]);
+    }
+
+    #[test]
+    fn later_readers_go_on_where_earlier_ones_stopped() {
+        let (data, opened) = (data(), Arc::new(AtomicUsize::new(0)));
+        let cache = Arc::new(BlockCache::new(1024, 2));
+        // Like hydration, every chunk gets its own reader
+        for chunk in 0..10 {
+            let mut reader = reader(&cache, &data, &opened);
+            assert_eq!(read_at(&mut reader, chunk * 1024, 1024), data[chunk as usize * 1024..][..1024]);
+        }
+        assert_eq!(opened.load(Ordering::SeqCst), 1);
+        // The parked decoder is past the start, a reader there opens the stream again
+        let mut reader = reader(&cache, &data, &opened);
+        assert_eq!(read_at(&mut reader, 0, 10), data[..10]);
+        assert_eq!(opened.load(Ordering::SeqCst), 2);
+    }
+}
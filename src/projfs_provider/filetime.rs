@@ -0,0 +1,49 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Seconds between the FILETIME epoch (1601-01-01) and the Unix epoch
+const UNIX_EPOCH_OFFSET: i64 = 11_644_473_600;
+/// FILETIMEs count 100ns intervals
+const TICKS_PER_SECOND: i64 = 10_000_000;
+
+/// Times too far out for a FILETIME, which only broken files have, end up at its limits
+pub fn from_unix_seconds(seconds: i64) -> i64 {
+    seconds.saturating_add(UNIX_EPOCH_OFFSET).saturating_mul(TICKS_PER_SECOND)
+}
+
+/// Unix seconds of a date and time in UTC, for formats that store them broken down
+pub fn unix_seconds_from_civil(year: i64, month: i64, day: i64, hour: i64, minute: i64, second: i64) -> i64 {
+    // Days from civil, shifted so the year starts in March and leap days come last
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+    days * 86400 + hour * 3600 + minute * 60 + second
+}
+
+/// Date and time in UTC of Unix seconds, the other way around from `unix_seconds_from_civil`
+pub fn civil_from_unix_seconds(seconds: i64) -> (i64, i64, i64, i64, i64, i64) {
+    let days = seconds.div_euclid(86400) + 719468;
+    let time = seconds.rem_euclid(86400);
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day, time / 3600, time % 3600 / 60, time % 60)
+}
+
+pub fn from_system_time(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => from_unix_seconds(0) + (d.as_nanos() / 100) as i64,
+        Err(e) => from_unix_seconds(0) - (e.duration().as_nanos() / 100) as i64,
+    }
+}
+
+pub fn now() -> i64 {
+    from_system_time(SystemTime::now())
+}
@@ -0,0 +1,83 @@
+use std::ffi::OsString;
+
+use windows::Win32::Storage::ProjectedFileSystem;
+
+use super::base::{compare_file_names, DirEntryBuffer, EnumerationState, MatchType};
+
+/// Enumerates a snapshot of a directory taken when the enumeration started, so changes
+/// to the source can't shift entries around in the middle of a listing
+pub struct ListEnumeration {
+    entries: Vec<(OsString, ProjectedFileSystem::PRJ_FILE_BASIC_INFO)>,
+    next_index: usize,
+    search: Option<MatchType>,
+}
+
+impl ListEnumeration {
+    /// The entries have to be in ProjFS order already, see `compare_file_names`
+    pub fn new(entries: Vec<(OsString, ProjectedFileSystem::PRJ_FILE_BASIC_INFO)>) -> ListEnumeration {
+        ListEnumeration {
+            entries,
+            next_index: 0,
+            search: None,
+        }
+    }
+
+    /// For entries in whatever order the source produced them
+    pub fn sorted(mut entries: Vec<(OsString, ProjectedFileSystem::PRJ_FILE_BASIC_INFO)>) -> ListEnumeration {
+        entries.sort_by(|a, b| compare_file_names(&a.0, &b.0));
+        ListEnumeration::new(entries)
+    }
+}
+
+impl EnumerationState for ListEnumeration {
+    fn get_search(&self) -> Option<&MatchType> {
+        self.search.as_ref()
+    }
+
+    fn set_search(&mut self, search: MatchType) {
+        self.search = Some(search);
+        // Setting the search again means the scan restarts
+        self.next_index = 0;
+    }
+
+    fn enumerate(&mut self, _callbackdata: *const ProjectedFileSystem::PRJ_CALLBACK_DATA, buffer: &mut DirEntryBuffer) -> windows::core::HRESULT {
+        let search = match &self.search {
+            Some(s) => s,
+            None => {
+                return windows::Win32::Foundation::E_INVALIDARG;
+            }
+        };
+        while let Some((name, file_info)) = self.entries.get(self.next_index) {
+            match search {
+                MatchType::All => {},
+                MatchType::Exact(s) => {
+                    if compare_file_names(s, name) != std::cmp::Ordering::Equal {
+                        self.next_index += 1;
+                        continue;
+                    }
+                },
+                MatchType::Wildcards(w) => {
+                    unsafe {
+                        if ProjectedFileSystem::PrjFileNameMatch(name.as_os_str(), w.as_os_str()) == windows::Win32::Foundation::BOOLEAN(0) {
+                            self.next_index += 1;
+                            continue;
+                        }
+                    }
+                },
+            }
+
+            // If the buffer fills up we stop here and send this entry again on the next call
+            if buffer.add(name, file_info).is_err() {
+                return windows::Win32::Foundation::S_OK;
+            }
+            self.next_index += 1;
+        }
+
+        windows::Win32::Foundation::S_OK
+    }
+
+    fn end(&mut self) {
+        self.next_index = 0;
+        self.search = None;
+    }
+}
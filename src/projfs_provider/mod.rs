@@ -1,5 +1,25 @@
+mod access;
+mod audit;
 mod base;
+mod cache;
+mod control;
+mod decompressing_reader;
+pub mod filetime;
+mod list_enumeration;
+mod prefetch;
 mod runner;
+mod virtual_tree;
+mod visibility;
+mod window_reader;
 
-pub use base::{ProjFSProvider, EnumerationState, MatchType, SeekRead, VirtualizationOptions, NotificationMapping, FILE_TRANSFER_CHUNK_SIZE};
-pub use runner::ProjFSRunner;
\ No newline at end of file
+pub use access::AccessRule;
+pub use audit::AuditOptions;
+pub use base::{compare_file_names, ProjFSProvider, EnumerationState, MatchType, SeekRead, VirtualizationOptions, NotificationMapping, FILE_TRANSFER_CHUNK_SIZE};
+pub use cache::CacheOptions;
+pub use decompressing_reader::{BlockCache, DecompressingReader};
+pub use list_enumeration::ListEnumeration;
+pub use prefetch::PrefetchOptions;
+pub use runner::{ProjFSRunner, RunnerOptions, invalidate_cache};
+pub use virtual_tree::{VirtualTree, EntryInfo};
+pub use visibility::VisibilityRule;
+pub use window_reader::WindowReader;
\ No newline at end of file
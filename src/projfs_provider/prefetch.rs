@@ -0,0 +1,342 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use super::access::TriggeringProcess;
+
+#[derive(Clone, Debug)]
+pub struct PrefetchOptions {
+    /// Learned model, imported on start if it exists and exported on stop
+    pub model: Option<PathBuf>,
+    /// A transition has to be seen this many times before it is used for predictions
+    pub min_count: u32,
+    /// Number of files predicted after each hydration
+    pub max_predictions: usize,
+    /// Transitions remembered at most. Past this every count is halved, which forgets the
+    /// ones seen only once and lets newer habits outweigh old ones.
+    pub max_transitions: usize,
+    /// Bytes the background reader may hydrate per `budget_interval`
+    pub budget_bytes: u64,
+    pub budget_interval: Duration,
+}
+
+impl Default for PrefetchOptions {
+    fn default() -> Self {
+        PrefetchOptions {
+            model: None,
+            min_count: 2,
+            max_predictions: 4,
+            max_transitions: 65_536,
+            budget_bytes: 256*1024*1024,
+            budget_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Transition {
+    from: PathBuf,
+    to: PathBuf,
+    count: u32,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct ModelFile {
+    transitions: Vec<Transition>,
+}
+
+/// How often each file was read after another one
+#[derive(Default)]
+struct Transitions {
+    next: HashMap<PathBuf, HashMap<PathBuf, u32>>,
+    /// Number of (from, to) pairs in `next`
+    count: usize,
+}
+
+impl Transitions {
+    fn add(&mut self, from: PathBuf, to: PathBuf, count: u32, max_transitions: usize) {
+        let seen = self.next.entry(from).or_default().entry(to).or_insert_with(|| {
+            self.count += 1;
+            0
+        });
+        *seen = seen.saturating_add(count);
+        while self.count > max_transitions {
+            self.decay();
+        }
+    }
+
+    /// Halves every count and drops the transitions that reach zero
+    fn decay(&mut self) {
+        for next in self.next.values_mut() {
+            next.retain(|_, count| {
+                *count /= 2;
+                *count > 0
+            });
+        }
+        self.next.retain(|_, next| !next.is_empty());
+        self.count = self.next.values().map(|n| n.len()).sum();
+    }
+}
+
+/// Learns which file a process usually reads after another one and hydrates the likely
+/// next files in the background by reading them through the virtualization root, which
+/// goes through the regular get_file_data path.
+pub struct Prefetcher {
+    options: PrefetchOptions,
+    transitions: RwLock<Transitions>,
+    last_hydrated: Mutex<HashMap<u32, PathBuf>>,
+    /// When each file was queued, it's queued again once a budget interval has passed
+    prefetched: Arc<Mutex<HashMap<PathBuf, Instant>>>,
+    queue: Mutex<Option<mpsc::Sender<PathBuf>>>,
+    stopping: Arc<AtomicBool>,
+    worker: Mutex<Option<std::thread::JoinHandle<()>>>,
+}
+
+impl Prefetcher {
+    pub fn new(options: PrefetchOptions) -> Result<Prefetcher, Box<dyn std::error::Error>> {
+        let prefetcher = Prefetcher {
+            options,
+            transitions: RwLock::new(Transitions::default()),
+            last_hydrated: Mutex::new(HashMap::new()),
+            prefetched: Arc::new(Mutex::new(HashMap::new())),
+            queue: Mutex::new(None),
+            stopping: Arc::new(AtomicBool::new(false)),
+            worker: Mutex::new(None),
+        };
+        if let Some(model) = &prefetcher.options.model {
+            if model.exists() {
+                prefetcher.import(model)?;
+            }
+        }
+        Ok(prefetcher)
+    }
+
+    /// `projected_size` tells how large the provider says a file is. It's asked before
+    /// anything is opened, since opening a file in the root already creates its placeholder.
+    pub fn start(&self, root: &Path, projected_size: impl Fn(&Path) -> Option<u64> + Send + 'static) {
+        let (tx, rx) = mpsc::channel::<PathBuf>();
+        let root = root.to_path_buf();
+        let budget_bytes = self.options.budget_bytes;
+        let budget_interval = self.options.budget_interval;
+        let prefetched = self.prefetched.clone();
+        let stopping = self.stopping.clone();
+        let worker = std::thread::spawn(move || {
+            let mut window_start = Instant::now();
+            let mut window_used = 0u64;
+            for file_path in rx {
+                // Whatever is still queued on stop is dropped
+                if stopping.load(Ordering::Relaxed) {
+                    break;
+                }
+                if window_start.elapsed() >= budget_interval {
+                    window_start = Instant::now();
+                    window_used = 0;
+                }
+                let size = match projected_size(&file_path) {
+                    Some(s) => s,
+                    None => {
+                        prefetched.lock().unwrap().remove(&file_path);
+                        continue;
+                    }
+                };
+                if window_used + size > budget_bytes {
+                    println!("Skipping prefetch of {file_path:?}, budget used up");
+                    // So the next prediction of it gets another chance
+                    prefetched.lock().unwrap().remove(&file_path);
+                    continue;
+                }
+                window_used += size;
+                println!("Prefetching {file_path:?}");
+                let read = fs::File::open(root.join(&file_path)).and_then(|mut f| std::io::copy(&mut f, &mut std::io::sink()));
+                if let Err(e) = read {
+                    println!("Prefetch of {file_path:?} failed: {e}");
+                    prefetched.lock().unwrap().remove(&file_path);
+                }
+            }
+        });
+        *self.queue.lock().unwrap() = Some(tx);
+        *self.worker.lock().unwrap() = Some(worker);
+    }
+
+    /// Stops the background reader. This must not be called while holding anything the
+    /// ProjFS callbacks need, since an in-flight read waits on them.
+    pub fn stop(&self) -> Result<(), Box<dyn std::error::Error>> {
+        // Dropping the sender ends the worker loop, the flag skips what's left in the queue
+        self.stopping.store(true, Ordering::Relaxed);
+        self.queue.lock().unwrap().take();
+        if let Some(worker) = self.worker.lock().unwrap().take() {
+            if worker.join().is_err() {
+                return Err("Prefetch worker panicked".into());
+            }
+        }
+        if let Some(model) = &self.options.model {
+            self.export(model)?;
+        }
+        Ok(())
+    }
+
+    /// Records a hydration and queues the files that usually follow it
+    pub fn record(&self, process: &TriggeringProcess, file_path: &Path) {
+        // Our own prefetch reads say nothing about access patterns
+        if process.id == std::process::id() {
+            return;
+        }
+        let previous = {
+            let mut last_hydrated = self.last_hydrated.lock().unwrap();
+            match last_hydrated.insert(process.id, file_path.to_path_buf()) {
+                // Chunked hydrations call back several times for the same file
+                Some(p) if p == file_path => {
+                    return;
+                }
+                v => v,
+            }
+        };
+        if let Some(previous) = previous {
+            self.transitions.write().unwrap().add(previous, file_path.to_path_buf(), 1, self.options.max_transitions);
+        }
+
+        let predictions = self.predict(file_path);
+        if predictions.is_empty() {
+            return;
+        }
+        let queue = self.queue.lock().unwrap();
+        let queue = match queue.as_ref() {
+            Some(q) => q,
+            None => {
+                return;
+            }
+        };
+        let mut prefetched = self.prefetched.lock().unwrap();
+        // Files read that long ago may have been dehydrated or changed since
+        prefetched.retain(|_, queued| queued.elapsed() < self.options.budget_interval);
+        for p in predictions {
+            if !prefetched.contains_key(&p) {
+                prefetched.insert(p.clone(), Instant::now());
+                // The worker only goes away on stop
+                let _ = queue.send(p);
+            }
+        }
+    }
+
+    fn predict(&self, file_path: &Path) -> Vec<PathBuf> {
+        let transitions = self.transitions.read().unwrap();
+        let mut next: Vec<(&PathBuf, u32)> = match transitions.next.get(file_path) {
+            Some(n) => n.iter()
+                .filter(|(_, count)| **count >= self.options.min_count)
+                .map(|(p, count)| (p, *count))
+                .collect(),
+            None => {
+                return Vec::new();
+            }
+        };
+        next.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        next.into_iter().take(self.options.max_predictions).map(|(p, _)| p.clone()).collect()
+    }
+
+    pub fn export(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let transitions = self.transitions.read().unwrap();
+        let mut model = ModelFile::default();
+        for (from, next) in transitions.next.iter() {
+            for (to, count) in next {
+                model.transitions.push(Transition {
+                    from: from.clone(),
+                    to: to.clone(),
+                    count: *count,
+                });
+            }
+        }
+        let file = fs::File::create(path)?;
+        serde_json::to_writer(std::io::BufWriter::new(file), &model)?;
+        Ok(())
+    }
+
+    /// Merges a model exported on this or another machine into the current one
+    pub fn import(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let file = fs::File::open(path)?;
+        let model: ModelFile = serde_json::from_reader(std::io::BufReader::new(file))?;
+        let mut transitions = self.transitions.write().unwrap();
+        for t in model.transitions {
+            transitions.add(t.from, t.to, t.count, self.options.max_transitions);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(id: u32) -> TriggeringProcess {
+        TriggeringProcess {
+            id,
+            image_file_name: PathBuf::from("reader.exe"),
+        }
+    }
+
+    /// Has `process` read `files` one after another
+    fn read(prefetcher: &Prefetcher, process: u32, files: &[&str]) {
+        files.iter().for_each(|f| prefetcher.record(&self::process(process), Path::new(f)));
+    }
+
+    fn paths(files: &[&str]) -> Vec<PathBuf> {
+        files.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn learns_what_each_process_reads_next() {
+        let prefetcher = Prefetcher::new(PrefetchOptions::default()).unwrap();
+        // Two processes at once don't mix their sequences, chunked reads count once
+        read(&prefetcher, 1, &["a", "b"]);
+        read(&prefetcher, 2, &["a", "c", "c"]);
+        assert!(prefetcher.predict(Path::new("a")).is_empty());
+        read(&prefetcher, 1, &["a", "b", "a", "b", "a", "c"]);
+        assert_eq!(prefetcher.predict(Path::new("a")), paths(&["b", "c"]));
+        assert_eq!(prefetcher.predict(Path::new("b")), paths(&["a"]));
+        // Our own reads are the prefetches
+        read(&prefetcher, std::process::id(), &["c", "d", "c", "d"]);
+        assert!(prefetcher.predict(Path::new("c")).is_empty());
+    }
+
+    #[test]
+    fn forgets_rare_transitions_past_the_limit() {
+        let prefetcher = Prefetcher::new(PrefetchOptions {
+            min_count: 1,
+            max_transitions: 3,
+            ..Default::default()
+        }).unwrap();
+        read(&prefetcher, 1, &["a", "b", "a", "b", "a", "b", "c"]);
+        assert_eq!(prefetcher.transitions.read().unwrap().count, 3);
+        // One more halves the counts, a->b and b->a stay since they were seen more than once
+        read(&prefetcher, 1, &["d"]);
+        assert_eq!(prefetcher.transitions.read().unwrap().count, 2);
+        assert_eq!(prefetcher.predict(Path::new("a")), paths(&["b"]));
+        assert_eq!(prefetcher.predict(Path::new("b")), paths(&["a"]));
+        assert!(prefetcher.predict(Path::new("c")).is_empty());
+    }
+
+    #[test]
+    fn exports_and_merges_models() {
+        let model = std::env::temp_dir().join(format!("projfs-prefetch-{}-model.json", std::process::id()));
+        let first = Prefetcher::new(PrefetchOptions::default()).unwrap();
+        read(&first, 1, &["a", "b", "a", "b"]);
+        first.export(&model).unwrap();
+
+        let second = Prefetcher::new(PrefetchOptions {
+            model: Some(model.clone()),
+            ..Default::default()
+        }).unwrap();
+        assert_eq!(second.predict(Path::new("a")), paths(&["b"]));
+        // Counts add up, without overflowing
+        second.import(&model).unwrap();
+        assert_eq!(second.transitions.read().unwrap().next[Path::new("a")][Path::new("b")], 4);
+        std::fs::write(&model, r#"{"transitions": [{"from": "a", "to": "b", "count": 4294967295}]}"#).unwrap();
+        second.import(&model).unwrap();
+        assert_eq!(second.transitions.read().unwrap().next[Path::new("a")][Path::new("b")], u32::MAX);
+        let _ = std::fs::remove_file(&model);
+    }
+}
@@ -5,11 +5,66 @@ use std::collections::HashMap;
 use windows::Win32::Storage::ProjectedFileSystem;
 use widestring::{WideCStr, WideCString};
 
-use super::base::{ProjFSProvider, EnumerationState, MatchType, FILE_TRANSFER_CHUNK_SIZE};
+use super::base::{ProjFSProvider, EnumerationState, DirEntryBuffer, MatchType, NotificationMapping, FILE_TRANSFER_CHUNK_SIZE};
+use super::access::{AccessControl, Operation, TriggeringProcess};
+use super::visibility::NamespaceFilter;
+use super::audit::{AuditEvent, AuditLog, AuditOptions};
+use super::prefetch::{Prefetcher, PrefetchOptions};
+use super::cache::{CacheOptions, PlaceholderCache};
+use super::control::{ControlDirectory, CONTROL_DIRECTORY};
+
+/// Runner-level behaviour that applies on top of any provider
+#[derive(Debug, Default)]
+pub struct RunnerOptions {
+    pub access_control: AccessControl,
+    pub namespace_filter: NamespaceFilter,
+    pub audit: Option<AuditOptions>,
+    pub prefetch: Option<PrefetchOptions>,
+    pub cache: Option<CacheOptions>,
+    /// Answer for a hidden `.projfs` directory with the runner's own status
+    pub control: bool,
+}
 
 struct ProviderState {
     provider: Box<dyn ProjFSProvider>,
     enumerations: std::sync::RwLock<HashMap<windows::core::GUID, std::sync::RwLock<Box<dyn EnumerationState>>>>,
+    options: RunnerOptions,
+    audit: Option<AuditLog>,
+    prefetcher: Option<std::sync::Arc<Prefetcher>>,
+    cache: Option<PlaceholderCache>,
+    control: Option<ControlDirectory>,
+}
+
+impl ProviderState {
+    fn is_allowed(&self, callbackdata: *const ProjectedFileSystem::PRJ_CALLBACK_DATA, operation: Operation, file_path: &Path) -> bool {
+        let process = TriggeringProcess::from_callback_data(callbackdata);
+        if self.options.access_control.is_allowed(&process, operation) {
+            true
+        } else {
+            println!("Denied {operation:?} of {file_path:?} to {process:?}");
+            false
+        }
+    }
+
+    fn is_hidden(&self, callbackdata: *const ProjectedFileSystem::PRJ_CALLBACK_DATA, file_path: &Path) -> bool {
+        let process = TriggeringProcess::from_callback_data(callbackdata);
+        self.options.namespace_filter.is_hidden(&process, file_path)
+    }
+
+    fn audit(&self, callbackdata: *const ProjectedFileSystem::PRJ_CALLBACK_DATA, event: AuditEvent, file_path: &Path, notification: Option<ProjectedFileSystem::PRJ_NOTIFICATION>, bytes: u64, result: windows::core::HRESULT) {
+        if let Some(audit) = &self.audit {
+            let process = TriggeringProcess::from_callback_data(callbackdata);
+            audit.record(event, file_path, notification, bytes, &process, result);
+        }
+        if let Some(control) = &self.control {
+            control.record(event, file_path, bytes, result);
+        }
+    }
+
+    /// The control directory if `file_path` is in it, the provider never sees those paths
+    fn control_for(&self, file_path: &Path) -> Option<&ControlDirectory> {
+        self.control.as_ref().filter(|c| c.contains(file_path))
+    }
 }
 
 #[derive(Default)]
@@ -38,12 +93,26 @@ extern "system" fn start_dir_enum_callback(callbackdata: *const ProjectedFileSys
         WideCStr::from_ptr_str((*callbackdata).FilePathName.0).to_os_string().into()
     };
     
+    if state.is_hidden(callbackdata, &file_path) {
+        return windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into();
+    }
+    if !state.is_allowed(callbackdata, Operation::Enumerate, &file_path) {
+        return windows::Win32::Foundation::ERROR_ACCESS_DENIED.into();
+    }
+
     let enum_id = unsafe { *enumerationid };
 
+    let enumeration = match state.control_for(&file_path) {
+        Some(control) => control.enumeration(&file_path),
+        None => state.provider.new_enumeration(enum_id, &file_path),
+    };
     let mut enumerations = state.enumerations.write().unwrap();
-    if enumerations.insert(enum_id, std::sync::RwLock::new(state.provider.new_enumeration(enum_id, &file_path))).is_some() {
+    if enumerations.insert(enum_id, std::sync::RwLock::new(enumeration)).is_some() {
         windows::Win32::Foundation::E_INVALIDARG
     } else {
+        if let Some(control) = &state.control {
+            control.enumeration_started(enum_id, &file_path);
+        }
         windows::Win32::Foundation::S_OK
     }
 }
@@ -62,6 +131,9 @@ extern "system" fn end_dir_enum_callback(callbackdata: *const ProjectedFileSyste
     };
 
     let enum_id = unsafe { *enumerationid };
+    if let Some(control) = &state.control {
+        control.enumeration_ended(enum_id);
+    }
 
     let mut enumerations = state.enumerations.write().unwrap();
     match enumerations.remove(&enum_id) {
@@ -140,7 +212,28 @@ extern "system" fn get_dir_enum_callback(callbackdata: *const ProjectedFileSyste
         }
     }
 
-    enumeration.enumerate(callbackdata, direntrybufferhandle)
+    let dir_path : PathBuf = unsafe {
+        WideCStr::from_ptr_str((*callbackdata).FilePathName.0).to_os_string().into()
+    };
+    let process = TriggeringProcess::from_callback_data(callbackdata);
+    let filter = |name: &std::ffi::OsStr, file_info: &ProjectedFileSystem::PRJ_FILE_BASIC_INFO| {
+        let file_path = dir_path.join(name);
+        if state.options.namespace_filter.is_hidden(&process, &file_path) {
+            return false;
+        }
+        if let Some(cache) = &state.cache {
+            if cache.populate_from_enumeration() {
+                cache.insert(&file_path, Some(ProjectedFileSystem::PRJ_PLACEHOLDER_INFO {
+                    FileBasicInfo: *file_info,
+                    ..Default::default()
+                }));
+            }
+        }
+        true
+    };
+    let mut buffer = DirEntryBuffer::new(direntrybufferhandle, &filter);
+
+    enumeration.enumerate(callbackdata, &mut buffer)
 }
 
 extern "system" fn get_placeholder_info_callback(callbackdata: *const ProjectedFileSystem::PRJ_CALLBACK_DATA) -> windows::core::HRESULT {
@@ -159,7 +252,52 @@ extern "system" fn get_placeholder_info_callback(callbackdata: *const ProjectedF
         WideCStr::from_ptr_str((*callbackdata).FilePathName.0).to_os_string().into()
     };
 
-    let placeholder_info = match state.provider.get_placeholder_info(&file_path) {
+    let result = if state.is_hidden(callbackdata, &file_path) {
+        windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into()
+    } else if !state.is_allowed(callbackdata, Operation::Placeholder, &file_path) {
+        windows::Win32::Foundation::ERROR_ACCESS_DENIED.into()
+    } else {
+        write_placeholder_info(state, callbackdata, &file_path)
+    };
+    // Not audited, like their notifications
+    if state.control_for(&file_path).is_none() {
+        state.audit(callbackdata, AuditEvent::Placeholder, &file_path, None, 0, result);
+    }
+
+    result
+}
+
+/// The provider's placeholder info through the cache, if there is one
+fn cached_placeholder_info(state: &ProviderState, file_path: &Path) -> Result<ProjectedFileSystem::PRJ_PLACEHOLDER_INFO, windows::core::HRESULT> {
+    let cached = state.cache.as_ref().and_then(|c| c.get(file_path));
+    match cached {
+        Some(Some(p)) => Ok(p),
+        Some(None) => Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into()),
+        None => match state.provider.get_placeholder_info(file_path) {
+            Ok(p) => {
+                if let Some(cache) = &state.cache {
+                    cache.insert(file_path, Some(p));
+                }
+                Ok(p)
+            }
+            Err(e) => {
+                if let Some(cache) = &state.cache {
+                    if e == windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into() {
+                        cache.insert(file_path, None);
+                    }
+                }
+                Err(e)
+            }
+        },
+    }
+}
+
+fn write_placeholder_info(state: &ProviderState, callbackdata: *const ProjectedFileSystem::PRJ_CALLBACK_DATA, file_path: &Path) -> windows::core::HRESULT {
+    let placeholder_info = match state.control_for(file_path) {
+        Some(control) => control.placeholder_info(file_path),
+        None => cached_placeholder_info(state, file_path),
+    };
+    let placeholder_info = match placeholder_info {
         Ok(p) => p,
         Err(e) => {
             return e;
@@ -174,8 +312,25 @@ extern "system" fn get_placeholder_info_callback(callbackdata: *const ProjectedF
     }.map_err(|e| e.code()).err().unwrap_or(windows::Win32::Foundation::S_OK)
 }
 
+/// Rounds `p` down to a multiple of `v`, which has to be a power of two
 fn block_align_truncate(p : u64, v : u64) -> u64 {
-    (p) & (0-v)
+    p & !(v - 1)
+}
+
+/// Splits a write of `length` bytes at `byteoffset` into chunks of at most `FILE_TRANSFER_CHUNK_SIZE`,
+/// where every chunk but the last ends on a multiple of `alignment`
+fn transfer_chunks(byteoffset: u64, length: u64, alignment: u64) -> impl Iterator<Item = std::ops::Range<u64>> {
+    let end = byteoffset + length;
+    let mut start = byteoffset;
+    std::iter::from_fn(move || {
+        if start >= end {
+            return None;
+        }
+        let chunk_end = std::cmp::min(end, block_align_truncate(start + FILE_TRANSFER_CHUNK_SIZE, alignment));
+        let chunk = start..chunk_end;
+        start = chunk_end;
+        Some(chunk)
+    })
 }
 
 extern "system" fn get_file_data_callback(callbackdata: *const ProjectedFileSystem::PRJ_CALLBACK_DATA, byteoffset: u64, length: u32) -> windows::core::HRESULT {
@@ -194,7 +349,40 @@ extern "system" fn get_file_data_callback(callbackdata: *const ProjectedFileSyst
         WideCStr::from_ptr_str((*callbackdata).FilePathName.0).to_os_string().into()
     };
 
-    let mut reader = match state.provider.get_file_data(&file_path) {
+    let result = if state.is_hidden(callbackdata, &file_path) {
+        windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into()
+    } else if !state.is_allowed(callbackdata, Operation::Hydrate, &file_path) {
+        windows::Win32::Foundation::ERROR_ACCESS_DENIED.into()
+    } else {
+        write_file_data(state, callbackdata, &file_path, byteoffset, length)
+    };
+    // Reading control files is neither audited nor something to prefetch
+    if state.control_for(&file_path).is_some() {
+        return result;
+    }
+    let bytes = if result.is_ok() { length as u64 } else { 0 };
+    state.audit(callbackdata, AuditEvent::Hydrate, &file_path, None, bytes, result);
+    if let Some(prefetcher) = &state.prefetcher {
+        if result.is_ok() {
+            prefetcher.record(&TriggeringProcess::from_callback_data(callbackdata), &file_path);
+        }
+    }
+
+    result
+}
+
+fn write_file_data(state: &ProviderState, callbackdata: *const ProjectedFileSystem::PRJ_CALLBACK_DATA, file_path: &Path, byteoffset: u64, length: u32) -> windows::core::HRESULT {
+    if let Some(cache) = &state.cache {
+        if let Some(None) = cache.get(file_path) {
+            return windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into();
+        }
+    }
+
+    let reader = match state.control_for(file_path) {
+        Some(control) => control.file_data(file_path),
+        None => state.provider.get_file_data(file_path),
+    };
+    let mut reader = match reader {
         Ok(r) => r,
         Err(e) => {
             return e;
@@ -204,55 +392,56 @@ extern "system" fn get_file_data_callback(callbackdata: *const ProjectedFileSyst
     if reader.seek(std::io::SeekFrom::Start(byteoffset)).is_err() {
         return windows::Win32::Foundation::E_ABORT;
     }
-    
-    let (write_start_offset, mut write_length) = if length as u64 <= FILE_TRANSFER_CHUNK_SIZE {
-        // Read the entire chunk in one go
-        (byteoffset, length)
+
+    let alignment = if length as u64 <= FILE_TRANSFER_CHUNK_SIZE {
+        // Read the entire request in one go
+        1
     } else {
-        let instance_info = unsafe {
+        // Every chunk but the last has to end on the write alignment
+        let instance_info = match unsafe {
             ProjectedFileSystem::PrjGetVirtualizationInstanceInfo((*callbackdata).NamespaceVirtualizationContext)
-        }.unwrap();
-
-        let write_end_offset = block_align_truncate(byteoffset + FILE_TRANSFER_CHUNK_SIZE, instance_info.WriteAlignment as u64);
-
-        (write_end_offset, (write_end_offset - byteoffset) as u32)
+        } {
+            Ok(i) => i,
+            Err(e) => {
+                return e.code();
+            }
+        };
+        std::cmp::max(instance_info.WriteAlignment, 1) as u64
     };
 
-
+    let buffer_length = std::cmp::min(length as u64, FILE_TRANSFER_CHUNK_SIZE) as usize;
     let write_buffer = unsafe {
-        ProjectedFileSystem::PrjAllocateAlignedBuffer((*callbackdata).NamespaceVirtualizationContext, write_length as usize)
+        ProjectedFileSystem::PrjAllocateAlignedBuffer((*callbackdata).NamespaceVirtualizationContext, buffer_length)
     };
     if write_buffer.is_null() {
         return windows::Win32::Foundation::E_OUTOFMEMORY;
     }
-    let write_slice = unsafe {
-        std::slice::from_raw_parts_mut(write_buffer as *mut u8, write_length as usize)
+    let buffer_slice = unsafe {
+        std::slice::from_raw_parts_mut(write_buffer as *mut u8, buffer_length)
     };
 
-    let mut cur_length = length;
-    while write_length > 0 {
-        // TODO: This might need to check if less was read
-        if reader.read(write_slice).is_err() {
-            return windows::Win32::Foundation::E_ABORT;
+    let mut result = windows::Win32::Foundation::S_OK;
+    for chunk in transfer_chunks(byteoffset, length as u64, alignment) {
+        let write_offset = chunk.start;
+        let write_length = (chunk.end - chunk.start) as usize;
+        if let Err(e) = reader.read_exact(&mut buffer_slice[..write_length]) {
+            println!("Reading {file_path:?} at {write_offset} failed: {e}");
+            result = windows::Win32::Foundation::E_ABORT;
+            break;
         }
         unsafe {
-            if let Err(e) = ProjectedFileSystem::PrjWriteFileData((*callbackdata).NamespaceVirtualizationContext, &(*callbackdata).DataStreamId, write_buffer, write_start_offset, write_length) {
-                ProjectedFileSystem::PrjFreeAlignedBuffer(write_buffer);
-                return e.code();
+            if let Err(e) = ProjectedFileSystem::PrjWriteFileData((*callbackdata).NamespaceVirtualizationContext, &(*callbackdata).DataStreamId, write_buffer, write_offset, write_length as u32) {
+                result = e.code();
+                break;
             }
         }
-        cur_length -= write_length;
-        if cur_length < write_length {
-            write_length = cur_length;
-        }
     }
 
     unsafe {
         ProjectedFileSystem::PrjFreeAlignedBuffer(write_buffer)
     };
-    //windows::Win32::Foundation::ERROR_ACCESS_DENIED.into()
 
-    windows::Win32::Foundation::S_OK
+    result
 }
 
 extern "system" fn query_file_name_callback(callbackdata: *const ProjectedFileSystem::PRJ_CALLBACK_DATA) -> windows::core::HRESULT { 
@@ -271,7 +460,30 @@ extern "system" fn query_file_name_callback(callbackdata: *const ProjectedFileSy
         WideCStr::from_ptr_str((*callbackdata).FilePathName.0).to_os_string().into()
     };
 
-    state.provider.query_file_name(&file_path)
+    if state.is_hidden(callbackdata, &file_path) {
+        return windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into();
+    }
+    if let Some(control) = state.control_for(&file_path) {
+        return control.query_file_name(&file_path);
+    }
+
+    let cache = match &state.cache {
+        Some(c) => c,
+        None => {
+            return state.provider.query_file_name(&file_path);
+        }
+    };
+    match cache.get(&file_path) {
+        Some(Some(_)) => windows::Win32::Foundation::S_OK,
+        Some(None) => windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into(),
+        None => {
+            let result = state.provider.query_file_name(&file_path);
+            if result == windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into() {
+                cache.insert(&file_path, None);
+            }
+            result
+        }
+    }
 }
 
 // extern "system" fn cancel_command_callback(callbackdata: *const ProjectedFileSystem::PRJ_CALLBACK_DATA) {
@@ -291,21 +503,69 @@ extern "system" fn notification_callback(callbackdata: *const ProjectedFileSyste
         }
     };
     let is_directory = is_directory != windows::Win32::Foundation::BOOLEAN(0);
-    
-    state.provider.notification(callbackdata, is_directory, notification, destinationfilename, operationparameters)
+    let file_path : PathBuf = unsafe {
+        WideCStr::from_ptr_str((*callbackdata).FilePathName.0).to_os_string().into()
+    };
+    // Not audited, every look at a control file would show up in the next one
+    if let Some(control) = state.control_for(&file_path) {
+        return control.notification(callbackdata, is_directory, notification);
+    }
+
+    let result = state.provider.notification(callbackdata, is_directory, notification, destinationfilename, operationparameters);
+    state.audit(callbackdata, AuditEvent::Notification, &file_path, Some(notification), 0, result);
+    if let Some(cache) = &state.cache {
+        match notification {
+            ProjectedFileSystem::PRJ_NOTIFICATION_NEW_FILE_CREATED
+            | ProjectedFileSystem::PRJ_NOTIFICATION_FILE_HANDLE_CLOSED_FILE_DELETED => {
+                cache.invalidate(&file_path);
+            }
+            ProjectedFileSystem::PRJ_NOTIFICATION_FILE_RENAMED => {
+                cache.invalidate(&file_path);
+                if !destinationfilename.is_null() {
+                    let dest_file_name : PathBuf = unsafe {
+                        WideCStr::from_ptr_str(destinationfilename.0).to_os_string().into()
+                    };
+                    cache.invalidate(&dest_file_name);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    result
+}
+/// For providers whose source changes on its own, so the runner doesn't keep answering from stale cached info
+pub fn invalidate_cache(context: ProjectedFileSystem::PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT, file_path: &Path) {
+    let data = GLOBAL_STATE.read().unwrap();
+    if let Some(cache) = data.providers.get(&context.0).and_then(|s| s.cache.as_ref()) {
+        cache.invalidate(file_path);
+    }
+}
+
+/// The size the provider gives a file, looked up without creating a placeholder for it
+fn projected_size(context: ProjectedFileSystem::PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT, file_path: &Path) -> Option<u64> {
+    let data = GLOBAL_STATE.read().unwrap();
+    let state = data.providers.get(&context.0)?;
+    let info = cached_placeholder_info(state, file_path).ok()?;
+    (info.FileBasicInfo.IsDirectory == windows::Win32::Foundation::BOOLEAN(0)).then_some(info.FileBasicInfo.FileSize as u64)
 }
+
 pub struct ProjFSRunner {
     root: PathBuf,
     id: windows::core::GUID,
     instance: ProjectedFileSystem::PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
+    options: RunnerOptions,
+    prefetcher: Option<std::sync::Arc<Prefetcher>>,
 }
 
 impl ProjFSRunner {
-    pub fn new() -> ProjFSRunner {
+    pub fn new(options: RunnerOptions) -> ProjFSRunner {
         ProjFSRunner {
             root: PathBuf::new(),
             id: windows::core::GUID::zeroed(),
             instance: ProjectedFileSystem::PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT::default(),
+            options,
+            prefetcher: None,
         }
     }
 
@@ -329,7 +589,22 @@ impl ProjFSRunner {
             fs::canonicalize(&root)?
         };
 
-        let prov_options = provider.init(root)?;
+        let audit = match &self.options.audit {
+            Some(audit_options) => Some(AuditLog::open(audit_options.clone())?),
+            None => None,
+        };
+        self.prefetcher = match &self.options.prefetch {
+            Some(prefetch_options) => Some(std::sync::Arc::new(Prefetcher::new(prefetch_options.clone())?)),
+            None => None,
+        };
+
+        let mut prov_options = provider.init(root)?;
+        if self.options.control {
+            prov_options.notification_mappings.push(NotificationMapping {
+                bit_mask: ProjectedFileSystem::PRJ_NOTIFY_FILE_HANDLE_CLOSED_NO_MODIFICATION,
+                root: PathBuf::from(CONTROL_DIRECTORY),
+            });
+        }
         let callbacks = ProjectedFileSystem::PRJ_CALLBACKS {
             // Required
             StartDirectoryEnumerationCallback: Some(start_dir_enum_callback),
@@ -379,6 +654,15 @@ impl ProjFSRunner {
         let state = ProviderState{
             provider,
             enumerations: std::sync::RwLock::new(HashMap::new()),
+            // Has to come before the options are moved into the state
+            cache: self.options.cache.clone().map(PlaceholderCache::new),
+            control: self.options.control.then(|| {
+                let config = format!("root: {:?}\n{:#?}\n", self.root, self.options);
+                ControlDirectory::new(config, self.options.audit.as_ref().map(|a| a.path.clone()))
+            }),
+            options: std::mem::take(&mut self.options),
+            audit,
+            prefetcher: self.prefetcher.clone(),
         };
         {
             let mut data = GLOBAL_STATE.write()?;
@@ -389,13 +673,24 @@ impl ProjFSRunner {
                 v.provider.start(self.instance)?;
             }
         }
+        if let Some(prefetcher) = &self.prefetcher {
+            let context = self.instance;
+            prefetcher.start(&self.root, move |file_path| projected_size(context, file_path));
+        }
 
         Ok(())
     }
 
     pub fn stop(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let mut data = GLOBAL_STATE.write()?;
-        match data.providers.remove(&self.instance.0) {
+        // In-flight prefetch reads need the callbacks, so finish them before taking the state away
+        if let Some(prefetcher) = self.prefetcher.take() {
+            prefetcher.stop()?;
+        }
+
+        // Providers stop outside the lock, their background threads may still need the
+        // state to finish. Callbacks coming in meanwhile find no provider and fail.
+        let state = GLOBAL_STATE.write()?.providers.remove(&self.instance.0);
+        match state {
             Some(mut p) => {
                 p.provider.stop()?;
             },
@@ -412,4 +707,43 @@ impl ProjFSRunner {
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks(byteoffset: u64, length: u64, alignment: u64) -> Vec<(u64, u64)> {
+        transfer_chunks(byteoffset, length, alignment).map(|c| (c.start, c.end - c.start)).collect()
+    }
+
+    #[test]
+    fn writes_up_to_a_chunk_at_once() {
+        assert!(chunks(0, 0, 1).is_empty());
+        assert_eq!(chunks(0, FILE_TRANSFER_CHUNK_SIZE - 1, 1), [(0, FILE_TRANSFER_CHUNK_SIZE - 1)]);
+        assert_eq!(chunks(0, FILE_TRANSFER_CHUNK_SIZE, 1), [(0, FILE_TRANSFER_CHUNK_SIZE)]);
+    }
+
+    #[test]
+    fn splits_larger_writes_on_the_alignment() {
+        assert_eq!(chunks(0, FILE_TRANSFER_CHUNK_SIZE + 1, 4096), [(0, FILE_TRANSFER_CHUNK_SIZE), (FILE_TRANSFER_CHUNK_SIZE, 1)]);
+        // Starting off the alignment makes the first chunk shorter
+        assert_eq!(chunks(100, 2 * FILE_TRANSFER_CHUNK_SIZE, 4096), [
+            (100, FILE_TRANSFER_CHUNK_SIZE - 100),
+            (FILE_TRANSFER_CHUNK_SIZE, FILE_TRANSFER_CHUNK_SIZE),
+            (2 * FILE_TRANSFER_CHUNK_SIZE, 100),
+        ]);
+    }
+
+    #[test]
+    fn covers_writes_past_4gib() {
+        let start = (1u64 << 32) - 4096;
+        let length = 3 * FILE_TRANSFER_CHUNK_SIZE + 4096;
+        let all = chunks(start, length, 4096);
+        assert_eq!(all.len(), 4);
+        assert_eq!(all.first().unwrap().0, start);
+        assert!(all.windows(2).all(|w| w[0].0 + w[0].1 == w[1].0 && (w[1].0 % 4096) == 0));
+        assert!(all.iter().all(|c| c.1 <= FILE_TRANSFER_CHUNK_SIZE));
+        assert_eq!(all.iter().map(|c| c.1).sum::<u64>(), length);
+    }
+}
@@ -0,0 +1,481 @@
+use std::collections::{HashMap, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::path::{Component, Path};
+
+use windows::Win32::Storage::ProjectedFileSystem;
+
+use super::base::{compare_file_names, EnumerationState};
+use super::list_enumeration::ListEnumeration;
+
+/// What ProjFS gets to know about an entry, times are FILETIMEs
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EntryInfo {
+    pub is_directory: bool,
+    pub size: u64,
+    pub creation_time: i64,
+    pub last_access_time: i64,
+    pub last_write_time: i64,
+    pub change_time: i64,
+    pub attributes: u32,
+}
+
+impl EntryInfo {
+    pub fn directory(time: i64) -> EntryInfo {
+        EntryInfo {
+            is_directory: true,
+            size: 0,
+            creation_time: time,
+            last_access_time: time,
+            last_write_time: time,
+            change_time: time,
+            attributes: 0,
+        }
+    }
+
+    pub fn file(size: u64, time: i64) -> EntryInfo {
+        EntryInfo {
+            is_directory: false,
+            size,
+            ..EntryInfo::directory(time)
+        }
+    }
+
+    pub fn basic_info(&self) -> ProjectedFileSystem::PRJ_FILE_BASIC_INFO {
+        ProjectedFileSystem::PRJ_FILE_BASIC_INFO {
+            IsDirectory: if self.is_directory {
+                windows::Win32::Foundation::BOOLEAN(1)
+            } else {
+                windows::Win32::Foundation::BOOLEAN(0)
+            },
+            FileSize: self.size as i64,
+            CreationTime: self.creation_time,
+            LastAccessTime: self.last_access_time,
+            LastWriteTime: self.last_write_time,
+            ChangeTime: self.change_time,
+            FileAttributes: self.attributes,
+        }
+    }
+
+    pub fn placeholder_info(&self) -> ProjectedFileSystem::PRJ_PLACEHOLDER_INFO {
+        ProjectedFileSystem::PRJ_PLACEHOLDER_INFO {
+            FileBasicInfo: self.basic_info(),
+            ..Default::default()
+        }
+    }
+}
+
+pub type NodeId = usize;
+
+/// Same as Linux allows when following symlinks
+const MAX_LINK_DEPTH: usize = 40;
+
+/// Directories up to this size take out of order inserts in place. Larger ones get
+/// appended to and indexed by name instead, until `sort` puts them in order again.
+const SORTED_INSERT_LIMIT: usize = 1024;
+
+pub struct Node<C> {
+    pub name: OsString,
+    pub info: EntryInfo,
+    pub content: C,
+    // Kept in ProjFS order so lookups can binary search and enumerations can go straight through
+    children: Vec<NodeId>,
+}
+
+/// In-memory namespace where every directory keeps its children sorted by ProjFS collation.
+/// Lookups cost a binary search per path component and enumerations only touch the children.
+/// `C` is whatever the provider needs to produce the content of an entry.
+///
+/// Loading large directories out of order would move the children around on every insert,
+/// so those are kept unsorted while loading. Call `sort` once everything is in.
+pub struct VirtualTree<C> {
+    nodes: Vec<Node<C>>,
+    /// Name index of the directories whose children aren't in order
+    unsorted: HashMap<NodeId, HashMap<String, NodeId>>,
+}
+
+/// Case insensitive like ProjFS names, for the index of unsorted directories
+fn name_key(name: &OsStr) -> String {
+    name.to_string_lossy().to_uppercase()
+}
+
+impl<C: Default> Default for VirtualTree<C> {
+    fn default() -> Self {
+        VirtualTree::new(EntryInfo::directory(0), C::default())
+    }
+}
+
+impl<C> VirtualTree<C> {
+    pub const ROOT: NodeId = 0;
+
+    pub fn new(root_info: EntryInfo, root_content: C) -> VirtualTree<C> {
+        VirtualTree {
+            nodes: vec![Node {
+                name: OsString::new(),
+                info: root_info,
+                content: root_content,
+                children: Vec::new(),
+            }],
+            unsorted: HashMap::new(),
+        }
+    }
+
+    pub fn node(&self, id: NodeId) -> &Node<C> {
+        &self.nodes[id]
+    }
+
+    pub fn children(&self, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+        self.nodes[id].children.iter().copied()
+    }
+
+    fn child_position(&self, parent: NodeId, name: &OsStr) -> Result<usize, usize> {
+        self.nodes[parent].children.binary_search_by(|c| compare_file_names(&self.nodes[*c].name, name))
+    }
+
+    pub fn child(&self, parent: NodeId, name: &OsStr) -> Option<NodeId> {
+        match self.unsorted.get(&parent) {
+            Some(index) => index.get(&name_key(name)).copied(),
+            None => self.child_position(parent, name).ok().map(|i| self.nodes[parent].children[i]),
+        }
+    }
+
+    pub fn lookup(&self, path: &Path) -> Option<NodeId> {
+        let mut id = Self::ROOT;
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => {
+                    id = self.child(id, name)?;
+                }
+                Component::CurDir => {}
+                _ => {
+                    return None;
+                }
+            }
+        }
+        Some(id)
+    }
+
+    /// Adds a child, or replaces the info and content of an existing one with the same name
+    pub fn insert(&mut self, parent: NodeId, name: &OsStr, info: EntryInfo, content: C) -> NodeId {
+        let children = &self.nodes[parent].children;
+        // Inserting in order is the common case and doesn't need a search
+        let position = match children.last() {
+            _ if self.unsorted.contains_key(&parent) => self.child(parent, name).ok_or(children.len()),
+            Some(last) if compare_file_names(&self.nodes[*last].name, name) == std::cmp::Ordering::Less => Err(children.len()),
+            None => Err(0),
+            _ => self.child_position(parent, name).map(|i| children[i]),
+        };
+        let position = match position {
+            Ok(id) => {
+                let node = &mut self.nodes[id];
+                node.info = info;
+                node.content = content;
+                return id;
+            }
+            Err(i) => i,
+        };
+
+        let id = self.nodes.len();
+        self.nodes.push(Node {
+            name: name.to_os_string(),
+            info,
+            content,
+            children: Vec::new(),
+        });
+        let nodes = &mut self.nodes;
+        let children_count = nodes[parent].children.len();
+        if let Some(index) = self.unsorted.get_mut(&parent) {
+            index.insert(name_key(name), id);
+            nodes[parent].children.push(id);
+        } else if position == children_count || children_count < SORTED_INSERT_LIMIT {
+            nodes[parent].children.insert(position, id);
+        } else {
+            let mut index: HashMap<String, NodeId> = nodes[parent].children.iter().map(|c| (name_key(&nodes[*c].name), *c)).collect();
+            index.insert(name_key(name), id);
+            nodes[parent].children.push(id);
+            self.unsorted.insert(parent, index);
+        }
+        id
+    }
+
+    /// Puts the directories that were loaded out of order back in ProjFS order
+    pub fn sort(&mut self) {
+        for (parent, _) in self.unsorted.drain() {
+            let mut children = std::mem::take(&mut self.nodes[parent].children);
+            children.sort_by(|a, b| compare_file_names(&self.nodes[*a].name, &self.nodes[*b].name));
+            self.nodes[parent].children = children;
+        }
+    }
+
+    /// Detaches a child and everything below it. The nodes stay allocated, so their ids remain valid.
+    pub fn remove(&mut self, parent: NodeId, name: &OsStr) -> Option<NodeId> {
+        let i = match self.unsorted.get_mut(&parent) {
+            Some(index) => {
+                let id = index.remove(&name_key(name))?;
+                self.nodes[parent].children.iter().position(|c| *c == id)?
+            }
+            None => self.child_position(parent, name).ok()?,
+        };
+        Some(self.nodes[parent].children.remove(i))
+    }
+
+    pub fn clear_children(&mut self, id: NodeId) {
+        self.unsorted.remove(&id);
+        self.nodes[id].children.clear();
+    }
+
+    /// Finds a path that starts at the root, following links on the way like a file system would.
+    /// `link` tells which contents are links and where they lead, also from the root.
+    pub fn resolve<'a>(&'a self, path: &'a str, link: &impl Fn(&'a C) -> Option<&'a str>) -> Option<NodeId> {
+        let mut pending: VecDeque<&str> = path.split('/').collect();
+        let mut stack = vec![Self::ROOT];
+        let mut depth = 0;
+        while let Some(part) = pending.pop_front() {
+            match part {
+                "" | "." => {}
+                // The root is its own parent
+                ".." => {
+                    if stack.len() > 1 {
+                        stack.pop();
+                    }
+                }
+                name => {
+                    let current = *stack.last().unwrap();
+                    if !self.nodes[current].info.is_directory {
+                        return None;
+                    }
+                    let child = self.child(current, OsStr::new(name))?;
+                    match link(&self.nodes[child].content) {
+                        Some(target) => {
+                            depth += 1;
+                            if depth > MAX_LINK_DEPTH {
+                                return None;
+                            }
+                            stack.truncate(1);
+                            for p in target.split('/').rev() {
+                                pending.push_front(p);
+                            }
+                        }
+                        None => stack.push(child),
+                    }
+                }
+            }
+        }
+        stack.last().copied()
+    }
+
+    /// Turns links into copies of the file they lead to, since placeholders can't be symlinks.
+    /// Links to directories and links that lead nowhere are removed, returns how many were.
+    pub fn resolve_links(&mut self, link: impl Fn(&C) -> Option<&str>) -> usize where C: Clone {
+        let mut links = Vec::new();
+        let mut directories = vec![Self::ROOT];
+        while let Some(directory) = directories.pop() {
+            for &child in &self.nodes[directory].children {
+                if link(&self.nodes[child].content).is_some() {
+                    links.push((directory, child));
+                } else if self.nodes[child].info.is_directory {
+                    directories.push(child);
+                }
+            }
+        }
+
+        // Everything is resolved before anything changes, so links to links still find their way
+        let resolved: Vec<_> = links.iter().map(|(_, id)| {
+            let target = link(&self.nodes[*id].content)?;
+            self.resolve(target, &link).filter(|t| !self.nodes[*t].info.is_directory)
+        }).collect();
+        let mut removed = 0;
+        for ((parent, id), target) in links.into_iter().zip(resolved) {
+            match target {
+                Some(target) => {
+                    let (size, attributes, content) = {
+                        let t = &self.nodes[target];
+                        (t.info.size, t.info.attributes, t.content.clone())
+                    };
+                    let node = &mut self.nodes[id];
+                    node.info.size = size;
+                    node.info.attributes = attributes;
+                    node.content = content;
+                }
+                None => {
+                    let name = self.nodes[id].name.clone();
+                    self.remove(parent, &name);
+                    removed += 1;
+                }
+            }
+        }
+        removed
+    }
+
+    /// Adds an entry by path, creating missing parent directories with `directory_info`
+    pub fn insert_path(&mut self, path: &Path, info: EntryInfo, content: C, directory_info: EntryInfo) -> Result<NodeId, String> where C: Default {
+        let name = match path.file_name() {
+            Some(n) => n,
+            None => {
+                return Err(format!("{path:?} has no file name"));
+            }
+        };
+        let mut parent = Self::ROOT;
+        if let Some(parent_path) = path.parent() {
+            for component in parent_path.components() {
+                let component = match component {
+                    Component::Normal(c) => c,
+                    Component::CurDir => {
+                        continue;
+                    }
+                    _ => {
+                        return Err(format!("{path:?} is not a plain relative path"));
+                    }
+                };
+                parent = match self.child(parent, component) {
+                    Some(id) => {
+                        if !self.nodes[id].info.is_directory {
+                            return Err(format!("{path:?} is below a file"));
+                        }
+                        id
+                    }
+                    None => self.insert(parent, component, directory_info, C::default()),
+                };
+            }
+        }
+        Ok(self.insert(parent, name, info, content))
+    }
+
+    /// Snapshot enumeration of a directory, unknown paths and files enumerate as empty
+    pub fn enumeration(&self, path: &Path) -> Box<dyn EnumerationState> {
+        self.enumeration_with(path, |node| node.info)
+    }
+
+    /// Same as `enumeration`, for providers that only know some of the info once it's asked for
+    pub fn enumeration_with(&self, path: &Path, info: impl Fn(&Node<C>) -> EntryInfo) -> Box<dyn EnumerationState> {
+        let (entries, sorted) = match self.lookup(path) {
+            Some(id) if self.nodes[id].info.is_directory => {
+                let entries = self.children(id)
+                    .map(|c| (self.nodes[c].name.clone(), info(&self.nodes[c]).basic_info()))
+                    .collect();
+                (entries, !self.unsorted.contains_key(&id))
+            }
+            _ => (Vec::new(), true),
+        };
+        if sorted {
+            Box::new(ListEnumeration::new(entries))
+        } else {
+            Box::new(ListEnumeration::sorted(entries))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names<C>(tree: &VirtualTree<C>, id: NodeId) -> Vec<String> {
+        tree.children(id).map(|c| tree.node(c).name.to_string_lossy().into_owned()).collect()
+    }
+
+    fn is_sorted<C>(tree: &VirtualTree<C>, id: NodeId) -> bool {
+        let children: Vec<_> = tree.children(id).collect();
+        children.windows(2).all(|w| compare_file_names(&tree.node(w[0]).name, &tree.node(w[1]).name) == std::cmp::Ordering::Less)
+    }
+
+    #[test]
+    fn keeps_children_in_order() {
+        let mut tree = VirtualTree::new(EntryInfo::directory(0), 0);
+        for name in ["b", "D", "a", "c"] {
+            tree.insert(VirtualTree::<u32>::ROOT, OsStr::new(name), EntryInfo::file(1, 0), 0);
+        }
+        assert_eq!(names(&tree, VirtualTree::<u32>::ROOT), ["a", "b", "c", "D"]);
+    }
+
+    #[test]
+    fn replaces_entries_with_the_same_name() {
+        let mut tree = VirtualTree::new(EntryInfo::directory(0), 0);
+        let first = tree.insert(VirtualTree::<u32>::ROOT, OsStr::new("File"), EntryInfo::file(1, 0), 1);
+        let second = tree.insert(VirtualTree::<u32>::ROOT, OsStr::new("FILE"), EntryInfo::file(2, 0), 2);
+        assert_eq!(first, second);
+        assert_eq!(tree.node(first).info.size, 2);
+        assert_eq!(tree.node(first).content, 2);
+        assert_eq!(tree.children(VirtualTree::<u32>::ROOT).count(), 1);
+    }
+
+    #[test]
+    fn loads_large_directories_out_of_order() {
+        let mut tree = VirtualTree::new(EntryInfo::directory(0), 0);
+        let count = SORTED_INSERT_LIMIT as u32 * 3;
+        // Every name twice, in an order far from sorted
+        for round in 0..2 {
+            for i in (0..count).rev() {
+                tree.insert_path(Path::new(&format!("dir/f{:05}", (i * 7919) % count)), EntryInfo::file(round, 0), round, EntryInfo::directory(0)).unwrap();
+            }
+        }
+        let dir = tree.lookup(Path::new("DIR")).unwrap();
+        assert_eq!(tree.children(dir).count(), count as usize);
+        assert_eq!(tree.lookup(Path::new("dir/F00042")).map(|id| tree.node(id).content), Some(1));
+        assert_eq!(tree.remove(dir, OsStr::new("f00042")).map(|id| tree.node(id).content), Some(1));
+        assert_eq!(tree.lookup(Path::new("dir/f00042")), None);
+
+        tree.sort();
+        assert!(is_sorted(&tree, dir));
+        assert_eq!(tree.children(dir).count(), count as usize - 1);
+        assert!(tree.lookup(Path::new("dir/f00043")).is_some());
+        // Going out of order again works the same way
+        tree.insert(dir, OsStr::new("f00042"), EntryInfo::file(0, 0), 2);
+        assert_eq!(tree.lookup(Path::new("dir/f00042")).map(|id| tree.node(id).content), Some(2));
+        tree.sort();
+        assert!(is_sorted(&tree, dir));
+    }
+
+    #[test]
+    fn inserts_paths() {
+        let mut tree = VirtualTree::new(EntryInfo::directory(0), 0);
+        tree.insert_path(Path::new("a/b/c.txt"), EntryInfo::file(3, 0), 1, EntryInfo::directory(0)).unwrap();
+        assert!(tree.node(tree.lookup(Path::new("a/b")).unwrap()).info.is_directory);
+        assert_eq!(tree.node(tree.lookup(Path::new("A/B/C.TXT")).unwrap()).info.size, 3);
+        assert!(tree.insert_path(Path::new("a/b/c.txt/d"), EntryInfo::file(0, 0), 0, EntryInfo::directory(0)).is_err());
+        assert!(tree.insert_path(Path::new("../e"), EntryInfo::file(0, 0), 0, EntryInfo::directory(0)).is_err());
+        assert_eq!(tree.lookup(Path::new("a/missing")), None);
+    }
+
+    #[derive(Clone, Debug, Default, PartialEq)]
+    enum Content {
+        #[default]
+        None,
+        Data(u32),
+        Link(String),
+    }
+
+    fn link(content: &Content) -> Option<&str> {
+        match content {
+            Content::Link(target) => Some(target),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn resolves_links() {
+        let mut tree = VirtualTree::new(EntryInfo::directory(0), Content::None);
+        let directory = EntryInfo::directory(0);
+        for (path, content) in [
+            ("dir/file", Content::Data(1)),
+            ("dir/relative", Content::Link("/dir/../dir/./file".into())),
+            ("chained", Content::Link("/dir/relative".into())),
+            ("through_link/x", Content::None),
+            ("alias", Content::Link("/dir".into())),
+            ("via_alias", Content::Link("/alias/file".into())),
+            ("dangling", Content::Link("/nowhere".into())),
+            ("loop", Content::Link("/loop".into())),
+        ] {
+            let info = if content == Content::None { directory } else { EntryInfo::file(5, 0) };
+            tree.insert_path(Path::new(path), info, content, directory).unwrap();
+        }
+        assert_eq!(tree.resolve("/alias/file", &link), tree.lookup(Path::new("dir/file")));
+
+        // The links to the directory, to nothing and to themselves go
+        assert_eq!(tree.resolve_links(link), 3);
+        for path in ["dir/relative", "chained", "via_alias"] {
+            assert_eq!(tree.lookup(Path::new(path)).map(|id| tree.node(id).content.clone()), Some(Content::Data(1)), "{path}");
+        }
+        for path in ["alias", "dangling", "loop"] {
+            assert_eq!(tree.lookup(Path::new(path)), None, "{path}");
+        }
+    }
+}
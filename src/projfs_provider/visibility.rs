@@ -0,0 +1,99 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use windows::Win32::Storage::ProjectedFileSystem;
+
+use super::access::{ProcessMatch, TriggeringProcess};
+
+/// Hides `root` and everything below it from matching processes
+#[derive(Clone, Debug, PartialEq)]
+pub struct VisibilityRule {
+    pub process: ProcessMatch,
+    pub root: PathBuf,
+}
+
+impl VisibilityRule {
+    fn covers(&self, file_path: &Path) -> bool {
+        let mut path_components = file_path.components();
+        for root_component in self.root.components() {
+            match path_components.next() {
+                Some(c) => unsafe {
+                    if ProjectedFileSystem::PrjFileNameCompare(root_component.as_os_str(), c.as_os_str()) != 0 {
+                        return false;
+                    }
+                },
+                None => {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+
+/// Parses `<process>:<path>`, e.g. `MSBuild.exe:docs\internal` or `C:\Tools\MSBuild.exe:docs`.
+/// Paths in the projection can't contain ':', so the last one separates the two.
+impl FromStr for VisibilityRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (process, root) = match s.rsplit_once(':') {
+            Some(v) => v,
+            None => {
+                return Err(format!("Visibility rule {s:?} must be <process>:<path>"));
+            }
+        };
+        if root.is_empty() {
+            return Err(format!("Visibility rule {s:?} would hide the whole projection"));
+        }
+        Ok(VisibilityRule {
+            process: process.parse()?,
+            root: root.into(),
+        })
+    }
+}
+
+/// Trims the namespace per process. Only what the provider projects is filtered, anything
+/// already on disk (placeholders created for another process, hydrated or full files) stays visible.
+#[derive(Clone, Debug, Default)]
+pub struct NamespaceFilter {
+    pub rules: Vec<VisibilityRule>,
+}
+
+impl NamespaceFilter {
+    pub fn is_hidden(&self, process: &TriggeringProcess, file_path: &Path) -> bool {
+        self.rules.iter().any(|r| r.covers(file_path) && r.process.matches(process))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_image_name() {
+        let rule: VisibilityRule = "MSBuild.exe:docs\\internal".parse().unwrap();
+        assert_eq!(rule.process, ProcessMatch::Image("MSBuild.exe".into()));
+        assert_eq!(rule.root, PathBuf::from("docs\\internal"));
+    }
+
+    #[test]
+    fn parses_image_path() {
+        let rule: VisibilityRule = "C:\\Tools\\MSBuild.exe:docs".parse().unwrap();
+        assert_eq!(rule.process, ProcessMatch::Image("C:\\Tools\\MSBuild.exe".into()));
+        assert_eq!(rule.root, PathBuf::from("docs"));
+    }
+
+    #[test]
+    fn parses_process_id() {
+        let rule: VisibilityRule = "pid=42:secret".parse().unwrap();
+        assert_eq!(rule.process, ProcessMatch::Id(42));
+    }
+
+    #[test]
+    fn rejects_incomplete_rules() {
+        assert!("MSBuild.exe".parse::<VisibilityRule>().is_err());
+        assert!("MSBuild.exe:".parse::<VisibilityRule>().is_err());
+        assert!(":docs".parse::<VisibilityRule>().is_err());
+    }
+}
@@ -0,0 +1,53 @@
+use std::io::{Read, Seek, SeekFrom};
+
+/// `length` bytes of `inner` starting at `offset`, for content stored inside a bigger file
+pub struct WindowReader<R> {
+    inner: R,
+    offset: u64,
+    length: u64,
+    current: u64,
+}
+
+impl<R: Read + Seek> WindowReader<R> {
+    pub fn new(inner: R, offset: u64, length: u64) -> WindowReader<R> {
+        WindowReader {
+            inner,
+            offset,
+            length,
+            current: 0,
+        }
+    }
+}
+
+impl<R: Read + Seek> Read for WindowReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining_bytes = self.length.saturating_sub(self.current);
+        if remaining_bytes == 0 {
+            return Ok(0);
+        }
+        let read_len = std::cmp::min(buf.len() as u64, remaining_bytes) as usize;
+        // Seeking every time keeps the inner position in sync no matter how this was seeked
+        self.inner.seek(SeekFrom::Start(self.offset + self.current))?;
+        let read = self.inner.read(&mut buf[..read_len])?;
+        self.current += read as u64;
+
+        Ok(read)
+    }
+}
+
+impl<R: Read + Seek> Seek for WindowReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(v) => Some(v),
+            SeekFrom::End(v) => self.length.checked_add_signed(v),
+            SeekFrom::Current(v) => self.current.checked_add_signed(v),
+        };
+        match new_position {
+            Some(v) => {
+                self.current = v;
+                Ok(self.current)
+            }
+            None => Err(std::io::Error::from(std::io::ErrorKind::InvalidInput)),
+        }
+    }
+}
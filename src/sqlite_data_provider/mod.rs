@@ -0,0 +1,5 @@
+mod provider;
+mod queries;
+mod render;
+
+pub use provider::SqliteDataProvider;
@@ -0,0 +1,419 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rusqlite::{Connection, OpenFlags};
+use windows::Win32::Storage::ProjectedFileSystem;
+
+use crate::projfs_provider::{compare_file_names, filetime, ProjFSProvider, EnumerationState, ListEnumeration, SeekRead, VirtualizationOptions, EntryInfo};
+use super::queries::{is_valid_file_name, SavedQueries, SavedQuery};
+use super::render::{render, Format};
+
+/// A rendering newer than this is reused for placeholders, so looking at a file a few
+/// times in a row doesn't query the database every time
+const FRESH_FOR: Duration = Duration::from_secs(10);
+/// Renderings kept for hydrations take up this much at most, the oldest are dropped first.
+/// Hydrating one of those renders it again, it only has to come out the same size.
+const KEPT_BYTES: usize = 256 * 1024 * 1024;
+
+const TABLES_QUERY: &str = "SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite\\_%' ESCAPE '\\'";
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Source {
+    Table(String),
+    /// Index into the saved queries
+    Query(usize),
+}
+
+enum Item {
+    Directory,
+    File(Source, Format),
+}
+
+struct Rendering {
+    data: Arc<[u8]>,
+    /// FILETIME of when the rows were read
+    time: i64,
+    made: Instant,
+}
+
+type Key = (Source, Format);
+
+/// Renderings placeholders were made from, until the files are hydrated
+type Renderings = Arc<Mutex<HashMap<Key, Arc<Rendering>>>>;
+
+/// Hands out a kept rendering and forgets it once its last byte went out
+struct RenderingReader {
+    data: Cursor<Arc<[u8]>>,
+    key: Key,
+    renderings: Renderings,
+}
+
+impl Read for RenderingReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.data.read(buf)
+    }
+}
+
+impl Seek for RenderingReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.data.seek(pos)
+    }
+}
+
+impl Drop for RenderingReader {
+    fn drop(&mut self) {
+        if self.data.position() < self.data.get_ref().len() as u64 {
+            return;
+        }
+        let mut renderings = self.renderings.lock().unwrap();
+        // Unless a new placeholder got a newer one in the meantime
+        if renderings.get(&self.key).map(|r| Arc::ptr_eq(&r.data, self.data.get_ref())).unwrap_or(false) {
+            renderings.remove(&self.key);
+        }
+    }
+}
+
+/// Projects the tables of a SQLite database as `tables/<name>.csv` and `tables/<name>.jsonl`,
+/// saved queries go to `queries/`. Files are rendered from the current rows when their
+/// placeholder is made, and that rendering is kept until they're hydrated. Once hydrated they
+/// stay as they are, deleting one brings back the live version.
+///
+/// Listings don't render anything, files that haven't been looked at yet are listed as empty
+/// until then. That makes filling the placeholder cache from enumerations a bad idea here.
+pub struct SqliteDataProvider {
+    database: PathBuf,
+    queries: Vec<SavedQuery>,
+    renderings: Renderings,
+    kept_bytes: usize,
+    // Size of the latest placeholder of every file, renderings made later have to match it
+    sizes: Mutex<HashMap<Key, u64>>,
+    load_time: i64,
+}
+
+fn file_name(name: &str, format: Format) -> OsString {
+    format!("{name}.{}", format.extension()).into()
+}
+
+fn database_error(file_path: &Path, e: rusqlite::Error) -> windows::core::HRESULT {
+    println!("Database query for {file_path:?} failed: {e}");
+    windows::Win32::Foundation::E_FAIL
+}
+
+impl SqliteDataProvider {
+    pub fn new(database: &Path, queries: Option<&Path>) -> Result<SqliteDataProvider, Box<dyn std::error::Error>> {
+        let queries = match queries {
+            Some(path) => SavedQueries::load(path)?.queries,
+            None => Vec::new(),
+        };
+        let provider = SqliteDataProvider {
+            database: database.to_path_buf(),
+            queries,
+            renderings: Arc::new(Mutex::new(HashMap::new())),
+            kept_bytes: KEPT_BYTES,
+            sizes: Mutex::new(HashMap::new()),
+            load_time: filetime::now(),
+        };
+        // Catch typos now rather than on the first hydration
+        let connection = provider.connect()?;
+        for query in &provider.queries {
+            connection.prepare(&query.sql).map_err(|e| format!("Query {:?}: {e}", query.name))?;
+        }
+        println!("{} tables, {} saved queries", provider.tables(&connection)?.len(), provider.queries.len());
+        Ok(provider)
+    }
+
+    fn connect(&self) -> rusqlite::Result<Connection> {
+        // Read-only keeps saved queries from changing anything
+        Connection::open_with_flags(&self.database, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)
+    }
+
+    /// Tables whose names can be file names, the others are left out
+    fn tables(&self, connection: &Connection) -> rusqlite::Result<Vec<String>> {
+        let mut statement = connection.prepare(TABLES_QUERY)?;
+        let names = statement.query_map([], |row| row.get::<_, String>(0))?.collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(names.into_iter().filter(|name| is_valid_file_name(name)).collect())
+    }
+
+    /// Files of a directory, None if `directory` isn't one
+    fn files(&self, directory: &str) -> rusqlite::Result<Option<Vec<(OsString, Source, Format)>>> {
+        let names: Vec<(String, Source)> = match directory {
+            "tables" => self.tables(&self.connect()?)?.into_iter().map(|t| (t.clone(), Source::Table(t))).collect(),
+            "queries" if !self.queries.is_empty() => self.queries.iter().enumerate().map(|(i, q)| (q.name.clone(), Source::Query(i))).collect(),
+            _ => return Ok(None),
+        };
+        Ok(Some(
+            names
+                .into_iter()
+                .flat_map(|(name, source)| Format::ALL.map(|format| (file_name(&name, format), source.clone(), format)))
+                .collect(),
+        ))
+    }
+
+    fn directories(&self) -> Vec<&'static str> {
+        if self.queries.is_empty() {
+            vec!["tables"]
+        } else {
+            vec!["tables", "queries"]
+        }
+    }
+
+    fn directory(&self, name: &OsStr) -> Option<&'static str> {
+        self.directories().into_iter().find(|d| compare_file_names(OsStr::new(d), name) == Ordering::Equal)
+    }
+
+    fn resolve(&self, file_path: &Path) -> rusqlite::Result<Option<Item>> {
+        let names: Option<Vec<&OsStr>> = file_path
+            .components()
+            .map(|c| match c {
+                Component::Normal(name) => Some(name),
+                _ => None,
+            })
+            .collect();
+        Ok(match names.as_deref() {
+            Some([]) => Some(Item::Directory),
+            Some([name]) => self.directory(name).map(|_| Item::Directory),
+            Some([parent, name]) => match self.directory(parent) {
+                Some(d) => self
+                    .files(d)?
+                    .unwrap_or_default()
+                    .into_iter()
+                    .find(|f| compare_file_names(&f.0, name) == Ordering::Equal)
+                    .map(|(_, source, format)| Item::File(source, format)),
+                None => None,
+            },
+            _ => None,
+        })
+    }
+
+    fn render(&self, source: &Source, format: Format) -> rusqlite::Result<Arc<Rendering>> {
+        let sql = match source {
+            Source::Table(name) => format!("SELECT * FROM \"{}\"", name.replace('"', "\"\"")),
+            Source::Query(i) => self.queries[*i].sql.clone(),
+        };
+        let time = filetime::now();
+        Ok(Arc::new(Rendering {
+            data: render(&self.connect()?, &sql, format)?.into(),
+            time,
+            made: Instant::now(),
+        }))
+    }
+
+    /// Keeps a rendering for its hydration, dropping the oldest others while there are too
+    /// many bytes kept. One that's bigger than all of them is kept on its own.
+    fn keep(&self, key: Key, rendering: Arc<Rendering>) {
+        let mut renderings = self.renderings.lock().unwrap();
+        renderings.insert(key.clone(), rendering);
+        let mut kept: usize = renderings.values().map(|r| r.data.len()).sum();
+        while kept > self.kept_bytes {
+            let oldest = renderings.iter().filter(|(k, _)| **k != key).min_by_key(|(_, r)| r.made).map(|(k, _)| k.clone());
+            match oldest {
+                Some(oldest) => kept -= renderings.remove(&oldest).unwrap().data.len(),
+                None => break,
+            }
+        }
+    }
+
+    /// Renders the file for a new placeholder and keeps the rendering for its hydration.
+    /// One that's younger than `FRESH_FOR` is used again.
+    fn placeholder_info(&self, key: Key) -> rusqlite::Result<EntryInfo> {
+        let fresh = self.renderings.lock().unwrap().get(&key).filter(|r| r.made.elapsed() < FRESH_FOR).cloned();
+        let rendering = match fresh {
+            Some(rendering) => rendering,
+            None => {
+                let rendering = self.render(&key.0, key.1)?;
+                self.keep(key.clone(), rendering.clone());
+                rendering
+            }
+        };
+        self.sizes.lock().unwrap().insert(key, rendering.data.len() as u64);
+        Ok(EntryInfo::file(rendering.data.len() as u64, rendering.time))
+    }
+
+    /// What's known without rendering: the kept rendering, or the size of the latest placeholder
+    fn listed_info(&self, key: &Key) -> EntryInfo {
+        if let Some(rendering) = self.renderings.lock().unwrap().get(key) {
+            return EntryInfo::file(rendering.data.len() as u64, rendering.time);
+        }
+        EntryInfo::file(self.sizes.lock().unwrap().get(key).copied().unwrap_or(0), self.load_time)
+    }
+
+    /// The kept rendering, or a new one if it has the size the placeholder was made with
+    fn hydration_rendering(&self, file_path: &Path, key: &Key) -> Result<Arc<Rendering>, windows::core::HRESULT> {
+        if let Some(rendering) = self.renderings.lock().unwrap().get(key) {
+            return Ok(rendering.clone());
+        }
+        // Hydrating again after a failure, or a placeholder of an earlier run which can't be checked
+        let rendering = self.render(&key.0, key.1).map_err(|e| database_error(file_path, e))?;
+        if let Some(size) = self.sizes.lock().unwrap().get(key) {
+            if *size != rendering.data.len() as u64 {
+                println!("{file_path:?} changed since its placeholder was made, delete it to see the current rows");
+                return Err(windows::Win32::Foundation::E_FAIL);
+            }
+        }
+        self.keep(key.clone(), rendering.clone());
+        Ok(rendering)
+    }
+}
+
+impl ProjFSProvider for SqliteDataProvider {
+    fn init(&mut self, _root: &Path) -> Result<VirtualizationOptions, Box<dyn std::error::Error>> {
+        Ok(VirtualizationOptions::default())
+    }
+
+    fn start(&mut self, _context: ProjectedFileSystem::PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn new_enumeration(&self, _id: windows::core::GUID, file_path: &Path) -> Box<dyn EnumerationState> {
+        let directory_info = EntryInfo::directory(self.load_time).basic_info();
+        let mut entries = Vec::new();
+        if file_path.as_os_str().is_empty() {
+            entries = self.directories().into_iter().map(|d| (OsString::from(d), directory_info)).collect();
+        } else if let Some(name) = self.directory(file_path.as_os_str()) {
+            match self.files(name) {
+                Ok(files) => {
+                    for (file_name, source, format) in files.unwrap_or_default() {
+                        entries.push((file_name, self.listed_info(&(source, format)).basic_info()));
+                    }
+                }
+                Err(e) => println!("Could not list {file_path:?}: {e}"),
+            }
+        }
+        Box::new(ListEnumeration::sorted(entries))
+    }
+
+    fn get_placeholder_info(&self, file_path: &Path) -> Result<ProjectedFileSystem::PRJ_PLACEHOLDER_INFO, windows::core::HRESULT> {
+        match self.resolve(file_path) {
+            Ok(Some(Item::Directory)) => Ok(EntryInfo::directory(self.load_time).placeholder_info()),
+            Ok(Some(Item::File(source, format))) => match self.placeholder_info((source, format)) {
+                Ok(info) => Ok(info.placeholder_info()),
+                Err(e) => Err(database_error(file_path, e)),
+            },
+            Ok(None) => Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into()),
+            Err(e) => Err(database_error(file_path, e)),
+        }
+    }
+
+    fn get_file_data(&self, file_path: &Path) -> Result<Box<dyn SeekRead>, windows::core::HRESULT> {
+        let (source, format) = match self.resolve(file_path) {
+            Ok(Some(Item::File(source, format))) => (source, format),
+            Ok(_) => return Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into()),
+            Err(e) => return Err(database_error(file_path, e)),
+        };
+        let key = (source, format);
+        let rendering = self.hydration_rendering(file_path, &key)?;
+        Ok(Box::new(RenderingReader {
+            data: Cursor::new(rendering.data.clone()),
+            key,
+            renderings: self.renderings.clone(),
+        }))
+    }
+
+    fn query_file_name(&self, file_path: &Path) -> windows::core::HRESULT {
+        match self.resolve(file_path) {
+            Ok(Some(_)) => windows::Win32::Foundation::S_OK,
+            Ok(None) => windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into(),
+            Err(e) => database_error(file_path, e),
+        }
+    }
+
+    fn notification(&self, _callbackdata: *const ProjectedFileSystem::PRJ_CALLBACK_DATA, _is_directory: bool, _notification: ProjectedFileSystem::PRJ_NOTIFICATION, _destinationfilename: windows::core::PCWSTR, _operationparameters: *mut ProjectedFileSystem::PRJ_NOTIFICATION_PARAMETERS) -> windows::core::HRESULT {
+        windows::Win32::Foundation::S_OK
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A database file that is removed again when the test is done
+    struct Fixture(PathBuf, Connection);
+
+    impl Fixture {
+        fn new(name: &str) -> Fixture {
+            let path = std::env::temp_dir().join(format!("projfs-sqlite-data-{}-{name}.db", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            let connection = Connection::open(&path).unwrap();
+            connection.execute_batch("CREATE TABLE items (id INTEGER PRIMARY KEY, name TEXT); INSERT INTO items VALUES (1, 'one');").unwrap();
+            Fixture(path, connection)
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn items() -> Key {
+        (Source::Table("items".to_string()), Format::Csv)
+    }
+
+    fn size(provider: &SqliteDataProvider) -> i64 {
+        provider.get_placeholder_info(Path::new("tables/items.csv")).unwrap().FileBasicInfo.FileSize
+    }
+
+    fn read(provider: &SqliteDataProvider) -> Result<String, windows::core::HRESULT> {
+        let mut data = String::new();
+        provider.get_file_data(Path::new("tables/items.csv"))?.read_to_string(&mut data).unwrap();
+        Ok(data)
+    }
+
+    #[test]
+    fn lists_without_rendering() {
+        let fixture = Fixture::new("listing");
+        let provider = SqliteDataProvider::new(&fixture.0, None).unwrap();
+        assert_eq!(provider.listed_info(&items()).size, 0);
+        assert!(provider.renderings.lock().unwrap().is_empty());
+        assert_eq!(size(&provider), 16);
+        assert_eq!(provider.listed_info(&items()).size, 16);
+        assert_eq!(provider.query_file_name(Path::new("tables/ITEMS.jsonl")), windows::Win32::Foundation::S_OK);
+    }
+
+    #[test]
+    fn hydrates_what_the_placeholder_was_made_from() {
+        let fixture = Fixture::new("hydration");
+        let provider = SqliteDataProvider::new(&fixture.0, None).unwrap();
+        assert_eq!(size(&provider), 16);
+        fixture.1.execute_batch("INSERT INTO items VALUES (2, 'two')").unwrap();
+        assert_eq!(read(&provider).unwrap(), "id,name\r\n1,one\r\n");
+        // Hydrated, the rendering is gone but its size is remembered
+        assert!(provider.renderings.lock().unwrap().is_empty());
+        assert_eq!(provider.listed_info(&items()).size, 16);
+    }
+
+    #[test]
+    fn fails_rather_than_changing_the_size() {
+        let fixture = Fixture::new("size");
+        let provider = SqliteDataProvider::new(&fixture.0, None).unwrap();
+        assert_eq!(size(&provider), 16);
+        assert_eq!(read(&provider).unwrap(), "id,name\r\n1,one\r\n");
+        fixture.1.execute_batch("UPDATE items SET name = 'three'").unwrap();
+        assert_eq!(read(&provider).err(), Some(windows::Win32::Foundation::E_FAIL));
+        fixture.1.execute_batch("UPDATE items SET name = 'uno'").unwrap();
+        assert_eq!(read(&provider).unwrap(), "id,name\r\n1,uno\r\n");
+    }
+
+    #[test]
+    fn drops_the_oldest_renderings_past_the_limit() {
+        let fixture = Fixture::new("limit");
+        let mut provider = SqliteDataProvider::new(&fixture.0, None).unwrap();
+        // Room for the CSV or the JSON Lines, not both
+        provider.kept_bytes = 30;
+        assert_eq!(size(&provider), 16);
+        provider.get_placeholder_info(Path::new("tables/items.jsonl")).unwrap();
+        let kept: Vec<Key> = provider.renderings.lock().unwrap().keys().cloned().collect();
+        assert_eq!(kept, [(Source::Table("items".to_string()), Format::JsonLines)]);
+        // Still the same size, so it can be rendered again
+        assert_eq!(read(&provider).unwrap(), "id,name\r\n1,one\r\n");
+    }
+}
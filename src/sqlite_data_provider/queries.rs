@@ -0,0 +1,60 @@
+use std::cmp::Ordering;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::projfs_provider::compare_file_names;
+
+/// Queries to project next to the tables, as JSON or TOML depending on the extension:
+///
+/// ```toml
+/// [[query]]
+/// name = "open-orders"
+/// sql = "SELECT id, customer, total FROM orders WHERE shipped IS NULL"
+/// ```
+///
+/// Each one shows up as `queries/<name>.csv` and `queries/<name>.jsonl`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SavedQueries {
+    #[serde(default, alias = "query")]
+    pub queries: Vec<SavedQuery>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SavedQuery {
+    pub name: String,
+    pub sql: String,
+}
+
+/// Characters Windows doesn't allow in file names
+pub fn is_valid_file_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.chars().any(|c| c.is_control() || "\\/:*?\"<>|".contains(c))
+}
+
+impl SavedQueries {
+    pub fn load(path: &Path) -> Result<SavedQueries, Box<dyn std::error::Error>> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{path:?}: {e}"))?;
+        let is_toml = path.extension().map(|e| e.eq_ignore_ascii_case("toml")).unwrap_or(false);
+        let saved: SavedQueries = if is_toml {
+            toml::from_str(&text).map_err(|e| format!("{path:?}: {e}"))?
+        } else {
+            serde_json::from_str(&text).map_err(|e| format!("{path:?}: {e}"))?
+        };
+        for (i, query) in saved.queries.iter().enumerate() {
+            if !is_valid_file_name(&query.name) {
+                return Err(format!("{:?} can't be used as a file name", query.name).into());
+            }
+            if saved.queries[..i].iter().any(|q| compare_file_names(OsStr::new(&q.name), OsStr::new(&query.name)) == Ordering::Equal) {
+                return Err(format!("There is more than one query named {:?}", query.name).into());
+            }
+        }
+        Ok(saved)
+    }
+}
@@ -0,0 +1,102 @@
+use std::fmt::Write;
+
+use rusqlite::types::ValueRef;
+use rusqlite::Connection;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Format {
+    /// RFC 4180, with a header row
+    Csv,
+    /// One JSON object per row, keys in column order
+    JsonLines,
+}
+
+impl Format {
+    pub const ALL: [Format; 2] = [Format::Csv, Format::JsonLines];
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::JsonLines => "jsonl",
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::with_capacity(bytes.len() * 2), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    })
+}
+
+fn csv_field(out: &mut String, value: ValueRef) {
+    let text = match value {
+        ValueRef::Null => return,
+        ValueRef::Integer(i) => i.to_string(),
+        ValueRef::Real(f) => f.to_string(),
+        ValueRef::Text(t) => String::from_utf8_lossy(t).into_owned(),
+        ValueRef::Blob(b) => hex(b),
+    };
+    if text.contains([',', '"', '\r', '\n']) {
+        out.push('"');
+        out.push_str(&text.replace('"', "\"\""));
+        out.push('"');
+    } else {
+        out.push_str(&text);
+    }
+}
+
+fn json_value(value: ValueRef) -> serde_json::Value {
+    match value {
+        ValueRef::Null => serde_json::Value::Null,
+        ValueRef::Integer(i) => i.into(),
+        ValueRef::Real(f) => serde_json::Number::from_f64(f).map(serde_json::Value::Number).unwrap_or(serde_json::Value::Null),
+        ValueRef::Text(t) => String::from_utf8_lossy(t).into(),
+        ValueRef::Blob(b) => hex(b).into(),
+    }
+}
+
+/// Runs `sql` and writes out every row it returns. Blobs become hex strings.
+pub fn render(connection: &Connection, sql: &str, format: Format) -> rusqlite::Result<Vec<u8>> {
+    let mut statement = connection.prepare(sql)?;
+    let columns: Vec<String> = statement.column_names().into_iter().map(String::from).collect();
+    let mut out = String::new();
+    if format == Format::Csv {
+        for (i, column) in columns.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            csv_field(&mut out, ValueRef::Text(column.as_bytes()));
+        }
+        out.push_str("\r\n");
+    }
+    // Column names as JSON strings, serde_json's maps would sort them
+    let keys: Vec<String> = columns.iter().map(|c| serde_json::Value::from(c.as_str()).to_string()).collect();
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        match format {
+            Format::Csv => {
+                for i in 0..columns.len() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    csv_field(&mut out, row.get_ref(i)?);
+                }
+                out.push_str("\r\n");
+            }
+            Format::JsonLines => {
+                out.push('{');
+                for (i, key) in keys.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    out.push_str(key);
+                    out.push(':');
+                    out.push_str(&json_value(row.get_ref(i)?).to_string());
+                }
+                out.push_str("}\n");
+            }
+        }
+    }
+    Ok(out.into_bytes())
+}
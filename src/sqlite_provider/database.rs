@@ -0,0 +1,172 @@
+use std::cmp::Ordering;
+use std::ffi::OsStr;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, Row};
+
+use crate::projfs_provider::compare_file_names;
+
+/// Tables the provider reads. The database is only ever opened read-only, so they have to be
+/// there already. Entries at the top of the projection have parent 0, files without content are
+/// empty and files can share a content row. Names compare like ProjFS does for ASCII.
+pub const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS content (
+    id INTEGER PRIMARY KEY,
+    data BLOB NOT NULL
+);
+CREATE TABLE IF NOT EXISTS entries (
+    id INTEGER PRIMARY KEY,
+    parent INTEGER NOT NULL DEFAULT 0,
+    name TEXT NOT NULL COLLATE NOCASE,
+    is_directory INTEGER NOT NULL DEFAULT 0,
+    mtime INTEGER,
+    readonly INTEGER NOT NULL DEFAULT 0,
+    content INTEGER REFERENCES content(id),
+    UNIQUE (parent, name)
+);
+";
+
+const CHILD_QUERY: &str = "SELECT entries.id, entries.name, entries.is_directory, length(content.data), entries.mtime, entries.readonly, entries.content
+    FROM entries LEFT JOIN content ON content.id = entries.content
+    WHERE entries.parent = ?1 AND entries.name = ?2";
+
+const CHILDREN_QUERY: &str = "SELECT entries.id, entries.name, entries.is_directory, length(content.data), entries.mtime, entries.readonly, entries.content
+    FROM entries LEFT JOIN content ON content.id = entries.content
+    WHERE entries.parent = ?1";
+
+/// How long a connection waits for a writer to let go of the database
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A row of `entries`, size is the length of its content
+pub struct Entry {
+    pub id: i64,
+    pub name: String,
+    pub is_directory: bool,
+    pub size: u64,
+    /// Unix seconds
+    pub mtime: Option<i64>,
+    pub readonly: bool,
+    pub content: Option<i64>,
+}
+
+fn entry_from_row(row: &Row) -> rusqlite::Result<Entry> {
+    Ok(Entry {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        is_directory: row.get(2)?,
+        size: row.get::<_, Option<i64>>(3)?.unwrap_or(0) as u64,
+        mtime: row.get(4)?,
+        readonly: row.get(5)?,
+        content: row.get(6)?,
+    })
+}
+
+/// Read-only connections to the database, kept around between callbacks
+pub struct Database {
+    path: PathBuf,
+    idle: Mutex<Vec<Connection>>,
+}
+
+impl Database {
+    /// Opens the database read-only. It should be in WAL mode (`PRAGMA journal_mode = WAL`,
+    /// which sticks with the file): then a read transaction keeps seeing the commit it started
+    /// with while writers carry on, otherwise writers wait for every listing and hydration.
+    pub fn open(path: &Path) -> Result<Database, Box<dyn std::error::Error>> {
+        let database = Database {
+            path: path.to_path_buf(),
+            idle: Mutex::new(Vec::new()),
+        };
+        let connection = database.acquire()?;
+        // Preparing the queries checks the tables and columns they need
+        if let Err(e) = connection.prepare(CHILDREN_QUERY) {
+            return Err(format!("{path:?} doesn't have the tables this needs ({e}), they look like this:\n{SCHEMA}").into());
+        }
+        let journal_mode: String = connection.query_row("PRAGMA journal_mode", [], |row| row.get(0))?;
+        if !journal_mode.eq_ignore_ascii_case("wal") {
+            println!("{path:?} isn't in WAL mode, writers will have to wait for the projection to finish reading");
+        }
+        database.release(connection);
+        Ok(database)
+    }
+
+    /// An idle connection or a new one, give it back with `release`
+    pub fn acquire(&self) -> rusqlite::Result<Connection> {
+        if let Some(connection) = self.idle.lock().unwrap().pop() {
+            return Ok(connection);
+        }
+        let connection = Connection::open_with_flags(&self.path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)?;
+        connection.busy_timeout(BUSY_TIMEOUT)?;
+        Ok(connection)
+    }
+
+    pub fn release(&self, connection: Connection) {
+        self.idle.lock().unwrap().push(connection);
+    }
+
+    /// Runs `f` in a read transaction so everything it queries comes from the same commit
+    pub fn snapshot<T>(&self, f: impl FnOnce(&Connection) -> rusqlite::Result<T>) -> rusqlite::Result<T> {
+        let connection = self.acquire()?;
+        // Dropping the transaction rolls it back, there's nothing to commit
+        let result = connection.unchecked_transaction().and_then(|transaction| f(&transaction));
+        self.release(connection);
+        result
+    }
+}
+
+fn child(connection: &Connection, parent: i64, name: &OsStr) -> rusqlite::Result<Option<Entry>> {
+    let name_text = match name.to_str() {
+        Some(n) => n,
+        None => return Ok(None),
+    };
+    let mut statement = connection.prepare_cached(CHILD_QUERY)?;
+    let entry = statement.query_row(params![parent, name_text], entry_from_row).optional()?;
+    if entry.is_some() || name_text.is_ascii() {
+        return Ok(entry);
+    }
+    // NOCASE only folds ASCII, anything else gets compared the way ProjFS does
+    Ok(children(connection, parent)?
+        .into_iter()
+        .find(|e| compare_file_names(OsStr::new(&e.name), name) == Ordering::Equal))
+}
+
+pub fn children(connection: &Connection, parent: i64) -> rusqlite::Result<Vec<Entry>> {
+    let mut statement = connection.prepare_cached(CHILDREN_QUERY)?;
+    let rows = statement.query_map(params![parent], entry_from_row)?;
+    rows.collect()
+}
+
+/// Follows `path` one name at a time, None for the root and paths that aren't there
+pub fn lookup(connection: &Connection, path: &Path) -> rusqlite::Result<Option<Entry>> {
+    let mut found: Option<Entry> = None;
+    for component in path.components() {
+        let name = match component {
+            Component::Normal(name) => name,
+            _ => return Ok(None),
+        };
+        let parent = match &found {
+            Some(entry) if !entry.is_directory => return Ok(None),
+            Some(entry) => entry.id,
+            None => 0,
+        };
+        found = match child(connection, parent, name)? {
+            Some(entry) => Some(entry),
+            None => return Ok(None),
+        };
+    }
+    Ok(found)
+}
+
+/// Entries of the directory at `path`, None if there's no directory there
+pub fn list(connection: &Connection, path: &Path) -> rusqlite::Result<Option<Vec<Entry>>> {
+    let parent = if path.as_os_str().is_empty() {
+        0
+    } else {
+        match lookup(connection, path)? {
+            Some(entry) if entry.is_directory => entry.id,
+            _ => return Ok(None),
+        }
+    };
+    children(connection, parent).map(Some)
+}
@@ -0,0 +1,4 @@
+mod database;
+mod provider;
+
+pub use provider::SqliteProvider;
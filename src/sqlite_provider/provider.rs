@@ -0,0 +1,300 @@
+use std::ffi::OsString;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Arc;
+
+use rusqlite::{Connection, DatabaseName};
+use windows::Win32::Storage::{FileSystem, ProjectedFileSystem};
+
+use crate::projfs_provider::{filetime, ProjFSProvider, EnumerationState, ListEnumeration, SeekRead, VirtualizationOptions, EntryInfo};
+use super::database::{self, Database, Entry};
+
+/// Projects the entries of a SQLite database, see `database.rs` for its tables and why it
+/// should be in WAL mode. Listings and hydrations each read from a single commit, so they
+/// can't see half of what another process writes to the database in the meantime.
+pub struct SqliteProvider {
+    database: Arc<Database>,
+    load_time: i64,
+}
+
+/// Reads a content blob a piece at a time. The reader's connection stays in the read
+/// transaction the file was looked up in until it's dropped.
+struct BlobReader {
+    database: Arc<Database>,
+    // Only taken back in `drop`
+    connection: Option<Connection>,
+    content: i64,
+    size: u64,
+    position: u64,
+}
+
+impl BlobReader {
+    fn new(database: Arc<Database>, connection: Connection, content: i64, size: u64) -> BlobReader {
+        BlobReader {
+            database,
+            connection: Some(connection),
+            content,
+            size,
+            position: 0,
+        }
+    }
+}
+
+impl Read for BlobReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position >= self.size {
+            return Ok(0);
+        }
+        // Opened for every read, which is cheap next to the callback it serves
+        let mut blob = self.connection
+            .as_ref()
+            .unwrap()
+            .blob_open(DatabaseName::Main, "content", "data", self.content, true)
+            .map_err(std::io::Error::other)?;
+        blob.seek(SeekFrom::Start(self.position))?;
+        let read = blob.read(buf)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for BlobReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(v) => Some(v),
+            SeekFrom::End(v) => self.size.checked_add_signed(v),
+            SeekFrom::Current(v) => self.position.checked_add_signed(v),
+        };
+        match new_position {
+            Some(v) => {
+                self.position = v;
+                Ok(self.position)
+            }
+            None => Err(std::io::Error::from(std::io::ErrorKind::InvalidInput)),
+        }
+    }
+}
+
+impl Drop for BlobReader {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            if connection.execute_batch("ROLLBACK").is_ok() {
+                self.database.release(connection);
+            }
+        }
+    }
+}
+
+fn database_error(file_path: &Path, e: rusqlite::Error) -> windows::core::HRESULT {
+    println!("Database query for {file_path:?} failed: {e}");
+    windows::Win32::Foundation::E_FAIL
+}
+
+impl SqliteProvider {
+    pub fn new(database: &Path) -> Result<SqliteProvider, Box<dyn std::error::Error>> {
+        Ok(SqliteProvider {
+            database: Arc::new(Database::open(database)?),
+            load_time: filetime::now(),
+        })
+    }
+
+    fn entry_info(&self, entry: &Entry) -> EntryInfo {
+        let time = entry.mtime.map(filetime::from_unix_seconds).unwrap_or(self.load_time);
+        let mut info = if entry.is_directory {
+            EntryInfo::directory(time)
+        } else {
+            EntryInfo::file(entry.size, time)
+        };
+        if entry.readonly {
+            info.attributes = FileSystem::FILE_ATTRIBUTE_READONLY.0;
+        }
+        info
+    }
+
+    fn lookup(&self, file_path: &Path) -> Result<Entry, windows::core::HRESULT> {
+        match self.database.snapshot(|c| database::lookup(c, file_path)) {
+            Ok(Some(entry)) => Ok(entry),
+            Ok(None) => Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into()),
+            Err(e) => Err(database_error(file_path, e)),
+        }
+    }
+}
+
+impl ProjFSProvider for SqliteProvider {
+    fn init(&mut self, _root: &Path) -> Result<VirtualizationOptions, Box<dyn std::error::Error>> {
+        Ok(VirtualizationOptions::default())
+    }
+
+    fn start(&mut self, _context: ProjectedFileSystem::PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn new_enumeration(&self, _id: windows::core::GUID, file_path: &Path) -> Box<dyn EnumerationState> {
+        // The whole listing is read in one transaction, later commits show up in the next enumeration
+        let entries = match self.database.snapshot(|c| database::list(c, file_path)) {
+            Ok(entries) => entries.unwrap_or_default(),
+            Err(e) => {
+                println!("Could not list {file_path:?}: {e}");
+                Vec::new()
+            }
+        };
+        let entries = entries
+            .iter()
+            .map(|e| (OsString::from(&e.name), self.entry_info(e).basic_info()))
+            .collect();
+        Box::new(ListEnumeration::sorted(entries))
+    }
+
+    fn get_placeholder_info(&self, file_path: &Path) -> Result<ProjectedFileSystem::PRJ_PLACEHOLDER_INFO, windows::core::HRESULT> {
+        let entry = self.lookup(file_path)?;
+        let mut placeholder = self.entry_info(&entry).placeholder_info();
+        // A new content row means new data, even if the size stays the same
+        if let Some(content) = entry.content {
+            let content_id = &mut placeholder.VersionInfo.ContentID;
+            content_id[..8].copy_from_slice(&content.to_le_bytes());
+            content_id[8..16].copy_from_slice(&entry.size.to_le_bytes());
+        }
+        Ok(placeholder)
+    }
+
+    fn get_file_data(&self, file_path: &Path) -> Result<Box<dyn SeekRead>, windows::core::HRESULT> {
+        let connection = self.database.acquire().map_err(|e| database_error(file_path, e))?;
+        // Left open until the reader is done, so the blob can't change between reads
+        let entry = connection
+            .execute_batch("BEGIN")
+            .and_then(|_| database::lookup(&connection, file_path));
+        let entry = match entry {
+            Ok(Some(entry)) if !entry.is_directory => entry,
+            result => {
+                if connection.execute_batch("ROLLBACK").is_ok() {
+                    self.database.release(connection);
+                }
+                return Err(match result {
+                    Err(e) => database_error(file_path, e),
+                    _ => windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into(),
+                });
+            }
+        };
+        Ok(Box::new(BlobReader::new(self.database.clone(), connection, entry.content.unwrap_or(0), entry.size)))
+    }
+
+    fn query_file_name(&self, file_path: &Path) -> windows::core::HRESULT {
+        match self.lookup(file_path) {
+            Ok(_) => windows::Win32::Foundation::S_OK,
+            Err(e) => e,
+        }
+    }
+
+    fn notification(&self, _callbackdata: *const ProjectedFileSystem::PRJ_CALLBACK_DATA, _is_directory: bool, _notification: ProjectedFileSystem::PRJ_NOTIFICATION, _destinationfilename: windows::core::PCWSTR, _operationparameters: *mut ProjectedFileSystem::PRJ_NOTIFICATION_PARAMETERS) -> windows::core::HRESULT {
+        windows::Win32::Foundation::S_OK
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::params;
+
+    use super::*;
+    use super::super::database::SCHEMA;
+
+    /// A database file that is removed again, with its WAL files, when the test is done
+    struct Fixture(std::path::PathBuf);
+
+    impl Fixture {
+        fn new(name: &str, schema: bool) -> (Fixture, Connection) {
+            let path = std::env::temp_dir().join(format!("projfs-sqlite-{}-{name}.db", std::process::id()));
+            let fixture = Fixture(path);
+            fixture.remove();
+            let connection = Connection::open(&fixture.0).unwrap();
+            if schema {
+                connection.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(())).unwrap();
+                connection.execute_batch(SCHEMA).unwrap();
+            }
+            (fixture, connection)
+        }
+
+        fn remove(&self) {
+            for suffix in ["", "-wal", "-shm"] {
+                let mut path = self.0.clone().into_os_string();
+                path.push(suffix);
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            self.remove();
+        }
+    }
+
+    fn read(provider: &SqliteProvider, path: &str) -> Vec<u8> {
+        let mut data = Vec::new();
+        provider.get_file_data(Path::new(path)).ok().unwrap().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn needs_the_tables_and_leaves_the_database_alone() {
+        let (fixture, connection) = Fixture::new("no-schema", false);
+        connection.execute_batch("CREATE TABLE other (id INTEGER)").unwrap();
+        let error = SqliteProvider::new(&fixture.0).err().unwrap().to_string();
+        assert!(error.contains("CREATE TABLE IF NOT EXISTS entries"), "{error}");
+        let tables: i64 = connection.query_row("SELECT count(*) FROM sqlite_master", [], |r| r.get(0)).unwrap();
+        assert_eq!(tables, 1);
+        let journal_mode: String = connection.query_row("PRAGMA journal_mode", [], |r| r.get(0)).unwrap();
+        assert_eq!(journal_mode, "delete");
+    }
+
+    #[test]
+    fn projects_entries_and_content() {
+        let (fixture, connection) = Fixture::new("entries", true);
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 253) as u8).collect();
+        connection.execute("INSERT INTO content (id, data) VALUES (1, ?1)", params![data]).unwrap();
+        connection.execute_batch("
+            INSERT INTO entries (id, parent, name, is_directory) VALUES (1, 0, 'Dir', 1);
+            INSERT INTO entries (id, parent, name, mtime, readonly, content) VALUES (2, 1, 'data.bin', 1650000000, 1, 1);
+            INSERT INTO entries (id, parent, name) VALUES (3, 1, 'empty.txt');
+        ").unwrap();
+        let provider = SqliteProvider::new(&fixture.0).unwrap();
+        let info = provider.get_placeholder_info(Path::new("dir/DATA.BIN")).unwrap();
+        assert_eq!(info.FileBasicInfo.FileSize, 100_000);
+        assert_eq!(info.FileBasicInfo.FileAttributes, FileSystem::FILE_ATTRIBUTE_READONLY.0);
+        assert_eq!(info.FileBasicInfo.LastWriteTime, filetime::from_unix_seconds(1_650_000_000));
+        assert_eq!(read(&provider, "Dir/data.bin"), data);
+        assert_eq!(read(&provider, "Dir/empty.txt"), b"");
+        assert!(provider.get_file_data(Path::new("Dir")).is_err());
+        assert_eq!(provider.query_file_name(Path::new("Dir/missing")), windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into());
+
+        let mut reader = provider.get_file_data(Path::new("Dir/data.bin")).ok().unwrap();
+        let mut piece = [0u8; 1000];
+        for offset in [99_000, 5, 50_000] {
+            reader.seek(SeekFrom::Start(offset)).unwrap();
+            reader.read_exact(&mut piece).unwrap();
+            assert_eq!(piece[..], data[offset as usize..][..1000]);
+        }
+    }
+
+    #[test]
+    fn readers_keep_the_commit_they_started_with() {
+        let (fixture, connection) = Fixture::new("snapshot", true);
+        connection.execute_batch("
+            INSERT INTO content (id, data) VALUES (1, x'0102030405');
+            INSERT INTO entries (id, parent, name, content) VALUES (1, 0, 'file', 1);
+        ").unwrap();
+        let provider = SqliteProvider::new(&fixture.0).unwrap();
+        let mut reader = provider.get_file_data(Path::new("file")).ok().unwrap();
+        let mut first = [0u8; 2];
+        reader.read_exact(&mut first).unwrap();
+        connection.execute_batch("UPDATE content SET data = x'0909090909' WHERE id = 1").unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!([&first[..], &rest].concat(), [1, 2, 3, 4, 5]);
+        drop(reader);
+        assert_eq!(read(&provider, "file"), [9; 5]);
+    }
+}
@@ -0,0 +1,721 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use windows::Win32::Storage::FileSystem;
+
+use crate::projfs_provider::{filetime, EntryInfo, VirtualTree};
+
+const MAGIC: u32 = 0x73717368;
+const SUPERBLOCK_SIZE: usize = 96;
+const METADATA_BLOCK_SIZE: usize = 8192;
+const METADATA_UNCOMPRESSED: u16 = 0x8000;
+/// Set in the size of data blocks and fragments that are stored as they are
+const DATA_UNCOMPRESSED: u32 = 1 << 24;
+const NO_FRAGMENT: u32 = 0xffff_ffff;
+const FRAGMENT_ENTRY_SIZE: usize = 16;
+
+const BASIC_DIRECTORY: u16 = 1;
+const BASIC_FILE: u16 = 2;
+const BASIC_SYMLINK: u16 = 3;
+const EXTENDED_DIRECTORY: u16 = 8;
+const EXTENDED_FILE: u16 = 9;
+const EXTENDED_SYMLINK: u16 = 10;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Compression {
+    Gzip,
+    Lzma,
+    Xz,
+    Zstd,
+}
+
+/// Where a data block or fragment block is, `size` still has the uncompressed flag
+#[derive(Clone, Copy, Debug)]
+pub struct Block {
+    pub start: u64,
+    pub size: u32,
+}
+
+/// Where the content of a file comes from, kept in the tree
+#[derive(Debug)]
+pub struct FileData {
+    pub size: u64,
+    /// All but the tail if it sits in a fragment, a size of 0 is a sparse block
+    pub blocks: Vec<Block>,
+    /// Fragment block holding the tail and where in there it starts
+    pub fragment: Option<(Block, u32)>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub enum SquashContent {
+    /// Directories
+    #[default]
+    None,
+    File(Arc<FileData>),
+    /// Where a symlink leads, from the root. Only until the whole tree is read.
+    Link(String),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Superblock {
+    pub block_size: u32,
+    pub compression: Compression,
+    fragment_count: u32,
+    root_inode: u64,
+    inode_table: u64,
+    directory_table: u64,
+    fragment_table: u64,
+}
+
+enum Inode {
+    Directory {
+        mtime: u32,
+        block: u32,
+        offset: u16,
+        /// Of the listing, which is 3 bytes shorter
+        size: u32,
+    },
+    File {
+        mtime: u32,
+        mode: u16,
+        data: FileData,
+    },
+    Symlink {
+        mtime: u32,
+        target: String,
+    },
+    /// Devices, fifos and sockets
+    Other,
+}
+
+fn u16_at(b: &[u8], o: usize) -> u16 {
+    u16::from_le_bytes([b[o], b[o + 1]])
+}
+
+fn u32_at(b: &[u8], o: usize) -> u32 {
+    u32::from_le_bytes(b[o..o + 4].try_into().unwrap())
+}
+
+fn u64_at(b: &[u8], o: usize) -> u64 {
+    u64::from_le_bytes(b[o..o + 8].try_into().unwrap())
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+/// Sizes that come out of the image, checked before anything is allocated for them
+fn bounded(size: u64, image_length: u64) -> std::io::Result<usize> {
+    if size > image_length {
+        return Err(invalid(&format!("{size} bytes don't fit into the image")));
+    }
+    Ok(size as usize)
+}
+
+fn read_at(file: &mut File, offset: u64, size: usize) -> std::io::Result<Vec<u8>> {
+    let mut data = vec![0u8; size];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut data)?;
+    Ok(data)
+}
+
+impl Compression {
+    fn from_id(id: u16) -> Result<Compression, String> {
+        match id {
+            1 => Ok(Compression::Gzip),
+            2 => Ok(Compression::Lzma),
+            4 => Ok(Compression::Xz),
+            6 => Ok(Compression::Zstd),
+            3 => Err("lzo compressed images aren't supported".to_string()),
+            5 => Err("lz4 compressed images aren't supported".to_string()),
+            _ => Err(format!("Unknown compression {id}")),
+        }
+    }
+
+    /// Blocks are compressed on their own and never get bigger than `limit`, one byte more
+    /// is enough to know a broken one is
+    fn decompress(&self, data: &[u8], limit: usize) -> std::io::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(limit);
+        let cap = limit as u64 + 1;
+        match self {
+            // Despite the name these are zlib streams
+            Compression::Gzip => {
+                flate2::read::ZlibDecoder::new(data).take(cap).read_to_end(&mut out)?;
+            }
+            Compression::Lzma => {
+                lzma_rs::lzma_decompress(&mut &data[..], &mut TakeWrite { inner: &mut out, remaining: cap }).map_err(|e| invalid(&format!("{e:?}")))?;
+            }
+            Compression::Xz => {
+                lzma_rs::xz_decompress(&mut &data[..], &mut TakeWrite { inner: &mut out, remaining: cap }).map_err(|e| invalid(&format!("{e:?}")))?;
+            }
+            Compression::Zstd => {
+                let mut source = data;
+                let mut decoder = ruzstd::StreamingDecoder::new(&mut source).map_err(|e| invalid(&e))?;
+                (&mut decoder).take(cap).read_to_end(&mut out)?;
+            }
+        }
+        if out.len() > limit {
+            return Err(invalid("Block is bigger than it can be"));
+        }
+        Ok(out)
+    }
+}
+
+/// `Read::take` for the decoders that write what they decompress, it fails once the limit is reached
+struct TakeWrite<W> {
+    inner: W,
+    remaining: u64,
+}
+
+impl<W: Write> Write for TakeWrite<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.remaining == 0 && !buf.is_empty() {
+            return Err(invalid("Block is bigger than it can be"));
+        }
+        let length = std::cmp::min(buf.len() as u64, self.remaining) as usize;
+        let written = self.inner.write(&buf[..length])?;
+        self.remaining -= written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads a data or fragment block, sparse blocks aren't stored so they don't come through here
+pub fn read_block(file: &mut File, compression: Compression, block: Block, limit: usize) -> std::io::Result<Vec<u8>> {
+    let data = read_at(file, block.start, (block.size & !DATA_UNCOMPRESSED) as usize)?;
+    if block.size & DATA_UNCOMPRESSED != 0 {
+        Ok(data)
+    } else {
+        compression.decompress(&data, limit)
+    }
+}
+
+struct MetadataBlock {
+    data: Vec<u8>,
+    /// Where the block after this one starts
+    next: u64,
+}
+
+/// Reads the metadata tables. Directories and inodes are packed into the same few blocks, so
+/// those are kept once decompressed.
+pub struct Image {
+    file: File,
+    /// Nothing read from the image can be bigger than this
+    length: u64,
+    pub superblock: Superblock,
+    fragments: Vec<Block>,
+    metadata: HashMap<u64, Rc<MetadataBlock>>,
+}
+
+/// Reads metadata that runs on from one block into the next
+struct MetadataReader<'a> {
+    image: &'a mut Image,
+    block: u64,
+    offset: usize,
+}
+
+impl<'a> Read for MetadataReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut block = self.image.metadata_block(self.block)?;
+        if self.offset >= block.data.len() {
+            self.offset -= block.data.len();
+            self.block = block.next;
+            block = self.image.metadata_block(self.block)?;
+        }
+        let read = std::cmp::min(buf.len(), block.data.len().saturating_sub(self.offset));
+        buf[..read].copy_from_slice(&block.data[self.offset..self.offset + read]);
+        self.offset += read;
+        Ok(read)
+    }
+}
+
+impl Image {
+    pub fn open(path: &Path) -> Result<Image, Box<dyn std::error::Error>> {
+        let mut file = File::open(path).map_err(|e| format!("{path:?}: {e}"))?;
+        let header = read_at(&mut file, 0, SUPERBLOCK_SIZE)?;
+        if u32_at(&header, 0) != MAGIC {
+            return Err(format!("{path:?} is not a squashfs image").into());
+        }
+        if (u16_at(&header, 28), u16_at(&header, 30)) != (4, 0) {
+            return Err(format!("Squashfs {}.{} isn't supported", u16_at(&header, 28), u16_at(&header, 30)).into());
+        }
+        let superblock = Superblock {
+            block_size: u32_at(&header, 12),
+            fragment_count: u32_at(&header, 16),
+            compression: Compression::from_id(u16_at(&header, 20))?,
+            root_inode: u64_at(&header, 32),
+            inode_table: u64_at(&header, 64),
+            directory_table: u64_at(&header, 72),
+            fragment_table: u64_at(&header, 80),
+        };
+        if !superblock.block_size.is_power_of_two() || superblock.block_size > 1024 * 1024 {
+            return Err(format!("Invalid block size {}", superblock.block_size).into());
+        }
+
+        let length = file.metadata()?.len();
+        let mut image = Image {
+            file,
+            length,
+            superblock,
+            fragments: Vec::new(),
+            metadata: HashMap::new(),
+        };
+        image.read_fragment_table()?;
+        Ok(image)
+    }
+
+    fn metadata_block(&mut self, position: u64) -> std::io::Result<Rc<MetadataBlock>> {
+        if let Some(block) = self.metadata.get(&position) {
+            return Ok(block.clone());
+        }
+        let header = read_at(&mut self.file, position, 2)?;
+        let header = u16_at(&header, 0);
+        let size = (header & !METADATA_UNCOMPRESSED) as usize;
+        let data = read_at(&mut self.file, position + 2, size)?;
+        let data = if header & METADATA_UNCOMPRESSED != 0 {
+            data
+        } else {
+            self.superblock.compression.decompress(&data, METADATA_BLOCK_SIZE)?
+        };
+        let block = Rc::new(MetadataBlock {
+            data,
+            next: position + 2 + size as u64,
+        });
+        self.metadata.insert(position, block.clone());
+        Ok(block)
+    }
+
+    fn metadata_reader(&mut self, block: u64, offset: usize) -> MetadataReader<'_> {
+        MetadataReader {
+            image: self,
+            block,
+            offset,
+        }
+    }
+
+    /// The fragment entries sit in metadata blocks that an array of their positions points to
+    fn read_fragment_table(&mut self) -> std::io::Result<()> {
+        let count = self.superblock.fragment_count as usize;
+        if count == 0 {
+            return Ok(());
+        }
+        let table_size = bounded(count as u64 * FRAGMENT_ENTRY_SIZE as u64, self.length)?;
+        let block_count = table_size.div_ceil(METADATA_BLOCK_SIZE);
+        let positions = read_at(&mut self.file, self.superblock.fragment_table, block_count * 8)?;
+        let mut entries = vec![0u8; table_size];
+        for (i, chunk) in entries.chunks_mut(METADATA_BLOCK_SIZE).enumerate() {
+            self.metadata_reader(u64_at(&positions, i * 8), 0).read_exact(chunk)?;
+        }
+        self.fragments = entries.chunks(FRAGMENT_ENTRY_SIZE).map(|e| Block {
+            start: u64_at(e, 0),
+            size: u32_at(e, 8),
+        }).collect();
+        Ok(())
+    }
+
+    /// Inode references are the position of the metadata block in the inode table and the offset in it
+    fn read_inode(&mut self, reference: u64) -> std::io::Result<Inode> {
+        let block_size = self.superblock.block_size as u64;
+        let fragments_len = self.fragments.len();
+        let image_length = self.length;
+        let mut reader = self.metadata_reader(self.superblock.inode_table + (reference >> 16), (reference & 0xffff) as usize);
+        let mut header = [0u8; 16];
+        reader.read_exact(&mut header)?;
+        let kind = u16_at(&header, 0);
+        let mode = u16_at(&header, 2);
+        let mtime = u32_at(&header, 8);
+        let inode = match kind {
+            BASIC_DIRECTORY => {
+                let mut b = [0u8; 16];
+                reader.read_exact(&mut b)?;
+                Inode::Directory {
+                    mtime,
+                    block: u32_at(&b, 0),
+                    offset: u16_at(&b, 10),
+                    size: u16_at(&b, 8) as u32,
+                }
+            }
+            EXTENDED_DIRECTORY => {
+                let mut b = [0u8; 24];
+                reader.read_exact(&mut b)?;
+                Inode::Directory {
+                    mtime,
+                    block: u32_at(&b, 8),
+                    offset: u16_at(&b, 18),
+                    size: u32_at(&b, 4),
+                }
+            }
+            BASIC_FILE | EXTENDED_FILE => {
+                let (blocks_start, fragment, fragment_offset, size) = if kind == BASIC_FILE {
+                    let mut b = [0u8; 16];
+                    reader.read_exact(&mut b)?;
+                    (u32_at(&b, 0) as u64, u32_at(&b, 4), u32_at(&b, 8), u32_at(&b, 12) as u64)
+                } else {
+                    let mut b = [0u8; 40];
+                    reader.read_exact(&mut b)?;
+                    (u64_at(&b, 0), u32_at(&b, 28), u32_at(&b, 32), u64_at(&b, 8))
+                };
+                // The tail only gets a block of its own without a fragment
+                let block_count = if fragment == NO_FRAGMENT {
+                    size.div_ceil(block_size)
+                } else {
+                    size / block_size
+                };
+                let mut sizes = vec![0u8; bounded(block_count.saturating_mul(4), image_length)?];
+                reader.read_exact(&mut sizes)?;
+                let mut start = blocks_start;
+                let blocks = sizes.chunks(4).map(|s| {
+                    let size = u32_at(s, 0);
+                    let block = Block {
+                        start,
+                        size,
+                    };
+                    start += (size & !DATA_UNCOMPRESSED) as u64;
+                    block
+                }).collect();
+                let fragment = if fragment == NO_FRAGMENT {
+                    None
+                } else if (fragment as usize) < fragments_len {
+                    Some((self.fragments[fragment as usize], fragment_offset))
+                } else {
+                    return Err(invalid("Fragment index out of range"));
+                };
+                Inode::File {
+                    mtime,
+                    mode,
+                    data: FileData {
+                        size,
+                        blocks,
+                        fragment,
+                    },
+                }
+            }
+            BASIC_SYMLINK | EXTENDED_SYMLINK => {
+                let mut b = [0u8; 8];
+                reader.read_exact(&mut b)?;
+                let mut target = vec![0u8; bounded(u32_at(&b, 4) as u64, image_length)?];
+                reader.read_exact(&mut target)?;
+                Inode::Symlink {
+                    mtime,
+                    target: String::from_utf8_lossy(&target).into_owned(),
+                }
+            }
+            _ => Inode::Other,
+        };
+        Ok(inode)
+    }
+
+    /// Names and inode references of a directory. The listing is made of runs of entries whose
+    /// inodes share a metadata block, each behind a header saying which block.
+    fn read_directory(&mut self, block: u32, offset: u16, size: u32) -> std::io::Result<Vec<(String, u64)>> {
+        let mut remaining = size.saturating_sub(3) as usize;
+        let mut reader = self.metadata_reader(self.superblock.directory_table + block as u64, offset as usize);
+        let mut entries = Vec::new();
+        while remaining > 0 {
+            let mut header = [0u8; 12];
+            reader.read_exact(&mut header)?;
+            remaining = remaining.checked_sub(header.len()).ok_or_else(|| invalid("Truncated directory"))?;
+            let count = u32_at(&header, 0) as usize + 1;
+            let start = u32_at(&header, 4) as u64;
+            for _ in 0..count {
+                let mut entry = [0u8; 8];
+                reader.read_exact(&mut entry)?;
+                let mut name = vec![0u8; u16_at(&entry, 6) as usize + 1];
+                reader.read_exact(&mut name)?;
+                remaining = remaining.checked_sub(entry.len() + name.len()).ok_or_else(|| invalid("Truncated directory"))?;
+                entries.push((String::from_utf8_lossy(&name).into_owned(), start << 16 | u16_at(&entry, 0) as u64));
+            }
+        }
+        Ok(entries)
+    }
+
+    pub fn build_tree(&mut self) -> Result<VirtualTree<SquashContent>, Box<dyn std::error::Error>> {
+        let (mtime, block, offset, size) = match self.read_inode(self.superblock.root_inode)? {
+            Inode::Directory { mtime, block, offset, size } => (mtime, block, offset, size),
+            _ => {
+                return Err("The root inode isn't a directory".into());
+            }
+        };
+        let mut tree = VirtualTree::new(EntryInfo::directory(filetime::from_unix_seconds(mtime as i64)), SquashContent::None);
+        let mut pending = vec![(VirtualTree::<SquashContent>::ROOT, String::new(), block, offset, size)];
+        // Broken images can list a directory in itself or below, every one only gets read once
+        let mut visited = HashSet::from([self.superblock.root_inode]);
+        while let Some((parent, parent_path, block, offset, size)) = pending.pop() {
+            for (name, reference) in self.read_directory(block, offset, size)? {
+                let path = format!("{parent_path}/{name}");
+                let (info, content) = match self.read_inode(reference)? {
+                    Inode::Directory { mtime, block, offset, size } => {
+                        if !visited.insert(reference) {
+                            println!("Leaving out {path:?}, it's a directory that's already in the tree");
+                            continue;
+                        }
+                        let info = EntryInfo::directory(filetime::from_unix_seconds(mtime as i64));
+                        let id = tree.insert(parent, OsStr::new(&name), info, SquashContent::None);
+                        pending.push((id, path, block, offset, size));
+                        continue;
+                    }
+                    Inode::File { mtime, mode, data } => {
+                        let mut info = EntryInfo::file(data.size, filetime::from_unix_seconds(mtime as i64));
+                        if mode & 0o222 == 0 {
+                            info.attributes = FileSystem::FILE_ATTRIBUTE_READONLY.0;
+                        }
+                        (info, SquashContent::File(Arc::new(data)))
+                    }
+                    Inode::Symlink { mtime, target } => {
+                        let target = if target.starts_with('/') {
+                            target
+                        } else {
+                            format!("{parent_path}/{target}")
+                        };
+                        (EntryInfo::file(0, filetime::from_unix_seconds(mtime as i64)), SquashContent::Link(target))
+                    }
+                    Inode::Other => {
+                        continue;
+                    }
+                };
+                tree.insert(parent, OsStr::new(&name), info, content);
+            }
+        }
+
+        tree.sort();
+        let left_out = tree.resolve_links(|c| match c {
+            SquashContent::Link(target) => Some(target),
+            _ => None,
+        });
+        if left_out > 0 {
+            println!("Left out {left_out} links to directories or to nothing");
+        }
+        Ok(tree)
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    const BLOCK_SIZE: u32 = 4096;
+
+    fn zlib(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// An inode, `body` is what follows the header they all have
+    fn inode(kind: u16, mode: u16, number: u32, body: &[u8]) -> Vec<u8> {
+        let mut inode = Vec::new();
+        inode.extend_from_slice(&kind.to_le_bytes());
+        inode.extend_from_slice(&mode.to_le_bytes());
+        // Owner and group
+        inode.extend_from_slice(&[0; 4]);
+        inode.extend_from_slice(&1_650_000_000u32.to_le_bytes());
+        inode.extend_from_slice(&number.to_le_bytes());
+        inode.extend_from_slice(body);
+        inode
+    }
+
+    fn directory_inode(number: u32, listing_offset: usize, listing: &[u8]) -> Vec<u8> {
+        let mut body = [0u8; 16];
+        body[8..10].copy_from_slice(&(listing.len() as u16 + 3).to_le_bytes());
+        body[10..12].copy_from_slice(&(listing_offset as u16).to_le_bytes());
+        inode(BASIC_DIRECTORY, 0o755, number, &body)
+    }
+
+    fn file_inode(number: u32, blocks_start: u32, fragment: Option<u32>, size: u32, block_sizes: &[u32]) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&blocks_start.to_le_bytes());
+        body.extend_from_slice(&fragment.unwrap_or(NO_FRAGMENT).to_le_bytes());
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&size.to_le_bytes());
+        for size in block_sizes {
+            body.extend_from_slice(&size.to_le_bytes());
+        }
+        inode(BASIC_FILE, 0o444, number, &body)
+    }
+
+    fn symlink_inode(number: u32, target: &str) -> Vec<u8> {
+        let mut body = 1u32.to_le_bytes().to_vec();
+        body.extend_from_slice(&(target.len() as u32).to_le_bytes());
+        body.extend_from_slice(target.as_bytes());
+        inode(BASIC_SYMLINK, 0o777, number, &body)
+    }
+
+    /// A listing of entries whose inodes are all in the first block of the inode table
+    fn listing(entries: &[(&str, usize)]) -> Vec<u8> {
+        let mut listing = Vec::new();
+        listing.extend_from_slice(&(entries.len() as u32 - 1).to_le_bytes());
+        listing.extend_from_slice(&[0; 8]);
+        for (name, offset) in entries {
+            listing.extend_from_slice(&(*offset as u16).to_le_bytes());
+            listing.extend_from_slice(&[0; 4]);
+            listing.extend_from_slice(&(name.len() as u16 - 1).to_le_bytes());
+            listing.extend_from_slice(name.as_bytes());
+        }
+        listing
+    }
+
+    fn metadata(data: &[u8], compressed: bool) -> Vec<u8> {
+        let (header, data) = if compressed {
+            let data = zlib(data);
+            (data.len() as u16, data)
+        } else {
+            (data.len() as u16 | METADATA_UNCOMPRESSED, data.to_vec())
+        };
+        let mut block = header.to_le_bytes().to_vec();
+        block.extend(data);
+        block
+    }
+
+    /// A gzip image file that is removed again when the test is done. `data` goes right after
+    /// the superblock, inodes and listings into one metadata block each.
+    pub struct Squashfs(pub PathBuf);
+
+    impl Squashfs {
+        fn new(name: &str, data: &[u8], inodes: &[u8], root_offset: usize, listings: &[u8], fragments: &[Block]) -> Squashfs {
+            let mut image = vec![0u8; SUPERBLOCK_SIZE];
+            image.extend_from_slice(data);
+            let inode_table = image.len() as u64;
+            image.extend(metadata(inodes, false));
+            let directory_table = image.len() as u64;
+            image.extend(metadata(listings, true));
+            let fragment_entries = image.len() as u64;
+            let entries: Vec<u8> = fragments.iter().flat_map(|f| [&f.start.to_le_bytes()[..], &f.size.to_le_bytes(), &[0; 4]].concat()).collect();
+            image.extend(metadata(&entries, false));
+            let fragment_table = image.len() as u64;
+            image.extend_from_slice(&fragment_entries.to_le_bytes());
+
+            let superblock = &mut image[..SUPERBLOCK_SIZE];
+            superblock[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+            superblock[12..16].copy_from_slice(&BLOCK_SIZE.to_le_bytes());
+            superblock[16..20].copy_from_slice(&(fragments.len() as u32).to_le_bytes());
+            superblock[20..22].copy_from_slice(&1u16.to_le_bytes());
+            superblock[28..30].copy_from_slice(&4u16.to_le_bytes());
+            superblock[32..40].copy_from_slice(&(root_offset as u64).to_le_bytes());
+            superblock[64..72].copy_from_slice(&inode_table.to_le_bytes());
+            superblock[72..80].copy_from_slice(&directory_table.to_le_bytes());
+            superblock[80..88].copy_from_slice(&fragment_table.to_le_bytes());
+            let path = std::env::temp_dir().join(format!("projfs-squashfs-{}-{name}.img", std::process::id()));
+            std::fs::write(&path, image).unwrap();
+            Squashfs(path)
+        }
+
+        /// `a` is a full block with its tail in a fragment, `b` a compressed block, `link` leads
+        /// to `a` and `sub/c` is a sparse block
+        pub fn files(name: &str) -> (Squashfs, Vec<u8>, Vec<u8>) {
+            let a: Vec<u8> = (0..BLOCK_SIZE + 100).map(|i| (i % 251) as u8).collect();
+            let b = b"compressed ".repeat(20);
+            let compressed_b = zlib(&b);
+            let mut data = a[..BLOCK_SIZE as usize].to_vec();
+            data.extend_from_slice(&compressed_b);
+            let fragment_start = (SUPERBLOCK_SIZE + data.len()) as u64;
+            data.extend_from_slice(&a[BLOCK_SIZE as usize..]);
+
+            let a_start = SUPERBLOCK_SIZE as u32;
+            let b_start = a_start + BLOCK_SIZE;
+            let mut inodes = file_inode(1, a_start, Some(0), a.len() as u32, &[BLOCK_SIZE | DATA_UNCOMPRESSED]);
+            let b_offset = inodes.len();
+            inodes.extend(file_inode(2, b_start, None, b.len() as u32, &[compressed_b.len() as u32]));
+            let link_offset = inodes.len();
+            inodes.extend(symlink_inode(3, "a"));
+            let c_offset = inodes.len();
+            inodes.extend(file_inode(4, 0, None, BLOCK_SIZE, &[0]));
+            let sub_offset = inodes.len();
+            let root_listing = listing(&[("a", 0), ("b", b_offset), ("link", link_offset), ("sub", sub_offset)]);
+            let sub_listing = listing(&[("c", c_offset)]);
+            inodes.extend(directory_inode(5, root_listing.len(), &sub_listing));
+            let root_offset = inodes.len();
+            inodes.extend(directory_inode(6, 0, &root_listing));
+
+            let fragment = Block {
+                start: fragment_start,
+                size: 100 | DATA_UNCOMPRESSED,
+            };
+            let image = Squashfs::new(name, &data, &inodes, root_offset, &[root_listing, sub_listing].concat(), &[fragment]);
+            (image, a, b)
+        }
+    }
+
+    impl Drop for Squashfs {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn reads_inodes_directories_and_fragments() {
+        let (image, a, b) = Squashfs::files("tree");
+        let tree = Image::open(&image.0).unwrap().build_tree().unwrap();
+        let node = |path: &str| tree.node(tree.lookup(Path::new(path)).unwrap());
+        let data = |path: &str| match &node(path).content {
+            SquashContent::File(data) => data.clone(),
+            _ => panic!("{path} isn't a file"),
+        };
+        assert_eq!(data("a").size, a.len() as u64);
+        assert_eq!(data("a").blocks.len(), 1);
+        assert_eq!(data("a").fragment.map(|(f, offset)| (f.size & !DATA_UNCOMPRESSED, offset)), Some((100, 0)));
+        assert_eq!(node("a").info.attributes, FileSystem::FILE_ATTRIBUTE_READONLY.0);
+        assert_eq!(node("a").info.last_write_time, filetime::from_unix_seconds(1_650_000_000));
+        assert_eq!(data("b").size, b.len() as u64);
+        assert!(data("b").fragment.is_none());
+        // Links to files become copies of them
+        assert_eq!(data("link").size, a.len() as u64);
+        assert!(node("sub").info.is_directory);
+        assert_eq!(data("sub/c").blocks[0].size, 0);
+    }
+
+    #[test]
+    fn reads_each_directory_once() {
+        let root_listing = listing(&[("self", 32), ("sub", 0)]);
+        let sub_listing = listing(&[("up", 32)]);
+        let mut inodes = directory_inode(1, root_listing.len(), &sub_listing);
+        inodes.extend(directory_inode(2, 0, &root_listing));
+        let image = Squashfs::new("loop", &[], &inodes, 32, &[root_listing, sub_listing].concat(), &[]);
+        let tree = Image::open(&image.0).unwrap().build_tree().unwrap();
+        let names = |path: &str| -> Vec<String> {
+            let id = tree.lookup(Path::new(path)).unwrap();
+            tree.children(id).map(|c| tree.node(c).name.to_string_lossy().into_owned()).collect()
+        };
+        assert_eq!(names(""), ["sub"]);
+        assert!(names("sub").is_empty());
+    }
+
+    #[test]
+    fn decompresses_no_more_than_a_block() {
+        let data = vec![7u8; 5000];
+        let mut lzma = Vec::new();
+        lzma_rs::lzma_compress(&mut &data[..], &mut lzma).unwrap();
+        let mut xz = Vec::new();
+        lzma_rs::xz_compress(&mut &data[..], &mut xz).unwrap();
+        for (compression, compressed) in [(Compression::Gzip, zlib(&data)), (Compression::Lzma, lzma), (Compression::Xz, xz)] {
+            assert_eq!(compression.decompress(&compressed, 8192).unwrap(), data);
+            assert_eq!(compression.decompress(&compressed, 5000).unwrap(), data);
+            assert_eq!(compression.decompress(&compressed, 4096).unwrap_err().kind(), std::io::ErrorKind::InvalidData, "{compression:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_tables_bigger_than_the_image() {
+        let mut superblock = vec![0u8; SUPERBLOCK_SIZE];
+        superblock[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        superblock[12..16].copy_from_slice(&131072u32.to_le_bytes());
+        superblock[16..20].copy_from_slice(&u32::MAX.to_le_bytes());
+        superblock[20..22].copy_from_slice(&1u16.to_le_bytes());
+        superblock[28..30].copy_from_slice(&4u16.to_le_bytes());
+        let path = std::env::temp_dir().join(format!("projfs-squashfs-{}.img", std::process::id()));
+        std::fs::write(&path, &superblock).unwrap();
+        let error = Image::open(&path).err().unwrap().to_string();
+        let _ = std::fs::remove_file(&path);
+        assert!(error.contains("don't fit into the image"), "{error}");
+        assert_eq!(bounded(96, 96).unwrap(), 96);
+    }
+}
@@ -0,0 +1,202 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use windows::Win32::Storage::ProjectedFileSystem;
+
+use crate::projfs_provider::{ProjFSProvider, EnumerationState, SeekRead, VirtualizationOptions, VirtualTree, BlockCache};
+use super::image::{read_block, Block, Compression, FileData, Image, SquashContent};
+
+/// Fragment blocks hold the tails of many small files, so they get read again and again
+const CACHED_BLOCKS: usize = 64;
+
+/// Projects the contents of a squashfs image, data blocks are decompressed as they're read
+pub struct SquashfsProvider {
+    image: PathBuf,
+    compression: Compression,
+    block_size: u64,
+    tree: Arc<VirtualTree<SquashContent>>,
+    cache: Arc<BlockCache>,
+}
+
+/// Reads a file block by block, sparse blocks are zeros and the tail may come from a fragment
+struct SquashReader {
+    file: File,
+    compression: Compression,
+    block_size: u64,
+    data: Arc<FileData>,
+    cache: Arc<BlockCache>,
+    position: u64,
+}
+
+impl SquashReader {
+    /// Blocks are cached by where they start in the image, which is unique to each
+    fn cached_block(&mut self, block: Block) -> std::io::Result<Arc<[u8]>> {
+        if let Some(data) = self.cache.get(block.start, 0) {
+            return Ok(data);
+        }
+        let data: Arc<[u8]> = read_block(&mut self.file, self.compression, block, self.block_size as usize)?.into();
+        self.cache.insert(block.start, 0, data.clone());
+        Ok(data)
+    }
+
+    fn block(&mut self, index: u64) -> std::io::Result<Arc<[u8]>> {
+        let length = std::cmp::min(self.block_size, self.data.size - index * self.block_size) as usize;
+        if let Some(block) = self.data.blocks.get(index as usize).copied() {
+            if block.size == 0 {
+                return Ok(vec![0u8; length].into());
+            }
+            return self.cached_block(block);
+        }
+        match self.data.fragment {
+            Some((fragment, offset)) => {
+                let offset = offset as usize;
+                let fragment = self.cached_block(fragment)?;
+                match fragment.get(offset..offset + length) {
+                    Some(tail) => Ok(tail.into()),
+                    None => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Tail is outside of its fragment")),
+                }
+            }
+            None => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "File is missing blocks")),
+        }
+    }
+}
+
+impl Read for SquashReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position >= self.data.size {
+            return Ok(0);
+        }
+        let block = self.block(self.position / self.block_size)?;
+        let within = (self.position % self.block_size) as usize;
+        if within >= block.len() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Block is shorter than the file says"));
+        }
+        let read = std::cmp::min(buf.len(), block.len() - within);
+        buf[..read].copy_from_slice(&block[within..within + read]);
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for SquashReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(v) => Some(v),
+            SeekFrom::End(v) => self.data.size.checked_add_signed(v),
+            SeekFrom::Current(v) => self.position.checked_add_signed(v),
+        };
+        match new_position {
+            Some(v) => {
+                self.position = v;
+                Ok(self.position)
+            }
+            None => Err(std::io::Error::from(std::io::ErrorKind::InvalidInput)),
+        }
+    }
+}
+
+impl SquashfsProvider {
+    pub fn new(image_path: &Path) -> Result<SquashfsProvider, Box<dyn std::error::Error>> {
+        let mut image = Image::open(image_path)?;
+        let superblock = image.superblock;
+        println!("{:?} image with {} byte blocks", superblock.compression, superblock.block_size);
+        let tree = image.build_tree()?;
+        Ok(SquashfsProvider {
+            image: image_path.to_path_buf(),
+            compression: superblock.compression,
+            block_size: superblock.block_size as u64,
+            tree: Arc::new(tree),
+            cache: Arc::new(BlockCache::new(superblock.block_size as u64, CACHED_BLOCKS)),
+        })
+    }
+}
+
+impl ProjFSProvider for SquashfsProvider {
+    fn init(&mut self, _root: &Path) -> Result<VirtualizationOptions, Box<dyn std::error::Error>> {
+        Ok(VirtualizationOptions::default())
+    }
+
+    fn start(&mut self, _context: ProjectedFileSystem::PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn new_enumeration(&self, _id: windows::core::GUID, file_path: &Path) -> Box<dyn EnumerationState> {
+        self.tree.enumeration(file_path)
+    }
+
+    fn get_placeholder_info(&self, file_path: &Path) -> Result<ProjectedFileSystem::PRJ_PLACEHOLDER_INFO, windows::core::HRESULT> {
+        match self.tree.lookup(file_path) {
+            Some(id) => Ok(self.tree.node(id).info.placeholder_info()),
+            None => Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into()),
+        }
+    }
+
+    fn get_file_data(&self, file_path: &Path) -> Result<Box<dyn SeekRead>, windows::core::HRESULT> {
+        let data = match self.tree.lookup(file_path).map(|id| &self.tree.node(id).content) {
+            Some(SquashContent::File(data)) => data.clone(),
+            _ => {
+                return Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into());
+            }
+        };
+        match File::open(&self.image) {
+            Ok(file) => Ok(Box::new(SquashReader {
+                file,
+                compression: self.compression,
+                block_size: self.block_size,
+                data,
+                cache: self.cache.clone(),
+                position: 0,
+            })),
+            Err(e) => {
+                println!("Could not open {:?} for {file_path:?}: {e}", self.image);
+                Err(windows::Win32::Foundation::E_FAIL)
+            }
+        }
+    }
+
+    fn query_file_name(&self, file_path: &Path) -> windows::core::HRESULT {
+        match self.tree.lookup(file_path) {
+            Some(_) => windows::Win32::Foundation::S_OK,
+            None => windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into(),
+        }
+    }
+
+    fn notification(&self, _callbackdata: *const ProjectedFileSystem::PRJ_CALLBACK_DATA, _is_directory: bool, _notification: ProjectedFileSystem::PRJ_NOTIFICATION, _destinationfilename: windows::core::PCWSTR, _operationparameters: *mut ProjectedFileSystem::PRJ_NOTIFICATION_PARAMETERS) -> windows::core::HRESULT {
+        windows::Win32::Foundation::S_OK
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::image::tests::Squashfs;
+
+    #[test]
+    fn reads_blocks_fragments_and_sparse_blocks() {
+        let (image, a, b) = Squashfs::files("provider");
+        let provider = SquashfsProvider::new(&image.0).unwrap();
+        let read = |path: &str| {
+            let mut data = Vec::new();
+            provider.get_file_data(Path::new(path)).ok().unwrap().read_to_end(&mut data).unwrap();
+            data
+        };
+        assert_eq!(read("a"), a);
+        assert_eq!(read("b"), b);
+        assert_eq!(read("link"), a);
+        assert_eq!(read("sub/c"), vec![0u8; 4096]);
+
+        // Across the end of the block into the fragment
+        let mut reader = provider.get_file_data(Path::new("a")).ok().unwrap();
+        let mut piece = [0u8; 196];
+        reader.seek(SeekFrom::Start(4000)).unwrap();
+        reader.read_exact(&mut piece[..96]).unwrap();
+        reader.read_exact(&mut piece[96..]).unwrap();
+        assert_eq!(piece[..], a[4000..]);
+    }
+}
@@ -0,0 +1,567 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufReader, Read, Seek};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use windows::Win32::Storage::FileSystem;
+
+use crate::projfs_provider::{filetime, EntryInfo, VirtualTree};
+
+const BLOCK_SIZE: u64 = 512;
+/// Bumped whenever what gets persisted changes, so older indexes are rebuilt
+const INDEX_VERSION: u32 = 1;
+/// Links can point at links, or at each other
+const MAX_LINK_DEPTH: usize = 16;
+/// Long names and pax records are read whole, nothing sane comes close to this
+const MAX_EXTENDED_HEADER_SIZE: u64 = 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+    HardLink,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TarEntry {
+    /// Relative with '/' separators
+    pub path: String,
+    pub kind: EntryKind,
+    pub size: u64,
+    pub mode: u32,
+    /// Unix seconds
+    pub mtime: i64,
+    /// Where the data starts in the archive
+    pub offset: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+}
+
+/// Every entry of an archive and where its data is. Scanning means reading every header of the
+/// archive, so the index is kept next to it as `<archive>.index.json` and reused while the archive
+/// keeps its size and modification time.
+#[derive(Serialize, Deserialize)]
+pub struct TarIndex {
+    version: u32,
+    archive_size: u64,
+    archive_modified: i64,
+    pub entries: Vec<TarEntry>,
+}
+
+/// Where tar headers are read from. Seekable sources skip over file data without reading it.
+pub trait TarSource: Read {
+    fn skip(&mut self, count: u64) -> std::io::Result<()>;
+}
+
+impl<R: Read + Seek> TarSource for BufReader<R> {
+    fn skip(&mut self, count: u64) -> std::io::Result<()> {
+        let count = i64::try_from(count).map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidData))?;
+        self.seek_relative(count)
+    }
+}
+
+/// Source that can only be read front to back, like a compressed archive
+pub struct Streamed<R> {
+    inner: R,
+    /// How much has been read or skipped so far
+    pub position: u64,
+}
+
+impl<R: Read> Streamed<R> {
+    pub fn new(inner: R) -> Streamed<R> {
+        Streamed {
+            inner,
+            position: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for Streamed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Read> TarSource for Streamed<R> {
+    fn skip(&mut self, count: u64) -> std::io::Result<()> {
+        if std::io::copy(&mut self.take(count), &mut std::io::sink())? != count {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+}
+
+// Values that don't fit the header fields come from pax or GNU headers in front of the entry
+#[derive(Default)]
+struct Overrides {
+    path: Option<String>,
+    link: Option<String>,
+    size: Option<u64>,
+    mtime: Option<i64>,
+}
+
+fn index_path(archive: &Path) -> PathBuf {
+    let mut path = archive.as_os_str().to_os_string();
+    path.push(".index.json");
+    path.into()
+}
+
+fn c_string(field: &[u8]) -> String {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+fn number(field: &[u8]) -> Result<u64, String> {
+    if field[0] & 0x80 != 0 {
+        // GNU base-256 for values that don't fit in octal
+        return Ok(field[1..].iter().fold((field[0] & 0x7f) as u64, |a, b| (a << 8) | *b as u64));
+    }
+    let text = String::from_utf8_lossy(field);
+    let text = text.trim_matches(|c: char| c == '\0' || c == ' ');
+    if text.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(text, 8).map_err(|e| format!("Invalid number {text:?} in tar header: {e}"))
+}
+
+/// Sizes come from pax and base-256 fields too, which can claim anything
+fn padded(size: u64) -> Result<u64, String> {
+    size.checked_next_multiple_of(BLOCK_SIZE).ok_or_else(|| format!("Tar entry of {size} bytes is too large"))
+}
+
+fn header_name(header: &[u8; 512]) -> String {
+    let name = c_string(&header[0..100]);
+    let prefix = c_string(&header[345..500]);
+    if &header[257..262] == b"ustar" && !prefix.is_empty() {
+        format!("{prefix}/{name}")
+    } else {
+        name
+    }
+}
+
+fn is_valid_checksum(header: &[u8; 512]) -> Result<bool, String> {
+    let stored = number(&header[148..156])?;
+    let field = 148..156;
+    let unsigned: u64 = header.iter().enumerate().map(|(i, b)| if field.contains(&i) { b' ' as u64 } else { *b as u64 }).sum();
+    // Some old archivers summed signed bytes
+    let signed: i64 = header.iter().enumerate().map(|(i, b)| if field.contains(&i) { b' ' as i64 } else { *b as i8 as i64 }).sum();
+    Ok(stored == unsigned || stored as i64 == signed)
+}
+
+fn parse_pax(data: &[u8], next: &mut Overrides) -> Result<(), String> {
+    // Records are "<length> <key>=<value>\n", the length counting the whole record
+    let mut rest = data;
+    while !rest.is_empty() {
+        let space = rest.iter().position(|b| *b == b' ').ok_or("Malformed pax record")?;
+        let length: usize = std::str::from_utf8(&rest[..space]).ok().and_then(|l| l.parse().ok()).ok_or("Malformed pax record length")?;
+        if length <= space + 1 || length > rest.len() {
+            return Err("Malformed pax record length".into());
+        }
+        let record = &rest[space + 1..length - 1];
+        rest = &rest[length..];
+        let equals = record.iter().position(|b| *b == b'=').ok_or("Malformed pax record")?;
+        let value = String::from_utf8_lossy(&record[equals + 1..]).into_owned();
+        match &record[..equals] {
+            b"path" => next.path = Some(value),
+            b"linkpath" => next.link = Some(value),
+            b"size" => next.size = Some(value.parse().map_err(|_| format!("Invalid pax size {value:?}"))?),
+            // Fractions of a second are more than placeholders need
+            b"mtime" => next.mtime = value.split('.').next().and_then(|s| s.parse().ok()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Relative path without '.' components, None for the root or anything trying to leave it
+pub fn normalize(name: &str) -> Option<String> {
+    let mut parts = Vec::new();
+    for part in name.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                return None;
+            }
+            p => parts.push(p),
+        }
+    }
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join("/"))
+    }
+}
+
+/// Every entry of the archive, up to its end marker
+pub fn scan<S: TarSource>(reader: &mut S) -> Result<Vec<TarEntry>, Box<dyn std::error::Error>> {
+    let mut entries = Vec::new();
+    let mut next = Overrides::default();
+    let mut header = [0u8; 512];
+    let mut offset = 0u64;
+    loop {
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            // Archives cut off right after an entry are common enough to accept
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        let header_offset = offset;
+        offset += BLOCK_SIZE;
+        if header.iter().all(|b| *b == 0) {
+            break;
+        }
+        if !is_valid_checksum(&header)? {
+            return Err(format!("Invalid tar header at offset {header_offset}").into());
+        }
+
+        let typeflag = header[156];
+        let header_size = number(&header[124..136])?;
+        if matches!(typeflag, b'x' | b'g' | b'L' | b'K') {
+            if header_size > MAX_EXTENDED_HEADER_SIZE {
+                return Err(format!("Extended header at offset {header_offset} claims {header_size} bytes").into());
+            }
+            let mut data = vec![0u8; header_size as usize];
+            reader.read_exact(&mut data)?;
+            let padded_size = padded(header_size)?;
+            reader.skip(padded_size - header_size)?;
+            offset += padded_size;
+            match typeflag {
+                b'x' => parse_pax(&data, &mut next)?,
+                b'L' => next.path = Some(c_string(&data)),
+                b'K' => next.link = Some(c_string(&data)),
+                // Global pax headers mostly carry comments
+                _ => {}
+            }
+            continue;
+        }
+
+        let name = next.path.take().unwrap_or_else(|| header_name(&header));
+        let link = next.link.take().unwrap_or_else(|| c_string(&header[157..257]));
+        let size = next.size.take().unwrap_or(header_size);
+        let mtime = match next.mtime.take() {
+            Some(m) => m,
+            None => number(&header[136..148])? as i64,
+        };
+        // Links, devices, directories and fifos have no data whatever their size says
+        let data_size = if (b'1'..=b'6').contains(&typeflag) { 0 } else { size };
+        let data_offset = offset;
+        let padded_size = padded(data_size)?;
+        offset = offset.checked_add(padded_size).ok_or_else(|| format!("Tar entry at offset {header_offset} ends past the largest possible archive"))?;
+        reader.skip(padded_size)?;
+
+        let kind = match typeflag {
+            // Old archives mark directories with a trailing slash only
+            b'0' | b'\0' if name.ends_with('/') => EntryKind::Directory,
+            b'0' | b'\0' | b'7' => EntryKind::File,
+            b'1' => EntryKind::HardLink,
+            b'2' => EntryKind::Symlink,
+            b'5' => EntryKind::Directory,
+            _ => {
+                println!("Leaving out {name:?}, tar entries of type {:?} aren't supported", typeflag as char);
+                continue;
+            }
+        };
+        let path = match normalize(&name) {
+            Some(p) => p,
+            None => {
+                if name.split('/').any(|p| p == "..") {
+                    println!("Leaving out {name:?}, it points outside of the archive");
+                }
+                continue;
+            }
+        };
+        entries.push(TarEntry {
+            path,
+            kind,
+            size: if kind == EntryKind::File { size } else { 0 },
+            mode: number(&header[100..108])? as u32,
+            mtime,
+            offset: data_offset,
+            link: matches!(kind, EntryKind::Symlink | EntryKind::HardLink).then(|| link),
+        });
+    }
+    Ok(entries)
+}
+
+/// Where `link` of the entry at `path` leads. Hard links are relative to the root of the archive,
+/// symlinks to the directory they are in, and absolute symlinks to the root again.
+fn link_target(path: &str, kind: EntryKind, link: &str) -> Option<String> {
+    let base = match path.rsplit_once('/') {
+        Some((parent, _)) if kind == EntryKind::Symlink && !link.starts_with('/') => parent,
+        _ => "",
+    };
+    let mut parts: Vec<&str> = base.split('/').filter(|p| !p.is_empty()).collect();
+    for part in link.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop()?;
+            }
+            p => parts.push(p),
+        }
+    }
+    Some(parts.join("/"))
+}
+
+/// Index of the entry that `entries[index]` leads to. `by_path` has the indices of every path in archive order.
+fn resolve(entries: &[TarEntry], by_path: &HashMap<&str, Vec<usize>>, index: usize) -> Option<usize> {
+    let mut current = index;
+    for _ in 0..MAX_LINK_DEPTH {
+        let entry = &entries[current];
+        let link = match entry.kind {
+            EntryKind::File | EntryKind::Directory => {
+                return Some(current);
+            }
+            EntryKind::Symlink | EntryKind::HardLink => entry.link.as_deref()?,
+        };
+        let target = link_target(&entry.path, entry.kind, link)?;
+        let candidates = by_path.get(target.as_str())?;
+        current = match entry.kind {
+            // Hard links are made while extracting, to whatever is there at that point
+            EntryKind::HardLink => *candidates.iter().rev().find(|i| **i < current)?,
+            // Symlinks are followed once everything is extracted
+            _ => *candidates.last()?,
+        };
+    }
+    None
+}
+
+/// Namespace of the archive, files keep the offset of their data. Links are projected as copies
+/// of the file they lead to, links to directories and dangling ones are left out.
+pub fn build_tree(entries: &[TarEntry]) -> VirtualTree<Option<u64>> {
+    // Later entries replace earlier ones with the same path, like extracting would
+    let mut by_path: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (i, entry) in entries.iter().enumerate() {
+        by_path.entry(entry.path.as_str()).or_default().push(i);
+    }
+    let directory_info = EntryInfo::directory(filetime::now());
+    let mut tree = VirtualTree::new(directory_info, None);
+    for (i, entry) in entries.iter().enumerate() {
+        let time = filetime::from_unix_seconds(entry.mtime);
+        let (info, content) = match resolve(entries, &by_path, i).map(|t| (t, &entries[t])) {
+            Some((_, target)) if target.kind == EntryKind::File => {
+                let mut info = EntryInfo::file(target.size, time);
+                if target.mode & 0o222 == 0 {
+                    info.attributes = FileSystem::FILE_ATTRIBUTE_READONLY.0;
+                }
+                (info, Some(target.offset))
+            }
+            Some((t, _)) if t == i => (EntryInfo::directory(time), None),
+            _ => {
+                println!("Leaving out {:?}, its link {:?} doesn't lead to a file", entry.path, entry.link);
+                continue;
+            }
+        };
+        if let Err(e) = tree.insert_path(Path::new(&entry.path), info, content, directory_info) {
+            println!("Leaving out {:?}: {e}", entry.path);
+        }
+    }
+    tree.sort();
+    tree
+}
+
+impl TarIndex {
+    /// Loads the index persisted next to the archive, or scans the archive and persists a new one
+    pub fn open(archive: &Path) -> Result<TarIndex, Box<dyn std::error::Error>> {
+        let metadata = fs::metadata(archive)?;
+        let archive_size = metadata.len();
+        let archive_modified = metadata.modified().map(filetime::from_system_time).unwrap_or(0);
+        let index_path = index_path(archive);
+        if let Ok(text) = fs::read_to_string(&index_path) {
+            match serde_json::from_str::<TarIndex>(&text) {
+                Ok(index) if index.version == INDEX_VERSION && index.archive_size == archive_size && index.archive_modified == archive_modified => {
+                    return Ok(index);
+                }
+                Ok(_) => println!("{index_path:?} is out of date, scanning {archive:?}"),
+                Err(e) => println!("Could not load {index_path:?}, scanning {archive:?}: {e}"),
+            }
+        }
+
+        let index = TarIndex {
+            version: INDEX_VERSION,
+            archive_size,
+            archive_modified,
+            entries: scan(&mut BufReader::new(fs::File::open(archive)?))?,
+        };
+        // Not being able to keep it only costs another scan next time
+        let persisted = serde_json::to_string(&index).map_err(std::io::Error::from).and_then(|text| fs::write(&index_path, text));
+        if let Err(e) = persisted {
+            println!("Could not save {index_path:?}: {e}");
+        }
+        Ok(index)
+    }
+
+    pub fn build_tree(&self) -> VirtualTree<Option<u64>> {
+        build_tree(&self.entries)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Builds archives in memory, one ustar header per entry
+    #[derive(Default)]
+    pub(crate) struct Archive(Vec<u8>);
+
+    impl Archive {
+        pub(crate) fn entry(mut self, typeflag: u8, name: &str, link: &str, data: &[u8]) -> Archive {
+            let mut header = [0u8; 512];
+            header[..name.len()].copy_from_slice(name.as_bytes());
+            header[100..108].copy_from_slice(b"0000644\0");
+            header[124..136].copy_from_slice(format!("{:011o}\0", data.len()).as_bytes());
+            header[136..148].copy_from_slice(b"14000000000\0");
+            header[156] = typeflag;
+            header[157..157 + link.len()].copy_from_slice(link.as_bytes());
+            header[257..265].copy_from_slice(b"ustar\x0000");
+            header[148..156].fill(b' ');
+            let checksum: u32 = header.iter().map(|b| *b as u32).sum();
+            header[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+            self.0.extend_from_slice(&header);
+            self.0.extend_from_slice(data);
+            self.0.resize(padded(self.0.len() as u64).unwrap() as usize, 0);
+            self
+        }
+
+        pub(crate) fn file(self, name: &str, data: &[u8]) -> Archive {
+            self.entry(b'0', name, "", data)
+        }
+
+        /// The archive with its end marker
+        pub(crate) fn finish(mut self) -> Vec<u8> {
+            self.0.extend_from_slice(&[0u8; 1024]);
+            self.0
+        }
+
+        pub(crate) fn scan(self) -> Result<Vec<TarEntry>, Box<dyn std::error::Error>> {
+            scan(&mut Streamed::new(self.finish().as_slice()))
+        }
+    }
+
+    fn pax(records: &[(&str, &str)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for (key, value) in records {
+            let body = format!(" {key}={value}\n");
+            // The length counts its own digits
+            let mut length = body.len() + 1;
+            while body.len() + length.to_string().len() != length {
+                length += 1;
+            }
+            data.extend_from_slice(format!("{length}{body}").as_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn finds_entries_and_their_data() {
+        let entries = Archive::default()
+            .entry(b'5', "dir/", "", b"")
+            .file("dir/a.txt", b"hello")
+            .file("./b.txt", &[7u8; 600])
+            .entry(b'2', "dir/link", "a.txt", b"")
+            .scan()
+            .unwrap();
+        let paths: Vec<_> = entries.iter().map(|e| (e.path.as_str(), e.kind, e.size, e.offset)).collect();
+        assert_eq!(paths, [
+            ("dir", EntryKind::Directory, 0, 512),
+            ("dir/a.txt", EntryKind::File, 5, 1024),
+            ("b.txt", EntryKind::File, 600, 2048),
+            ("dir/link", EntryKind::Symlink, 0, 3584),
+        ]);
+        assert_eq!(entries[1].mode, 0o644);
+        assert_eq!(entries[1].mtime, 0o14000000000);
+        assert_eq!(entries[3].link.as_deref(), Some("a.txt"));
+    }
+
+    #[test]
+    fn applies_long_names_and_pax_headers() {
+        let long_name = format!("{}/file.txt", "d".repeat(150));
+        let entries = Archive::default()
+            .entry(b'L', "././@LongLink", "", format!("{long_name}\0").as_bytes())
+            .file("truncated", b"x")
+            .entry(b'x', "pax", "", &pax(&[("path", "pax/name.txt"), ("mtime", "1234.5")]))
+            .file("short", b"yy")
+            .file("plain", b"")
+            .scan()
+            .unwrap();
+        let paths: Vec<_> = entries.iter().map(|e| (e.path.as_str(), e.mtime)).collect();
+        assert_eq!(paths, [(long_name.as_str(), 0o14000000000), ("pax/name.txt", 1234), ("plain", 0o14000000000)]);
+    }
+
+    #[test]
+    fn rejects_broken_archives() {
+        let mut archive = Archive::default().file("a", b"data");
+        archive.0[0] = b'b';
+        assert!(archive.scan().is_err());
+
+        let mut archive = Archive::default().entry(b'L', "././@LongLink", "", b"");
+        // 8 GiB of long name
+        archive.0[124..136].copy_from_slice(b"77777777777\0");
+        archive.0[148..156].fill(b' ');
+        let checksum: u32 = archive.0[..512].iter().map(|b| *b as u32).sum();
+        archive.0[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+        assert!(archive.scan().is_err());
+    }
+
+    #[test]
+    fn rejects_sizes_past_the_largest_archive() {
+        let pax_size = Archive::default().entry(b'x', "pax", "", &pax(&[("size", &u64::MAX.to_string())])).file("huge", b"");
+        assert!(pax_size.scan().is_err());
+        let mut base_256 = Archive::default().file("huge", b"");
+        base_256.0[124] = 0x80;
+        base_256.0[125..136].fill(0xff);
+        base_256.0[148..156].fill(b' ');
+        let checksum: u32 = base_256.0[..512].iter().map(|b| *b as u32).sum();
+        base_256.0[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+        assert!(base_256.scan().is_err());
+    }
+
+    #[test]
+    fn clamps_times_past_what_filetimes_hold() {
+        let entries = Archive::default()
+            .entry(b'x', "pax", "", &pax(&[("mtime", &i64::MAX.to_string())]))
+            .file("late", b"")
+            .entry(b'x', "pax", "", &pax(&[("mtime", &i64::MIN.to_string())]))
+            .file("early", b"")
+            .scan()
+            .unwrap();
+        let tree = build_tree(&entries);
+        let time = |path: &str| tree.node(tree.lookup(Path::new(path)).unwrap()).info.last_write_time;
+        assert_eq!(time("late"), i64::MAX);
+        assert_eq!(time("early"), i64::MIN);
+    }
+
+    #[test]
+    fn leaves_out_paths_outside_the_archive() {
+        let entries = Archive::default().file("../evil", b"").file("fine/../../evil", b"").file("ok", b"").scan().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].path, "ok");
+    }
+
+    #[test]
+    fn resolves_hard_links_to_earlier_entries() {
+        let entries = Archive::default()
+            .file("a", b"first")
+            .entry(b'1', "hard", "a", b"")
+            .entry(b'2', "soft", "a", b"")
+            .file("a", b"second version")
+            .entry(b'1', "dangling", "later", b"")
+            .file("later", b"")
+            .scan()
+            .unwrap();
+        let tree = build_tree(&entries);
+        let offset = |path: &str| tree.lookup(Path::new(path)).and_then(|id| tree.node(id).content);
+        let size = |path: &str| tree.lookup(Path::new(path)).map(|id| tree.node(id).info.size);
+        assert_eq!(offset("hard"), Some(entries[0].offset));
+        assert_eq!(size("hard"), Some(5));
+        // Symlinks see the archive as extracted
+        assert_eq!(offset("soft"), Some(entries[3].offset));
+        assert_eq!(size("a"), Some(14));
+        assert_eq!(tree.lookup(Path::new("dangling")), None);
+    }
+}
@@ -0,0 +1,7 @@
+mod index;
+mod provider;
+
+pub use index::{build_tree, normalize, scan, EntryKind, Streamed, TarEntry};
+pub use provider::TarProvider;
+#[cfg(test)]
+pub(crate) use index::tests;
@@ -0,0 +1,83 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use windows::Win32::Storage::ProjectedFileSystem;
+
+use crate::projfs_provider::{ProjFSProvider, EnumerationState, SeekRead, VirtualizationOptions, VirtualTree, WindowReader};
+use super::index::TarIndex;
+
+/// Projects the contents of a tar archive, file data is read straight out of the archive
+pub struct TarProvider {
+    archive: PathBuf,
+    // Offsets of the file data in the archive
+    tree: Arc<VirtualTree<Option<u64>>>,
+}
+
+impl TarProvider {
+    pub fn new(archive: &Path) -> TarProvider {
+        TarProvider {
+            archive: archive.to_path_buf(),
+            tree: Default::default(),
+        }
+    }
+}
+
+impl ProjFSProvider for TarProvider {
+    fn init(&mut self, _root: &Path) -> Result<VirtualizationOptions, Box<dyn std::error::Error>> {
+        let index = TarIndex::open(&self.archive)?;
+        println!("{:?} has {} entries", self.archive, index.entries.len());
+        self.tree = Arc::new(index.build_tree());
+        Ok(VirtualizationOptions::default())
+    }
+
+    fn start(&mut self, _context: ProjectedFileSystem::PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn new_enumeration(&self, _id: windows::core::GUID, file_path: &Path) -> Box<dyn EnumerationState> {
+        self.tree.enumeration(file_path)
+    }
+
+    fn get_placeholder_info(&self, file_path: &Path) -> Result<ProjectedFileSystem::PRJ_PLACEHOLDER_INFO, windows::core::HRESULT> {
+        match self.tree.lookup(file_path) {
+            Some(id) => Ok(self.tree.node(id).info.placeholder_info()),
+            None => Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into()),
+        }
+    }
+
+    fn get_file_data(&self, file_path: &Path) -> Result<Box<dyn SeekRead>, windows::core::HRESULT> {
+        let (offset, size) = match self.tree.lookup(file_path).map(|id| self.tree.node(id)) {
+            Some(node) => match node.content {
+                Some(offset) => (offset, node.info.size),
+                None => {
+                    return Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into());
+                }
+            },
+            None => {
+                return Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into());
+            }
+        };
+        match std::fs::File::open(&self.archive) {
+            Ok(f) => Ok(Box::new(WindowReader::new(f, offset, size))),
+            Err(e) => {
+                println!("Could not open {:?} for {file_path:?}: {e}", self.archive);
+                Err(windows::Win32::Foundation::E_FAIL)
+            }
+        }
+    }
+
+    fn query_file_name(&self, file_path: &Path) -> windows::core::HRESULT {
+        match self.tree.lookup(file_path) {
+            Some(_) => windows::Win32::Foundation::S_OK,
+            None => windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into(),
+        }
+    }
+
+    fn notification(&self, _callbackdata: *const ProjectedFileSystem::PRJ_CALLBACK_DATA, _is_directory: bool, _notification: ProjectedFileSystem::PRJ_NOTIFICATION, _destinationfilename: windows::core::PCWSTR, _operationparameters: *mut ProjectedFileSystem::PRJ_NOTIFICATION_PARAMETERS) -> windows::core::HRESULT {
+        windows::Win32::Foundation::S_OK
+    }
+}
@@ -1,6 +1,4 @@
 mod provider;
-mod enumeration;
 mod virtual_files;
-mod zero_reader;
 
 pub use provider::ZerosProvider;
\ No newline at end of file
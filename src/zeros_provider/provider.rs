@@ -3,23 +3,28 @@ use std::path::{PathBuf, Path};
 use widestring::WideCStr;
 use windows::Win32::Storage::ProjectedFileSystem;
 
-use crate::projfs_provider::{ProjFSProvider, EnumerationState, SeekRead, VirtualizationOptions, NotificationMapping};
-use super::zero_reader::ZeroReader;
+use crate::generators::{Generator, GeneratorReader};
+use crate::projfs_provider::{ProjFSProvider, EnumerationState, SeekRead, VirtualizationOptions, NotificationMapping, VirtualTree, EntryInfo};
 use super::virtual_files::VIRTUAL_FILES;
-use super::enumeration::FakeFileEnumerateState;
 
 
-#[derive(Default)]
 pub struct ZerosProvider {
+    generator: Generator,
     start_time: i64,
     root: PathBuf,
     files_read: std::sync::atomic::AtomicUsize,
-    file_names_read: std::sync::RwLock<std::vec::Vec<PathBuf>>,
+    tree: std::sync::Arc<VirtualTree<()>>,
 }
 
 impl ZerosProvider {
-    pub fn new() -> ZerosProvider {
-        ZerosProvider::default()
+    pub fn new(generator: Generator) -> ZerosProvider {
+        ZerosProvider {
+            generator,
+            start_time: 0,
+            root: PathBuf::new(),
+            files_read: std::sync::atomic::AtomicUsize::new(0),
+            tree: Default::default(),
+        }
     }
 }
 
@@ -27,6 +32,27 @@ impl ProjFSProvider for ZerosProvider {
     fn init(&mut self, root: &Path) -> Result<VirtualizationOptions, Box<dyn std::error::Error>> {
         self.root = root.to_path_buf();
 
+        // The tree has to be ready before virtualization starts, so this can't wait for start()
+        let ftp :*mut i64 = &mut self.start_time;
+        let ip = ftp as *mut windows::Win32::Foundation::FILETIME;
+        unsafe {
+            windows::Win32::System::SystemInformation::GetSystemTimeAsFileTime(ip);
+        }
+        let directory_info = EntryInfo::directory(self.start_time);
+        let mut tree = VirtualTree::new(directory_info, ());
+        for vf in VIRTUAL_FILES {
+            if vf.0.is_empty() {
+                continue;
+            }
+            let info = if vf.1 {
+                directory_info
+            } else {
+                EntryInfo::file(vf.2, self.start_time)
+            };
+            tree.insert_path(Path::new(vf.0), info, (), directory_info)?;
+        }
+        self.tree = std::sync::Arc::new(tree);
+
         // Get all the notifications
         let mut options = VirtualizationOptions::default();
         options.notification_mappings.push(NotificationMapping{
@@ -46,12 +72,6 @@ impl ProjFSProvider for ZerosProvider {
         Ok(options)
     }
     fn start(&mut self, _instance: ProjectedFileSystem::PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT) -> Result<(), Box<dyn std::error::Error>> {
-        let ftp :*mut i64 = &mut self.start_time;
-        let ip = ftp as *mut windows::Win32::Foundation::FILETIME;
-        unsafe {
-            windows::Win32::System::SystemInformation::GetSystemTimeAsFileTime(ip);
-        }
-
         Ok(())
     }
 
@@ -60,74 +80,34 @@ impl ProjFSProvider for ZerosProvider {
     }
 
     fn new_enumeration(&self, _id: windows::core::GUID, file_path: &Path) -> Box<dyn EnumerationState> {
-        Box::new(FakeFileEnumerateState::new(self.start_time, file_path))
+        self.tree.enumeration(file_path)
     }
 
     fn get_placeholder_info(&self, file_path: &Path) -> Result<ProjectedFileSystem::PRJ_PLACEHOLDER_INFO, windows::core::HRESULT> {
-        let search_name = file_path.as_os_str();
-        for vf in VIRTUAL_FILES {
-            unsafe {
-                let found_name = std::ffi::OsString::from(vf.0);
-                if ProjectedFileSystem::PrjFileNameCompare(found_name, search_name) != 0 {
-                    continue;
-                }
-            }
-    
-            return Ok(ProjectedFileSystem::PRJ_PLACEHOLDER_INFO {
-                FileBasicInfo: ProjectedFileSystem::PRJ_FILE_BASIC_INFO {
-                    IsDirectory: if vf.1 {
-                        windows::Win32::Foundation::BOOLEAN(1)
-                    } else {
-                        windows::Win32::Foundation::BOOLEAN(0)
-                    },
-                    FileSize: vf.2 as i64,
-                    CreationTime: self.start_time,
-                    LastAccessTime: self.start_time,
-                    LastWriteTime: self.start_time,
-                    ChangeTime: self.start_time,
-                    FileAttributes: 0,
-                },
-                ..Default::default()
-            });
+        match self.tree.lookup(file_path) {
+            Some(id) => Ok(self.tree.node(id).info.placeholder_info()),
+            None => Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into()),
         }
-
-        Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into())
     }
 
     fn get_file_data(&self, file_path: &Path) -> Result<Box<dyn SeekRead>, windows::core::HRESULT> {
-        let search_name = file_path.as_os_str();
-        for vf in VIRTUAL_FILES {
-            let found_name = std::ffi::OsString::from(vf.0);
-            unsafe {
-                if ProjectedFileSystem::PrjFileNameCompare(found_name, search_name) != 0 {
-                    continue;
-                }
-            }
-
-            // Do thread safe mutations without mutability on self
-            self.files_read.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-            {
-                let mut lock = self.file_names_read.write().unwrap();
-                lock.push(file_path.to_path_buf());
+        let info = match self.tree.lookup(file_path) {
+            Some(id) if !self.tree.node(id).info.is_directory => self.tree.node(id).info,
+            _ => {
+                return Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into());
             }
-            return Ok(Box::new(ZeroReader::new(vf.2 as u64)));
-        }
+        };
 
-        Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into())
+        // Do thread safe mutations without mutability on self
+        self.files_read.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        Ok(Box::new(GeneratorReader::new(self.generator.clone(), info.size)))
     }
 
     fn query_file_name(&self, file_path: &Path) -> windows::core::HRESULT {
-        let search_name = file_path.as_os_str();
-        for vf in VIRTUAL_FILES {
-            unsafe {
-                let found_name = std::ffi::OsString::from(vf.0);
-                if ProjectedFileSystem::PrjFileNameCompare(found_name, search_name) == 0 {
-                    return windows::Win32::Foundation::S_OK;
-                }
-            }
+        match self.tree.lookup(file_path) {
+            Some(_) => windows::Win32::Foundation::S_OK,
+            None => windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into(),
         }
-    
-        windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into()
     }
 
     fn notification(&self, callbackdata: *const ProjectedFileSystem::PRJ_CALLBACK_DATA, is_directory: bool, notification: ProjectedFileSystem::PRJ_NOTIFICATION, destinationfilename: windows::core::PCWSTR, _operationparameters: *mut ProjectedFileSystem::PRJ_NOTIFICATION_PARAMETERS) -> windows::core::HRESULT {
@@ -1,6 +1,7 @@
+use crate::projfs_provider::FILE_TRANSFER_CHUNK_SIZE;
 
-// TODO: Interface for ordering
-pub const VIRTUAL_FILES: &[(&str, bool, u8)] = &[
+// Order doesn't matter, the VirtualTree built from this keeps entries sorted
+pub const VIRTUAL_FILES: &[(&str, bool, u64)] = &[
     ("", true, 0),
     ("ooo", false, 2),
     ("other", true, 0),
@@ -16,4 +17,14 @@ pub const VIRTUAL_FILES: &[(&str, bool, u8)] = &[
     ("zeros\\7", false, 7),
     ("zeros\\8", false, 8),
     ("zeros\\9", false, 9),
-];
\ No newline at end of file
+    // Around the chunk size hydration is split at, and past what fits in 32 bits
+    ("large", true, 0),
+    ("large\\chunk_minus_one", false, FILE_TRANSFER_CHUNK_SIZE - 1),
+    ("large\\chunk", false, FILE_TRANSFER_CHUNK_SIZE),
+    ("large\\chunk_plus_one", false, FILE_TRANSFER_CHUNK_SIZE + 1),
+    ("large\\three_chunks_and_a_page", false, 3*FILE_TRANSFER_CHUNK_SIZE + 4096),
+    ("large\\4GiB_minus_one", false, (1 << 32) - 1),
+    ("large\\4GiB", false, 1 << 32),
+    ("large\\4GiB_plus_one", false, (1 << 32) + 1),
+    ("large\\5GiB", false, 5 << 30),
+];
@@ -0,0 +1,475 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use windows::Win32::Storage::FileSystem;
+
+use crate::projfs_provider::{filetime, EntryInfo, VirtualTree};
+
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06054b50;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06064b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x02014b50;
+const LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+const END_OF_CENTRAL_DIRECTORY_SIZE: usize = 22;
+const CENTRAL_HEADER_SIZE: usize = 46;
+const LOCAL_HEADER_SIZE: usize = 30;
+
+const ZIP64_EXTRA_ID: u16 = 0x0001;
+const EXTENDED_TIMESTAMP_EXTRA_ID: u16 = 0x5455;
+
+const FLAG_ENCRYPTED: u16 = 1;
+const FLAG_UTF8: u16 = 1 << 11;
+
+pub const METHOD_STORED: u16 = 0;
+pub const METHOD_DEFLATED: u16 = 8;
+
+const HOST_UNIX: u16 = 3;
+const DOS_DIRECTORY: u32 = 0x10;
+
+/// Where to find the data of a file, kept in the tree
+#[derive(Clone, Copy, Debug)]
+pub struct ZipEntry {
+    /// Position in the central directory, unique in the archive
+    pub id: u64,
+    pub method: u16,
+    pub compressed_size: u64,
+    pub header_offset: u64,
+}
+
+struct CentralEntry {
+    name: String,
+    info: EntryInfo,
+    entry: ZipEntry,
+    flags: u16,
+}
+
+fn u16_at(b: &[u8], o: usize) -> u16 {
+    u16::from_le_bytes([b[o], b[o + 1]])
+}
+
+fn u32_at(b: &[u8], o: usize) -> u32 {
+    u32::from_le_bytes(b[o..o + 4].try_into().unwrap())
+}
+
+fn u64_at(b: &[u8], o: usize) -> u64 {
+    u64::from_le_bytes(b[o..o + 8].try_into().unwrap())
+}
+
+fn read_at(file: &mut File, offset: u64, size: usize) -> std::io::Result<Vec<u8>> {
+    let mut data = vec![0u8; size];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut data)?;
+    Ok(data)
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+/// Unix seconds of a DOS date and time, which have no time zone and are taken as UTC
+fn dos_time(date: u16, time: u16) -> i64 {
+    filetime::unix_seconds_from_civil((date >> 9) as i64 + 1980, ((date >> 5) & 0xf) as i64, (date & 0x1f) as i64,
+        (time >> 11) as i64, ((time >> 5) & 0x3f) as i64, (time & 0x1f) as i64 * 2)
+}
+
+/// Offset and size of the central directory and how many entries it has, from the ZIP64
+/// records where the classic ones ran out of bits
+fn find_central_directory(file: &mut File) -> Result<(u64, u64, u64), Box<dyn std::error::Error>> {
+    let file_size = file.seek(SeekFrom::End(0))?;
+    // The end record is followed by a comment of up to 64KiB
+    let tail_size = std::cmp::min(file_size, (END_OF_CENTRAL_DIRECTORY_SIZE + 0xffff) as u64);
+    let tail = read_at(file, file_size - tail_size, tail_size as usize)?;
+    if tail.len() < END_OF_CENTRAL_DIRECTORY_SIZE {
+        return Err("Not a zip archive".into());
+    }
+    let end = (0..=tail.len() - END_OF_CENTRAL_DIRECTORY_SIZE).rev()
+        .find(|i| u32_at(&tail, *i) == END_OF_CENTRAL_DIRECTORY_SIGNATURE)
+        .ok_or("Not a zip archive")?;
+
+    let count = u16_at(&tail, end + 10) as u64;
+    let size = u32_at(&tail, end + 12) as u64;
+    let offset = u32_at(&tail, end + 16) as u64;
+    if count != 0xffff && size != 0xffff_ffff && offset != 0xffff_ffff {
+        return Ok((offset, size, count));
+    }
+
+    if end < 20 || u32_at(&tail, end - 20) != ZIP64_LOCATOR_SIGNATURE {
+        return Err("ZIP64 archive without a ZIP64 locator".into());
+    }
+    let record = read_at(file, u64_at(&tail, end - 20 + 8), 56)?;
+    if u32_at(&record, 0) != ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE {
+        return Err("Invalid ZIP64 end of central directory record".into());
+    }
+    Ok((u64_at(&record, 48), u64_at(&record, 40), u64_at(&record, 32)))
+}
+
+fn read_central_directory(file: &mut File) -> Result<Vec<CentralEntry>, Box<dyn std::error::Error>> {
+    let (offset, size, count) = find_central_directory(file)?;
+    // Taken from the end records as they are, a broken one mustn't decide how much gets allocated
+    let file_size = file.seek(SeekFrom::End(0))?;
+    if offset.checked_add(size).map(|end| end > file_size).unwrap_or(true) {
+        return Err(invalid("Central directory goes past the end of the archive").into());
+    }
+    if count > size / CENTRAL_HEADER_SIZE as u64 {
+        return Err(invalid("Central directory has more entries than fit in it").into());
+    }
+    let directory = read_at(file, offset, size as usize)?;
+    let mut entries = Vec::new();
+    let mut position = 0;
+    for id in 0..count {
+        let header = directory.get(position..position + CENTRAL_HEADER_SIZE).ok_or("Truncated central directory")?;
+        if u32_at(header, 0) != CENTRAL_HEADER_SIGNATURE {
+            return Err(format!("Invalid central directory entry at offset {}", offset + position as u64).into());
+        }
+        let version_made_by = u16_at(header, 4);
+        let flags = u16_at(header, 8);
+        let method = u16_at(header, 10);
+        let mut modified = dos_time(u16_at(header, 14), u16_at(header, 12));
+        let mut compressed_size = u32_at(header, 20) as u64;
+        let mut size = u32_at(header, 24) as u64;
+        let name_length = u16_at(header, 28) as usize;
+        let extra_length = u16_at(header, 30) as usize;
+        let comment_length = u16_at(header, 32) as usize;
+        let external_attributes = u32_at(header, 38);
+        let mut header_offset = u32_at(header, 42) as u64;
+
+        let name_start = position + CENTRAL_HEADER_SIZE;
+        let extra_start = name_start + name_length;
+        let next = extra_start + extra_length + comment_length;
+        let name = directory.get(name_start..extra_start).ok_or("Truncated central directory")?;
+        let mut extra = directory.get(extra_start..extra_start + extra_length).ok_or("Truncated central directory")?;
+        position = next;
+
+        while extra.len() >= 4 {
+            let id = u16_at(extra, 0);
+            let length = std::cmp::min(u16_at(extra, 2) as usize, extra.len() - 4);
+            let mut field = &extra[4..4 + length];
+            extra = &extra[4 + length..];
+            match id {
+                // Only the values that didn't fit are there, in this order
+                ZIP64_EXTRA_ID => {
+                    for value in [&mut size, &mut compressed_size, &mut header_offset] {
+                        if *value == 0xffff_ffff && field.len() >= 8 {
+                            *value = u64_at(field, 0);
+                            field = &field[8..];
+                        }
+                    }
+                }
+                EXTENDED_TIMESTAMP_EXTRA_ID if field.len() >= 5 && field[0] & 1 != 0 => {
+                    modified = i32::from_le_bytes(field[1..5].try_into().unwrap()) as i64;
+                }
+                _ => {}
+            }
+        }
+
+        // Names that aren't flagged as UTF-8 are code page 437, close enough for anything ASCII
+        let name = if flags & FLAG_UTF8 != 0 {
+            String::from_utf8_lossy(name).into_owned()
+        } else {
+            name.iter().map(|b| *b as char).collect()
+        };
+        let is_directory = name.ends_with('/') || (version_made_by >> 8 != HOST_UNIX && external_attributes & DOS_DIRECTORY != 0);
+        let attributes = if version_made_by >> 8 == HOST_UNIX {
+            let mode = external_attributes >> 16;
+            if mode != 0 && mode & 0o222 == 0 {
+                FileSystem::FILE_ATTRIBUTE_READONLY.0
+            } else {
+                0
+            }
+        } else {
+            external_attributes & (FileSystem::FILE_ATTRIBUTE_READONLY.0 | FileSystem::FILE_ATTRIBUTE_HIDDEN.0 | FileSystem::FILE_ATTRIBUTE_SYSTEM.0 | FileSystem::FILE_ATTRIBUTE_ARCHIVE.0)
+        };
+        let time = filetime::from_unix_seconds(modified);
+        let mut info = if is_directory {
+            EntryInfo::directory(time)
+        } else {
+            EntryInfo::file(size, time)
+        };
+        info.attributes = attributes;
+        entries.push(CentralEntry {
+            name,
+            info,
+            entry: ZipEntry {
+                id,
+                method,
+                compressed_size,
+                header_offset,
+            },
+            flags,
+        });
+    }
+    Ok(entries)
+}
+
+/// Namespace of the archive from its central directory. Entries that can't be served, encrypted
+/// ones or ones compressed with anything but deflate, are left out.
+pub fn build_tree(archive: &Path) -> Result<VirtualTree<Option<ZipEntry>>, Box<dyn std::error::Error>> {
+    let entries = read_central_directory(&mut File::open(archive)?)?;
+    let directory_info = EntryInfo::directory(filetime::now());
+    let mut tree = VirtualTree::new(directory_info, None);
+    for central in entries {
+        let parts: Vec<&str> = central.name.split(['/', '\\']).filter(|p| !p.is_empty() && *p != ".").collect();
+        if parts.is_empty() {
+            continue;
+        }
+        if parts.contains(&"..") {
+            println!("Leaving out {:?}, it points outside of the archive", central.name);
+            continue;
+        }
+        let content = if central.info.is_directory {
+            None
+        } else if central.flags & FLAG_ENCRYPTED != 0 {
+            println!("Leaving out {:?}, it is encrypted", central.name);
+            continue;
+        } else if !matches!(central.entry.method, METHOD_STORED | METHOD_DEFLATED) {
+            println!("Leaving out {:?}, compression method {} isn't supported", central.name, central.entry.method);
+            continue;
+        } else {
+            Some(central.entry)
+        };
+        if let Err(e) = tree.insert_path(Path::new(&parts.join("/")), central.info, content, directory_info) {
+            println!("Leaving out {:?}: {e}", central.name);
+        }
+    }
+    tree.sort();
+    Ok(tree)
+}
+
+/// The local header in front of the data can have a different extra field than the central directory
+pub fn data_offset(file: &mut File, entry: &ZipEntry) -> std::io::Result<u64> {
+    let header = read_at(file, entry.header_offset, LOCAL_HEADER_SIZE)?;
+    if u32_at(&header, 0) != LOCAL_HEADER_SIGNATURE {
+        return Err(invalid("Invalid local file header"));
+    }
+    Ok(entry.header_offset + LOCAL_HEADER_SIZE as u64 + u16_at(&header, 26) as u64 + u16_at(&header, 28) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    struct Member<'a> {
+        name: &'a str,
+        data: Vec<u8>,
+        size: u32,
+        method: u16,
+        flags: u16,
+        /// Version made by and external attributes
+        host: (u16, u32),
+        extra: Vec<u8>,
+    }
+
+    impl Member<'_> {
+        fn stored<'a>(name: &'a str, data: &[u8]) -> Member<'a> {
+            Member {
+                name,
+                data: data.to_vec(),
+                size: data.len() as u32,
+                method: METHOD_STORED,
+                flags: 0,
+                host: (20, 0),
+                extra: Vec::new(),
+            }
+        }
+
+        fn deflated<'a>(name: &'a str, data: &[u8]) -> Member<'a> {
+            let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data).unwrap();
+            Member {
+                data: encoder.finish().unwrap(),
+                method: METHOD_DEFLATED,
+                ..Member::stored(name, data)
+            }
+        }
+    }
+
+    /// An archive file that is removed again when the test is done
+    struct Archive(std::path::PathBuf);
+
+    impl Archive {
+        /// `zip64` moves the central directory location into the ZIP64 records
+        fn new(name: &str, members: &[Member], zip64: bool) -> Archive {
+            let mut bytes = Vec::new();
+            let mut central = Vec::new();
+            for member in members {
+                let offset = bytes.len() as u32;
+                bytes.extend_from_slice(&LOCAL_HEADER_SIGNATURE.to_le_bytes());
+                bytes.extend_from_slice(&[20, 0]);
+                bytes.extend_from_slice(&member.flags.to_le_bytes());
+                bytes.extend_from_slice(&member.method.to_le_bytes());
+                // 2022-04-15 12:30:10, CRC, sizes
+                bytes.extend_from_slice(&[0xc5, 0x63, 0x8f, 0x54, 0, 0, 0, 0]);
+                bytes.extend_from_slice(&(member.data.len() as u32).to_le_bytes());
+                bytes.extend_from_slice(&member.size.to_le_bytes());
+                bytes.extend_from_slice(&(member.name.len() as u16).to_le_bytes());
+                // A local extra field the central directory doesn't have
+                bytes.extend_from_slice(&4u16.to_le_bytes());
+                bytes.extend_from_slice(member.name.as_bytes());
+                bytes.extend_from_slice(&[0xfe, 0xca, 0, 0]);
+                bytes.extend_from_slice(&member.data);
+
+                central.extend_from_slice(&CENTRAL_HEADER_SIGNATURE.to_le_bytes());
+                central.extend_from_slice(&member.host.0.to_le_bytes());
+                central.extend_from_slice(&[20, 0]);
+                central.extend_from_slice(&member.flags.to_le_bytes());
+                central.extend_from_slice(&member.method.to_le_bytes());
+                central.extend_from_slice(&[0xc5, 0x63, 0x8f, 0x54, 0, 0, 0, 0]);
+                central.extend_from_slice(&(member.data.len() as u32).to_le_bytes());
+                central.extend_from_slice(&member.size.to_le_bytes());
+                central.extend_from_slice(&(member.name.len() as u16).to_le_bytes());
+                central.extend_from_slice(&(member.extra.len() as u16).to_le_bytes());
+                // Comment length, disk, internal attributes
+                central.extend_from_slice(&[0; 6]);
+                central.extend_from_slice(&member.host.1.to_le_bytes());
+                central.extend_from_slice(&offset.to_le_bytes());
+                central.extend_from_slice(member.name.as_bytes());
+                central.extend_from_slice(&member.extra);
+            }
+            let offset = bytes.len() as u64;
+            bytes.extend_from_slice(&central);
+            let (count, size, offset32) = if zip64 {
+                let record = bytes.len() as u64;
+                bytes.extend_from_slice(&ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes());
+                bytes.extend_from_slice(&[0; 28]);
+                bytes.extend_from_slice(&(members.len() as u64).to_le_bytes());
+                bytes.extend_from_slice(&(central.len() as u64).to_le_bytes());
+                bytes.extend_from_slice(&offset.to_le_bytes());
+                bytes.extend_from_slice(&ZIP64_LOCATOR_SIGNATURE.to_le_bytes());
+                bytes.extend_from_slice(&[0; 4]);
+                bytes.extend_from_slice(&record.to_le_bytes());
+                bytes.extend_from_slice(&[1, 0, 0, 0]);
+                (0xffff, 0xffff_ffff, 0xffff_ffff)
+            } else {
+                (members.len() as u16, central.len() as u32, offset as u32)
+            };
+            bytes.extend_from_slice(&END_OF_CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes());
+            bytes.extend_from_slice(&[0; 4]);
+            bytes.extend_from_slice(&count.to_le_bytes());
+            bytes.extend_from_slice(&count.to_le_bytes());
+            bytes.extend_from_slice(&size.to_le_bytes());
+            bytes.extend_from_slice(&offset32.to_le_bytes());
+            bytes.extend_from_slice(&7u16.to_le_bytes());
+            bytes.extend_from_slice(b"comment");
+
+            let path = std::env::temp_dir().join(format!("projfs-zip-{}-{name}.zip", std::process::id()));
+            std::fs::write(&path, bytes).unwrap();
+            Archive(path)
+        }
+
+        fn data(&self, tree: &VirtualTree<Option<ZipEntry>>, path: &str) -> Vec<u8> {
+            let entry = tree.lookup(Path::new(path)).and_then(|id| tree.node(id).content).unwrap();
+            let mut file = File::open(&self.0).unwrap();
+            let offset = data_offset(&mut file, &entry).unwrap();
+            let data = read_at(&mut file, offset, entry.compressed_size as usize).unwrap();
+            if entry.method == METHOD_STORED {
+                return data;
+            }
+            let mut inflated = Vec::new();
+            flate2::read::DeflateDecoder::new(&data[..]).read_to_end(&mut inflated).unwrap();
+            inflated
+        }
+    }
+
+    impl Drop for Archive {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn info(tree: &VirtualTree<Option<ZipEntry>>, path: &str) -> Option<EntryInfo> {
+        tree.lookup(Path::new(path)).map(|id| tree.node(id).info)
+    }
+
+    #[test]
+    fn reads_the_central_directory() {
+        let text = b"deflated ".repeat(100);
+        let archive = Archive::new("central", &[
+            Member::stored("dir/", b""),
+            Member::stored("dir/stored.txt", b"stored"),
+            Member::deflated("deflated.txt", &text),
+            Member::stored("implied/deep/file", b"x"),
+        ], false);
+        let tree = build_tree(&archive.0).unwrap();
+        assert!(info(&tree, "dir").unwrap().is_directory);
+        assert!(info(&tree, "implied/deep").unwrap().is_directory);
+        let stored = info(&tree, "dir/stored.txt").unwrap();
+        assert_eq!(stored.size, 6);
+        assert_eq!(stored.last_write_time, filetime::from_unix_seconds(filetime::unix_seconds_from_civil(2022, 4, 15, 12, 30, 10)));
+        assert_eq!(info(&tree, "deflated.txt").unwrap().size, text.len() as u64);
+        assert_eq!(archive.data(&tree, "dir/stored.txt"), b"stored");
+        assert_eq!(archive.data(&tree, "deflated.txt"), text);
+    }
+
+    #[test]
+    fn reads_zip64_records() {
+        let mut big = Member::stored("big", b"zip64");
+        big.size = 0xffff_ffff;
+        // Only the uncompressed size didn't fit
+        big.extra = [&ZIP64_EXTRA_ID.to_le_bytes()[..], &8u16.to_le_bytes(), &(5u64 << 32).to_le_bytes()].concat();
+        let archive = Archive::new("zip64", &[Member::stored("small", b"ok"), big], true);
+        let tree = build_tree(&archive.0).unwrap();
+        assert_eq!(info(&tree, "small").unwrap().size, 2);
+        assert_eq!(info(&tree, "big").unwrap().size, 5 << 32);
+        assert_eq!(archive.data(&tree, "big"), b"zip64");
+    }
+
+    #[test]
+    fn applies_extra_fields_and_attributes() {
+        let mut timestamped = Member::stored("timestamped", b"");
+        timestamped.extra = [&EXTENDED_TIMESTAMP_EXTRA_ID.to_le_bytes()[..], &5u16.to_le_bytes(), &[1], &1_650_000_000i32.to_le_bytes()].concat();
+        let mut unix_read_only = Member::stored("unix", b"");
+        unix_read_only.host = (HOST_UNIX << 8 | 20, 0o100444 << 16);
+        let mut dos_directory = Member::stored("dos", b"");
+        dos_directory.host = (20, DOS_DIRECTORY | FileSystem::FILE_ATTRIBUTE_HIDDEN.0);
+        let archive = Archive::new("extra", &[timestamped, unix_read_only, dos_directory], false);
+        let tree = build_tree(&archive.0).unwrap();
+        assert_eq!(info(&tree, "timestamped").unwrap().last_write_time, filetime::from_unix_seconds(1_650_000_000));
+        assert_eq!(info(&tree, "unix").unwrap().attributes, FileSystem::FILE_ATTRIBUTE_READONLY.0);
+        let dos = info(&tree, "dos").unwrap();
+        assert!(dos.is_directory);
+        assert_eq!(dos.attributes, FileSystem::FILE_ATTRIBUTE_HIDDEN.0);
+    }
+
+    #[test]
+    fn leaves_out_entries_it_cannot_serve() {
+        let mut encrypted = Member::stored("encrypted", b"secret");
+        encrypted.flags = FLAG_ENCRYPTED;
+        let mut bzip2 = Member::stored("bzip2", b"");
+        bzip2.method = 12;
+        let archive = Archive::new("unserved", &[encrypted, bzip2, Member::stored("../escape", b""), Member::stored("kept", b"")], false);
+        let tree = build_tree(&archive.0).unwrap();
+        for path in ["encrypted", "bzip2", "escape"] {
+            assert!(info(&tree, path).is_none(), "{path}");
+        }
+        assert!(info(&tree, "kept").is_some());
+    }
+
+    #[test]
+    fn rejects_files_without_a_central_directory() {
+        let archive = Archive::new("broken", &[Member::stored("a", b"a")], false);
+        let mut bytes = std::fs::read(&archive.0).unwrap();
+        let end = bytes.len() - END_OF_CENTRAL_DIRECTORY_SIZE - 7;
+        bytes[end] = 0;
+        std::fs::write(&archive.0, &bytes).unwrap();
+        assert!(build_tree(&archive.0).is_err());
+        std::fs::write(&archive.0, b"short").unwrap();
+        assert!(build_tree(&archive.0).is_err());
+    }
+
+    #[test]
+    fn rejects_central_directories_bigger_than_the_archive() {
+        let archive = Archive::new("too-big", &[Member::stored("a", b"a")], true);
+        let bytes = std::fs::read(&archive.0).unwrap();
+        let record = (0..bytes.len() - 4).rev().find(|i| u32_at(&bytes, *i) == ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE).unwrap();
+        let is_invalid_data = |error: Box<dyn std::error::Error>| error.downcast::<std::io::Error>().map(|e| e.kind() == std::io::ErrorKind::InvalidData).unwrap_or(false);
+        for (field, value) in [(40, u64::MAX / 2), (32, u64::MAX)] {
+            let mut broken = bytes.clone();
+            broken[record + field..record + field + 8].copy_from_slice(&value.to_le_bytes());
+            std::fs::write(&archive.0, &broken).unwrap();
+            assert!(is_invalid_data(build_tree(&archive.0).err().unwrap()));
+        }
+    }
+}
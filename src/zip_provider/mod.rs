@@ -0,0 +1,4 @@
+mod archive;
+mod provider;
+
+pub use provider::ZipProvider;
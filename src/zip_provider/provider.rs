@@ -0,0 +1,101 @@
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use windows::Win32::Storage::ProjectedFileSystem;
+
+use crate::projfs_provider::{ProjFSProvider, EnumerationState, SeekRead, VirtualizationOptions, VirtualTree, WindowReader, BlockCache, DecompressingReader};
+use super::archive::{build_tree, data_offset, ZipEntry, METHOD_DEFLATED};
+
+const BLOCK_SIZE: u64 = 64 * 1024;
+const CACHED_BLOCKS: usize = 256;
+
+/// Projects the contents of a zip archive. Stored files are read straight out of the archive,
+/// deflated ones are inflated as they are read.
+pub struct ZipProvider {
+    archive: PathBuf,
+    tree: Arc<VirtualTree<Option<ZipEntry>>>,
+    cache: Arc<BlockCache>,
+}
+
+impl ZipProvider {
+    pub fn new(archive: &Path) -> ZipProvider {
+        ZipProvider {
+            archive: archive.to_path_buf(),
+            tree: Default::default(),
+            cache: Arc::new(BlockCache::new(BLOCK_SIZE, CACHED_BLOCKS)),
+        }
+    }
+}
+
+impl ProjFSProvider for ZipProvider {
+    fn init(&mut self, _root: &Path) -> Result<VirtualizationOptions, Box<dyn std::error::Error>> {
+        self.tree = Arc::new(build_tree(&self.archive)?);
+        Ok(VirtualizationOptions::default())
+    }
+
+    fn start(&mut self, _context: ProjectedFileSystem::PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn new_enumeration(&self, _id: windows::core::GUID, file_path: &Path) -> Box<dyn EnumerationState> {
+        self.tree.enumeration(file_path)
+    }
+
+    fn get_placeholder_info(&self, file_path: &Path) -> Result<ProjectedFileSystem::PRJ_PLACEHOLDER_INFO, windows::core::HRESULT> {
+        match self.tree.lookup(file_path) {
+            Some(id) => Ok(self.tree.node(id).info.placeholder_info()),
+            None => Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into()),
+        }
+    }
+
+    fn get_file_data(&self, file_path: &Path) -> Result<Box<dyn SeekRead>, windows::core::HRESULT> {
+        let (entry, size) = match self.tree.lookup(file_path).map(|id| self.tree.node(id)) {
+            Some(node) => match node.content {
+                Some(entry) => (entry, node.info.size),
+                None => {
+                    return Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into());
+                }
+            },
+            None => {
+                return Err(windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into());
+            }
+        };
+        let opened = std::fs::File::open(&self.archive).and_then(|mut f| {
+            let offset = data_offset(&mut f, &entry)?;
+            Ok((f, offset))
+        });
+        let (file, offset) = match opened {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Could not read {file_path:?} from {:?}: {e}", self.archive);
+                return Err(windows::Win32::Foundation::E_FAIL);
+            }
+        };
+        if entry.method != METHOD_DEFLATED {
+            return Ok(Box::new(WindowReader::new(file, offset, size)));
+        }
+
+        let archive = self.archive.clone();
+        let open = move || -> std::io::Result<Box<dyn Read + Send>> {
+            let file = std::fs::File::open(&archive)?;
+            Ok(Box::new(flate2::read::DeflateDecoder::new(WindowReader::new(file, offset, entry.compressed_size))))
+        };
+        Ok(Box::new(DecompressingReader::new(self.cache.clone(), entry.id, size, Box::new(open))))
+    }
+
+    fn query_file_name(&self, file_path: &Path) -> windows::core::HRESULT {
+        match self.tree.lookup(file_path) {
+            Some(_) => windows::Win32::Foundation::S_OK,
+            None => windows::Win32::Foundation::ERROR_FILE_NOT_FOUND.into(),
+        }
+    }
+
+    fn notification(&self, _callbackdata: *const ProjectedFileSystem::PRJ_CALLBACK_DATA, _is_directory: bool, _notification: ProjectedFileSystem::PRJ_NOTIFICATION, _destinationfilename: windows::core::PCWSTR, _operationparameters: *mut ProjectedFileSystem::PRJ_NOTIFICATION_PARAMETERS) -> windows::core::HRESULT {
+        windows::Win32::Foundation::S_OK
+    }
+}